@@ -80,8 +80,8 @@ event_loop! = |ids, names| {
             event_loop!(new_ids, new_names)
         }
         
-        Disconnected(client_id) => {
-            Stdout.line!("Client ${client_id.to_str()} disconnected")
+        Disconnected(client_id, code, _reason) => {
+            Stdout.line!("Client ${client_id.to_str()} disconnected (${code.to_str()})")
             
             # Broadcast leave message
             leave_msg = "{\"type\": \"system\", \"text\": \"Client ${client_id.to_str()} left\"}"
@@ -105,11 +105,140 @@ event_loop! = |ids, names| {
             event_loop!(ids, names)
         }
         
+        ChatMessage(client_id, room, id, text) => {
+            # Only produced once set_envelope_protocol!(True) is called
+            Stdout.line!("Client ${client_id.to_str()} in ${room}: ${text}")
+            broadcast_result = WebServer.broadcast_room!(room, WebServer.envelope!("message", room, id, text))
+            match broadcast_result { Ok({}) => {} Err(_e) => {} }
+            event_loop!(ids, names)
+        }
+        
+        JoinRequest(client_id, room, id) => {
+            match WebServer.join_room!(client_id, room) {
+                Ok({}) => {}
+                Err(msg) => {
+                    match WebServer.send!(client_id, WebServer.envelope!("error", room, id, msg)) { Ok({}) => {} Err(_e) => {} }
+                }
+            }
+            event_loop!(ids, names)
+        }
+        
+        Command(client_id, kind, _room, id, _payload) => {
+            reply = WebServer.envelope!("error", "", id, "Unknown command: ${kind}")
+            match WebServer.send!(client_id, reply) { Ok({}) => {} Err(_e) => {} }
+            event_loop!(ids, names)
+        }
+        
+        AdminCommand(line) => {
+            Stdout.line!("Admin command: ${line}")
+            match line.trim() {
+                "shutdown" => WebServer.shutdown!()
+                "drain" => WebServer.drain!(10000, "Server restarting, reconnect in a moment")
+                _ => Stderr.line!("Unknown admin command: ${line}")
+            }
+            event_loop!(ids, names)
+        }
+        
+        BinaryMessage(client_id, data) => {
+            Stdout.line!("Client ${client_id.to_str()} sent ${data.len().to_str()} bytes of binary data")
+            
+            # Relay binary payloads as-is
+            broadcast_result = WebServer.broadcast_binary!(data)
+            match broadcast_result { Ok({}) => {} Err(_e) => {} }
+            
+            event_loop!(ids, names)
+        }
+        
         Error(msg) => {
             Stderr.line!("Error: ${msg}")
             event_loop!(ids, names)
         }
         
+        HandshakeRequest(client_id, _target, _headers) => {
+            # No handshake policy is installed, so accept everything
+            accept_result = WebServer.accept_handshake!(client_id)
+            match accept_result { Ok({}) => {} Err(_e) => {} }
+            event_loop!(ids, names)
+        }
+        
+        HookMessage(room, sender, text) => {
+            Stdout.line!("Hook message in ${room} from ${sender}: ${text}")
+            event_loop!(ids, names)
+        }
+        
+        HttpRequest(request_id, _method, _target, _headers, _body) => {
+            # No HTTP endpoints are routed to the app yet
+            respond_result = WebServer.respond!(request_id, 404, [], [])
+            match respond_result { Ok({}) => {} Err(_e) => {} }
+            event_loop!(ids, names)
+        }
+        
+        Delivered(_message_id) => event_loop!(ids, names)
+        
+        DeliveryFailed(message_id, reason) => {
+            Stderr.line!("Message ${message_id.to_str()} not delivered: ${reason}")
+            event_loop!(ids, names)
+        }
+        
+        Idle => event_loop!(ids, names)
+        
+        Resumed(client_id) => {
+            Stdout.line!("Client ${client_id.to_str()} resumed its session")
+            event_loop!(ids, names)
+        }
+        
+        GraphqlSubscribe(client_id, id, _payload) => {
+            # The example app has no GraphQL schema
+            gql_result = WebServer.graphql_error!(client_id, id, "[{\"message\":\"No schema\"}]")
+            match gql_result { Ok({}) => {} Err(_e) => {} }
+            event_loop!(ids, names)
+        }
+        
+        GraphqlComplete(_client_id, _id) => event_loop!(ids, names)
+        
+        RpcCall(client_id, id, _method, _params) => {
+            # The example app implements no methods
+            rpc_result = WebServer.rpc_error!(client_id, id, -32601, "Method not found")
+            match rpc_result { Ok({}) => {} Err(_e) => {} }
+            event_loop!(ids, names)
+        }
+        
+        RateLimited(client_id, reason) => {
+            Stderr.line!("Rate limited client ${client_id.to_str()}: ${reason}")
+            event_loop!(ids, names)
+        }
+        
+        Banned(client_id, address) => {
+            Stderr.line!("Banned client ${client_id.to_str()} (${address})")
+            event_loop!(ids, names)
+        }
+        
+        Muted(_client_id, _remaining_ms) => event_loop!(ids, names)
+        
+        Overflow(dropped) => {
+            Stderr.line!("Event queue full, dropped ${dropped.to_str()} events")
+            event_loop!(ids, names)
+        }
+        
+        PermissionDenied(_client_id, _room, _action) => event_loop!(ids, names)
+        
+        FileUploaded(_user, url, size, _mime) => {
+            Stdout.line!("Uploaded ${url} (${size.to_str()} bytes)")
+            event_loop!(ids, names)
+        }
+        
+        Flagged(client_id, reason, _text) => {
+            Stderr.line!("Held back a message from client ${client_id.to_str()}: ${reason}")
+            event_loop!(ids, names)
+        }
+        
+        Timer(_timer_id, _tag) => event_loop!(ids, names)
+        
+        UpstreamMessage(conn_id, text) => {
+            Stdout.line!("Upstream ${conn_id.to_str()}: ${text}")
+            event_loop!(ids, names)
+        }
+        
         Shutdown => {
             Stdout.line!("Server shutting down")
             Ok({})
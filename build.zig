@@ -60,10 +60,12 @@ const all_targets = [_]RocTarget{
 
 pub fn build(b: *std.Build) void {
     const optimize = b.standardOptimizeOption(.{});
+    const embed_static = b.option(bool, "embed-static", "Compile the static/ directory into the host library") orelse false;
 
     // Get the roc dependency and its builtins module
     const roc_dep = b.dependency("roc", .{});
     const builtins_module = roc_dep.module("builtins");
+    const embedded_module = addEmbeddedStatic(b, embed_static);
 
     // Cleanup step
     const cleanup_step = b.step("clean", "Remove all built library files");
@@ -89,7 +91,7 @@ pub fn build(b: *std.Build) void {
         target_step.dependOn(cleanup_step);
 
         const target = b.resolveTargetQuery(roc_target.toZigTarget());
-        const host_lib = buildHostLib(b, target, optimize, builtins_module);
+        const host_lib = buildHostLib(b, target, optimize, builtins_module, embedded_module);
 
         const copy_target = b.addUpdateSourceFiles();
         copy_target.addCopyFileToSource(
@@ -115,7 +117,7 @@ pub fn build(b: *std.Build) void {
         return;
     };
 
-    const native_lib = buildHostLib(b, native_target, optimize, builtins_module);
+    const native_lib = buildHostLib(b, native_target, optimize, builtins_module, embedded_module);
     b.installArtifact(native_lib);
 
     const copy_native = b.addUpdateSourceFiles();
@@ -136,6 +138,7 @@ pub fn build(b: *std.Build) void {
             .optimize = optimize,
             .imports = &.{
                 .{ .name = "builtins", .module = builtins_module },
+                .{ .name = "embedded_static", .module = embedded_module },
             },
         }),
     });
@@ -145,6 +148,36 @@ pub fn build(b: *std.Build) void {
 
     const run_host_tests = b.addRunArtifact(host_tests);
     test_step.dependOn(&run_host_tests.step);
+
+    // The frame codec and the request parser have no dependencies and are tested
+    // on their own; `zig build test --fuzz` keeps feeding their fuzz tests new inputs
+    for ([_][]const u8{ "platform/codec.zig", "platform/http.zig" }) |path| {
+        const parser_tests = b.addTest(.{
+            .root_module = b.createModule(.{
+                .root_source_file = b.path(path),
+                .target = native_target,
+                .optimize = optimize,
+            }),
+        });
+
+        const run_parser_tests = b.addRunArtifact(parser_tests);
+        test_step.dependOn(&run_parser_tests.step);
+    }
+
+    // Benchmarks always build with ReleaseFast, whatever -Doptimize says
+    const bench_step = b.step("bench", "Time frame encoding, decoding and request parsing");
+
+    const bench_exe = b.addExecutable(.{
+        .name = "bench",
+        .root_module = b.createModule(.{
+            .root_source_file = b.path("platform/bench.zig"),
+            .target = native_target,
+            .optimize = .ReleaseFast,
+        }),
+    });
+
+    const run_bench = b.addRunArtifact(bench_exe);
+    bench_step.dependOn(&run_bench.step);
 }
 
 fn detectNativeRocTarget(target: std.Target) ?RocTarget {
@@ -202,6 +235,7 @@ fn buildHostLib(
     target: std.Build.ResolvedTarget,
     optimize: std.builtin.OptimizeMode,
     builtins_module: *std.Build.Module,
+    embedded_module: *std.Build.Module,
 ) *std.Build.Step.Compile {
     const host_lib = b.addLibrary(.{
         .name = "host",
@@ -214,6 +248,7 @@ fn buildHostLib(
             .pic = true,
             .imports = &.{
                 .{ .name = "builtins", .module = builtins_module },
+                .{ .name = "embedded_static", .module = embedded_module },
             },
         }),
     });
@@ -225,3 +260,42 @@ fn buildHostLib(
 
     return host_lib;
 }
+
+/// Generate the `embedded_static` module: a table of every file under static/
+/// (via @embedFile) when `embed` is set, or an empty table otherwise.
+fn addEmbeddedStatic(b: *std.Build, embed: bool) *std.Build.Module {
+    const wf = b.addWriteFiles();
+
+    var source = std.ArrayListUnmanaged(u8){};
+    source.appendSlice(b.allocator,
+        \\pub const File = struct { path: []const u8, content: []const u8 };
+        \\pub const files = [_]File{
+        \\
+    ) catch @panic("OOM");
+
+    if (embed) {
+        var dir = b.build_root.handle.openDir("static", .{ .iterate = true }) catch |err| {
+            std.debug.print("embed-static: cannot open static/: {}\n", .{err});
+            @panic("embed-static requires a static/ directory");
+        };
+        defer dir.close();
+
+        var walker = dir.walk(b.allocator) catch @panic("OOM");
+        defer walker.deinit();
+        while (walker.next() catch @panic("walk static/ failed")) |entry| {
+            if (entry.kind != .file) continue;
+            const rel = std.mem.replaceOwned(u8, b.allocator, entry.path, "\\", "/") catch @panic("OOM");
+            _ = wf.addCopyFile(b.path(b.pathJoin(&.{ "static", entry.path })), b.pathJoin(&.{ "files", rel }));
+            source.appendSlice(b.allocator, b.fmt(
+                "    .{{ .path = \"{s}\", .content = @embedFile(\"files/{s}\") }},\n",
+                .{ rel, rel },
+            )) catch @panic("OOM");
+        }
+    }
+
+    source.appendSlice(b.allocator, "};\n") catch @panic("OOM");
+
+    return b.createModule(.{
+        .root_source_file = wf.add("embedded_static.zig", source.items),
+    });
+}
@@ -0,0 +1,4 @@
+Clock :: [].{
+    now_utc! : () => U64
+    monotonic_millis! : () => U64
+}
@@ -0,0 +1,4 @@
+Env :: [].{
+    get! : Str => [Ok(Str), Err(Str)]
+    args! : () => List(Str)
+}
@@ -0,0 +1,5 @@
+File :: [].{
+    read_utf8! : Str => [Ok(Str), Err(Str)]
+    write_utf8! : Str, Str => [Ok({}), Err(Str)]
+    append! : Str, Str => [Ok({}), Err(Str)]
+}
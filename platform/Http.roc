@@ -0,0 +1,3 @@
+Http :: [].{
+    fetch! : Str, Str, List((Str, Str)), List(U8) => [Ok({ status : U16, headers : List((Str, Str)), body : List(U8) }), Err(Str)]
+}
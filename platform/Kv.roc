@@ -0,0 +1,6 @@
+Kv :: [].{
+    get! : Str => [Ok(Str), Err(Str)]
+    set! : Str, Str => [Ok({}), Err(Str)]
+    delete! : Str => [Ok({}), Err(Str)]
+    list_prefix! : Str => [Ok(List(Str)), Err(Str)]
+}
@@ -0,0 +1,4 @@
+Random :: [].{
+    u64! : () => U64
+    bytes! : U64 => List(U8)
+}
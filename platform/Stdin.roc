@@ -0,0 +1,3 @@
+Stdin :: [].{
+    line! : () => [Ok(Str), Err(Str)]
+}
@@ -1,8 +1,101 @@
 WebServer :: [].{
     listen! : U16 => [Ok({}), Err(Str)]
+    listen_with_config! : Str => [Ok({}), Err(Str)]
     run! : () => [Ok({}), Err(Str)]
-    accept! : () => [Connected(U64), Disconnected(U64), Message(U64, Str), Error(Str), Shutdown]
+    accept! : () => [AdminCommand(Str), Banned(U64, Str), ChatMessage(U64, Str, Str, Str), Command(U64, Str, Str, Str, Str), Connected(U64), Delivered(U64), DeliveryFailed(U64, Str), Disconnected(U64, U16, Str), Message(U64, Str), Muted(U64, U64), Overflow(U64), PermissionDenied(U64, Str, Str), BinaryMessage(U64, List(U8)), Error(Str), FileUploaded(Str, Str, U64, Str), Flagged(U64, Str, Str), GraphqlComplete(U64, Str), GraphqlSubscribe(U64, Str, Str), HandshakeRequest(U64, Str, List((Str, Str))), HookMessage(Str, Str, Str), HttpRequest(U64, Str, Str, List((Str, Str)), List(U8)), Idle, JoinRequest(U64, Str, Str), RateLimited(U64, Str), Resumed(U64), RpcCall(U64, Str, Str, Str), Shutdown, Timer(U64, Str), UpstreamMessage(U64, Str)]
+    try_accept! : () => [AdminCommand(Str), Banned(U64, Str), ChatMessage(U64, Str, Str, Str), Command(U64, Str, Str, Str, Str), Connected(U64), Delivered(U64), DeliveryFailed(U64, Str), Disconnected(U64, U16, Str), Message(U64, Str), Muted(U64, U64), Overflow(U64), PermissionDenied(U64, Str, Str), BinaryMessage(U64, List(U8)), Error(Str), FileUploaded(Str, Str, U64, Str), Flagged(U64, Str, Str), GraphqlComplete(U64, Str), GraphqlSubscribe(U64, Str, Str), HandshakeRequest(U64, Str, List((Str, Str))), HookMessage(Str, Str, Str), HttpRequest(U64, Str, Str, List((Str, Str)), List(U8)), Idle, JoinRequest(U64, Str, Str), RateLimited(U64, Str), Resumed(U64), RpcCall(U64, Str, Str, Str), Shutdown, Timer(U64, Str), UpstreamMessage(U64, Str)]
+    accept_timeout! : U64 => [AdminCommand(Str), Banned(U64, Str), ChatMessage(U64, Str, Str, Str), Command(U64, Str, Str, Str, Str), Connected(U64), Delivered(U64), DeliveryFailed(U64, Str), Disconnected(U64, U16, Str), Message(U64, Str), Muted(U64, U64), Overflow(U64), PermissionDenied(U64, Str, Str), BinaryMessage(U64, List(U8)), Error(Str), FileUploaded(Str, Str, U64, Str), Flagged(U64, Str, Str), GraphqlComplete(U64, Str), GraphqlSubscribe(U64, Str, Str), HandshakeRequest(U64, Str, List((Str, Str))), HookMessage(Str, Str, Str), HttpRequest(U64, Str, Str, List((Str, Str)), List(U8)), Idle, JoinRequest(U64, Str, Str), RateLimited(U64, Str), Resumed(U64), RpcCall(U64, Str, Str, Str), Shutdown, Timer(U64, Str), UpstreamMessage(U64, Str)]
     send! : U64, Str => [Ok({}), Err(Str)]
+    send_binary! : U64, List(U8) => [Ok({}), Err(Str)]
+    send_many! : List(U64), Str => List((U64, Str))
+    send_with_ack! : U64, Str => [Ok(U64), Err(Str)]
     broadcast! : Str => [Ok({}), Err(Str)]
+    broadcast_binary! : List(U8) => [Ok({}), Err(Str)]
+    broadcast_host! : Str, Str => [Ok({}), Err(Str)]
+    set_admin_token! : Str => {}
+    set_base_path! : Str => [Ok({}), Err(Str)]
+    set_cache_control! : Str => [Ok({}), Err(Str)]
+    set_directory_listing! : Bool => {}
+    set_error_page! : U16, Str => [Ok({}), Err(Str)]
+    set_virtual_host! : Str, Str => [Ok({}), Err(Str)]
+    set_client_data! : U64, Str, Str => [Ok({}), Err(Str)]
+    get_client_data! : U64, Str => [Ok(Str), Err(Str)]
+    set_role! : U64, Str, Str => [Ok({}), Err(Str)]
+    get_role! : U64, Str => [Ok(Str), Err(Str)]
+    join_room! : U64, Str => [Ok({}), Err(Str)]
+    leave_room! : U64, Str => {}
+    broadcast_room! : Str, Str => [Ok({}), Err(Str)]
+    publish_room! : Str, Str => [Ok(U64), Err(Str)]
+    replay_since! : U64, Str, U64 => [Ok(U64), Err(Str)]
+    notify_room! : Str, Str, Str => [Ok({}), Err(Str)]
+    set_room_history! : U64, U64 => {}
+    set_history_store! : Str => [Ok({}), Err(Str)]
+    room_history! : Str, U64 => [Ok(List({ client_id : U64, received_ms : U64, room : Str, text : Str })), Err(Str)]
+    set_room_policy! : Str, Str, Str => [Ok({}), Err(Str)]
+    set_uploads! : Str, U64, List(Str) => [Ok({}), Err(Str)]
+    list_rooms! : () => List(Str)
+    clients! : () => List(U64)
+    stats! : () => { bytes_in : U64, bytes_out : U64, clients : U64, connections : U64, messages_in : U64, messages_out : U64, queued_events : U64, rooms : U64, uptime_ms : U64 }
+    client_stats! : U64 => [Ok({ bytes_in : U64, bytes_out : U64, connected_ms : U64, last_activity_ms : U64, messages_in : U64, messages_out : U64, queued_bytes : U64 }), Err(Str)]
+    allow_origin! : Str => [Ok({}), Err(Str)]
+    add_route! : Str, Str, Str => [Ok({}), Err(Str)]
+    add_subprotocol! : Str => [Ok({}), Err(Str)]
+    add_webhook! : Str, List(Str), Str => [Ok({}), Err(Str)]
+    allow_admin_ip! : Str => [Ok({}), Err(Str)]
+    allow_ip! : Str => [Ok({}), Err(Str)]
+    deny_ip! : Str => [Ok({}), Err(Str)]
+    ban_ip! : Str, U64 => [Ok({}), Err(Str)]
+    unban_ip! : Str => [Ok({}), Err(Str)]
+    ban_client! : U64, U64 => [Ok({}), Err(Str)]
+    mute_client! : U64, U64 => [Ok({}), Err(Str)]
+    unmute_client! : U64 => [Ok({}), Err(Str)]
+    trust_proxy! : Str => [Ok({}), Err(Str)]
+    set_envelope_protocol! : Bool => {}
+    envelope! : Str, Str, Str, Str => Str
+    set_rpc_protocol! : Bool => {}
+    rpc_respond! : U64, Str, Str => [Ok({}), Err(Str)]
+    rpc_error! : U64, Str, I64, Str => [Ok({}), Err(Str)]
+    graphql_next! : U64, Str, Str => [Ok({}), Err(Str)]
+    graphql_error! : U64, Str, Str => [Ok({}), Err(Str)]
+    graphql_complete! : U64, Str => [Ok({}), Err(Str)]
+    set_socketio! : Bool => {}
+    socketio_emit! : U64, Str, Str => [Ok({}), Err(Str)]
+    socketio_ack! : U64, Str, Str => [Ok({}), Err(Str)]
+    set_handshake_policy! : Bool => {}
+    set_handshake_timeout! : U64 => {}
+    set_hook_secret! : Str => {}
+    accept_handshake! : U64 => [Ok({}), Err(Str)]
+    reject_handshake! : U64, U16 => [Ok({}), Err(Str)]
+    require_auth! : Str => {}
+    create_session! : Str, U64 => [Ok(Str), Err(Str)]
+    client_session! : U64 => [Ok(Str), Err(Str)]
+    set_session_secret! : Str => [Ok({}), Err(Str)]
+    route_http! : Str => [Ok({}), Err(Str)]
+    route_websocket! : Str, Str => [Ok({}), Err(Str)]
+    respond! : U64, U16, List((Str, Str)), List(U8) => [Ok({}), Err(Str)]
+    configure! : { static_dir : Str, index_file : Str, spa_fallback : Bool } => [Ok({}), Err(Str)]
+    reload_config! : Str => [Ok({}), Err(Str)]
+    connect! : Str => [Ok(U64), Err(Str)]
     close! : U64 => {}
+    set_timer! : U64, Str => [Ok(U64), Err(Str)]
+    cancel_timer! : U64 => {}
+    set_keepalive! : U64, U64 => {}
+    set_log_level! : Str => [Ok({}), Err(Str)]
+    set_max_body_size! : U64 => {}
+    set_max_connections! : U64 => {}
+    set_max_frame_size! : U64 => {}
+    set_max_header_size! : U64 => {}
+    set_max_message_size! : U64 => {}
+    set_message_rate! : U64, U64 => {}
+    set_connect_rate! : U64, U64 => {}
+    set_mime_type! : Str, Str => [Ok({}), Err(Str)]
+    set_resume_grace! : U64 => {}
+    set_send_queue_limit! : U64 => {}
+    set_event_queue_limit! : U64, Str => [Ok({}), Err(Str)]
+    set_socket_options! : { no_delay : Bool, keepalive : Bool, send_buffer : U64, recv_buffer : U64 } => {}
+    set_static_buffer! : U64 => {}
+    set_static_cache! : U64 => {}
+    drain! : U64, Str => {}
+    shutdown! : () => {}
+    watch_stdin! : Bool => {}
 }
@@ -0,0 +1,129 @@
+//! Timing loops for the frame codec and the request parser, run with `zig build bench`
+//! Each case repeats for about a fifth of a second and reports the time per call
+const std = @import("std");
+const codec = @import("codec.zig");
+const http = @import("http.zig");
+
+const allocator = std.heap.page_allocator;
+
+/// How long each case keeps running
+const run_ns = 200 * std.time.ns_per_ms;
+/// Calls between clock reads, so reading the clock costs next to nothing
+const batch = 256;
+
+const upgrade_request = "GET /chat?room=general HTTP/1.1\r\n" ++
+    "Host: chat.example.com\r\n" ++
+    "User-Agent: Mozilla/5.0 (X11; Linux x86_64; rv:128.0) Gecko/20100101 Firefox/128.0\r\n" ++
+    "Accept: */*\r\n" ++
+    "Accept-Language: en-US,en;q=0.5\r\n" ++
+    "Accept-Encoding: gzip, deflate, br\r\n" ++
+    "Sec-WebSocket-Version: 13\r\n" ++
+    "Origin: https://chat.example.com\r\n" ++
+    "Sec-WebSocket-Extensions: permessage-deflate\r\n" ++
+    "Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n" ++
+    "Connection: keep-alive, Upgrade\r\n" ++
+    "Cookie: session=0123456789abcdef0123456789abcdef\r\n" ++
+    "Upgrade: websocket\r\n\r\n";
+
+const chunked_request = "POST /send HTTP/1.1\r\n" ++
+    "Host: chat.example.com\r\n" ++
+    "Content-Type: text/plain\r\n" ++
+    "Transfer-Encoding: chunked\r\n\r\n" ++
+    "40\r\n" ++ "x" ** 64 ++ "\r\n" ++
+    "40\r\n" ++ "y" ** 64 ++ "\r\n" ++
+    "10;ext=1\r\n" ++ "z" ** 16 ++ "\r\n" ++
+    "0\r\n\r\n";
+
+pub fn main() !void {
+    var small: [125]u8 = undefined;
+    var large: [64 * 1024]u8 = undefined;
+    for (&small, 0..) |*byte, i| byte.* = @truncate(i);
+    for (&large, 0..) |*byte, i| byte.* = @truncate(i);
+    const mask = [4]u8{ 0x37, 0xFA, 0x21, 0x3D };
+
+    var out = std.ArrayListUnmanaged(u8){};
+    defer out.deinit(allocator);
+
+    try bench("encode 125 B", small.len, Encode{ .out = &out, .payload = &small, .mask = null });
+    try bench("encode 64 KiB", large.len, Encode{ .out = &out, .payload = &large, .mask = null });
+    try bench("encode 125 B masked", small.len, Encode{ .out = &out, .payload = &small, .mask = mask });
+    try bench("encode 64 KiB masked", large.len, Encode{ .out = &out, .payload = &large, .mask = mask });
+
+    // decode unmasks in place, so the payload flips between masked and clear on
+    // alternate calls; the work per call is the same either way
+    const small_frame = try allocator.dupe(u8, try codec.encode(allocator, &out, .text, &small, mask));
+    defer allocator.free(small_frame);
+    const large_frame = try allocator.dupe(u8, try codec.encode(allocator, &out, .binary, &large, mask));
+    defer allocator.free(large_frame);
+    try bench("decode 125 B masked", small.len, Decode{ .frame = small_frame });
+    try bench("decode 64 KiB masked", large.len, Decode{ .frame = large_frame });
+
+    try bench("parse upgrade request", upgrade_request.len, ParseHead{ .raw = upgrade_request });
+    // The chunked body is decoded in place, so each call starts from a fresh copy
+    var scratch: [chunked_request.len]u8 = undefined;
+    try bench("parse chunked request", chunked_request.len, ParseChunked{ .raw = chunked_request, .scratch = &scratch });
+}
+
+const Encode = struct {
+    out: *std.ArrayListUnmanaged(u8),
+    payload: []const u8,
+    mask: ?[4]u8,
+
+    fn run(self: Encode) !void {
+        const frame = try codec.encode(allocator, self.out, .binary, self.payload, self.mask);
+        std.mem.doNotOptimizeAway(frame.ptr);
+    }
+};
+
+const Decode = struct {
+    frame: []u8,
+
+    fn run(self: Decode) !void {
+        const frame = (try codec.decode(self.frame, .{ .masked = true, .max_payload = 1 << 20 })) orelse return error.Incomplete;
+        std.mem.doNotOptimizeAway(frame.payload.ptr);
+    }
+};
+
+const ParseHead = struct {
+    raw: []const u8,
+
+    fn run(self: ParseHead) !void {
+        const request = (try http.parseHead(self.raw)) orelse return error.Incomplete;
+        if (!request.isWebSocketUpgrade()) return error.NotUpgrade;
+        std.mem.doNotOptimizeAway(request.header("Sec-WebSocket-Key"));
+    }
+};
+
+const ParseChunked = struct {
+    raw: []const u8,
+    scratch: []u8,
+
+    fn run(self: ParseChunked) !void {
+        @memcpy(self.scratch, self.raw);
+        var request = (try http.parseHead(self.scratch)) orelse return error.Incomplete;
+        if (!try http.readBody(self.scratch, &request, 1 << 20)) return error.Incomplete;
+        std.mem.doNotOptimizeAway(request.body.ptr);
+    }
+};
+
+/// Call `case.run()` for run_ns and print the time per call and the throughput
+/// over `bytes`, the input each call handles
+fn bench(name: []const u8, bytes: usize, case: anytype) !void {
+    // Warm up caches and let the output buffer reach its full size
+    for (0..batch) |_| try case.run();
+
+    var timer = try std.time.Timer.start();
+    var calls: u64 = 0;
+    var elapsed: u64 = 0;
+    while (elapsed < run_ns) {
+        for (0..batch) |_| try case.run();
+        calls += batch;
+        elapsed = timer.read();
+    }
+
+    const ns_per_call = @as(f64, @floatFromInt(elapsed)) / @as(f64, @floatFromInt(calls));
+    const mb_per_s = @as(f64, @floatFromInt(bytes)) / ns_per_call * std.time.ns_per_s / (1024 * 1024);
+    var line_buf: [128]u8 = undefined;
+    const line = try std.fmt.bufPrint(&line_buf, "{s:<24} {d:>10.1} ns/call {d:>10.1} MiB/s\n", .{ name, ns_per_call, mb_per_s });
+    try std.fs.File.stdout().writeAll(line);
+}
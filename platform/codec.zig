@@ -0,0 +1,404 @@
+//! WebSocket frame codec (RFC 6455 section 5)
+//! Parses, validates, unmasks and encodes frames in plain byte buffers, with no
+//! knowledge of sockets or clients
+const std = @import("std");
+
+pub const Opcode = enum(u4) {
+    continuation = 0x0,
+    text = 0x1,
+    binary = 0x2,
+    close = 0x8,
+    ping = 0x9,
+    pong = 0xA,
+    _,
+
+    pub fn isControl(self: Opcode) bool {
+        return @intFromEnum(self) & 0x8 != 0;
+    }
+};
+
+/// Control frames carry at most this many payload bytes
+pub const max_control_payload = 125;
+
+/// Longest frame header: 2 bytes, 8 of extended length and a 4 byte mask
+pub const max_header_len = 14;
+
+/// Fields of a frame header; header_len covers the extended length and mask
+pub const Header = struct {
+    fin: bool,
+    rsv: u8,
+    opcode: Opcode,
+    masked: bool,
+    mask: [4]u8,
+    payload_len: u64,
+    header_len: usize,
+};
+
+/// Parse the frame header at the start of `buf`, or null if it has not fully arrived
+pub fn parseHeader(buf: []const u8) ?Header {
+    if (buf.len < 2) return null;
+
+    var header = Header{
+        .fin = (buf[0] & 0x80) != 0,
+        .rsv = buf[0] & 0x70,
+        .opcode = @enumFromInt(@as(u4, @truncate(buf[0] & 0x0F))),
+        .masked = (buf[1] & 0x80) != 0,
+        .mask = undefined,
+        .payload_len = buf[1] & 0x7F,
+        .header_len = 2,
+    };
+
+    if (header.payload_len == 126) {
+        if (buf.len < 4) return null;
+        header.payload_len = std.mem.readInt(u16, buf[2..4], .big);
+        header.header_len = 4;
+    } else if (header.payload_len == 127) {
+        if (buf.len < 10) return null;
+        header.payload_len = std.mem.readInt(u64, buf[2..10], .big);
+        header.header_len = 10;
+    }
+
+    if (header.masked) {
+        if (buf.len < header.header_len + 4) return null;
+        @memcpy(&header.mask, buf[header.header_len .. header.header_len + 4]);
+        header.header_len += 4;
+    }
+    return header;
+}
+
+/// What a decoded frame is checked against
+pub const Limits = struct {
+    /// Whether frames must be masked: true for a server reading a client, false for a client
+    masked: bool,
+    /// Largest payload accepted
+    max_payload: u64,
+};
+
+pub const DecodeError = error{ ProtocolError, PayloadTooLarge };
+
+/// Check a header against the protocol rules and `limits`
+pub fn validate(header: Header, limits: Limits) DecodeError!void {
+    // No extensions are negotiated, so the reserved bits must be clear
+    if (header.rsv != 0) return error.ProtocolError;
+    switch (header.opcode) {
+        .continuation, .text, .binary, .close, .ping, .pong => {},
+        _ => return error.ProtocolError,
+    }
+    // Clients must mask every frame, servers must never mask
+    if (header.masked != limits.masked) return error.ProtocolError;
+    // Control frames cannot be fragmented and carry at most 125 bytes
+    if (header.opcode.isControl() and (!header.fin or header.payload_len > max_control_payload)) return error.ProtocolError;
+    if (header.payload_len > limits.max_payload) return error.PayloadTooLarge;
+}
+
+/// A complete frame at the front of a buffer
+pub const Frame = struct {
+    fin: bool,
+    opcode: Opcode,
+    /// Unmasked in place; valid until the buffer changes
+    payload: []u8,
+    /// Bytes of the buffer the frame occupies
+    len: usize,
+};
+
+/// Decode the frame at the start of `buf`, or null until all of it has arrived.
+/// Invalid and oversize frames are reported as soon as their header is in.
+pub fn decode(buf: []u8, limits: Limits) DecodeError!?Frame {
+    const header = parseHeader(buf) orelse return null;
+    try validate(header, limits);
+
+    // Compared before adding so a huge declared length cannot overflow
+    if (header.payload_len > buf.len - header.header_len) return null;
+    const len = header.header_len + @as(usize, @intCast(header.payload_len));
+
+    const payload = buf[header.header_len..len];
+    if (header.masked) applyMask(payload, header.mask);
+    return .{ .fin = header.fin, .opcode = header.opcode, .payload = payload, .len = len };
+}
+
+/// XOR `bytes` with the repeating 4-byte mask, which both masks and unmasks
+pub fn applyMask(bytes: []u8, mask: [4]u8) void {
+    for (bytes, 0..) |*byte, i| {
+        byte.* ^= mask[i % 4];
+    }
+}
+
+/// Write a final frame's header, masked with `mask` if given, and return its length
+pub fn writeHeader(header: *[max_header_len]u8, opcode: Opcode, payload_len: usize, mask: ?[4]u8) usize {
+    header[0] = 0x80 | @as(u8, @intFromEnum(opcode)); // FIN + opcode
+    var len: usize = undefined;
+    if (payload_len < 126) {
+        header[1] = @intCast(payload_len);
+        len = 2;
+    } else if (payload_len <= 65535) {
+        header[1] = 126;
+        std.mem.writeInt(u16, header[2..4], @intCast(payload_len), .big);
+        len = 4;
+    } else {
+        header[1] = 127;
+        std.mem.writeInt(u64, header[2..10], payload_len, .big);
+        len = 10;
+    }
+    if (mask) |key| {
+        header[1] |= 0x80;
+        @memcpy(header[len .. len + 4], &key);
+        len += 4;
+    }
+    return len;
+}
+
+/// Header and payload of a final frame, assembled in `out` and masked with
+/// `mask` if given (frames sent to a server must be)
+pub fn encode(allocator: std.mem.Allocator, out: *std.ArrayListUnmanaged(u8), opcode: Opcode, payload: []const u8, mask: ?[4]u8) ![]const u8 {
+    var header: [max_header_len]u8 = undefined;
+    const header_len = writeHeader(&header, opcode, payload.len, mask);
+
+    out.clearRetainingCapacity();
+    try out.ensureTotalCapacity(allocator, header_len + payload.len);
+    out.appendSliceAssumeCapacity(header[0..header_len]);
+    out.appendSliceAssumeCapacity(payload);
+    if (mask) |key| applyMask(out.items[header_len..], key);
+    return out.items;
+}
+
+/// Whether a peer may send this status code in a Close frame
+pub fn isValidCloseCode(code: u16) bool {
+    return switch (code) {
+        1000...1003, 1007...1014, 3000...4999 => true,
+        else => false,
+    };
+}
+
+/// Status and reason of a Close frame
+pub const Close = struct {
+    /// Null when the frame carried no status
+    code: ?u16,
+    /// Points into the payload
+    reason: []const u8,
+};
+
+/// Read a Close frame's payload: empty, or a valid status code and a UTF-8 reason
+pub fn parseClose(payload: []const u8) error{ ProtocolError, InvalidPayload }!Close {
+    if (payload.len == 0) return .{ .code = null, .reason = "" };
+    if (payload.len == 1) return error.ProtocolError;
+    const code = std.mem.readInt(u16, payload[0..2], .big);
+    if (!isValidCloseCode(code)) return error.ProtocolError;
+    if (!std.unicode.utf8ValidateSlice(payload[2..])) return error.InvalidPayload;
+    return .{ .code = code, .reason = payload[2..] };
+}
+
+/// Build a Close frame's payload in `buf`, cutting the reason to fit without
+/// splitting a UTF-8 sequence, which the peer would reject
+pub fn writeClose(buf: *[max_control_payload]u8, code: u16, reason: []const u8) []const u8 {
+    std.mem.writeInt(u16, buf[0..2], code, .big);
+    var reason_len = @min(reason.len, buf.len - 2);
+    // Back up over continuation bytes (10xxxxxx) to the start of the cut code point
+    if (reason_len < reason.len) {
+        while (reason_len > 0 and reason[reason_len] & 0xC0 == 0x80) reason_len -= 1;
+    }
+    @memcpy(buf[2 .. 2 + reason_len], reason[0..reason_len]);
+    return buf[0 .. 2 + reason_len];
+}
+
+const testing = std.testing;
+
+const server_limits = Limits{ .masked = true, .max_payload = 1 << 20 };
+const client_limits = Limits{ .masked = false, .max_payload = 1 << 20 };
+
+test "parseHeader waits for the whole header" {
+    // Masked binary frame with a 64-bit length
+    const frame = [_]u8{ 0x82, 0xFF, 0, 0, 0, 0, 0, 1, 0, 0, 1, 2, 3, 4 };
+    for (0..frame.len) |len| {
+        try testing.expect(parseHeader(frame[0..len]) == null);
+    }
+    const header = parseHeader(&frame).?;
+    try testing.expectEqual(@as(u64, 65536), header.payload_len);
+    try testing.expectEqual(@as(usize, 14), header.header_len);
+    try testing.expectEqualSlices(u8, &.{ 1, 2, 3, 4 }, &header.mask);
+}
+
+test "parseHeader reads 7-bit, 16-bit and 64-bit lengths" {
+    const short = parseHeader(&.{ 0x81, 125 }).?;
+    try testing.expectEqual(@as(u64, 125), short.payload_len);
+    try testing.expectEqual(@as(usize, 2), short.header_len);
+    try testing.expect(short.fin);
+    try testing.expectEqual(Opcode.text, short.opcode);
+
+    const medium = parseHeader(&.{ 0x02, 126, 0x01, 0x00 }).?;
+    try testing.expectEqual(@as(u64, 256), medium.payload_len);
+    try testing.expectEqual(@as(usize, 4), medium.header_len);
+    try testing.expect(!medium.fin);
+
+    const long = parseHeader(&.{ 0x82, 127, 0, 0, 0, 1, 0, 0, 0, 0 }).?;
+    try testing.expectEqual(@as(u64, 1 << 32), long.payload_len);
+    try testing.expectEqual(@as(usize, 10), long.header_len);
+}
+
+test "validate rejects what RFC 6455 forbids" {
+    const ok = parseHeader(&.{ 0x81, 0x80, 0, 0, 0, 0 }).?;
+    try validate(ok, server_limits);
+
+    // Reserved bits without a negotiated extension
+    for ([_]u8{ 0x40, 0x20, 0x10 }) |rsv| {
+        var header = ok;
+        header.rsv = rsv;
+        try testing.expectError(error.ProtocolError, validate(header, server_limits));
+    }
+
+    // Reserved opcodes
+    for ([_]u4{ 0x3, 0x7, 0xB, 0xF }) |opcode| {
+        var header = ok;
+        header.opcode = @enumFromInt(opcode);
+        try testing.expectError(error.ProtocolError, validate(header, server_limits));
+    }
+
+    // Masking must match the direction
+    try testing.expectError(error.ProtocolError, validate(ok, client_limits));
+    var unmasked = ok;
+    unmasked.masked = false;
+    try testing.expectError(error.ProtocolError, validate(unmasked, server_limits));
+
+    // Control frames: never fragmented, at most 125 bytes
+    var ping = ok;
+    ping.opcode = .ping;
+    ping.payload_len = max_control_payload;
+    try validate(ping, server_limits);
+    ping.payload_len = max_control_payload + 1;
+    try testing.expectError(error.ProtocolError, validate(ping, server_limits));
+    ping.payload_len = 0;
+    ping.fin = false;
+    try testing.expectError(error.ProtocolError, validate(ping, server_limits));
+
+    var big = ok;
+    big.payload_len = server_limits.max_payload + 1;
+    try testing.expectError(error.PayloadTooLarge, validate(big, server_limits));
+}
+
+test "decode reports bad frames before the payload arrives" {
+    var buf = [_]u8{ 0x83, 0x85, 1, 2, 3, 4 };
+    try testing.expectError(error.ProtocolError, decode(&buf, server_limits));
+
+    var huge = [_]u8{ 0x82, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 1, 2, 3, 4 };
+    try testing.expectError(error.PayloadTooLarge, decode(&huge, server_limits));
+    try testing.expect(try decode(&huge, .{ .masked = true, .max_payload = std.math.maxInt(u64) }) == null);
+}
+
+test "encode and decode round-trip" {
+    const allocator = testing.allocator;
+    var out = std.ArrayListUnmanaged(u8){};
+    defer out.deinit(allocator);
+
+    const payload = try allocator.alloc(u8, 70000);
+    defer allocator.free(payload);
+    for (payload, 0..) |*byte, i| byte.* = @truncate(i * 7);
+
+    for ([_]usize{ 0, 1, 125, 126, 65535, 65536, 70000 }) |len| {
+        for ([_]?[4]u8{ null, .{ 0xA1, 0xB2, 0xC3, 0xD4 } }) |mask| {
+            const encoded = try encode(allocator, &out, .binary, payload[0..len], mask);
+            const frame = (try decode(out.items, .{ .masked = mask != null, .max_payload = 1 << 20 })).?;
+            try testing.expectEqual(encoded.len, frame.len);
+            try testing.expect(frame.fin);
+            try testing.expectEqual(Opcode.binary, frame.opcode);
+            try testing.expectEqualSlices(u8, payload[0..len], frame.payload);
+
+            // Every strict prefix is incomplete
+            try testing.expect(try decode(out.items[0 .. encoded.len - 1], .{ .masked = mask != null, .max_payload = 1 << 20 }) == null);
+        }
+    }
+}
+
+test "applyMask undoes itself" {
+    var bytes = "Hello, WebSocket".*;
+    const key = [4]u8{ 0x37, 0xFA, 0x21, 0x3D };
+    applyMask(&bytes, key);
+    try testing.expect(!std.mem.eql(u8, &bytes, "Hello, WebSocket"));
+    applyMask(&bytes, key);
+    try testing.expectEqualStrings("Hello, WebSocket", &bytes);
+}
+
+test "isValidCloseCode" {
+    for ([_]u16{ 1000, 1001, 1002, 1003, 1007, 1011, 1014, 3000, 4999 }) |code| {
+        try testing.expect(isValidCloseCode(code));
+    }
+    // 1004-1006 and 1015 are reserved and never sent on the wire
+    for ([_]u16{ 0, 999, 1004, 1005, 1006, 1015, 1016, 2999, 5000 }) |code| {
+        try testing.expect(!isValidCloseCode(code));
+    }
+}
+
+test "parseClose" {
+    const empty = try parseClose("");
+    try testing.expectEqual(@as(?u16, null), empty.code);
+    try testing.expectEqualStrings("", empty.reason);
+
+    const normal = try parseClose("\x03\xe8bye");
+    try testing.expectEqual(@as(?u16, 1000), normal.code);
+    try testing.expectEqualStrings("bye", normal.reason);
+
+    try testing.expectError(error.ProtocolError, parseClose("\x03"));
+    try testing.expectError(error.ProtocolError, parseClose("\x03\xed"));
+    try testing.expectError(error.InvalidPayload, parseClose("\x03\xe8\xff"));
+}
+
+test "writeClose keeps short reasons whole" {
+    var buf: [max_control_payload]u8 = undefined;
+    const payload = writeClose(&buf, 1001, "Going away");
+    const close = try parseClose(payload);
+    try testing.expectEqual(@as(?u16, 1001), close.code);
+    try testing.expectEqualStrings("Going away", close.reason);
+}
+
+test "writeClose never splits a code point" {
+    var buf: [max_control_payload]u8 = undefined;
+    // 123 bytes of room: 61 two-byte characters fill 122 and the 62nd does not fit
+    const reason = "é" ** 70;
+    const payload = writeClose(&buf, 1000, reason);
+    try testing.expectEqual(@as(usize, 2 + 122), payload.len);
+    const close = try parseClose(payload);
+    try testing.expectEqualStrings(reason[0..122], close.reason);
+
+    // A four-byte character straddling the limit is left out entirely
+    const emoji = "a" ** 121 ++ "\u{1F600}";
+    const cut = try parseClose(writeClose(&buf, 1000, emoji));
+    try testing.expectEqualStrings("a" ** 121, cut.reason);
+
+    // Exactly filling the room is not a cut
+    const exact = "a" ** 119 ++ "\u{1F600}";
+    try testing.expectEqualStrings(exact, (try parseClose(writeClose(&buf, 1000, exact))).reason);
+}
+
+test "fuzz decode" {
+    try testing.fuzz({}, fuzzDecode, .{});
+}
+
+fn fuzzDecode(_: void, input: []const u8) anyerror!void {
+    const buf = try testing.allocator.dupe(u8, input);
+    defer testing.allocator.free(buf);
+
+    for ([_]bool{ true, false }) |masked| {
+        @memcpy(buf, input);
+        const frame = (decode(buf, .{ .masked = masked, .max_payload = std.math.maxInt(u64) }) catch continue) orelse continue;
+        try testing.expect(frame.len <= buf.len);
+        try testing.expect(frame.payload.len <= frame.len);
+        if (frame.opcode.isControl()) {
+            try testing.expect(frame.fin and frame.payload.len <= max_control_payload);
+            if (frame.opcode == .close) _ = parseClose(frame.payload) catch {};
+        }
+    }
+}
+
+test "fuzz encode round-trip" {
+    try testing.fuzz({}, fuzzRoundTrip, .{});
+}
+
+fn fuzzRoundTrip(_: void, input: []const u8) anyerror!void {
+    var out = std.ArrayListUnmanaged(u8){};
+    defer out.deinit(testing.allocator);
+
+    // The input's first bytes double as the mask when there are enough of them
+    const mask: ?[4]u8 = if (input.len >= 4) input[0..4].* else null;
+    _ = try encode(testing.allocator, &out, .binary, input, mask);
+    const frame = (try decode(out.items, .{ .masked = mask != null, .max_payload = std.math.maxInt(u64) })).?;
+    try testing.expectEqual(out.items.len, frame.len);
+    try testing.expectEqualSlices(u8, input, frame.payload);
+}
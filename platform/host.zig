@@ -2,6 +2,10 @@
 //! Implements a WebSocket server for the Roc chat application
 const std = @import("std");
 const builtins = @import("builtins");
+const codec = @import("codec.zig");
+const http = @import("http.zig");
+/// Static files compiled in with `zig build native -Dembed-static` (empty otherwise)
+const embedded_static = @import("embedded_static");
 
 // Use lower-level C environ access to avoid std.os.environ initialization issues
 extern var environ: [*:null]?[*:0]u8;
@@ -40,13 +44,8 @@ var total_allocated: usize = 0;
 fn rocAllocFn(roc_alloc: *builtins.host_abi.RocAlloc, env: *anyopaque) callconv(.c) void {
     _ = env;
 
-    const result = c_allocator.rawAlloc(
-        roc_alloc.length,
-        std.mem.Alignment.fromByteUnits(@max(roc_alloc.alignment, @alignOf(usize))),
-        @returnAddress(),
-    );
-
-    roc_alloc.answer = result orelse {
+    const alignment = std.mem.Alignment.fromByteUnits(@max(roc_alloc.alignment, @alignOf(usize)));
+    roc_alloc.answer = allocBlock(roc_alloc.length, alignment) orelse {
         const stderr: std.fs.File = .stderr();
         stderr.writeAll("\x1b[31mHost error:\x1b[0m allocation failed, out of memory\n") catch {};
         std.process.exit(1);
@@ -56,10 +55,7 @@ fn rocAllocFn(roc_alloc: *builtins.host_abi.RocAlloc, env: *anyopaque) callconv(
     alloc_count += 1;
     total_allocated += roc_alloc.length;
     if (alloc_count % 100 == 0) {
-        const stderr = std.fs.File.stderr();
-        var buf: [128]u8 = undefined;
-        const msg = std.fmt.bufPrint(&buf, "ALLOC STATS: allocs={} deallocs={} diff={} total_bytes={}\n", .{ alloc_count, dealloc_count, alloc_count - dealloc_count, total_allocated }) catch "ALLOC STATS: ???\n";
-        stderr.writeAll(msg) catch {};
+        logger.debug(null, "ALLOC STATS: allocs={} deallocs={} diff={} total_bytes={}", .{ alloc_count, dealloc_count, alloc_count - dealloc_count, total_allocated });
     }
 }
 
@@ -67,35 +63,79 @@ fn rocAllocFn(roc_alloc: *builtins.host_abi.RocAlloc, env: *anyopaque) callconv(
 fn rocDeallocFn(roc_dealloc: *builtins.host_abi.RocDealloc, env: *anyopaque) callconv(.c) void {
     _ = env;
     dealloc_count += 1;
-    const slice = @as([*]u8, @ptrCast(roc_dealloc.ptr))[0..0];
-    c_allocator.rawFree(
-        slice,
-        std.mem.Alignment.fromByteUnits(@max(roc_dealloc.alignment, @alignOf(usize))),
-        @returnAddress(),
-    );
+    const alignment = std.mem.Alignment.fromByteUnits(@max(roc_dealloc.alignment, @alignOf(usize)));
+    freeBlock(roc_dealloc.ptr, alignment);
 }
 
 /// Roc reallocation function using C allocator
 fn rocReallocFn(roc_realloc: *builtins.host_abi.RocRealloc, env: *anyopaque) callconv(.c) void {
     _ = env;
 
-    const align_enum = std.mem.Alignment.fromByteUnits(@max(roc_realloc.alignment, @alignOf(usize)));
+    const alignment = std.mem.Alignment.fromByteUnits(@max(roc_realloc.alignment, @alignOf(usize)));
+
+    // Blocks at malloc's natural alignment came from malloc itself; realloc knows
+    // their old size, which Roc does not pass, so it copies only what exists
+    if (!isOverAligned(alignment)) {
+        roc_realloc.answer = std.c.realloc(roc_realloc.answer, roc_realloc.new_length) orelse {
+            const stderr: std.fs.File = .stderr();
+            stderr.writeAll("\x1b[31mHost error:\x1b[0m reallocation failed, out of memory\n") catch {};
+            std.process.exit(1);
+        };
+        return;
+    }
 
-    const new_ptr = c_allocator.rawAlloc(roc_realloc.new_length, align_enum, @returnAddress()) orelse {
+    const new_ptr = allocBlock(roc_realloc.new_length, alignment) orelse {
         const stderr: std.fs.File = .stderr();
         stderr.writeAll("\x1b[31mHost error:\x1b[0m reallocation failed, out of memory\n") catch {};
         std.process.exit(1);
     };
 
+    // Over-aligned blocks record their length in the header
     const old_ptr: [*]const u8 = @ptrCast(roc_realloc.answer);
-    @memcpy(new_ptr[0..roc_realloc.new_length], old_ptr[0..roc_realloc.new_length]);
-
-    const old_slice = @as([*]u8, @ptrCast(roc_realloc.answer))[0..0];
-    c_allocator.rawFree(old_slice, align_enum, @returnAddress());
+    const copy_length = @min(blockLength(roc_realloc.answer), roc_realloc.new_length);
+    @memcpy(new_ptr[0..copy_length], old_ptr[0..copy_length]);
+    freeBlock(roc_realloc.answer, alignment);
 
     roc_realloc.answer = new_ptr;
 }
 
+/// Whether blocks of this alignment need more than malloc guarantees
+fn isOverAligned(alignment: std.mem.Alignment) bool {
+    return alignment.compare(.gt, .of(std.c.max_align_t));
+}
+
+/// Allocate a block for Roc. Blocks at malloc's natural alignment come straight
+/// from malloc, so realloc and free can take them back. Over-aligned blocks are
+/// preceded by a header of one alignment unit whose last word holds the length,
+/// since Roc passes no old length to realloc and libc cannot report one on
+/// every target.
+fn allocBlock(length: usize, alignment: std.mem.Alignment) ?[*]u8 {
+    if (!isOverAligned(alignment)) return @ptrCast(std.c.malloc(length));
+
+    const header = alignment.toByteUnits();
+    const base = c_allocator.rawAlloc(header + length, alignment, @returnAddress()) orelse return null;
+    const block = base + header;
+    const length_slot: *usize = @ptrCast(@alignCast(block - @sizeOf(usize)));
+    length_slot.* = length;
+    return block;
+}
+
+/// Length recorded in an over-aligned block's header
+fn blockLength(ptr: *anyopaque) usize {
+    const block: [*]u8 = @ptrCast(ptr);
+    const length_slot: *const usize = @ptrCast(@alignCast(block - @sizeOf(usize)));
+    return length_slot.*;
+}
+
+/// Free a block from allocBlock, including any header in front of it
+fn freeBlock(ptr: *anyopaque, alignment: std.mem.Alignment) void {
+    if (!isOverAligned(alignment)) return std.c.free(ptr);
+
+    const block: [*]u8 = @ptrCast(ptr);
+    const base = block - alignment.toByteUnits();
+    c_allocator.rawFree(base[0..0], alignment, @returnAddress());
+}
+
 /// Roc debug function
 fn rocDbgFn(roc_dbg: *const builtins.host_abi.RocDbg, env: *anyopaque) callconv(.c) void {
     _ = env;
@@ -121,12 +161,18 @@ fn rocExpectFailedFn(roc_expect: *const builtins.host_abi.RocExpectFailed, env:
 
 /// Roc crashed function
 fn rocCrashedFn(roc_crashed: *const builtins.host_abi.RocCrashed, env: *anyopaque) callconv(.c) noreturn {
-    _ = env;
     const message = roc_crashed.utf8_bytes[0..roc_crashed.len];
     const stderr = std.fs.File.stderr();
     stderr.writeAll("\n\x1b[31mRoc crashed:\x1b[0m ") catch {};
     stderr.writeAll(message) catch {};
     stderr.writeAll("\n") catch {};
+
+    // Tell connected clients the server failed rather than letting their sockets reset
+    const host: *HostEnv = @ptrCast(@alignCast(env));
+    if (host.server) |server| {
+        logger.err(null, "Application crashed: {s}", .{message});
+        server.closeAll(close_internal_error, "Internal server error");
+    }
     std.process.exit(1);
 }
 
@@ -145,9 +191,11 @@ comptime {
 
 fn __main() callconv(.c) void {}
 
+/// Command-line arguments, program name first; for Env.args!
+var process_args: []const [*:0]u8 = &.{};
+
 fn main(argc: c_int, argv: [*][*:0]u8) callconv(.c) c_int {
-    _ = argc;
-    _ = argv;
+    process_args = argv[0..@intCast(argc)];
     initEnviron();
     return platform_main();
 }
@@ -157,391 +205,6365 @@ const RocStr = builtins.str.RocStr;
 const RocList = builtins.list.RocList;
 
 // ============================================================================
-// WebSocket Server Implementation
+// Logging
 // ============================================================================
 
-const WebSocketOpcode = enum(u4) {
-    continuation = 0x0,
-    text = 0x1,
-    binary = 0x2,
-    close = 0x8,
-    ping = 0x9,
-    pong = 0xA,
-};
+const LogLevel = enum(u8) {
+    debug,
+    info,
+    warn,
+    err,
+    off,
+
+    fn parse(name: []const u8) ?LogLevel {
+        if (std.ascii.eqlIgnoreCase(name, "debug")) return .debug;
+        if (std.ascii.eqlIgnoreCase(name, "info")) return .info;
+        if (std.ascii.eqlIgnoreCase(name, "warn")) return .warn;
+        if (std.ascii.eqlIgnoreCase(name, "error")) return .err;
+        if (std.ascii.eqlIgnoreCase(name, "off")) return .off;
+        return null;
+    }
 
-const WebSocketClient = struct {
-    id: u64,
-    stream: std.net.Stream,
-    is_websocket: bool = false,
-    is_closed: bool = false,
+    fn label(self: LogLevel) []const u8 {
+        return switch (self) {
+            .debug => "debug",
+            .info => "info",
+            .warn => "warn",
+            .err => "error",
+            .off => "off",
+        };
+    }
 };
 
-const WebSocketEvent = union(enum) {
-    connected: u64,
-    disconnected: u64,
-    message: struct { client_id: u64, text: []const u8 },
-    err: []const u8,
-    shutdown: void,
+/// Host log sink: level-filtered, timestamped lines on stderr, optionally as JSON.
+/// Configured from CHATSERVER_LOG / CHATSERVER_LOG_FORMAT or WebServer.set_log_level!
+const Logger = struct {
+    level: LogLevel = .info,
+    json: bool = false,
+
+    fn write(self: *const Logger, level: LogLevel, client_id: ?u64, comptime fmt: []const u8, args: anytype) void {
+        if (@intFromEnum(level) < @intFromEnum(self.level)) return;
+
+        var msg_buf: [1024]u8 = undefined;
+        const msg = std.fmt.bufPrint(&msg_buf, fmt, args) catch "(log message too long)";
+        var ts_buf: [24]u8 = undefined;
+        const ts = formatTimestamp(&ts_buf, std.time.milliTimestamp());
+
+        var line_buf: [2048]u8 = undefined;
+        var line = std.io.fixedBufferStream(&line_buf);
+        const w = line.writer();
+        if (self.json) {
+            w.print("{{\"ts\":\"{s}\",\"level\":\"{s}\"", .{ ts, level.label() }) catch {};
+            if (client_id) |id| w.print(",\"client\":{}", .{id}) catch {};
+            w.writeAll(",\"msg\":") catch {};
+            writeJsonString(w, msg) catch {};
+            w.writeAll("}\n") catch {};
+        } else {
+            w.print("{s} {s}", .{ ts, level.label() }) catch {};
+            if (client_id) |id| w.print(" [client {}]", .{id}) catch {};
+            w.print(" {s}\n", .{msg}) catch {};
+        }
+        std.fs.File.stderr().writeAll(line.getWritten()) catch {};
+    }
+
+    fn debug(self: *const Logger, client_id: ?u64, comptime fmt: []const u8, args: anytype) void {
+        self.write(.debug, client_id, fmt, args);
+    }
+
+    fn info(self: *const Logger, client_id: ?u64, comptime fmt: []const u8, args: anytype) void {
+        self.write(.info, client_id, fmt, args);
+    }
+
+    fn warn(self: *const Logger, client_id: ?u64, comptime fmt: []const u8, args: anytype) void {
+        self.write(.warn, client_id, fmt, args);
+    }
+
+    fn err(self: *const Logger, client_id: ?u64, comptime fmt: []const u8, args: anytype) void {
+        self.write(.err, client_id, fmt, args);
+    }
 };
 
-const WebSocketServer = struct {
-    allocator: std.mem.Allocator,
-    listener: ?std.net.Server,
-    clients: std.AutoHashMap(u64, WebSocketClient),
-    next_client_id: u64,
-    event_queue: std.ArrayListUnmanaged(WebSocketEvent),
-    is_running: bool,
-    static_dir: ?[]const u8,
+var logger: Logger = .{};
+
+/// Format milliseconds since the epoch as an ISO 8601 UTC timestamp
+fn formatTimestamp(buf: *[24]u8, ms: i64) []const u8 {
+    const epoch_secs = std.time.epoch.EpochSeconds{ .secs = @intCast(@divFloor(ms, 1000)) };
+    const year_day = epoch_secs.getEpochDay().calculateYearDay();
+    const month_day = year_day.calculateMonthDay();
+    const day_secs = epoch_secs.getDaySeconds();
+    return std.fmt.bufPrint(buf, "{d:0>4}-{d:0>2}-{d:0>2}T{d:0>2}:{d:0>2}:{d:0>2}.{d:0>3}Z", .{
+        year_day.year,
+        month_day.month.numeric(),
+        month_day.day_index + 1,
+        day_secs.getHoursIntoDay(),
+        day_secs.getMinutesIntoHour(),
+        day_secs.getSecondsIntoMinute(),
+        @as(u64, @intCast(@mod(ms, 1000))),
+    }) catch buf[0..0];
+}
 
-    fn init(allocator: std.mem.Allocator) WebSocketServer {
+/// Write `text` as a quoted JSON string
+fn writeJsonString(writer: anytype, text: []const u8) !void {
+    try writer.writeByte('"');
+    for (text) |c| {
+        switch (c) {
+            '"' => try writer.writeAll("\\\""),
+            '\\' => try writer.writeAll("\\\\"),
+            '\n' => try writer.writeAll("\\n"),
+            '\r' => try writer.writeAll("\\r"),
+            '\t' => try writer.writeAll("\\t"),
+            0...0x08, 0x0B, 0x0C, 0x0E...0x1F => try writer.print("\\u{x:0>4}", .{c}),
+            else => try writer.writeByte(c),
+        }
+    }
+    try writer.writeByte('"');
+}
+
+// ============================================================================
+// HTTP Request Parsing
+// ============================================================================
+
+// The parser itself is in http.zig
+const HttpHeader = http.Header;
+const HttpRequest = http.Request;
+const max_http_headers = http.max_headers;
+const parseHttpHead = http.parseHead;
+const readHttpBody = http.readBody;
+
+/// Default for the largest request head (request line + headers) buffered before giving up
+const max_http_head_size = 16 * 1024;
+
+/// Default time a connection has to deliver its complete request
+const default_handshake_timeout_ms = 10_000;
+
+/// A request handed to the application, owning copies of everything it references
+const HttpRequestEvent = struct {
+    client_id: u64,
+    method: []const u8,
+    target: []const u8,
+    headers: []HttpHeader,
+    body: []const u8,
+    /// Raw request bytes the slices above point into
+    storage: []u8,
+
+    /// Copy `request`, whose slices point into `raw`, into owned memory
+    fn fromRequest(allocator: std.mem.Allocator, client_id: u64, raw: []const u8, request: *const HttpRequest) !HttpRequestEvent {
+        const storage = try allocator.dupe(u8, raw[0..request.len]);
+        errdefer allocator.free(storage);
+        const headers = try allocator.alloc(HttpHeader, request.header_count);
+
+        const rebase = struct {
+            fn slice(old: []const u8, new: []const u8, part: []const u8) []const u8 {
+                if (part.len == 0) return "";
+                const offset = @intFromPtr(part.ptr) - @intFromPtr(old.ptr);
+                return new[offset .. offset + part.len];
+            }
+        }.slice;
+
+        for (request.headers[0..request.header_count], headers) |h, *out| {
+            out.* = .{ .name = rebase(raw, storage, h.name), .value = rebase(raw, storage, h.value) };
+        }
         return .{
-            .allocator = allocator,
-            .listener = null,
-            .clients = std.AutoHashMap(u64, WebSocketClient).init(allocator),
-            .next_client_id = 1,
-            .event_queue = .{},
-            .is_running = false,
-            .static_dir = null,
+            .client_id = client_id,
+            .method = rebase(raw, storage, request.method),
+            .target = rebase(raw, storage, request.target),
+            .headers = headers,
+            .body = rebase(raw, storage, request.body),
+            .storage = storage,
         };
     }
 
-    fn deinit(self: *WebSocketServer) void {
-        if (self.listener) |*l| {
-            l.deinit();
-        }
+    fn deinit(self: HttpRequestEvent, allocator: std.mem.Allocator) void {
+        allocator.free(self.headers);
+        allocator.free(self.storage);
+    }
+};
 
-        var it = self.clients.valueIterator();
-        while (it.next()) |client| {
-            client.stream.close();
+/// Whether `text` is an HTTP token (RFC 9110 section 5.6.2), as header names and
+/// subprotocol names must be
+fn isHttpToken(text: []const u8) bool {
+    for (text) |c| {
+        if (std.ascii.isAlphanumeric(c)) continue;
+        if (std.mem.indexOfScalar(u8, "!#$%&'*+-.^_`|~", c) == null) return false;
+    }
+    return text.len > 0;
+}
+
+/// Reason phrase for a status code sent by respond!
+fn httpReasonPhrase(status: u16) []const u8 {
+    return switch (status) {
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        413 => "Content Too Large",
+        415 => "Unsupported Media Type",
+        422 => "Unprocessable Content",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        else => "",
+    };
+}
+
+const http_day_names = [_][]const u8{ "Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat" };
+const http_month_names = [_][]const u8{ "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec" };
+
+/// Format seconds since the epoch as an IMF-fixdate ("Sun, 06 Nov 1994 08:49:37 GMT")
+fn formatHttpDate(buf: *[29]u8, secs: i64) []const u8 {
+    const epoch_secs = std.time.epoch.EpochSeconds{ .secs = @intCast(@max(secs, 0)) };
+    const epoch_day = epoch_secs.getEpochDay();
+    const year_day = epoch_day.calculateYearDay();
+    const month_day = year_day.calculateMonthDay();
+    const day_secs = epoch_secs.getDaySeconds();
+    // 1970-01-01 was a Thursday
+    const weekday = (epoch_day.day + 4) % 7;
+    return std.fmt.bufPrint(buf, "{s}, {d:0>2} {s} {d:0>4} {d:0>2}:{d:0>2}:{d:0>2} GMT", .{
+        http_day_names[weekday],
+        month_day.day_index + 1,
+        http_month_names[month_day.month.numeric() - 1],
+        year_day.year,
+        day_secs.getHoursIntoDay(),
+        day_secs.getMinutesIntoHour(),
+        day_secs.getSecondsIntoMinute(),
+    }) catch buf[0..0];
+}
+
+/// Parse an IMF-fixdate back into seconds since the epoch
+fn parseHttpDate(text: []const u8) ?i64 {
+    if (text.len != 29 or !std.mem.eql(u8, text[26..], "GMT")) return null;
+
+    const day = std.fmt.parseInt(i64, text[5..7], 10) catch return null;
+    const year = std.fmt.parseInt(i64, text[12..16], 10) catch return null;
+    const hour = std.fmt.parseInt(i64, text[17..19], 10) catch return null;
+    const minute = std.fmt.parseInt(i64, text[20..22], 10) catch return null;
+    const second = std.fmt.parseInt(i64, text[23..25], 10) catch return null;
+    var month: i64 = 0;
+    for (http_month_names, 1..) |name, i| {
+        if (std.mem.eql(u8, name, text[8..11])) month = @intCast(i);
+    }
+    if (month == 0 or day == 0 or day > 31 or hour > 23 or minute > 59 or second > 60) return null;
+
+    // Days from civil date (proleptic Gregorian), shifted so the year starts in March
+    const y = if (month <= 2) year - 1 else year;
+    const era = @divFloor(y, 400);
+    const yoe = y - era * 400;
+    const mp = @mod(month + 9, 12);
+    const doy = @divFloor(153 * mp + 2, 5) + day - 1;
+    const doe = yoe * 365 + @divFloor(yoe, 4) - @divFloor(yoe, 100) + doy;
+    const days = era * 146097 + doe - 719468;
+
+    return days * 86400 + hour * 3600 + minute * 60 + second;
+}
+
+/// Whether an If-None-Match value lists `etag` (weak comparison) or is "*"
+fn etagMatches(if_none_match: []const u8, etag: []const u8) bool {
+    if (std.mem.eql(u8, std.mem.trim(u8, if_none_match, " \t"), "*")) return true;
+    const want = if (std.mem.startsWith(u8, etag, "W/")) etag[2..] else etag;
+    var it = std.mem.splitScalar(u8, if_none_match, ',');
+    while (it.next()) |part| {
+        const candidate = std.mem.trim(u8, part, " \t");
+        const opaque_tag = if (std.mem.startsWith(u8, candidate, "W/")) candidate[2..] else candidate;
+        if (std.mem.eql(u8, opaque_tag, want)) return true;
+    }
+    return false;
+}
+
+/// The first file in a multipart/form-data body: its filename and content, both
+/// pointing into `body`
+fn multipartFile(body: []const u8, content_type: []const u8) !struct { filename: []const u8, content: []const u8 } {
+    const param = "boundary=";
+    const at = std.ascii.indexOfIgnoreCase(content_type, param) orelse return error.MissingBoundary;
+    var boundary = content_type[at + param.len ..];
+    if (std.mem.indexOfScalar(u8, boundary, ';')) |end| boundary = boundary[0..end];
+    boundary = std.mem.trim(u8, boundary, " \t\"");
+    if (boundary.len == 0 or boundary.len > 70) return error.MissingBoundary;
+
+    var delimiter_buf: [76]u8 = undefined;
+    const delimiter = std.fmt.bufPrint(&delimiter_buf, "\r\n--{s}", .{boundary}) catch unreachable;
+    // The first delimiter may open the body without a preceding line break
+    var pos = if (std.mem.startsWith(u8, body, delimiter[2..]))
+        delimiter.len - 2
+    else
+        (std.mem.indexOf(u8, body, delimiter) orelse return error.MalformedMultipart) + delimiter.len;
+
+    while (true) {
+        if (std.mem.startsWith(u8, body[pos..], "--")) return error.NoFile;
+        if (!std.mem.startsWith(u8, body[pos..], "\r\n")) return error.MalformedMultipart;
+        const head_start = pos + 2;
+        const head_end = std.mem.indexOfPos(u8, body, head_start, "\r\n\r\n") orelse return error.MalformedMultipart;
+        const content_start = head_end + 4;
+        const content_end = std.mem.indexOfPos(u8, body, content_start, delimiter) orelse return error.MalformedMultipart;
+
+        var lines = std.mem.splitSequence(u8, body[head_start..head_end], "\r\n");
+        while (lines.next()) |line| {
+            if (!std.ascii.startsWithIgnoreCase(line, "content-disposition:")) continue;
+            const name_param = "filename=\"";
+            const name_at = std.mem.indexOf(u8, line, name_param) orelse continue;
+            const name_start = name_at + name_param.len;
+            const name_end = std.mem.indexOfScalarPos(u8, line, name_start, '"') orelse return error.MalformedMultipart;
+            return .{ .filename = line[name_start..name_end], .content = body[content_start..content_end] };
         }
-        self.clients.deinit();
-        self.event_queue.deinit(self.allocator);
+        pos = content_end + delimiter.len;
     }
+}
 
-    fn listen(self: *WebSocketServer, port: u16) !void {
-        const address = std.net.Address.initIp4(.{ 0, 0, 0, 0 }, port);
-        self.listener = try address.listen(.{
-            .reuse_address = true,
-        });
-        self.is_running = true;
-        self.static_dir = "static";
+/// A filename's extension, lowercased into `buf`; only short alphanumeric ones are kept
+fn uploadExtension(buf: []u8, filename: []const u8) ?[]const u8 {
+    const base = std.fs.path.basename(filename);
+    const dot = std.mem.lastIndexOfScalar(u8, base, '.') orelse return null;
+    const ext = base[dot + 1 ..];
+    if (ext.len == 0 or ext.len > buf.len) return null;
+    for (ext) |c| {
+        if (!std.ascii.isAlphanumeric(c)) return null;
     }
+    return std.ascii.lowerString(buf, ext);
+}
 
-    fn accept(self: *WebSocketServer) !WebSocketEvent {
-        while (true) {
-            // First check event queue
-            if (self.event_queue.items.len > 0) {
-                return self.event_queue.orderedRemove(0);
-            }
+/// The built-in extension for a Content-Type, ignoring parameters
+fn extensionForMime(content_type: []const u8) ?[]const u8 {
+    const mime = std.mem.trim(u8, content_type[0 .. std.mem.indexOfScalar(u8, content_type, ';') orelse content_type.len], " \t");
+    for (mime_types) |entry| {
+        if (std.ascii.eqlIgnoreCase(entry.mime, mime)) return entry.ext;
+    }
+    return null;
+}
 
-            if (!self.is_running) {
-                return .shutdown;
-            }
+/// Whether a type matches an allowed entry: exact, or a whole family as in "image/*"
+fn mimeAllowed(allowed: []const []const u8, mime: []const u8) bool {
+    for (allowed) |entry| {
+        if (std.mem.endsWith(u8, entry, "/*")) {
+            if (std.ascii.startsWithIgnoreCase(mime, entry[0 .. entry.len - 1])) return true;
+        } else if (std.ascii.eqlIgnoreCase(entry, mime)) {
+            return true;
+        }
+    }
+    return false;
+}
 
-            var listener = &(self.listener.?);
+/// Built-in extension -> Content-Type table, consulted after custom mappings
+const mime_types = [_]struct { ext: []const u8, mime: []const u8 }{
+    .{ .ext = "html", .mime = "text/html" },
+    .{ .ext = "htm", .mime = "text/html" },
+    .{ .ext = "css", .mime = "text/css" },
+    .{ .ext = "js", .mime = "text/javascript" },
+    .{ .ext = "mjs", .mime = "text/javascript" },
+    .{ .ext = "json", .mime = "application/json" },
+    .{ .ext = "map", .mime = "application/json" },
+    .{ .ext = "webmanifest", .mime = "application/manifest+json" },
+    .{ .ext = "xml", .mime = "application/xml" },
+    .{ .ext = "txt", .mime = "text/plain" },
+    .{ .ext = "md", .mime = "text/markdown" },
+    .{ .ext = "csv", .mime = "text/csv" },
+    .{ .ext = "wasm", .mime = "application/wasm" },
+    .{ .ext = "pdf", .mime = "application/pdf" },
+    .{ .ext = "zip", .mime = "application/zip" },
+    .{ .ext = "png", .mime = "image/png" },
+    .{ .ext = "jpg", .mime = "image/jpeg" },
+    .{ .ext = "jpeg", .mime = "image/jpeg" },
+    .{ .ext = "gif", .mime = "image/gif" },
+    .{ .ext = "webp", .mime = "image/webp" },
+    .{ .ext = "avif", .mime = "image/avif" },
+    .{ .ext = "svg", .mime = "image/svg+xml" },
+    .{ .ext = "ico", .mime = "image/x-icon" },
+    .{ .ext = "bmp", .mime = "image/bmp" },
+    .{ .ext = "woff", .mime = "font/woff" },
+    .{ .ext = "woff2", .mime = "font/woff2" },
+    .{ .ext = "ttf", .mime = "font/ttf" },
+    .{ .ext = "otf", .mime = "font/otf" },
+    .{ .ext = "mp3", .mime = "audio/mpeg" },
+    .{ .ext = "ogg", .mime = "audio/ogg" },
+    .{ .ext = "wav", .mime = "audio/wav" },
+    .{ .ext = "mp4", .mime = "video/mp4" },
+    .{ .ext = "webm", .mime = "video/webm" },
+};
 
-            // Set up poll to check for new connections and client data
-            var poll_fds = std.ArrayListUnmanaged(std.posix.pollfd){};
-            defer poll_fds.deinit(self.allocator);
+/// Whether a Content-Type is textual and should carry charset=utf-8
+fn isTextMime(mime: []const u8) bool {
+    if (std.mem.indexOfScalar(u8, mime, ';') != null) return false; // already parameterized
+    return std.mem.startsWith(u8, mime, "text/") or
+        std.mem.eql(u8, mime, "application/json") or
+        std.mem.eql(u8, mime, "application/manifest+json") or
+        std.mem.eql(u8, mime, "application/xml") or
+        std.mem.eql(u8, mime, "image/svg+xml");
+}
 
-            // Add listener socket
-            try poll_fds.append(self.allocator, .{
-                .fd = listener.stream.handle,
-                .events = std.posix.POLL.IN,
-                .revents = 0,
-            });
+/// Static files up to this size are read into memory (and may be cached); larger ones are streamed
+const default_static_buffer = 1024 * 1024;
 
-            // Add all client sockets
-            var client_ids = std.ArrayListUnmanaged(u64){};
-            defer client_ids.deinit(self.allocator);
+/// Most of a streamed file written per poll wakeup, so one fast reader cannot starve the rest
+const download_chunk_size = 1024 * 1024;
 
-            var it = self.clients.iterator();
-            while (it.next()) |entry| {
-                if (!entry.value_ptr.is_closed) {
-                    try poll_fds.append(self.allocator, .{
-                        .fd = entry.value_ptr.stream.handle,
-                        .events = std.posix.POLL.IN,
-                        .revents = 0,
-                    });
-                    try client_ids.append(self.allocator, entry.key_ptr.*);
-                }
-            }
+/// Files smaller than this are sent as-is even when a compressed sidecar exists
+const min_compress_size = 1024;
 
-            // Poll with longer timeout (5 seconds) to avoid busy spinning
-            const ready = std.posix.poll(poll_fds.items, 5000) catch |err| {
-                const msg = std.fmt.allocPrint(self.allocator, "Poll error: {}", .{err}) catch "Poll error";
-                return .{ .err = msg };
-            };
+/// Pre-compressed variants looked for next to a static file, in order of preference
+const compressed_sidecars = [_]struct { coding: []const u8, suffix: []const u8 }{
+    .{ .coding = "br", .suffix = ".br" },
+    .{ .coding = "gzip", .suffix = ".gz" },
+};
 
-            if (ready == 0) {
-                // Timeout - just continue polling
-                continue;
+/// Whether a Content-Type benefits from compression
+fn isCompressibleMime(mime: []const u8) bool {
+    return isTextMime(mime) or
+        std.mem.eql(u8, mime, "application/wasm") or
+        std.mem.startsWith(u8, mime, "text/");
+}
+
+/// Whether an Accept-Encoding value allows `coding` (q=0 excludes it)
+fn acceptsEncoding(accept_encoding: []const u8, coding: []const u8) bool {
+    var wildcard = false;
+    var it = std.mem.splitScalar(u8, accept_encoding, ',');
+    while (it.next()) |part| {
+        var params = std.mem.splitScalar(u8, part, ';');
+        const name = std.mem.trim(u8, params.next() orelse continue, " \t");
+        var allowed = true;
+        while (params.next()) |param| {
+            const p = std.mem.trim(u8, param, " \t");
+            if (std.mem.startsWith(u8, p, "q=")) {
+                const q = std.fmt.parseFloat(f32, p[2..]) catch 1.0;
+                allowed = q > 0;
             }
+        }
+        if (std.ascii.eqlIgnoreCase(name, coding)) return allowed;
+        if (std.mem.eql(u8, name, "*")) wildcard = allowed;
+    }
+    return wildcard;
+}
 
-            // Check listener for new connections
-            if (poll_fds.items[0].revents & std.posix.POLL.IN != 0) {
-                const connection = listener.accept() catch |err| {
-                    const msg = std.fmt.allocPrint(self.allocator, "Accept error: {}", .{err}) catch "Accept error";
-                    return .{ .err = msg };
-                };
+/// Whether the client's cached copy is current. If-None-Match takes precedence;
+/// If-Modified-Since is only consulted when the file has an mtime.
+fn isNotModified(request: *const HttpRequest, etag: []const u8, mtime_secs: ?i64) bool {
+    if (request.header("If-None-Match")) |value| return etagMatches(value, etag);
+    const mtime = mtime_secs orelse return false;
+    const since = parseHttpDate(request.header("If-Modified-Since") orelse return false) orelse return false;
+    return mtime <= since;
+}
 
-                const client_id = self.next_client_id;
-                self.next_client_id += 1;
+/// The bytes a `Range: bytes=` header asks for out of `size`, end inclusive.
+/// Null means the whole representation: no range, several ranges, or one that
+/// does not parse, all of which RFC 9110 lets a server answer with 200.
+fn parseByteRange(header: []const u8, size: usize) error{Unsatisfiable}!?struct { start: usize, end: usize } {
+    const unit = "bytes=";
+    if (!std.ascii.startsWithIgnoreCase(header, unit)) return null;
+    const spec = std.mem.trim(u8, header[unit.len..], " \t");
+    if (std.mem.indexOfScalar(u8, spec, ',') != null) return null;
+    const dash = std.mem.indexOfScalar(u8, spec, '-') orelse return null;
+    const first = std.mem.trim(u8, spec[0..dash], " \t");
+    const last = std.mem.trim(u8, spec[dash + 1 ..], " \t");
+
+    if (first.len == 0) {
+        // A suffix: the final N bytes
+        const suffix = std.fmt.parseInt(usize, last, 10) catch return null;
+        if (suffix == 0 or size == 0) return error.Unsatisfiable;
+        return .{ .start = size - @min(suffix, size), .end = size - 1 };
+    }
+    const start = std.fmt.parseInt(usize, first, 10) catch return null;
+    const end = if (last.len == 0) std.math.maxInt(usize) else std.fmt.parseInt(usize, last, 10) catch return null;
+    if (end < start) return null;
+    if (start >= size) return error.Unsatisfiable;
+    return .{ .start = start, .end = @min(end, size - 1) };
+}
 
-                try self.clients.put(client_id, .{
-                    .id = client_id,
-                    .stream = connection.stream,
-                    .is_websocket = false,
-                });
+/// Whether a Range header still applies: If-Range must name the current ETag
+/// (compared strongly) or Last-Modified date, otherwise the whole file is sent
+fn rangeApplies(request: *const HttpRequest, etag: []const u8, last_modified: ?[]const u8) bool {
+    const condition = std.mem.trim(u8, request.header("If-Range") orelse return true, " \t");
+    if (condition.len > 0 and condition[0] == '"') return std.mem.eql(u8, condition, etag);
+    if (std.mem.startsWith(u8, condition, "W/")) return false;
+    return std.mem.eql(u8, condition, last_modified orelse return false);
+}
 
-                // Handle HTTP upgrade in a separate step
-                if (self.handleNewConnection(client_id)) |event| {
-                    return event;
-                } else |_| {
-                    // Connection handling failed, remove client
-                    if (self.clients.fetchRemove(client_id)) |kv| {
-                        kv.value.stream.close();
-                    }
-                }
-            }
+/// Contents of a file compiled into the host, by path relative to static/
+fn findEmbeddedFile(path: []const u8) ?[]const u8 {
+    for (embedded_static.files) |file| {
+        if (std.mem.eql(u8, file.path, path)) return file.content;
+    }
+    return null;
+}
 
-            // Check clients for incoming data
-            for (poll_fds.items[1..], 0..) |pfd, i| {
-                if (pfd.revents & std.posix.POLL.IN != 0) {
-                    const client_id = client_ids.items[i];
-                    if (self.handleClientData(client_id)) |event| {
-                        return event;
-                    } else |_| {
-                        // Error reading, client disconnected
-                        if (self.clients.fetchRemove(client_id)) |kv| {
-                            kv.value.stream.close();
-                        }
-                        return .{ .disconnected = client_id };
-                    }
-                }
+/// Resolve `rel` under the static root, following symlinks.
+/// Fails with error.Forbidden if the result lies outside the root.
+fn resolveStaticPath(root_dir: std.fs.Dir, root: []const u8, rel: []const u8, buf: []u8) ![]const u8 {
+    const resolved = try root_dir.realpath(rel, buf);
+    if (!std.mem.startsWith(u8, resolved, root) or
+        resolved.len <= root.len or resolved[root.len] != '/')
+    {
+        return error.Forbidden;
+    }
+    return resolved;
+}
 
-                if (pfd.revents & (std.posix.POLL.HUP | std.posix.POLL.ERR) != 0) {
-                    const client_id = client_ids.items[i];
-                    if (self.clients.fetchRemove(client_id)) |kv| {
-                        kv.value.stream.close();
-                    }
-                    return .{ .disconnected = client_id };
-                }
-            }
-            // No events this poll cycle, continue waiting
+/// Percent-decode a request path and normalize it to a relative path under the
+/// static root. Rejects "..", NUL bytes and backslashes, including encoded forms.
+fn sanitizeRequestPath(buf: []u8, raw: []const u8) ![]const u8 {
+    var decoded_buf: [1024]u8 = undefined;
+    if (raw.len > decoded_buf.len) return error.PathTooLong;
+
+    var decoded_len: usize = 0;
+    var i: usize = 0;
+    while (i < raw.len) : (i += 1) {
+        var c = raw[i];
+        if (c == '%') {
+            // Exactly two hex digits; parseInt would also take a sign such as "%+f"
+            if (i + 2 >= raw.len) return error.InvalidPath;
+            const high = std.fmt.charToDigit(raw[i + 1], 16) catch return error.InvalidPath;
+            const low = std.fmt.charToDigit(raw[i + 2], 16) catch return error.InvalidPath;
+            c = high * 16 + low;
+            i += 2;
         }
+        if (c == 0 or c == '\\') return error.InvalidPath;
+        decoded_buf[decoded_len] = c;
+        decoded_len += 1;
     }
 
-    fn handleNewConnection(self: *WebSocketServer, client_id: u64) !WebSocketEvent {
-        const client = self.clients.getPtr(client_id) orelse return error.ClientNotFound;
+    // Rebuild from segments, dropping "." and empty ones
+    var out_len: usize = 0;
+    var segments = std.mem.splitScalar(u8, decoded_buf[0..decoded_len], '/');
+    while (segments.next()) |segment| {
+        if (segment.len == 0 or std.mem.eql(u8, segment, ".")) continue;
+        if (std.mem.eql(u8, segment, "..")) return error.InvalidPath;
+        const sep: usize = if (out_len > 0) 1 else 0;
+        if (out_len + sep + segment.len > buf.len) return error.PathTooLong;
+        if (sep == 1) {
+            buf[out_len] = '/';
+            out_len += 1;
+        }
+        @memcpy(buf[out_len .. out_len + segment.len], segment);
+        out_len += segment.len;
+    }
+    return buf[0..out_len];
+}
 
-        var buf: [4096]u8 = undefined;
-        const n = try client.stream.read(&buf);
-        if (n == 0) return error.ConnectionClosed;
+/// Index files tried for "/" and directories when none are configured, in order
+const default_index_files = "index.html, index.htm";
 
-        const request = buf[0..n];
+/// Names in an index file list such as "index.html, index.htm"
+fn indexCandidates(list: []const u8) std.mem.TokenIterator(u8, .any) {
+    return std.mem.tokenizeAny(u8, list, ", ");
+}
 
-        // Parse HTTP request
-        if (std.mem.indexOf(u8, request, "Upgrade: websocket")) |_| {
-            // WebSocket upgrade request
-            if (try self.handleWebSocketUpgrade(client, request)) {
-                client.is_websocket = true;
-                return .{ .connected = client_id };
-            }
-        } else if (std.mem.startsWith(u8, request, "GET ")) {
-            // Regular HTTP request - serve static files
-            try self.handleHttpRequest(client, request);
-            client.is_closed = true;
+/// Write `text` escaped for HTML content or a quoted attribute value
+fn writeHtmlEscaped(writer: anytype, text: []const u8) !void {
+    for (text) |c| {
+        switch (c) {
+            '&' => try writer.writeAll("&amp;"),
+            '<' => try writer.writeAll("&lt;"),
+            '>' => try writer.writeAll("&gt;"),
+            '"' => try writer.writeAll("&quot;"),
+            '\'' => try writer.writeAll("&#39;"),
+            else => try writer.writeByte(c),
         }
+    }
+}
 
-        return error.NotWebSocket;
+/// Write a file name as one URL path segment, percent-encoding all but unreserved characters
+fn writePathSegment(writer: anytype, name: []const u8) !void {
+    for (name) |c| {
+        if (std.ascii.isAlphanumeric(c) or std.mem.indexOfScalar(u8, "-._~", c) != null) {
+            try writer.writeByte(c);
+        } else {
+            try writer.print("%{X:0>2}", .{c});
+        }
     }
+}
 
-    fn handleWebSocketUpgrade(self: *WebSocketServer, client: *WebSocketClient, request: []const u8) !bool {
-        _ = self;
+/// First entry of a forwarding header a chain of proxies may have appended to
+fn firstForwarded(value: []const u8) []const u8 {
+    return std.mem.trim(u8, value[0 .. std.mem.indexOfScalar(u8, value, ',') orelse value.len], " \t");
+}
 
-        // Find Sec-WebSocket-Key
-        const key_header = "Sec-WebSocket-Key: ";
-        const key_start = std.mem.indexOf(u8, request, key_header) orelse return false;
-        const key_value_start = key_start + key_header.len;
-        const key_end = std.mem.indexOfPos(u8, request, key_value_start, "\r\n") orelse return false;
-        const key = request[key_value_start..key_end];
+/// Longest Host name a virtual host may have
+const max_host_name = 253;
 
-        // Compute accept key
-        const magic = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
-        var hasher = std.crypto.hash.Sha1.init(.{});
-        hasher.update(key);
-        hasher.update(magic);
-        const hash = hasher.finalResult();
+/// The Host header without its port, or null when absent
+fn requestHostName(request: *const HttpRequest) ?[]const u8 {
+    const host = std.mem.trim(u8, request.header("Host") orelse return null, " \t");
+    if (host.len > 0 and host[0] == '[') {
+        const end = std.mem.indexOfScalar(u8, host, ']') orelse return null;
+        return host[0 .. end + 1];
+    }
+    return host[0 .. std.mem.indexOfScalar(u8, host, ':') orelse host.len];
+}
 
-        var accept_key: [28]u8 = undefined;
-        _ = std.base64.standard.Encoder.encode(&accept_key, &hash);
+/// The room a virtual host's client means by `room`. Names outside the host's
+/// namespace get its "<host>/" prefix, so communities never share a room.
+fn clientRoom(client: *const WebSocketClient, room: []const u8, buf: []u8) ![]const u8 {
+    const host = client.data.get("vhost") orelse return room;
+    if (room.len > host.len and std.mem.startsWith(u8, room, host) and room[host.len] == '/') return room;
+    return std.fmt.bufPrint(buf, "{s}/{s}", .{ host, room }) catch error.NameTooLong;
+}
 
-        // Send upgrade response
-        const response = "HTTP/1.1 101 Switching Protocols\r\n" ++
-            "Upgrade: websocket\r\n" ++
-            "Connection: Upgrade\r\n" ++
-            "Sec-WebSocket-Accept: ";
+// ============================================================================
+// Authentication
+// ============================================================================
 
-        _ = try client.stream.write(response);
-        _ = try client.stream.write(&accept_key);
-        _ = try client.stream.write("\r\n\r\n");
+const HmacSha256 = std.crypto.auth.hmac.sha2.HmacSha256;
+const base64url = std.base64.url_safe_no_pad;
 
-        return true;
+/// Bearer token from the Authorization header, or the `token` query parameter
+/// (browsers cannot set headers on a WebSocket)
+fn requestToken(request: *const HttpRequest) ?[]const u8 {
+    if (request.header("Authorization")) |value| {
+        if (value.len > 7 and std.ascii.eqlIgnoreCase(value[0..7], "Bearer ")) {
+            return std.mem.trim(u8, value[7..], " \t");
+        }
     }
+    return queryParam(request.query, "token");
+}
 
-    fn handleHttpRequest(self: *WebSocketServer, client: *WebSocketClient, request: []const u8) !void {
-        // Parse path
-        const path_start = std.mem.indexOf(u8, request, "GET ") orelse return;
-        const path_end = std.mem.indexOfPos(u8, request, path_start + 4, " ") orelse return;
-        var path = request[path_start + 4 .. path_end];
-
-        if (std.mem.eql(u8, path, "/")) {
-            path = "/index.html";
+/// Raw value of the first `name=value` pair in a query string
+fn queryParam(query: []const u8, name: []const u8) ?[]const u8 {
+    var params = std.mem.splitScalar(u8, query, '&');
+    while (params.next()) |param| {
+        if (param.len > name.len and std.mem.startsWith(u8, param, name) and param[name.len] == '=') {
+            return param[name.len + 1 ..];
         }
+    }
+    return null;
+}
 
-        // Serve static file
-        const static_dir = self.static_dir orelse "static";
-        var file_path_buf: [512]u8 = undefined;
-        const file_path = std.fmt.bufPrint(&file_path_buf, "{s}{s}", .{ static_dir, path }) catch {
-            try self.sendHttpError(client, 500, "Internal Server Error");
-            return;
-        };
+/// Verify an HS256 JWT and return its `sub` claim (owned).
+/// `exp` and `nbf` are enforced when present.
+fn verifyJwt(allocator: std.mem.Allocator, secret: []const u8, token: []const u8, now_secs: i64) ![]u8 {
+    var parts = std.mem.splitScalar(u8, token, '.');
+    const header_b64 = parts.next() orelse return error.MalformedToken;
+    const payload_b64 = parts.next() orelse return error.MalformedToken;
+    const signature_b64 = parts.next() orelse return error.MalformedToken;
+    if (parts.next() != null) return error.MalformedToken;
+
+    // Signature first, so nothing unauthenticated is parsed as JSON
+    var signature: [HmacSha256.mac_length]u8 = undefined;
+    if ((base64url.Decoder.calcSizeForSlice(signature_b64) catch return error.MalformedToken) != signature.len) {
+        return error.InvalidSignature;
+    }
+    base64url.Decoder.decode(&signature, signature_b64) catch return error.MalformedToken;
+    var expected: [HmacSha256.mac_length]u8 = undefined;
+    HmacSha256.create(&expected, token[0 .. header_b64.len + 1 + payload_b64.len], secret);
+    if (!std.crypto.timing_safe.eql([HmacSha256.mac_length]u8, signature, expected)) return error.InvalidSignature;
+
+    const header_json = try decodeBase64Url(allocator, header_b64);
+    defer allocator.free(header_json);
+    const header = std.json.parseFromSlice(struct { alg: []const u8 }, allocator, header_json, .{ .ignore_unknown_fields = true }) catch return error.MalformedToken;
+    defer header.deinit();
+    if (!std.mem.eql(u8, header.value.alg, "HS256")) return error.UnsupportedAlgorithm;
+
+    const payload_json = try decodeBase64Url(allocator, payload_b64);
+    defer allocator.free(payload_json);
+    const Claims = struct { sub: ?[]const u8 = null, exp: ?i64 = null, nbf: ?i64 = null };
+    const claims = std.json.parseFromSlice(Claims, allocator, payload_json, .{ .ignore_unknown_fields = true }) catch return error.MalformedToken;
+    defer claims.deinit();
+
+    if (claims.value.exp) |exp| {
+        if (now_secs >= exp) return error.TokenExpired;
+    }
+    if (claims.value.nbf) |nbf| {
+        if (now_secs < nbf) return error.TokenNotYetValid;
+    }
+    const sub = claims.value.sub orelse return error.MissingSubject;
+    return allocator.dupe(u8, sub);
+}
 
-        const file = std.fs.cwd().openFile(file_path, .{}) catch {
-            try self.sendHttpError(client, 404, "Not Found");
-            return;
-        };
-        defer file.close();
+/// Cookie carrying a signed session: <session id>.<base64url user>.<expiry>.<base64url HMAC>
+const session_cookie_name = "chat_session";
+
+/// Value of a cookie from the request's Cookie header
+fn requestCookie(request: *const HttpRequest, name: []const u8) ?[]const u8 {
+    const cookies = request.header("Cookie") orelse return null;
+    var it = std.mem.splitScalar(u8, cookies, ';');
+    while (it.next()) |pair| {
+        const trimmed = std.mem.trim(u8, pair, " \t");
+        const eq = std.mem.indexOfScalar(u8, trimmed, '=') orelse continue;
+        if (std.mem.eql(u8, trimmed[0..eq], name)) return trimmed[eq + 1 ..];
+    }
+    return null;
+}
 
-        const content = file.readToEndAlloc(self.allocator, 1024 * 1024) catch {
-            try self.sendHttpError(client, 500, "Internal Server Error");
-            return;
-        };
-        defer self.allocator.free(content);
+/// Build a Set-Cookie value holding a new signed session for `user`
+fn createSessionCookie(allocator: std.mem.Allocator, secret: []const u8, user: []const u8, ttl_secs: u64, now_secs: i64, base_path: []const u8) ![]u8 {
+    var id_bytes: [16]u8 = undefined;
+    std.crypto.random.bytes(&id_bytes);
+    const session_id = std.fmt.bytesToHex(id_bytes, .lower);
 
-        // Determine content type
-        const content_type = if (std.mem.endsWith(u8, path, ".html"))
-            "text/html"
-        else if (std.mem.endsWith(u8, path, ".js"))
-            "application/javascript"
-        else if (std.mem.endsWith(u8, path, ".css"))
-            "text/css"
-        else
-            "application/octet-stream";
+    const user_b64 = try allocator.alloc(u8, base64url.Encoder.calcSize(user.len));
+    defer allocator.free(user_b64);
+    _ = base64url.Encoder.encode(user_b64, user);
 
-        var header_buf: [256]u8 = undefined;
-        const header = std.fmt.bufPrint(&header_buf, "HTTP/1.1 200 OK\r\nContent-Type: {s}\r\nContent-Length: {d}\r\nConnection: close\r\n\r\n", .{ content_type, content.len }) catch return;
+    const expires = now_secs + @as(i64, @intCast(@min(ttl_secs, std.math.maxInt(i32))));
+    const signed = try std.fmt.allocPrint(allocator, "{s}.{s}.{d}", .{ session_id, user_b64, expires });
+    defer allocator.free(signed);
 
-        _ = try client.stream.write(header);
-        _ = try client.stream.write(content);
-    }
+    var mac: [HmacSha256.mac_length]u8 = undefined;
+    HmacSha256.create(&mac, signed, secret);
+    var mac_b64: [base64url.Encoder.calcSize(HmacSha256.mac_length)]u8 = undefined;
+    _ = base64url.Encoder.encode(&mac_b64, &mac);
 
-    fn sendHttpError(self: *WebSocketServer, client: *WebSocketClient, code: u16, message: []const u8) !void {
-        _ = self;
-        var buf: [256]u8 = undefined;
-        const response = std.fmt.bufPrint(&buf, "HTTP/1.1 {d} {s}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", .{ code, message }) catch return;
-        _ = try client.stream.write(response);
-    }
+    return std.fmt.allocPrint(allocator, "{s}={s}.{s}; Path={s}/; HttpOnly; SameSite=Lax; Max-Age={d}", .{ session_cookie_name, signed, mac_b64, base_path, ttl_secs });
+}
 
-    fn handleClientData(self: *WebSocketServer, client_id: u64) !WebSocketEvent {
-        const client = self.clients.getPtr(client_id) orelse return error.ClientNotFound;
+/// Check a session cookie's signature and expiry. The session id points into
+/// `cookie`; the user is owned by the caller.
+fn verifySessionCookie(allocator: std.mem.Allocator, secret: []const u8, cookie: []const u8, now_secs: i64) !struct { session_id: []const u8, user: []u8 } {
+    const sig_dot = std.mem.lastIndexOfScalar(u8, cookie, '.') orelse return error.MalformedSession;
+    const signed = cookie[0..sig_dot];
+
+    var mac: [HmacSha256.mac_length]u8 = undefined;
+    const sig_b64 = cookie[sig_dot + 1 ..];
+    if ((base64url.Decoder.calcSizeForSlice(sig_b64) catch return error.MalformedSession) != mac.len) return error.InvalidSignature;
+    base64url.Decoder.decode(&mac, sig_b64) catch return error.MalformedSession;
+    var expected: [HmacSha256.mac_length]u8 = undefined;
+    HmacSha256.create(&expected, signed, secret);
+    if (!std.crypto.timing_safe.eql([HmacSha256.mac_length]u8, mac, expected)) return error.InvalidSignature;
+
+    var parts = std.mem.splitScalar(u8, signed, '.');
+    const session_id = parts.next() orelse return error.MalformedSession;
+    const user_b64 = parts.next() orelse return error.MalformedSession;
+    const expires_text = parts.next() orelse return error.MalformedSession;
+    const expires = std.fmt.parseInt(i64, expires_text, 10) catch return error.MalformedSession;
+    if (now_secs >= expires) return error.SessionExpired;
+
+    const user = decodeBase64Url(allocator, user_b64) catch return error.MalformedSession;
+    return .{ .session_id = session_id, .user = user };
+}
 
-        if (!client.is_websocket) {
-            return error.NotWebSocket;
-        }
+fn decodeBase64Url(allocator: std.mem.Allocator, text: []const u8) ![]u8 {
+    const size = base64url.Decoder.calcSizeForSlice(text) catch return error.MalformedToken;
+    const out = try allocator.alloc(u8, size);
+    errdefer allocator.free(out);
+    base64url.Decoder.decode(out, text) catch return error.MalformedToken;
+    return out;
+}
 
-        var header: [14]u8 = undefined;
-        const header_read = try client.stream.read(header[0..2]);
-        if (header_read < 2) return error.ConnectionClosed;
+// ============================================================================
+// Content Filters
+// ============================================================================
 
-        const fin = (header[0] & 0x80) != 0;
-        _ = fin;
-        const opcode: WebSocketOpcode = @enumFromInt(@as(u4, @truncate(header[0] & 0x0F)));
-        const masked = (header[1] & 0x80) != 0;
-        var payload_len: u64 = header[1] & 0x7F;
+/// What happens to a message a content filter matches
+const FilterAction = enum {
+    /// Discard it
+    drop,
+    /// Deliver it with matches replaced by asterisks and overlong text cut short
+    mask,
+    /// Hold it back and report a Flagged event instead
+    flag,
+};
 
-        if (payload_len == 126) {
-            _ = try client.stream.read(header[2..4]);
-            payload_len = std.mem.readInt(u16, header[2..4], .big);
-        } else if (payload_len == 127) {
-            _ = try client.stream.read(header[2..10]);
-            payload_len = std.mem.readInt(u64, header[2..10], .big);
+/// A regular expression subset for content filters: literals, `.`, classes such
+/// as `[a-z0-9]` or `[^ ]`, the escapes \d \w \s and their negations, the
+/// quantifiers * + ?, and ^ and $ anchors. Matching ignores ASCII case. There is
+/// no grouping or alternation; list several patterns instead.
+const FilterPattern = struct {
+    source: []const u8,
+
+    fn init(source: []const u8) !FilterPattern {
+        var i: usize = if (source.len > 0 and source[0] == '^') 1 else 0;
+        while (i < source.len) {
+            if (source[i] == '$' and i == source.len - 1) break;
+            if (isQuantifier(source[i])) return error.NothingToRepeat;
+            i += try atomLength(source[i..]);
+            if (i < source.len and isQuantifier(source[i])) i += 1;
         }
+        return .{ .source = source };
+    }
 
-        var mask: [4]u8 = undefined;
-        if (masked) {
-            _ = try client.stream.read(&mask);
+    /// The first match starting at or after `from`, as byte offsets
+    fn find(self: FilterPattern, text: []const u8, from: usize) ?struct { start: usize, end: usize } {
+        if (self.source.len > 0 and self.source[0] == '^') {
+            if (from > 0) return null;
+            const end = matchHere(self.source[1..], text, 0) orelse return null;
+            return .{ .start = 0, .end = end };
         }
-
-        // Read payload
-        if (payload_len > 65536) return error.PayloadTooLarge;
-        const payload = try self.allocator.alloc(u8, @intCast(payload_len));
-
-        var total_read: usize = 0;
-        while (total_read < payload_len) {
-            const read = try client.stream.read(payload[total_read..]);
-            if (read == 0) break;
-            total_read += read;
+        var start = from;
+        while (start <= text.len) : (start += 1) {
+            if (matchHere(self.source, text, start)) |end| return .{ .start = start, .end = end };
         }
+        return null;
+    }
 
-        // Unmask
-        if (masked) {
-            for (payload, 0..) |*byte, i| {
-                byte.* ^= mask[i % 4];
-            }
-        }
+    fn isQuantifier(c: u8) bool {
+        return c == '*' or c == '+' or c == '?';
+    }
 
-        switch (opcode) {
-            .text => {
-                return .{ .message = .{ .client_id = client_id, .text = payload } };
-            },
-            .close => {
-                client.is_closed = true;
-                if (self.clients.fetchRemove(client_id)) |kv| {
-                    kv.value.stream.close();
+    /// Bytes taken by the atom at the start of `pattern`
+    fn atomLength(pattern: []const u8) !usize {
+        switch (pattern[0]) {
+            '\\' => return if (pattern.len < 2) error.TrailingBackslash else 2,
+            '[' => {
+                var i: usize = 1;
+                if (i < pattern.len and pattern[i] == '^') i += 1;
+                // A ']' first in the class is literal
+                if (i < pattern.len and pattern[i] == ']') i += 1;
+                while (i < pattern.len and pattern[i] != ']') : (i += 1) {
+                    if (pattern[i] == '\\') i += 1;
                 }
-                self.allocator.free(payload);
-                return .{ .disconnected = client_id };
-            },
-            .ping => {
-                // Send pong
-                try self.sendFrame(client, .pong, payload);
-                self.allocator.free(payload);
-                return error.ControlFrame;
-            },
-            .pong => {
-                self.allocator.free(payload);
-                return error.ControlFrame;
-            },
-            else => {
-                self.allocator.free(payload);
-                return error.UnsupportedOpcode;
+                return if (i >= pattern.len) error.UnterminatedClass else i + 1;
             },
+            else => return 1,
         }
     }
 
-    fn sendFrame(self: *WebSocketServer, client: *WebSocketClient, opcode: WebSocketOpcode, payload: []const u8) !void {
-        _ = self;
-        var header: [10]u8 = undefined;
-        var header_len: usize = 2;
-
-        header[0] = 0x80 | @as(u8, @intFromEnum(opcode)); // FIN + opcode
-
-        if (payload.len < 126) {
-            header[1] = @intCast(payload.len);
-        } else if (payload.len <= 65535) {
-            header[1] = 126;
-            std.mem.writeInt(u16, header[2..4], @intCast(payload.len), .big);
-            header_len = 4;
-        } else {
-            header[1] = 127;
-            std.mem.writeInt(u64, header[2..10], payload.len, .big);
-            header_len = 10;
+    /// Where a match of `pattern` anchored at `pos` ends
+    fn matchHere(pattern: []const u8, text: []const u8, pos: usize) ?usize {
+        if (pattern.len == 0) return pos;
+        if (pattern.len == 1 and pattern[0] == '$') return if (pos == text.len) pos else null;
+
+        const len = atomLength(pattern) catch unreachable;
+        const atom = pattern[0..len];
+        if (len < pattern.len and isQuantifier(pattern[len])) {
+            const rest = pattern[len + 1 ..];
+            const min: usize = if (pattern[len] == '+') 1 else 0;
+            const max: usize = if (pattern[len] == '?') @min(1, text.len - pos) else text.len - pos;
+            // Greedy, giving back one repetition at a time
+            var count: usize = 0;
+            while (count < max and atomMatches(atom, text[pos + count])) count += 1;
+            while (count >= min) : (count -= 1) {
+                if (matchHere(rest, text, pos + count)) |end| return end;
+                if (count == 0) break;
+            }
+            return null;
         }
+        if (pos < text.len and atomMatches(atom, text[pos])) return matchHere(pattern[len..], text, pos + 1);
+        return null;
+    }
 
-        _ = try client.stream.write(header[0..header_len]);
-        _ = try client.stream.write(payload);
+    fn atomMatches(atom: []const u8, c: u8) bool {
+        return switch (atom[0]) {
+            '.' => true,
+            '\\' => escapeMatches(atom[1], c),
+            '[' => classMatches(atom[1 .. atom.len - 1], c),
+            else => std.ascii.toLower(atom[0]) == std.ascii.toLower(c),
+        };
+    }
+
+    fn escapeMatches(escape: u8, c: u8) bool {
+        return switch (escape) {
+            'd' => std.ascii.isDigit(c),
+            'D' => !std.ascii.isDigit(c),
+            'w' => isWordByte(c),
+            'W' => !isWordByte(c),
+            's' => std.ascii.isWhitespace(c),
+            'S' => !std.ascii.isWhitespace(c),
+            else => std.ascii.toLower(escape) == std.ascii.toLower(c),
+        };
+    }
+
+    fn classMatches(class: []const u8, c: u8) bool {
+        const negated = class.len > 0 and class[0] == '^';
+        const lower = std.ascii.toLower(c);
+        var found = false;
+        var i: usize = @intFromBool(negated);
+        while (i < class.len) {
+            if (class[i] == '\\' and i + 1 < class.len) {
+                if (escapeMatches(class[i + 1], c)) found = true;
+                i += 2;
+            } else if (i + 2 < class.len and class[i + 1] == '-') {
+                if (lower >= std.ascii.toLower(class[i]) and lower <= std.ascii.toLower(class[i + 2])) found = true;
+                i += 3;
+            } else {
+                if (std.ascii.toLower(class[i]) == lower) found = true;
+                i += 1;
+            }
+        }
+        return found != negated;
+    }
+};
+
+/// Letters, digits, underscores and any byte of a non-ASCII character
+fn isWordByte(c: u8) bool {
+    return std.ascii.isAlphanumeric(c) or c == '_' or c >= 0x80;
+}
+
+/// Filters chat text passes through as it arrives, set from the [filter] config section
+const ContentFilter = struct {
+    /// Whole words, matched ignoring ASCII case
+    words: std.ArrayListUnmanaged([]const u8) = .{},
+    patterns: std.ArrayListUnmanaged(FilterPattern) = .{},
+    /// In characters; 0 for no limit
+    max_length: usize = 0,
+    /// Remove http://, https:// and www. links before the other filters run
+    strip_urls: bool = false,
+    action: FilterAction = .mask,
+
+    fn isEmpty(self: *const ContentFilter) bool {
+        return self.words.items.len == 0 and self.patterns.items.len == 0 and self.max_length == 0 and !self.strip_urls;
+    }
+
+    fn deinit(self: *ContentFilter, allocator: std.mem.Allocator) void {
+        for (self.words.items) |word| allocator.free(word);
+        self.words.deinit(allocator);
+        for (self.patterns.items) |pattern| allocator.free(pattern.source);
+        self.patterns.deinit(allocator);
+    }
+
+    fn setWords(self: *ContentFilter, allocator: std.mem.Allocator, words: []const []const u8) !void {
+        for (self.words.items) |word| allocator.free(word);
+        self.words.clearRetainingCapacity();
+        for (words) |word| {
+            if (word.len == 0) continue;
+            const owned = try allocator.dupe(u8, word);
+            errdefer allocator.free(owned);
+            try self.words.append(allocator, owned);
+        }
+    }
+
+    fn setPatterns(self: *ContentFilter, allocator: std.mem.Allocator, sources: []const []const u8) !void {
+        for (self.patterns.items) |pattern| allocator.free(pattern.source);
+        self.patterns.clearRetainingCapacity();
+        for (sources) |source| {
+            const owned = try allocator.dupe(u8, source);
+            errdefer allocator.free(owned);
+            try self.patterns.append(allocator, try FilterPattern.init(owned));
+        }
+    }
+
+    /// Run the filters over `text`. The returned text is a copy with links
+    /// stripped, matches masked and overlong text cut short; the reason names the
+    /// first filter that matched, null when none did. Both are owned.
+    fn apply(self: *const ContentFilter, allocator: std.mem.Allocator, text: []const u8) !struct { text: []u8, reason: ?[]u8 } {
+        var out = std.ArrayListUnmanaged(u8){};
+        errdefer out.deinit(allocator);
+        if (self.strip_urls) {
+            try stripUrls(allocator, &out, text);
+        } else {
+            try out.appendSlice(allocator, text);
+        }
+
+        var reason: ?[]u8 = null;
+        errdefer if (reason) |r| allocator.free(r);
+        if (self.max_length > 0) {
+            if (codepointOffset(out.items, self.max_length)) |end| {
+                reason = try allocator.dupe(u8, "max_length");
+                out.shrinkRetainingCapacity(end);
+            }
+        }
+        for (self.words.items) |word| {
+            var from: usize = 0;
+            while (std.ascii.indexOfIgnoreCasePos(out.items, from, word)) |start| {
+                const end = start + word.len;
+                from = start + 1;
+                if (start > 0 and isWordByte(out.items[start - 1])) continue;
+                if (end < out.items.len and isWordByte(out.items[end])) continue;
+                if (reason == null) reason = try std.fmt.allocPrint(allocator, "word {s}", .{word});
+                maskSpan(out.items, start, end);
+                from = end;
+            }
+        }
+        for (self.patterns.items) |pattern| {
+            var from: usize = 0;
+            while (pattern.find(out.items, from)) |span| {
+                from = @max(span.end, span.start + 1);
+                if (span.end == span.start) continue;
+                if (reason == null) reason = try std.fmt.allocPrint(allocator, "pattern {s}", .{pattern.source});
+                maskSpan(out.items, span.start, span.end);
+            }
+        }
+        return .{ .text = try out.toOwnedSlice(allocator), .reason = reason };
+    }
+
+    /// Copy `text` without its http://, https:// and www. links, leaving the spacing around them
+    fn stripUrls(allocator: std.mem.Allocator, out: *std.ArrayListUnmanaged(u8), text: []const u8) !void {
+        var i: usize = 0;
+        while (i < text.len) {
+            if (std.ascii.isWhitespace(text[i])) {
+                try out.append(allocator, text[i]);
+                i += 1;
+                continue;
+            }
+            var end = i;
+            while (end < text.len and !std.ascii.isWhitespace(text[end])) end += 1;
+            const token = text[i..end];
+            const is_url = std.ascii.startsWithIgnoreCase(token, "http://") or
+                std.ascii.startsWithIgnoreCase(token, "https://") or
+                std.ascii.startsWithIgnoreCase(token, "www.");
+            if (!is_url) try out.appendSlice(allocator, token);
+            i = end;
+        }
+    }
+
+    /// Byte offset of the character after the first `count`, null if the text is no longer
+    fn codepointOffset(text: []const u8, count: usize) ?usize {
+        var seen: usize = 0;
+        for (text, 0..) |c, i| {
+            if (c & 0xC0 == 0x80) continue;
+            if (seen == count) return i;
+            seen += 1;
+        }
+        return null;
+    }
+
+    /// Overwrite a span with asterisks, widened to whole UTF-8 characters
+    fn maskSpan(text: []u8, start: usize, end: usize) void {
+        var from = start;
+        while (from > 0 and text[from] & 0xC0 == 0x80) from -= 1;
+        var to = end;
+        while (to < text.len and text[to] & 0xC0 == 0x80) to += 1;
+        @memset(text[from..to], '*');
+    }
+};
+
+/// What the content filters decided about one message
+const FilterOutcome = union(enum) {
+    keep,
+    /// Deliver this owned text instead
+    replace: []u8,
+    drop,
+    /// Report Flagged with this owned reason
+    flag: []u8,
+};
+
+// ============================================================================
+// Configuration Files
+// ============================================================================
+
+/// Largest configuration file read
+const max_config_size = 64 * 1024;
+
+/// A WebSocket path pattern and the room its clients join, as in route_websocket!
+const RoomRoute = struct {
+    pattern: []const u8,
+    room: []const u8,
+};
+
+/// A method ("" for any), path pattern and target, as in add_route!
+const RouteConfig = struct {
+    method: []const u8,
+    pattern: []const u8,
+    target: RouteTarget,
+};
+
+/// A Host name and the static root it is served from, as in set_virtual_host!
+const VirtualHostConfig = struct {
+    host: []const u8,
+    static_dir: []const u8,
+};
+
+/// Settings read from a configuration file; absent keys leave the server's value alone
+const ServerConfig = struct {
+    /// Owns every string and list below
+    arena: std.heap.ArenaAllocator,
+    /// Used by listen_with_config! only; a reload cannot move the listener
+    bind: ?[]const u8 = null,
+    port: ?u16 = null,
+    reuse_port: ?bool = null,
+    base_path: ?[]const u8 = null,
+    log_level: ?LogLevel = null,
+    log_json: ?bool = null,
+    /// Replaces the allowed origins when present
+    allowed_origins: ?[]const []const u8 = null,
+    static_dir: ?[]const u8 = null,
+    index_file: ?[]const u8 = null,
+    spa_fallback: ?bool = null,
+    directory_listing: ?bool = null,
+    static_cache_bytes: ?u64 = null,
+    static_buffer_bytes: ?u64 = null,
+    max_connections: ?u64 = null,
+    max_frame_size: ?u64 = null,
+    max_message_size: ?u64 = null,
+    max_body_size: ?u64 = null,
+    max_header_size: ?u64 = null,
+    send_queue_bytes: ?u64 = null,
+    /// As set_event_queue_limit!
+    event_queue_limit: ?u64 = null,
+    event_overflow: ?OverflowPolicy = null,
+    /// As set_room_history!
+    history_size: ?u64 = null,
+    history_on_join: ?u64 = null,
+    /// As set_history_store!
+    history_dir: ?[]const u8 = null,
+    /// The [filter] section, as ContentFilter
+    filter_words: ?[]const []const u8 = null,
+    filter_patterns: ?[]const []const u8 = null,
+    filter_max_length: ?u64 = null,
+    filter_strip_urls: ?bool = null,
+    filter_action: ?FilterAction = null,
+    /// The [upload] section, as set_uploads!
+    upload_dir: ?[]const u8 = null,
+    upload_max_size: ?u64 = null,
+    upload_types: ?[]const []const u8 = null,
+    /// Secrets as for require_auth!, set_session_secret!, set_admin_token! and set_hook_secret!
+    jwt_secret: ?[]const u8 = null,
+    session_secret: ?[]const u8 = null,
+    admin_token: ?[]const u8 = null,
+    hook_secret: ?[]const u8 = null,
+    /// Entries of the [rooms] section, added to the WebSocket endpoints
+    rooms: std.ArrayListUnmanaged(RoomRoute) = .{},
+    vhosts: std.ArrayListUnmanaged(VirtualHostConfig) = .{},
+    routes: std.ArrayListUnmanaged(RouteConfig) = .{},
+
+    /// Read a TOML file, or JSON when the name ends in .json. On a TOML syntax or
+    /// value error `err_line` holds the offending line.
+    fn fromFile(allocator: std.mem.Allocator, path: []const u8, err_line: *usize) !ServerConfig {
+        const text = try std.fs.cwd().readFileAlloc(allocator, path, max_config_size);
+        defer allocator.free(text);
+
+        var config = ServerConfig{ .arena = std.heap.ArenaAllocator.init(allocator) };
+        errdefer config.deinit();
+        if (std.ascii.endsWithIgnoreCase(path, ".json")) {
+            try config.parseJson(text);
+        } else {
+            try config.parseToml(text, err_line);
+        }
+        return config;
+    }
+
+    fn deinit(self: *ServerConfig) void {
+        self.arena.deinit();
+    }
+
+    /// The subset of TOML a settings file needs: [section] headers and
+    /// `key = value` lines with strings, integers, booleans and string arrays.
+    /// Keys may be quoted, as paths in [rooms] are.
+    fn parseToml(self: *ServerConfig, text: []const u8, err_line: *usize) !void {
+        const allocator = self.arena.allocator();
+        var section: []const u8 = "";
+        var lines = std.mem.splitScalar(u8, text, '\n');
+        var line_no: usize = 0;
+        while (lines.next()) |raw| {
+            line_no += 1;
+            err_line.* = line_no;
+            const line = std.mem.trim(u8, stripTomlComment(raw), " \t\r");
+            if (line.len == 0) continue;
+
+            if (line[0] == '[') {
+                if (line[line.len - 1] != ']') return error.InvalidConfigLine;
+                section = std.mem.trim(u8, line[1 .. line.len - 1], " \t");
+                continue;
+            }
+
+            var key: []const u8 = undefined;
+            var rest: []const u8 = undefined;
+            if (line[0] == '"') {
+                const end = try tomlStringEnd(line);
+                key = try unescapeTomlString(allocator, line[1 .. end - 1]);
+                rest = std.mem.trimLeft(u8, line[end..], " \t");
+                if (rest.len == 0 or rest[0] != '=') return error.InvalidConfigLine;
+                rest = rest[1..];
+            } else {
+                const eq = std.mem.indexOfScalar(u8, line, '=') orelse return error.InvalidConfigLine;
+                key = std.mem.trim(u8, line[0..eq], " \t");
+                rest = line[eq + 1 ..];
+            }
+            try self.set(section, key, try ConfigValue.fromToml(allocator, std.mem.trim(u8, rest, " \t")));
+        }
+        err_line.* = 0;
+    }
+
+    /// The same settings as a JSON object whose nested objects are the sections
+    fn parseJson(self: *ServerConfig, text: []const u8) !void {
+        const allocator = self.arena.allocator();
+        const root = try std.json.parseFromSliceLeaky(std.json.Value, allocator, text, .{ .allocate = .alloc_always });
+        if (root != .object) return error.InvalidConfigValue;
+
+        var it = root.object.iterator();
+        while (it.next()) |entry| {
+            if (entry.value_ptr.* != .object) {
+                try self.set("", entry.key_ptr.*, try ConfigValue.fromJson(allocator, entry.value_ptr.*));
+                continue;
+            }
+            var section_it = entry.value_ptr.object.iterator();
+            while (section_it.next()) |field| {
+                try self.set(entry.key_ptr.*, field.key_ptr.*, try ConfigValue.fromJson(allocator, field.value_ptr.*));
+            }
+        }
+    }
+
+    fn set(self: *ServerConfig, section: []const u8, key: []const u8, value: ConfigValue) !void {
+        if (std.mem.eql(u8, section, "rooms")) {
+            if (key.len == 0 or key[0] != '/') return error.InvalidConfigValue;
+            const allocator = self.arena.allocator();
+            return self.rooms.append(allocator, .{ .pattern = try allocator.dupe(u8, key), .room = try value.string() });
+        }
+        if (std.mem.eql(u8, section, "routes")) {
+            // "GET /api/rooms/:id" for one method, a bare pattern for any
+            const allocator = self.arena.allocator();
+            const space = std.mem.indexOfScalar(u8, key, ' ');
+            const target = std.meta.stringToEnum(RouteTarget, try value.string()) orelse return error.InvalidConfigValue;
+            return self.routes.append(allocator, .{
+                .method = if (space) |i| try allocator.dupe(u8, key[0..i]) else "",
+                .pattern = try allocator.dupe(u8, if (space) |i| std.mem.trimLeft(u8, key[i + 1 ..], " ") else key),
+                .target = target,
+            });
+        }
+        if (std.mem.eql(u8, section, "vhosts")) {
+            const allocator = self.arena.allocator();
+            return self.vhosts.append(allocator, .{ .host = try allocator.dupe(u8, key), .static_dir = try value.string() });
+        }
+        if (std.mem.eql(u8, section, "tls")) return error.TlsNotSupported;
+
+        var name_buf: [128]u8 = undefined;
+        const name = if (section.len == 0)
+            key
+        else
+            std.fmt.bufPrint(&name_buf, "{s}.{s}", .{ section, key }) catch return error.UnknownConfigKey;
+
+        const eql = std.mem.eql;
+        if (eql(u8, name, "server.bind")) {
+            self.bind = try value.string();
+        } else if (eql(u8, name, "server.port")) {
+            self.port = std.math.cast(u16, try value.unsigned()) orelse return error.InvalidConfigValue;
+        } else if (eql(u8, name, "server.reuse_port")) {
+            self.reuse_port = try value.boolean();
+        } else if (eql(u8, name, "server.base_path")) {
+            self.base_path = try value.string();
+        } else if (eql(u8, name, "logging.level")) {
+            self.log_level = LogLevel.parse(try value.string()) orelse return error.InvalidConfigValue;
+        } else if (eql(u8, name, "logging.format")) {
+            const format = try value.string();
+            if (!eql(u8, format, "json") and !eql(u8, format, "text")) return error.InvalidConfigValue;
+            self.log_json = eql(u8, format, "json");
+        } else if (eql(u8, name, "allowed_origins")) {
+            self.allowed_origins = try value.strings();
+        } else if (eql(u8, name, "static.dir")) {
+            self.static_dir = try value.string();
+        } else if (eql(u8, name, "static.index")) {
+            self.index_file = try value.string();
+        } else if (eql(u8, name, "static.spa_fallback")) {
+            self.spa_fallback = try value.boolean();
+        } else if (eql(u8, name, "static.listing")) {
+            self.directory_listing = try value.boolean();
+        } else if (eql(u8, name, "static.cache_bytes")) {
+            self.static_cache_bytes = try value.unsigned();
+        } else if (eql(u8, name, "static.buffer_bytes")) {
+            self.static_buffer_bytes = try value.unsigned();
+        } else if (eql(u8, name, "limits.max_connections")) {
+            self.max_connections = try value.unsigned();
+        } else if (eql(u8, name, "limits.max_frame_size")) {
+            self.max_frame_size = try value.unsigned();
+        } else if (eql(u8, name, "limits.max_message_size")) {
+            self.max_message_size = try value.unsigned();
+        } else if (eql(u8, name, "limits.max_body_size")) {
+            self.max_body_size = try value.unsigned();
+        } else if (eql(u8, name, "limits.max_header_size")) {
+            self.max_header_size = try value.unsigned();
+        } else if (eql(u8, name, "limits.send_queue_bytes")) {
+            self.send_queue_bytes = try value.unsigned();
+        } else if (eql(u8, name, "limits.event_queue")) {
+            self.event_queue_limit = try value.unsigned();
+        } else if (eql(u8, name, "limits.event_overflow")) {
+            self.event_overflow = std.meta.stringToEnum(OverflowPolicy, try value.string()) orelse return error.InvalidConfigValue;
+        } else if (eql(u8, name, "history.size")) {
+            self.history_size = try value.unsigned();
+        } else if (eql(u8, name, "history.replay_on_join")) {
+            self.history_on_join = try value.unsigned();
+        } else if (eql(u8, name, "history.dir")) {
+            self.history_dir = try value.string();
+        } else if (eql(u8, name, "filter.words")) {
+            self.filter_words = try value.strings();
+        } else if (eql(u8, name, "filter.patterns")) {
+            const patterns = try value.strings();
+            for (patterns) |pattern| _ = try FilterPattern.init(pattern);
+            self.filter_patterns = patterns;
+        } else if (eql(u8, name, "filter.max_length")) {
+            self.filter_max_length = try value.unsigned();
+        } else if (eql(u8, name, "filter.strip_urls")) {
+            self.filter_strip_urls = try value.boolean();
+        } else if (eql(u8, name, "filter.action")) {
+            self.filter_action = std.meta.stringToEnum(FilterAction, try value.string()) orelse return error.InvalidConfigValue;
+        } else if (eql(u8, name, "upload.dir")) {
+            self.upload_dir = try value.string();
+        } else if (eql(u8, name, "upload.max_size")) {
+            self.upload_max_size = try value.unsigned();
+        } else if (eql(u8, name, "upload.types")) {
+            self.upload_types = try value.strings();
+        } else if (eql(u8, name, "auth.jwt_secret")) {
+            self.jwt_secret = try value.string();
+        } else if (eql(u8, name, "auth.session_secret")) {
+            const secret = try value.string();
+            if (secret.len < 16) return error.SecretTooShort;
+            self.session_secret = secret;
+        } else if (eql(u8, name, "auth.admin_token")) {
+            self.admin_token = try value.string();
+        } else if (eql(u8, name, "auth.hook_secret")) {
+            self.hook_secret = try value.string();
+        } else {
+            logger.warn(null, "Unknown config key {s}", .{name});
+            return error.UnknownConfigKey;
+        }
+    }
+};
+
+const ConfigValue = union(enum) {
+    string: []const u8,
+    integer: i64,
+    boolean: bool,
+    strings: []const []const u8,
+
+    fn fromToml(allocator: std.mem.Allocator, text: []const u8) !ConfigValue {
+        if (text.len == 0) return error.InvalidConfigValue;
+        if (std.mem.eql(u8, text, "true")) return .{ .boolean = true };
+        if (std.mem.eql(u8, text, "false")) return .{ .boolean = false };
+        if (text[0] == '"') {
+            const end = try tomlStringEnd(text);
+            if (end != text.len) return error.InvalidConfigValue;
+            return .{ .string = try unescapeTomlString(allocator, text[1 .. end - 1]) };
+        }
+        if (text[0] == '[') {
+            if (text[text.len - 1] != ']') return error.InvalidConfigValue;
+            var items = std.ArrayListUnmanaged([]const u8){};
+            var rest = std.mem.trim(u8, text[1 .. text.len - 1], " \t");
+            while (rest.len > 0) {
+                if (rest[0] != '"') return error.InvalidConfigValue;
+                const end = try tomlStringEnd(rest);
+                try items.append(allocator, try unescapeTomlString(allocator, rest[1 .. end - 1]));
+                rest = std.mem.trimLeft(u8, rest[end..], " \t");
+                if (rest.len == 0) break;
+                if (rest[0] != ',') return error.InvalidConfigValue;
+                rest = std.mem.trimLeft(u8, rest[1..], " \t");
+            }
+            return .{ .strings = items.items };
+        }
+        // Underscores may separate digit groups, as in 1_048_576
+        var digits: [32]u8 = undefined;
+        var len: usize = 0;
+        for (text) |c| {
+            if (c == '_') continue;
+            if (len == digits.len) return error.InvalidConfigValue;
+            digits[len] = c;
+            len += 1;
+        }
+        return .{ .integer = std.fmt.parseInt(i64, digits[0..len], 10) catch return error.InvalidConfigValue };
+    }
+
+    fn fromJson(allocator: std.mem.Allocator, value: std.json.Value) !ConfigValue {
+        return switch (value) {
+            .string => |s| .{ .string = s },
+            .integer => |n| .{ .integer = n },
+            .bool => |b| .{ .boolean = b },
+            .array => |array| {
+                const items = try allocator.alloc([]const u8, array.items.len);
+                for (array.items, items) |item, *out| {
+                    if (item != .string) return error.InvalidConfigValue;
+                    out.* = item.string;
+                }
+                return .{ .strings = items };
+            },
+            else => error.InvalidConfigValue,
+        };
+    }
+
+    fn string(self: ConfigValue) ![]const u8 {
+        return switch (self) {
+            .string => |s| s,
+            else => error.InvalidConfigValue,
+        };
+    }
+
+    fn strings(self: ConfigValue) ![]const []const u8 {
+        return switch (self) {
+            .strings => |s| s,
+            else => error.InvalidConfigValue,
+        };
+    }
+
+    fn boolean(self: ConfigValue) !bool {
+        return switch (self) {
+            .boolean => |b| b,
+            else => error.InvalidConfigValue,
+        };
+    }
+
+    fn unsigned(self: ConfigValue) !u64 {
+        return switch (self) {
+            .integer => |n| std.math.cast(u64, n) orelse error.InvalidConfigValue,
+            else => error.InvalidConfigValue,
+        };
+    }
+};
+
+/// Index just past the closing quote of the basic string `text` starts with
+fn tomlStringEnd(text: []const u8) !usize {
+    var i: usize = 1;
+    while (i < text.len) : (i += 1) {
+        switch (text[i]) {
+            '\\' => i += 1,
+            '"' => return i + 1,
+            else => {},
+        }
+    }
+    return error.InvalidConfigValue;
+}
+
+fn unescapeTomlString(allocator: std.mem.Allocator, text: []const u8) ![]const u8 {
+    if (std.mem.indexOfScalar(u8, text, '\\') == null) return allocator.dupe(u8, text);
+    var out = try std.ArrayListUnmanaged(u8).initCapacity(allocator, text.len);
+    var i: usize = 0;
+    while (i < text.len) : (i += 1) {
+        if (text[i] != '\\') {
+            out.appendAssumeCapacity(text[i]);
+            continue;
+        }
+        i += 1;
+        if (i == text.len) return error.InvalidConfigValue;
+        out.appendAssumeCapacity(switch (text[i]) {
+            'n' => '\n',
+            't' => '\t',
+            '"' => '"',
+            '\\' => '\\',
+            else => return error.InvalidConfigValue,
+        });
+    }
+    return out.items;
+}
+
+/// Drop a trailing # comment, leaving # inside strings alone
+fn stripTomlComment(line: []const u8) []const u8 {
+    var in_string = false;
+    var i: usize = 0;
+    while (i < line.len) : (i += 1) {
+        switch (line[i]) {
+            '\\' => if (in_string) {
+                i += 1;
+            },
+            '"' => in_string = !in_string,
+            '#' => if (!in_string) return line[0..i],
+            else => {},
+        }
+    }
+    return line;
+}
+
+// ============================================================================
+// Signals
+// ============================================================================
+
+/// How long SIGINT/SIGTERM give clients before they are closed
+const signal_drain_grace_ms = 5_000;
+
+/// Self-pipe: the handler writes the signal number, the event loop polls the read end
+var signal_pipe: ?[2]std.posix.fd_t = null;
+
+fn onSignal(sig: i32) callconv(.c) void {
+    const fds = signal_pipe orelse return;
+    const byte: u8 = @intCast(sig);
+    _ = std.posix.write(fds[1], &[_]u8{byte}) catch {};
+}
+
+/// Deliver `sig` through the signal pipe, creating the pipe on first use.
+/// Windows has no POSIX signals, so there this does nothing and Ctrl-C ends
+/// the process without a drain.
+fn watchSignal(comptime sig: comptime_int) !void {
+    if (@import("builtin").os.tag != .windows) {
+        if (signal_pipe == null) signal_pipe = try std.posix.pipe2(.{ .NONBLOCK = true, .CLOEXEC = true });
+        const action = std.posix.Sigaction{
+            .handler = .{ .handler = onSignal },
+            .mask = std.posix.sigemptyset(),
+            .flags = std.posix.SA.RESTART,
+        };
+        std.posix.sigaction(sig, &action, null);
+    }
+}
+
+// ============================================================================
+// WebSocket Server Implementation
+// ============================================================================
+
+const WebSocketOpcode = codec.Opcode;
+
+// Close status codes (RFC 6455 section 7.4.1)
+const close_normal: u16 = 1000;
+const close_going_away: u16 = 1001;
+const close_protocol_error: u16 = 1002;
+const close_no_status: u16 = 1005;
+const close_abnormal: u16 = 1006;
+const close_invalid_payload: u16 = 1007;
+const close_policy_violation: u16 = 1008;
+const close_message_too_big: u16 = 1009;
+const close_internal_error: u16 = 1011;
+const close_try_again_later: u16 = 1013;
+
+/// How long closeClient waits for the peer to echo a Close frame
+const close_echo_timeout_ms = 500;
+
+/// SSE fallback endpoints: the event stream, and where its clients post messages
+const sse_events_path = "/events";
+const sse_send_path = "/send";
+
+/// Inbound webhooks post to this prefix followed by the room name
+const hook_path_prefix = "/hooks/";
+
+/// Operator endpoints: clients, kick/{id}, broadcast, stats
+const admin_path_prefix = "/admin/";
+
+/// POST here to upload a file; stored files are served from beneath it
+const upload_path = "/upload";
+
+/// Types accepted by POST /upload unless set_uploads! lists others; nothing a browser would run
+const default_upload_types = [_][]const u8{ "image/png", "image/jpeg", "image/gif", "image/webp", "audio/*", "video/*", "application/pdf", "text/plain" };
+
+/// Orchestrator probes: liveness (the event loop answers) and readiness (new clients are welcome)
+const health_path = "/healthz";
+const ready_path = "/readyz";
+
+/// Allowed sustained rate (per second) and burst; a rate of 0 disables the limit
+const RateLimit = struct {
+    rate: u32 = 0,
+    burst: u32 = 0,
+};
+
+/// Token bucket for a RateLimit; a fresh bucket starts full
+const TokenBucket = struct {
+    tokens: f64 = 0,
+    last_ms: i64 = 0,
+
+    fn refill(self: *TokenBucket, limit: RateLimit, now_ms: i64) void {
+        const burst: f64 = @floatFromInt(@max(limit.burst, 1));
+        if (self.last_ms == 0) {
+            self.tokens = burst;
+        } else {
+            const elapsed: f64 = @floatFromInt(@max(now_ms - self.last_ms, 0));
+            self.tokens = @min(burst, self.tokens + elapsed * @as(f64, @floatFromInt(limit.rate)) / 1000.0);
+        }
+        self.last_ms = now_ms;
+    }
+
+    /// Consume a token, returning false if the bucket is empty
+    fn take(self: *TokenBucket, limit: RateLimit, now_ms: i64) bool {
+        if (limit.rate == 0) return true;
+        self.refill(limit, now_ms);
+        if (self.tokens < 1) return false;
+        self.tokens -= 1;
+        return true;
+    }
+};
+
+/// Key identifying a peer's IP address, ignoring the port
+fn addressKey(address: std.net.Address) u128 {
+    return switch (address.any.family) {
+        std.posix.AF.INET => std.mem.bigToNative(u32, address.in.sa.addr),
+        std.posix.AF.INET6 => std.mem.readInt(u128, &address.in6.sa.addr, .big),
+        else => 0,
+    };
+}
+
+/// An IPv4 or IPv6 network in CIDR notation; a bare address is a /32 or /128
+const Cidr = struct {
+    family: std.posix.sa_family_t,
+    base: u128,
+    prefix: u8,
+
+    fn parse(text: []const u8) !Cidr {
+        const slash = std.mem.indexOfScalar(u8, text, '/');
+        const ip = std.mem.trim(u8, text[0 .. slash orelse text.len], " ");
+        const address = std.net.Address.parseIp(ip, 0) catch return error.InvalidAddress;
+        const bits: u8 = if (address.any.family == std.posix.AF.INET) 32 else 128;
+        const prefix = if (slash) |i|
+            std.fmt.parseInt(u8, text[i + 1 ..], 10) catch return error.InvalidAddress
+        else
+            bits;
+        if (prefix > bits) return error.InvalidAddress;
+        return .{ .family = address.any.family, .base = addressKey(address), .prefix = prefix };
+    }
+
+    fn contains(self: Cidr, address: std.net.Address) bool {
+        if (address.any.family != self.family) return false;
+        if (self.prefix == 0) return true;
+        const bits: u8 = if (self.family == std.posix.AF.INET) 32 else 128;
+        const shift: u7 = @intCast(bits - self.prefix);
+        return (addressKey(address) >> shift) == (self.base >> shift);
+    }
+
+    fn anyContains(networks: []const Cidr, address: std.net.Address) bool {
+        for (networks) |network| {
+            if (network.contains(address)) return true;
+        }
+        return false;
+    }
+};
+
+/// A client's standing in a room, least to most privileged. Stored in the
+/// client's data as "role:<room>", falling back to "role", then to member.
+const Role = enum(u8) {
+    readonly,
+    member,
+    moderator,
+    owner,
+
+    fn parse(name: []const u8) ?Role {
+        return std.meta.stringToEnum(Role, name);
+    }
+};
+
+/// The least role a room requires for each operation (set_room_policy!)
+const RoomPolicy = struct {
+    join: Role = .readonly,
+    send: Role = .member,
+};
+
+/// A room operation subject to its policy
+const RoomAction = enum { join, send };
+
+/// An address or network banned or muted until a deadline (ban_ip!, ban_client!, mute_client!)
+const Restriction = struct {
+    network: Cidr,
+    /// 0 while it lasts until lifted
+    until_ms: i64,
+
+    fn active(self: Restriction, now: i64) bool {
+        return self.until_ms == 0 or now < self.until_ms;
+    }
+
+    /// The restriction in force for an address, if any
+    fn find(list: []const Restriction, address: std.net.Address, now: i64) ?Restriction {
+        for (list) |restriction| {
+            if (restriction.active(now) and restriction.network.contains(address)) return restriction;
+        }
+        return null;
+    }
+
+    /// Add or renew a restriction for `duration_ms` (0 for until lifted), dropping expired ones
+    fn add(list: *std.ArrayListUnmanaged(Restriction), allocator: std.mem.Allocator, network: Cidr, duration_ms: u64) !void {
+        const now = std.time.milliTimestamp();
+        const until_ms: i64 = if (duration_ms == 0) 0 else now +| (std.math.cast(i64, duration_ms) orelse std.math.maxInt(i64));
+        var i: usize = 0;
+        while (i < list.items.len) {
+            if (!list.items[i].active(now) or std.meta.eql(list.items[i].network, network)) {
+                _ = list.swapRemove(i);
+            } else {
+                i += 1;
+            }
+        }
+        try list.append(allocator, .{ .network = network, .until_ms = until_ms });
+    }
+
+    /// Lift every restriction on exactly this network
+    fn remove(list: *std.ArrayListUnmanaged(Restriction), network: Cidr) void {
+        var i: usize = 0;
+        while (i < list.items.len) {
+            if (std.meta.eql(list.items[i].network, network)) {
+                _ = list.swapRemove(i);
+            } else {
+                i += 1;
+            }
+        }
+    }
+};
+
+/// The address a client connected from, as resolved through trusted proxies
+fn clientAddress(client: *const WebSocketClient) ?std.net.Address {
+    const ip = client.data.get("address") orelse return null;
+    return std.net.Address.parseIp(ip, 0) catch null;
+}
+
+/// A single address as a network
+fn hostNetwork(address: std.net.Address) Cidr {
+    const bits: u8 = if (address.any.family == std.posix.AF.INET) 32 else 128;
+    return .{ .family = address.any.family, .base = addressKey(address), .prefix = bits };
+}
+
+/// An address without its port, e.g. "10.0.0.1" or "::1"
+fn formatIp(address: std.net.Address, buf: []u8) []const u8 {
+    const text = std.fmt.bufPrint(buf, "{f}", .{address}) catch return "";
+    const colon = std.mem.lastIndexOfScalar(u8, text, ':') orelse return text;
+    return std.mem.trim(u8, text[0..colon], "[]");
+}
+
+/// Per-IP buckets kept before idle ones are pruned
+const max_connect_buckets = 4096;
+
+/// How long to stop accepting after running out of file descriptors
+const accept_backoff_ms = 250;
+
+/// Remaining span of a streamed static file
+const Download = struct {
+    file: std.fs.File,
+    offset: u64,
+    /// Exclusive
+    end: u64,
+};
+
+const WebSocketClient = struct {
+    id: u64,
+    stream: std.net.Stream,
+    /// Peer address of an accepted connection (a proxy's, when behind one)
+    address: ?std.net.Address = null,
+    is_websocket: bool = false,
+    is_closed: bool = false,
+    /// Outbound connection dialed by connectUpstream; frames we send must be masked
+    is_upstream: bool = false,
+    /// Server-Sent Events pseudo-client: output is written as SSE, input arrives via POST
+    is_sse: bool = false,
+    /// Negotiated the MessagePack envelope subprotocol: envelopes travel as binary frames
+    msgpack: bool = false,
+    /// Set when the client negotiated graphql-transport-ws
+    graphql: ?GraphqlSession = null,
+    /// Set when the client negotiated STOMP 1.2
+    stomp: ?StompSession = null,
+    /// Static file body still being written by the poll loop
+    download: ?Download = null,
+    /// HTTP response still in the outbox; the connection closes once it has all been written
+    closing: bool = false,
+    /// Engine.IO client on /socket.io/: text goes out as Socket.IO "message" events
+    is_socketio: bool = false,
+    /// The Socket.IO client connected to the main namespace
+    socketio_connected: bool = false,
+    /// Secret an SSE client presents when posting messages (hex)
+    sse_token: [32]u8 = undefined,
+    /// Opcode of the fragmented message being reassembled, if any
+    fragment_opcode: ?WebSocketOpcode = null,
+    /// Payload collected so far from continuation frames
+    fragment_buf: std.ArrayListUnmanaged(u8) = .{},
+    /// Last time any frame was received from the client (ms since epoch)
+    last_activity_ms: i64 = 0,
+    /// Last time the server pinged the client (ms since epoch)
+    last_ping_ms: i64 = 0,
+    /// Application-defined attributes (username, auth status, ...), keys and values owned
+    data: std.StringHashMapUnmanaged([]const u8) = .{},
+    /// Encoded frames the socket has not accepted yet
+    outbox: std.ArrayListUnmanaged(u8) = .{},
+    /// Set once the outbox exceeds the server's limit; the client is dropped on the next poll
+    outbox_overflowed: bool = false,
+    /// Bytes ever accepted for sending (written or in the outbox)
+    bytes_queued: u64 = 0,
+    /// When the connection was accepted (ms since epoch)
+    connected_ms: i64 = 0,
+    traffic: Traffic = .{},
+    /// send_with_ack! messages whose bytes have not all reached the socket yet, oldest first
+    pending_acks: std.ArrayListUnmanaged(PendingAck) = .{},
+    /// Secret (hex) a reconnecting client presents to take this id over again
+    resume_token: ?[32]u8 = null,
+    /// Connection lost but resumable until this time (ms since epoch); 0 while attached.
+    /// Output collects in the outbox meanwhile.
+    detached_until_ms: i64 = 0,
+    /// HTTP request bytes received before the connection is upgraded or served
+    request_buf: std.ArrayListUnmanaged(u8) = .{},
+    /// Frame bytes received but not yet decoded; frames may span several reads
+    read_buf: std.ArrayListUnmanaged(u8) = .{},
+    /// Request was handed to the application and awaits respond!
+    http_pending: bool = false,
+    /// Interim 100 Continue already sent for the current request
+    sent_continue: bool = false,
+    /// A trusted proxy reported the request arrived over HTTPS
+    via_https: bool = false,
+    /// Upgrade request handed to the application, awaiting accept/reject_handshake!
+    handshake_pending: bool = false,
+    /// Sec-WebSocket-Accept value for a pending handshake
+    handshake_accept: [28]u8 = undefined,
+    /// Inbound message allowance under the server's message rate limit
+    message_bucket: TokenBucket = .{},
+    /// Accepted while the server was full; turned away once its request is read
+    over_capacity: bool = false,
+
+    fn deinit(self: *WebSocketClient, allocator: std.mem.Allocator) void {
+        self.stream.close();
+        if (self.download) |download| download.file.close();
+        self.fragment_buf.deinit(allocator);
+        self.outbox.deinit(allocator);
+        self.pending_acks.deinit(allocator);
+        self.request_buf.deinit(allocator);
+        self.read_buf.deinit(allocator);
+        if (self.graphql) |*session| session.deinit(allocator);
+        if (self.stomp) |*session| session.deinit(allocator);
+
+        var it = self.data.iterator();
+        while (it.next()) |entry| {
+            allocator.free(entry.key_ptr.*);
+            allocator.free(entry.value_ptr.*);
+        }
+        self.data.deinit(allocator);
+    }
+
+    fn setData(self: *WebSocketClient, allocator: std.mem.Allocator, key: []const u8, value: []const u8) !void {
+        const owned_value = try allocator.dupe(u8, value);
+        errdefer allocator.free(owned_value);
+
+        const gop = try self.data.getOrPut(allocator, key);
+        if (gop.found_existing) {
+            allocator.free(gop.value_ptr.*);
+        } else {
+            gop.key_ptr.* = allocator.dupe(u8, key) catch |err| {
+                _ = self.data.remove(key);
+                return err;
+            };
+        }
+        gop.value_ptr.* = owned_value;
+    }
+};
+
+const WebSocketEvent = union(enum) {
+    connected: u64,
+    disconnected: struct { client_id: u64, code: u16, reason: []const u8 },
+    message: struct { client_id: u64, text: []const u8 },
+    binary_message: struct { client_id: u64, data: []const u8 },
+    upstream_message: struct { client_id: u64, text: []const u8 },
+    /// Upgrade request awaiting the application's decision; owned, release with deinit
+    handshake_request: HttpRequestEvent,
+    /// Owned; release with deinit
+    http_request: HttpRequestEvent,
+    /// Message posted to /hooks/{room}; owned, release with deinit
+    hook_message: HookMessage,
+    /// File stored through POST /upload; owned, release with deinit
+    file_uploaded: FileUpload,
+    /// Text message parsed under the envelope protocol, or a Socket.IO event
+    /// (as a command); owned, release with deinit
+    envelope: Envelope,
+    /// Text message parsed under the JSON-RPC protocol; owned, release with deinit
+    rpc_call: RpcCall,
+    /// A graphql-transport-ws client started an operation; id and payload (JSON) are owned
+    graphql_subscribe: struct { client_id: u64, id: []const u8, payload: []const u8 },
+    /// A graphql-transport-ws client stopped a running operation; id is owned
+    graphql_complete: struct { client_id: u64, id: []const u8 },
+    /// A client or address exceeded a rate limit; reason is owned
+    rate_limited: struct { client_id: u64, reason: []const u8 },
+    /// A connected client was closed by a ban on its address; address is owned
+    banned: struct { client_id: u64, address: []const u8 },
+    /// A content filter set to flag matched a message, held back here; reason and text are owned
+    flagged: struct { client_id: u64, reason: []const u8, text: []const u8 },
+    /// A room policy refused a client's join or message; room is owned
+    permission_denied: struct { client_id: u64, room: []const u8, action: RoomAction },
+    /// Chat from a muted client was dropped; 0 remaining for a mute without end
+    muted: struct { client_id: u64, remaining_ms: u64 },
+    /// A dropped client came back with its resume token and keeps its id
+    resumed: u64,
+    /// A line the operator typed on stdin (watch_stdin!); owned, without the newline
+    admin_command: []const u8,
+    /// A set_timer! deadline passed; tag is owned
+    timer: struct { timer_id: u64, tag: []const u8 },
+    /// A send_with_ack! message was handed to the kernel in full
+    delivered: u64,
+    /// A send_with_ack! message was dropped with its connection; reason is static
+    delivery_failed: struct { message_id: u64, reason: []const u8 },
+    /// Events were discarded because the event queue was full; carries how many
+    overflow: u64,
+    /// Owned
+    err: []const u8,
+    shutdown: void,
+
+    /// Release what the event owns, for events discarded before reaching the application
+    fn deinit(self: WebSocketEvent, allocator: std.mem.Allocator) void {
+        switch (self) {
+            .disconnected => |info| allocator.free(info.reason),
+            .message => |msg| allocator.free(msg.text),
+            .upstream_message => |msg| allocator.free(msg.text),
+            .binary_message => |msg| allocator.free(msg.data),
+            .handshake_request, .http_request => |req| req.deinit(allocator),
+            .hook_message => |hook| hook.deinit(allocator),
+            .file_uploaded => |upload| upload.deinit(allocator),
+            .envelope => |envelope| envelope.deinit(allocator),
+            .rpc_call => |call| call.deinit(allocator),
+            .graphql_subscribe => |op| {
+                allocator.free(op.id);
+                allocator.free(op.payload);
+            },
+            .graphql_complete => |op| allocator.free(op.id),
+            .rate_limited => |info| allocator.free(info.reason),
+            .err => |msg| allocator.free(msg),
+            .banned => |info| allocator.free(info.address),
+            .flagged => |info| {
+                allocator.free(info.reason);
+                allocator.free(info.text);
+            },
+            .permission_denied => |info| allocator.free(info.room),
+            .admin_command => |command| allocator.free(command),
+            .timer => |info| allocator.free(info.tag),
+            .connected, .muted, .resumed, .delivered, .delivery_failed, .overflow, .shutdown => {},
+        }
+    }
+
+    /// The client whose traffic produced the event, if any
+    fn clientId(self: WebSocketEvent) ?u64 {
+        return switch (self) {
+            .message => |msg| msg.client_id,
+            .upstream_message => |msg| msg.client_id,
+            .binary_message => |msg| msg.client_id,
+            .handshake_request, .http_request => |req| req.client_id,
+            .envelope => |envelope| envelope.client_id,
+            .rpc_call => |call| call.client_id,
+            .graphql_subscribe => |op| op.client_id,
+            .graphql_complete => |op| op.client_id,
+            .rate_limited => |info| info.client_id,
+            .banned => |info| info.client_id,
+            .flagged => |info| info.client_id,
+            .permission_denied => |info| info.client_id,
+            .muted => |info| info.client_id,
+            else => null,
+        };
+    }
+
+    /// Connection lifecycle events are never discarded: there is at most one
+    /// of each per connection, and the application's bookkeeping relies on them
+    fn isLifecycle(self: WebSocketEvent) bool {
+        return switch (self) {
+            .connected, .disconnected, .resumed, .shutdown => true,
+            else => false,
+        };
+    }
+};
+
+/// What happens to an event that arrives while the event queue is full
+const OverflowPolicy = enum {
+    /// Discard the oldest queued event to make room
+    drop_oldest,
+    /// Discard the arriving event
+    drop_newest,
+    /// Discard the arriving event and close the client that produced it with 1013
+    disconnect_producer,
+};
+
+/// A file stored through POST /upload
+const FileUpload = struct {
+    /// The uploader's "user" attribute, "" without authentication
+    user: []const u8,
+    /// Where the file can be downloaded, e.g. /upload/3f9c….png
+    url: []const u8,
+    mime: []const u8,
+    size: u64,
+
+    fn init(allocator: std.mem.Allocator, user: []const u8, url: []const u8, mime: []const u8, size: u64) !FileUpload {
+        const owned_user = try allocator.dupe(u8, user);
+        errdefer allocator.free(owned_user);
+        const owned_url = try allocator.dupe(u8, url);
+        errdefer allocator.free(owned_url);
+        return .{ .user = owned_user, .url = owned_url, .mime = try allocator.dupe(u8, mime), .size = size };
+    }
+
+    fn deinit(self: FileUpload, allocator: std.mem.Allocator) void {
+        allocator.free(self.user);
+        allocator.free(self.url);
+        allocator.free(self.mime);
+    }
+};
+
+/// A message an external system posted to a room through POST /hooks/{room}
+const HookMessage = struct {
+    room: []const u8,
+    sender: []const u8,
+    text: []const u8,
+
+    fn init(allocator: std.mem.Allocator, room: []const u8, sender: []const u8, text: []const u8) !HookMessage {
+        const owned_room = try allocator.dupe(u8, room);
+        errdefer allocator.free(owned_room);
+        const owned_sender = try allocator.dupe(u8, sender);
+        errdefer allocator.free(owned_sender);
+        return .{ .room = owned_room, .sender = owned_sender, .text = try allocator.dupe(u8, text) };
+    }
+
+    fn deinit(self: HookMessage, allocator: std.mem.Allocator) void {
+        allocator.free(self.room);
+        allocator.free(self.sender);
+        allocator.free(self.text);
+    }
+};
+
+/// A text message in the envelope protocol (set_envelope_protocol!):
+/// {"type", "room"?, "id"?, "ts"?, "payload"?}
+const Envelope = struct {
+    client_id: u64,
+    kind: Kind,
+    /// The envelope's "type"; for commands, the application's own name
+    type_name: []const u8,
+    room: []const u8,
+    id: []const u8,
+    /// A message's text; a command's payload as JSON ("" when absent)
+    payload: []const u8,
+
+    const Kind = enum { chat_message, join_request, command };
+
+    /// "message" needs a room and a string payload, "join" a room; any other
+    /// type is a command. A "ts" must be a number and is otherwise ignored.
+    fn parse(allocator: std.mem.Allocator, client_id: u64, text: []const u8) !Envelope {
+        const parsed = std.json.parseFromSlice(std.json.Value, allocator, text, .{}) catch return error.MalformedEnvelope;
+        defer parsed.deinit();
+        if (parsed.value != .object) return error.MalformedEnvelope;
+        const fields = &parsed.value.object;
+
+        const type_name = try stringField(fields, "type") orelse return error.MissingType;
+        const room = try stringField(fields, "room") orelse "";
+        const id = try stringField(fields, "id") orelse "";
+        if (fields.get("ts")) |ts| {
+            if (ts != .integer and ts != .float) return error.InvalidTimestamp;
+        }
+        const payload_value = fields.get("payload") orelse .null;
+
+        const kind: Kind = if (std.mem.eql(u8, type_name, "message"))
+            .chat_message
+        else if (std.mem.eql(u8, type_name, "join"))
+            .join_request
+        else
+            .command;
+        if (kind != .command and room.len == 0) return error.MissingRoom;
+        if (kind == .chat_message and payload_value != .string) return error.InvalidPayload;
+
+        const owned_type = try allocator.dupe(u8, type_name);
+        errdefer allocator.free(owned_type);
+        const owned_room = try allocator.dupe(u8, room);
+        errdefer allocator.free(owned_room);
+        const owned_id = try allocator.dupe(u8, id);
+        errdefer allocator.free(owned_id);
+        const payload = switch (kind) {
+            .chat_message => try allocator.dupe(u8, payload_value.string),
+            .join_request => try allocator.dupe(u8, ""),
+            .command => if (payload_value == .null)
+                try allocator.dupe(u8, "")
+            else
+                try std.json.Stringify.valueAlloc(allocator, payload_value, .{}),
+        };
+        return .{
+            .client_id = client_id,
+            .kind = kind,
+            .type_name = owned_type,
+            .room = owned_room,
+            .id = owned_id,
+            .payload = payload,
+        };
+    }
+
+    /// A string field, null when absent or null
+    fn stringField(fields: *const std.json.ObjectMap, name: []const u8) !?[]const u8 {
+        const value = fields.get(name) orelse return null;
+        return switch (value) {
+            .string => |s| s,
+            .null => null,
+            else => error.InvalidField,
+        };
+    }
+
+    fn deinit(self: Envelope, allocator: std.mem.Allocator) void {
+        allocator.free(self.type_name);
+        allocator.free(self.room);
+        allocator.free(self.id);
+        allocator.free(self.payload);
+    }
+};
+
+/// Encode an envelope stamped with the current time; an empty room or id is left out
+fn writeEnvelope(w: anytype, type_name: []const u8, room: []const u8, id: []const u8, payload: []const u8) !void {
+    try w.writeAll("{\"type\":");
+    try writeJsonString(w, type_name);
+    if (room.len > 0) {
+        try w.writeAll(",\"room\":");
+        try writeJsonString(w, room);
+    }
+    if (id.len > 0) {
+        try w.writeAll(",\"id\":");
+        try writeJsonString(w, id);
+    }
+    try w.print(",\"ts\":{d},\"payload\":", .{std.time.milliTimestamp()});
+    try writeJsonString(w, payload);
+    try w.writeByte('}');
+}
+
+/// Subprotocol under which envelopes travel as MessagePack binary frames
+const msgpack_protocol = "chat.envelope.msgpack";
+
+/// Nesting deeper than this is rejected when decoding MessagePack
+const max_msgpack_depth = 32;
+
+/// Decode one MessagePack value and re-encode it as JSON, so binary envelopes go
+/// through the same validation as text ones. Bin fields must hold UTF-8.
+fn msgpackToJson(allocator: std.mem.Allocator, bytes: []const u8) ![]u8 {
+    var arena = std.heap.ArenaAllocator.init(allocator);
+    defer arena.deinit();
+    var reader = MsgpackReader{ .bytes = bytes };
+    const value = try reader.value(arena.allocator(), 0);
+    if (reader.pos != bytes.len) return error.TrailingBytes;
+    return std.json.Stringify.valueAlloc(allocator, value, .{});
+}
+
+/// Encode JSON text as MessagePack; null when the text is not JSON
+fn jsonToMsgpack(allocator: std.mem.Allocator, text: []const u8) ?[]u8 {
+    const parsed = std.json.parseFromSlice(std.json.Value, allocator, text, .{}) catch return null;
+    defer parsed.deinit();
+    var out = std.ArrayListUnmanaged(u8){};
+    writeMsgpack(out.writer(allocator), parsed.value) catch {
+        out.deinit(allocator);
+        return null;
+    };
+    return out.toOwnedSlice(allocator) catch null;
+}
+
+const MsgpackReader = struct {
+    bytes: []const u8,
+    pos: usize = 0,
+
+    fn take(self: *MsgpackReader, len: usize) ![]const u8 {
+        if (self.bytes.len - self.pos < len) return error.TruncatedMsgpack;
+        defer self.pos += len;
+        return self.bytes[self.pos .. self.pos + len];
+    }
+
+    fn int(self: *MsgpackReader, comptime T: type) !T {
+        const raw = try self.take(@sizeOf(T));
+        return std.mem.readInt(T, raw[0..@sizeOf(T)], .big);
+    }
+
+    fn value(self: *MsgpackReader, allocator: std.mem.Allocator, depth: usize) anyerror!std.json.Value {
+        if (depth > max_msgpack_depth) return error.MsgpackTooDeep;
+        const tag = (try self.take(1))[0];
+        return switch (tag) {
+            0x00...0x7f => .{ .integer = tag },
+            0x80...0x8f => self.map(allocator, tag & 0x0f, depth),
+            0x90...0x9f => self.array(allocator, tag & 0x0f, depth),
+            0xa0...0xbf => self.string(tag & 0x1f),
+            0xc0 => .null,
+            0xc2 => .{ .bool = false },
+            0xc3 => .{ .bool = true },
+            0xc4 => self.string(try self.int(u8)),
+            0xc5 => self.string(try self.int(u16)),
+            0xc6 => self.string(try self.int(u32)),
+            0xca => .{ .float = @as(f32, @bitCast(try self.int(u32))) },
+            0xcb => .{ .float = @bitCast(try self.int(u64)) },
+            0xcc => .{ .integer = try self.int(u8) },
+            0xcd => .{ .integer = try self.int(u16) },
+            0xce => .{ .integer = try self.int(u32) },
+            0xcf => .{ .integer = std.math.cast(i64, try self.int(u64)) orelse return error.IntegerTooLarge },
+            0xd0 => .{ .integer = try self.int(i8) },
+            0xd1 => .{ .integer = try self.int(i16) },
+            0xd2 => .{ .integer = try self.int(i32) },
+            0xd3 => .{ .integer = try self.int(i64) },
+            0xd9 => self.string(try self.int(u8)),
+            0xda => self.string(try self.int(u16)),
+            0xdb => self.string(try self.int(u32)),
+            0xdc => self.array(allocator, try self.int(u16), depth),
+            0xdd => self.array(allocator, try self.int(u32), depth),
+            0xde => self.map(allocator, try self.int(u16), depth),
+            0xdf => self.map(allocator, try self.int(u32), depth),
+            0xe0...0xff => .{ .integer = @as(i8, @bitCast(tag)) },
+            // Extension types (timestamps included) have no JSON equivalent
+            else => error.UnsupportedMsgpackType,
+        };
+    }
+
+    fn string(self: *MsgpackReader, len: usize) !std.json.Value {
+        const text = try self.take(len);
+        if (!std.unicode.utf8ValidateSlice(text)) return error.InvalidPayload;
+        return .{ .string = text };
+    }
+
+    fn array(self: *MsgpackReader, allocator: std.mem.Allocator, len: usize, depth: usize) !std.json.Value {
+        // Each element takes at least a byte; a bogus length fails here instead of allocating
+        if (len > self.bytes.len - self.pos) return error.TruncatedMsgpack;
+        var items = try std.json.Array.initCapacity(allocator, len);
+        for (0..len) |_| items.appendAssumeCapacity(try self.value(allocator, depth + 1));
+        return .{ .array = items };
+    }
+
+    fn map(self: *MsgpackReader, allocator: std.mem.Allocator, len: usize, depth: usize) !std.json.Value {
+        if (len > self.bytes.len - self.pos) return error.TruncatedMsgpack;
+        var fields = std.json.ObjectMap.init(allocator);
+        for (0..len) |_| {
+            const key = try self.value(allocator, depth + 1);
+            if (key != .string) return error.NonStringKey;
+            try fields.put(key.string, try self.value(allocator, depth + 1));
+        }
+        return .{ .object = fields };
+    }
+};
+
+fn writeMsgpack(w: anytype, value: std.json.Value) !void {
+    switch (value) {
+        .null => try w.writeByte(0xc0),
+        .bool => |b| try w.writeByte(if (b) 0xc3 else 0xc2),
+        .integer => |n| {
+            if (n >= 0 and n <= 0x7f) {
+                try w.writeByte(@intCast(n));
+            } else if (n < 0 and n >= -32) {
+                try w.writeByte(@bitCast(@as(i8, @intCast(n))));
+            } else if (n > 0 and n <= std.math.maxInt(u32)) {
+                try w.writeByte(0xce);
+                try w.writeInt(u32, @intCast(n), .big);
+            } else {
+                try w.writeByte(0xd3);
+                try w.writeInt(i64, n, .big);
+            }
+        },
+        .float => |f| {
+            try w.writeByte(0xcb);
+            try w.writeInt(u64, @bitCast(f), .big);
+        },
+        // Integers too big for i64 stay as their decimal text
+        .string, .number_string => |s| {
+            if (s.len <= 31) {
+                try w.writeByte(0xa0 | @as(u8, @intCast(s.len)));
+            } else if (s.len <= std.math.maxInt(u16)) {
+                try w.writeByte(0xda);
+                try w.writeInt(u16, @intCast(s.len), .big);
+            } else {
+                try w.writeByte(0xdb);
+                try w.writeInt(u32, @intCast(s.len), .big);
+            }
+            try w.writeAll(s);
+        },
+        .array => |items| {
+            try writeMsgpackLength(w, 0x90, 0xdc, items.items.len);
+            for (items.items) |item| try writeMsgpack(w, item);
+        },
+        .object => |fields| {
+            try writeMsgpackLength(w, 0x80, 0xde, fields.count());
+            var it = fields.iterator();
+            while (it.next()) |entry| {
+                try writeMsgpack(w, .{ .string = entry.key_ptr.* });
+                try writeMsgpack(w, entry.value_ptr.*);
+            }
+        },
+    }
+}
+
+/// Header of an array or map: the fix form below 16 entries, else the 16- or 32-bit form
+fn writeMsgpackLength(w: anytype, fix_tag: u8, tag16: u8, len: usize) !void {
+    if (len < 16) {
+        try w.writeByte(fix_tag | @as(u8, @intCast(len)));
+    } else if (len <= std.math.maxInt(u16)) {
+        try w.writeByte(tag16);
+        try w.writeInt(u16, @intCast(len), .big);
+    } else {
+        try w.writeByte(tag16 + 1);
+        try w.writeInt(u32, @intCast(len), .big);
+    }
+}
+
+/// JSON-RPC 2.0 error codes the host answers with itself
+const rpc_parse_error = -32700;
+const rpc_invalid_request = -32600;
+const rpc_method_not_found = -32601;
+
+/// A JSON-RPC 2.0 request or notification (set_rpc_protocol!)
+const RpcCall = struct {
+    client_id: u64,
+    /// The request's id as JSON, "" for a notification; rpc_respond! echoes it back
+    id: []const u8,
+    method: []const u8,
+    /// The params as JSON ("" when absent)
+    params: []const u8,
+
+    /// Batches are not supported and count as invalid requests
+    fn parse(allocator: std.mem.Allocator, client_id: u64, text: []const u8) !RpcCall {
+        const parsed = std.json.parseFromSlice(std.json.Value, allocator, text, .{}) catch return error.ParseError;
+        defer parsed.deinit();
+        if (parsed.value != .object) return error.InvalidRequest;
+        const fields = &parsed.value.object;
+
+        const version = fields.get("jsonrpc") orelse return error.InvalidRequest;
+        if (version != .string or !std.mem.eql(u8, version.string, "2.0")) return error.InvalidRequest;
+        const method = fields.get("method") orelse return error.InvalidRequest;
+        if (method != .string) return error.InvalidRequest;
+        const params = fields.get("params");
+        if (params) |value| {
+            if (value != .array and value != .object) return error.InvalidRequest;
+        }
+        const id = fields.get("id");
+        if (id) |value| switch (value) {
+            .string, .integer, .float, .number_string, .null => {},
+            else => return error.InvalidRequest,
+        };
+
+        const owned_id = if (id) |value| try std.json.Stringify.valueAlloc(allocator, value, .{}) else try allocator.dupe(u8, "");
+        errdefer allocator.free(owned_id);
+        const owned_method = try allocator.dupe(u8, method.string);
+        errdefer allocator.free(owned_method);
+        const owned_params = if (params) |value| try std.json.Stringify.valueAlloc(allocator, value, .{}) else try allocator.dupe(u8, "");
+        return .{ .client_id = client_id, .id = owned_id, .method = owned_method, .params = owned_params };
+    }
+
+    fn deinit(self: RpcCall, allocator: std.mem.Allocator) void {
+        allocator.free(self.id);
+        allocator.free(self.method);
+        allocator.free(self.params);
+    }
+};
+
+/// Encode a JSON-RPC error response; an empty id is sent as null
+fn writeRpcError(w: anytype, id: []const u8, code: i64, message: []const u8) !void {
+    try w.print("{{\"jsonrpc\":\"2.0\",\"id\":{s},\"error\":{{\"code\":{d},\"message\":", .{ if (id.len > 0) id else "null", code });
+    try writeJsonString(w, message);
+    try w.writeAll("}}");
+}
+
+/// Subprotocol of GraphQL over WebSocket; apps opt in with add_subprotocol!
+const graphql_protocol = "graphql-transport-ws";
+
+/// Close codes graphql-transport-ws defines for protocol violations
+const graphql_close_bad_request: u16 = 4400;
+const graphql_close_unauthorized: u16 = 4401;
+const graphql_close_subscriber_exists: u16 = 4409;
+const graphql_close_too_many_inits: u16 = 4429;
+
+/// A graphql-transport-ws client's progress through the protocol
+const GraphqlSession = struct {
+    /// connection_init was received and acknowledged
+    acknowledged: bool = false,
+    /// Ids of operations still running (owned)
+    operations: std.StringHashMapUnmanaged(void) = .{},
+
+    fn deinit(self: *GraphqlSession, allocator: std.mem.Allocator) void {
+        var it = self.operations.keyIterator();
+        while (it.next()) |id| allocator.free(id.*);
+        self.operations.deinit(allocator);
+    }
+
+    /// Forget an operation; false when it was not running
+    fn finish(self: *GraphqlSession, allocator: std.mem.Allocator, id: []const u8) bool {
+        const entry = self.operations.fetchRemove(id) orelse return false;
+        allocator.free(entry.key);
+        return true;
+    }
+};
+
+/// Engine.IO endpoint for Socket.IO clients (set_socketio!). Only the WebSocket
+/// transport is served; long-polling requests are refused.
+const socketio_path = "/socket.io/";
+
+/// Engine.IO heartbeat: the server sends "2" this often and the client answers "3"
+const socketio_ping_interval_ms = 25_000;
+/// How long clients wait past the interval before giving up on the server
+const socketio_ping_timeout_ms = 20_000;
+
+/// A Socket.IO packet, the payload of an Engine.IO message ("4..."):
+/// type digit, optional "/namespace,", optional ack id, then JSON data
+const SocketioPacket = struct {
+    kind: u8,
+    /// "/" when absent
+    namespace: []const u8,
+    /// Digits of the ack id, "" when the sender wants no ack
+    ack_id: []const u8,
+    /// JSON text, "" when absent
+    data: []const u8,
+
+    fn parse(text: []const u8) !SocketioPacket {
+        if (text.len == 0 or text[0] < '0' or text[0] > '6') return error.InvalidPacket;
+        var rest = text[1..];
+        var namespace: []const u8 = "/";
+        if (rest.len > 0 and rest[0] == '/') {
+            const end = std.mem.indexOfScalar(u8, rest, ',') orelse rest.len;
+            namespace = rest[0..end];
+            rest = rest[@min(end + 1, rest.len)..];
+        }
+        var digits: usize = 0;
+        while (digits < rest.len and std.ascii.isDigit(rest[digits])) digits += 1;
+        return .{ .kind = text[0], .namespace = namespace, .ack_id = rest[0..digits], .data = rest[digits..] };
+    }
+};
+
+/// Subprotocol of STOMP 1.2 over WebSocket; apps opt in with add_subprotocol!
+const stomp_protocol = "v12.stomp";
+
+/// Destination on which STOMP clients receive send! and broadcast! text
+const stomp_direct_destination = "/user/queue/messages";
+
+/// A STOMP frame. Header values are unescaped (except in CONNECT frames, as
+/// the spec requires); the first of repeated headers wins.
+const StompFrame = struct {
+    command: []const u8,
+    headers: std.StringArrayHashMapUnmanaged([]const u8) = .{},
+    body: []const u8,
+
+    /// Parse the frame in a WebSocket message. Names, values and body point
+    /// into `text` or the arena. A message of bare EOLs is a heart-beat.
+    fn parse(arena: std.mem.Allocator, text: []const u8) !StompFrame {
+        var rest = std.mem.trimLeft(u8, text, "\r\n");
+        if (rest.len == 0) return error.HeartBeat;
+
+        const command_end = std.mem.indexOfScalar(u8, rest, '\n') orelse return error.MalformedFrame;
+        var frame = StompFrame{ .command = std.mem.trimRight(u8, rest[0..command_end], "\r"), .body = "" };
+        rest = rest[command_end + 1 ..];
+        const escaped = !std.mem.eql(u8, frame.command, "CONNECT") and !std.mem.eql(u8, frame.command, "STOMP");
+
+        while (true) {
+            const line_end = std.mem.indexOfScalar(u8, rest, '\n') orelse return error.MalformedFrame;
+            const line = std.mem.trimRight(u8, rest[0..line_end], "\r");
+            rest = rest[line_end + 1 ..];
+            if (line.len == 0) break;
+
+            const colon = std.mem.indexOfScalar(u8, line, ':') orelse return error.MalformedFrame;
+            const name = if (escaped) try unescapeStompHeader(arena, line[0..colon]) else line[0..colon];
+            const value = if (escaped) try unescapeStompHeader(arena, line[colon + 1 ..]) else line[colon + 1 ..];
+            const gop = try frame.headers.getOrPut(arena, name);
+            if (!gop.found_existing) gop.value_ptr.* = value;
+        }
+
+        if (frame.headers.get("content-length")) |length_text| {
+            const length = std.fmt.parseInt(usize, length_text, 10) catch return error.MalformedFrame;
+            if (length >= rest.len or rest[length] != 0) return error.MalformedFrame;
+            frame.body = rest[0..length];
+        } else {
+            const end = std.mem.indexOfScalar(u8, rest, 0) orelse return error.MalformedFrame;
+            frame.body = rest[0..end];
+        }
+        return frame;
+    }
+
+    fn header(self: *const StompFrame, name: []const u8) ?[]const u8 {
+        return self.headers.get(name);
+    }
+};
+
+/// Undo STOMP 1.2 header escaping (\r, \n, \c, \\); undefined escapes are errors
+fn unescapeStompHeader(arena: std.mem.Allocator, raw: []const u8) ![]const u8 {
+    if (std.mem.indexOfScalar(u8, raw, '\\') == null) return raw;
+    var out = try std.ArrayListUnmanaged(u8).initCapacity(arena, raw.len);
+    var i: usize = 0;
+    while (i < raw.len) : (i += 1) {
+        if (raw[i] != '\\') {
+            out.appendAssumeCapacity(raw[i]);
+            continue;
+        }
+        i += 1;
+        if (i == raw.len) return error.MalformedFrame;
+        out.appendAssumeCapacity(switch (raw[i]) {
+            'r' => '\r',
+            'n' => '\n',
+            'c' => ':',
+            '\\' => '\\',
+            else => return error.MalformedFrame,
+        });
+    }
+    return out.items;
+}
+
+fn writeStompHeader(w: anytype, name: []const u8, value: []const u8) !void {
+    try w.writeAll(name);
+    try w.writeByte(':');
+    for (value) |c| {
+        switch (c) {
+            '\r' => try w.writeAll("\\r"),
+            '\n' => try w.writeAll("\\n"),
+            ':' => try w.writeAll("\\c"),
+            '\\' => try w.writeAll("\\\\"),
+            else => try w.writeByte(c),
+        }
+    }
+    try w.writeByte('\n');
+}
+
+/// A STOMP client's connection state and subscriptions
+const StompSession = struct {
+    connected: bool = false,
+    /// Subscription ids and the destinations (rooms) they follow, both owned
+    subscriptions: std.StringArrayHashMapUnmanaged([]const u8) = .{},
+    next_message_id: u64 = 0,
+
+    fn deinit(self: *StompSession, allocator: std.mem.Allocator) void {
+        var it = self.subscriptions.iterator();
+        while (it.next()) |entry| {
+            allocator.free(entry.key_ptr.*);
+            allocator.free(entry.value_ptr.*);
+        }
+        self.subscriptions.deinit(allocator);
+    }
+
+    /// The id of a subscription to this destination, if any
+    fn subscriptionFor(self: *const StompSession, destination: []const u8) ?[]const u8 {
+        var it = self.subscriptions.iterator();
+        while (it.next()) |entry| {
+            if (std.mem.eql(u8, entry.value_ptr.*, destination)) return entry.key_ptr.*;
+        }
+        return null;
+    }
+};
+
+/// WebSocket upgrade path and the room its clients join. A pattern ending in
+/// "/*" matches one more path segment, which names the room when room is "*".
+const WsEndpoint = struct {
+    pattern: []const u8,
+    room: []const u8,
+
+    /// Room for an upgrade to `path` ("" for none), or null if it does not match
+    fn match(self: WsEndpoint, path: []const u8) ?[]const u8 {
+        if (std.mem.endsWith(u8, self.pattern, "/*")) {
+            const prefix = self.pattern[0 .. self.pattern.len - 1];
+            if (!std.mem.startsWith(u8, path, prefix)) return null;
+            const segment = path[prefix.len..];
+            if (segment.len == 0 or std.mem.indexOfScalar(u8, segment, '/') != null) return null;
+            return if (std.mem.eql(u8, self.room, "*")) segment else self.room;
+        }
+        return if (std.mem.eql(u8, self.pattern, path)) self.room else null;
+    }
+};
+
+/// Most ":name" and "*" captures one route pattern may have
+const max_route_params = 8;
+
+/// Longest ":name" in a route pattern
+const max_route_param_name = 64;
+
+/// Where a route sends the requests it matches
+const RouteTarget = enum {
+    /// An HttpRequest event for the application
+    app,
+    /// The static file server
+    static,
+    /// 404, e.g. to hide part of the static tree
+    not_found,
+};
+
+/// Values captured by a route pattern, pointing into the pattern and request path
+const RouteParams = struct {
+    names: [max_route_params][]const u8 = undefined,
+    values: [max_route_params][]const u8 = undefined,
+    len: usize = 0,
+
+    fn add(self: *RouteParams, name: []const u8, value: []const u8) void {
+        // Patterns are checked for capacity when added
+        self.names[self.len] = name;
+        self.values[self.len] = value;
+        self.len += 1;
+    }
+};
+
+/// A request route: "/api/rooms/:id/messages" matches one segment per ":name",
+/// and a final "*" matches the rest of the path
+const HttpRoute = struct {
+    /// Null for any method
+    method: ?[]const u8,
+    pattern: []const u8,
+    target: RouteTarget,
+
+    fn validate(pattern: []const u8) !void {
+        if (pattern.len == 0 or pattern[0] != '/') return error.InvalidPattern;
+        var captures: usize = 0;
+        var segments = std.mem.splitScalar(u8, pattern[1..], '/');
+        while (segments.next()) |segment| {
+            if (std.mem.eql(u8, segment, "*")) {
+                if (segments.peek() != null) return error.InvalidPattern;
+                captures += 1;
+            } else if (segment.len > 0 and segment[0] == ':') {
+                if (segment.len == 1 or segment.len > max_route_param_name + 1) return error.InvalidPattern;
+                captures += 1;
+            }
+        }
+        if (captures > max_route_params) return error.TooManyParams;
+    }
+
+    fn allowsMethod(self: HttpRoute, method: []const u8) bool {
+        const allowed = self.method orelse return true;
+        return std.mem.eql(u8, allowed, method) or
+            (std.mem.eql(u8, allowed, "GET") and std.mem.eql(u8, method, "HEAD"));
+    }
+
+    /// Whether `path` fits the pattern, capturing its ":name" segments and "*" rest
+    fn match(self: HttpRoute, path: []const u8, params: *RouteParams) bool {
+        params.len = 0;
+        if (path.len == 0 or path[0] != '/') return false;
+        var pattern_segments = std.mem.splitScalar(u8, self.pattern[1..], '/');
+        var path_segments = std.mem.splitScalar(u8, path[1..], '/');
+        while (pattern_segments.next()) |segment| {
+            if (std.mem.eql(u8, segment, "*")) {
+                params.add("*", path_segments.rest());
+                return true;
+            }
+            const part = path_segments.next() orelse return false;
+            if (segment.len > 0 and segment[0] == ':') {
+                if (part.len == 0) return false;
+                params.add(segment[1..], part);
+            } else if (!std.mem.eql(u8, segment, part)) {
+                return false;
+            }
+        }
+        return path_segments.next() == null;
+    }
+};
+
+/// Identical notify_room! signals closer together than this are dropped
+const notify_min_interval_ms = 250;
+
+/// Recent signals remembered for throttling before old ones are pruned
+const max_recent_notifications = 4096;
+
+/// A send_with_ack! message, delivered once the client's written bytes reach `end`
+const PendingAck = struct {
+    message_id: u64,
+    end: u64,
+};
+
+/// A set_timer! deadline; fires once as a Timer event
+const Timer = struct {
+    id: u64,
+    due_ms: i64,
+    /// Owned until the timer fires or is cancelled
+    tag: []const u8,
+};
+
+/// Longest stdin line kept for an AdminCommand
+const max_admin_line = 4096;
+
+/// Counters for one connection or, on the server, for all connections ever
+const Traffic = struct {
+    /// Bytes read from the socket
+    bytes_in: u64 = 0,
+    /// Bytes accepted for sending (written or queued)
+    bytes_out: u64 = 0,
+    /// Text and binary messages received
+    messages_in: u64 = 0,
+    /// Text and binary messages sent
+    messages_out: u64 = 0,
+};
+
+/// Set of client ids subscribed to a room
+const Room = std.AutoHashMapUnmanaged(u64, void);
+
+/// Published messages kept per room for replay_since!
+const max_replay_messages = 256;
+
+/// A room's sequence counter and its most recent published envelopes, oldest
+/// first. Outlives the room's membership so clients rejoining an emptied room
+/// can still catch up.
+const RoomLog = struct {
+    next_seq: u64 = 1,
+    entries: std.ArrayListUnmanaged(LoggedMessage) = .{},
+
+    const LoggedMessage = struct {
+        seq: u64,
+        /// Envelope as sent (owned)
+        envelope: []u8,
+    };
+
+    fn deinit(self: *RoomLog, allocator: std.mem.Allocator) void {
+        for (self.entries.items) |entry| allocator.free(entry.envelope);
+        self.entries.deinit(allocator);
+    }
+};
+
+/// The last messages sent to a room, kept for clients that join later (set_room_history!).
+/// A ring: once full, each message overwrites the oldest.
+const RoomHistory = struct {
+    /// Messages as sent (owned); once full, the oldest is at `start`
+    messages: std.ArrayListUnmanaged([]u8) = .{},
+    start: usize = 0,
+
+    fn deinit(self: *RoomHistory, allocator: std.mem.Allocator) void {
+        for (self.messages.items) |message| allocator.free(message);
+        self.messages.deinit(allocator);
+    }
+
+    fn push(self: *RoomHistory, allocator: std.mem.Allocator, capacity: usize, message: []const u8) !void {
+        const owned = try allocator.dupe(u8, message);
+        if (self.messages.items.len < capacity) {
+            self.messages.append(allocator, owned) catch |err| {
+                allocator.free(owned);
+                return err;
+            };
+            return;
+        }
+        allocator.free(self.messages.items[self.start]);
+        self.messages.items[self.start] = owned;
+        self.start = (self.start + 1) % capacity;
+    }
+
+    /// The i-th message, counting from the oldest
+    fn get(self: *const RoomHistory, i: usize) []const u8 {
+        return self.messages.items[(self.start + i) % self.messages.items.len];
+    }
+};
+
+/// Most messages one room_history! call returns
+const max_history_query = 10_000;
+
+/// Every chat message on disk (set_history_store!), one append-only file of
+/// JSON lines per room: {"ts","clientId","room","text"}. A file is named after
+/// the SHA-256 of its room, so any room name fits. Lines are only ever added,
+/// so a crash can at worst leave the last one torn, and reading skips it.
+const HistoryStore = struct {
+    dir: std.fs.Dir,
+
+    const Entry = struct {
+        client_id: u64,
+        /// Milliseconds since the epoch when the host received the message
+        received_ms: u64,
+        room: []const u8,
+        text: []const u8,
+    };
+
+    /// Open the store under `path`, creating the directory if needed
+    fn open(path: []const u8) !HistoryStore {
+        return .{ .dir = try std.fs.cwd().makeOpenPath(path, .{}) };
+    }
+
+    fn close(self: *HistoryStore) void {
+        self.dir.close();
+    }
+
+    fn append(self: *HistoryStore, allocator: std.mem.Allocator, entry: Entry) !void {
+        var line = std.ArrayListUnmanaged(u8){};
+        defer line.deinit(allocator);
+        const w = line.writer(allocator);
+        try w.print("{{\"ts\":{d},\"clientId\":{d},\"room\":", .{ entry.received_ms, entry.client_id });
+        try writeJsonString(w, entry.room);
+        try w.writeAll(",\"text\":");
+        try writeJsonString(w, entry.text);
+        try w.writeAll("}\n");
+
+        var name_buf: [history_file_name_len]u8 = undefined;
+        const file = try self.dir.createFile(historyFileName(&name_buf, entry.room), .{ .truncate = false });
+        defer file.close();
+        try file.seekFromEnd(0);
+        try file.writeAll(line.items);
+    }
+
+    /// The last `limit` messages recorded for `room`, oldest first. Everything
+    /// returned lives in `arena`.
+    fn history(self: *HistoryStore, arena: std.mem.Allocator, room: []const u8, limit: usize) ![]Entry {
+        var name_buf: [history_file_name_len]u8 = undefined;
+        const file = self.dir.openFile(historyFileName(&name_buf, room), .{}) catch |err| switch (err) {
+            error.FileNotFound => return arena.alloc(Entry, 0),
+            else => return err,
+        };
+        defer file.close();
+        const size: usize = @intCast(try file.getEndPos());
+
+        // Walk back from the end to the line break before the last `limit` lines.
+        // The file ends with a break, so that is break number limit + 1.
+        var start = size;
+        var breaks: usize = 0;
+        var chunk: [4096]u8 = undefined;
+        scan: while (start > 0) {
+            const n: usize = @min(start, chunk.len);
+            const at = start - n;
+            _ = try file.preadAll(chunk[0..n], at);
+            var i = n;
+            while (i > 0) {
+                i -= 1;
+                if (chunk[i] != '\n') continue;
+                breaks += 1;
+                if (breaks > limit) {
+                    start = at + i + 1;
+                    break :scan;
+                }
+            }
+            start = at;
+        }
+
+        const tail = try arena.alloc(u8, size - start);
+        _ = try file.preadAll(tail, start);
+
+        var entries = std.ArrayListUnmanaged(Entry){};
+        var lines = std.mem.tokenizeScalar(u8, tail, '\n');
+        while (lines.next()) |line| {
+            const record = std.json.parseFromSliceLeaky(struct {
+                ts: u64,
+                clientId: u64,
+                room: []const u8,
+                text: []const u8,
+            }, arena, line, .{ .ignore_unknown_fields = true }) catch continue;
+            try entries.append(arena, .{
+                .client_id = record.clientId,
+                .received_ms = record.ts,
+                .room = record.room,
+                .text = record.text,
+            });
+        }
+        return entries.items;
+    }
+};
+
+const history_file_name_len = 2 * std.crypto.hash.sha2.Sha256.digest_length + ".jsonl".len;
+
+/// File holding a room's messages: the hex SHA-256 of its name
+fn historyFileName(buf: *[history_file_name_len]u8, room: []const u8) []const u8 {
+    var digest: [std.crypto.hash.sha2.Sha256.digest_length]u8 = undefined;
+    std.crypto.hash.sha2.Sha256.hash(room, &digest, .{});
+    return std.fmt.bufPrint(buf, "{s}.jsonl", .{&std.fmt.bytesToHex(digest, .lower)}) catch unreachable;
+}
+
+/// A static file held in memory, valid while the file's mtime and size are unchanged
+const StaticCacheEntry = struct {
+    content: []u8,
+    mtime: i128,
+    size: u64,
+};
+
+const WebSocketServer = struct {
+    allocator: std.mem.Allocator,
+    listener: ?std.net.Server,
+    clients: std.AutoHashMap(u64, WebSocketClient),
+    /// Room name (owned) -> members
+    rooms: std.StringHashMapUnmanaged(Room),
+    /// Room name (owned) -> publish_room! sequence and replay log
+    room_logs: std.StringHashMapUnmanaged(RoomLog),
+    /// Room name (owned) -> recent messages, while history_size > 0
+    room_histories: std.StringHashMapUnmanaged(RoomHistory),
+    /// Messages kept per room; 0 keeps none
+    history_size: usize,
+    /// How many of them a client gets when it joins a room
+    history_on_join: usize,
+    /// Where every chat message is also written, when set_history_store! names a directory
+    history_store: ?HistoryStore,
+    next_client_id: u64,
+    /// Id for the next send_with_ack! message
+    next_message_id: u64,
+    /// Pending set_timer! deadlines, unordered
+    timers: std.ArrayListUnmanaged(Timer),
+    /// Poll stdin and turn its lines into AdminCommand events
+    watch_stdin: bool,
+    /// Partial stdin line awaiting its newline
+    stdin_buf: std.ArrayListUnmanaged(u8),
+    next_timer_id: u64,
+    event_queue: std.ArrayListUnmanaged(WebSocketEvent),
+    /// Events held for the application before the overflow policy applies (0 for no limit)
+    event_queue_limit: usize,
+    event_overflow: OverflowPolicy,
+    /// Events discarded since the last Overflow event
+    events_dropped: u64,
+    is_running: bool,
+    /// Root for static files (owned); null means "static"
+    static_dir: ?[]const u8,
+    /// Files tried for "/" and directory paths, comma-separated (owned); null means default_index_files
+    index_file: ?[]const u8,
+    /// Serve the root index for unknown extensionless paths (client-side routing)
+    spa_fallback: bool,
+    /// List the entries of directories that have no index file
+    directory_listing: bool,
+    /// Path prefixes (owned) whose requests go to the application instead of the static server
+    http_routes: std.ArrayListUnmanaged([]const u8),
+    /// Tried in order before http_routes; once there are any, unrouted requests get 404
+    routes: std.ArrayListUnmanaged(HttpRoute),
+    /// Paths (owned) WebSocket clients may upgrade on; empty accepts any path
+    ws_endpoints: std.ArrayListUnmanaged(WsEndpoint),
+    /// Endpoints that receive POSTs for selected events, and the POSTs still owed to them
+    webhooks: WebhookWorker,
+    /// Origins (owned) browsers may connect from; empty allows any
+    allowed_origins: std.ArrayListUnmanaged([]const u8),
+    /// Sec-WebSocket-Protocol values (owned) the server speaks, most preferred first
+    subprotocols: std.ArrayListUnmanaged([]const u8),
+    /// Hand upgrade requests to the application as HandshakeRequest events
+    handshake_policy: bool,
+    /// Parse text messages as JSON envelopes into ChatMessage/JoinRequest/Command events
+    envelope_protocol: bool,
+    /// Parse text messages as JSON-RPC 2.0 requests into RpcCall events
+    rpc_protocol: bool,
+    /// Serve Socket.IO clients over WebSocket at /socket.io/
+    socketio: bool,
+    /// HS256 secret (owned) that connection tokens must be signed with; null disables auth
+    auth_secret: ?[]const u8,
+    /// Bearer token (owned) for POST /hooks/{room}; null disables the endpoint
+    hook_secret: ?[]const u8,
+    /// Bearer token (owned) for the /admin/ API; null disables it
+    admin_token: ?[]const u8,
+    /// Configuration file (owned) reread on SIGHUP; null until reload_config!
+    config_path: ?[]const u8,
+    /// Networks the admin API answers; empty means loopback only
+    admin_networks: std.ArrayListUnmanaged(Cidr),
+    /// When listen! succeeded (ms since epoch)
+    started_ms: i64,
+    /// Totals across every connection since start
+    traffic: Traffic,
+    /// Key (owned) for signing session cookies; generated on first use unless set
+    session_secret: ?[]const u8,
+    /// Inbound messages each client may send
+    message_limit: RateLimit,
+    /// Connection attempts each IP address may make
+    connect_limit: RateLimit,
+    /// IP address key -> connection attempt allowance
+    connect_buckets: std.AutoHashMapUnmanaged(u128, TokenBucket),
+    /// Networks clients must connect from; empty allows any
+    allowed_networks: std.ArrayListUnmanaged(Cidr),
+    /// Networks refused even when allowed
+    denied_networks: std.ArrayListUnmanaged(Cidr),
+    /// Addresses refused until their ban runs out or is lifted
+    bans: std.ArrayListUnmanaged(Restriction),
+    /// Addresses whose chat is dropped until their mute runs out or is lifted
+    mutes: std.ArrayListUnmanaged(Restriction),
+    /// Applied to chat text after mutes, from the [filter] config section
+    content_filter: ContentFilter,
+    /// Rooms with other than the default policy, keyed by owned room name
+    room_policies: std.StringHashMapUnmanaged(RoomPolicy),
+    /// URL prefix (owned, no trailing slash) requests may carry, e.g. "/chat"
+    base_path: ?[]const u8,
+    /// Proxies whose X-Forwarded-For / X-Real-IP headers are believed
+    trusted_proxies: std.ArrayListUnmanaged(Cidr),
+    /// Applied to every accepted connection
+    socket_options: SocketOptions,
+    /// Reused to assemble frames too large for the stack
+    send_buf: std.ArrayListUnmanaged(u8),
+    /// Reused to encode a frame once for many recipients
+    fanout_buf: std.ArrayListUnmanaged(u8),
+    /// Hash of (room, kind, payload) -> when that notify_room! signal last went out
+    recent_notifications: std.AutoHashMapUnmanaged(u64, i64),
+    /// Open connections beyond which new ones are turned away (0 is unlimited)
+    max_connections: usize,
+    /// Listener is ignored until this time after accept ran out of descriptors
+    accept_paused_until_ms: i64,
+    /// While draining, when the remaining clients are closed (0 when not draining)
+    drain_until_ms: i64,
+    /// Upper bound on a single frame's payload
+    max_frame_size: usize,
+    /// Upper bound on a message reassembled from fragments
+    max_message_size: usize,
+    /// Upper bound on an HTTP request body
+    max_body_size: usize,
+    /// Upper bound on an HTTP request head
+    max_header_size: usize,
+    /// Where POST /upload stores files; null while uploads are off
+    upload_dir: ?[]const u8,
+    /// Upper bound on an upload's request body, in place of max_body_size
+    max_upload_size: usize,
+    /// Types uploads may have; empty for default_upload_types
+    upload_types: std.ArrayListUnmanaged([]const u8),
+    /// Connections that have not sent a complete request by then are dropped (0 disables)
+    handshake_timeout_ms: i64,
+    /// Ping clients that have been quiet this long (0 disables keepalive)
+    keepalive_interval_ms: i64,
+    /// Disconnect clients that have been quiet this long (0 disables)
+    idle_timeout_ms: i64,
+    /// How long a dropped WebSocket client may resume its session (0 disables resuming)
+    resume_grace_ms: i64,
+    /// High-water mark for a client's unsent bytes before it is dropped as too slow
+    max_outbox_bytes: usize,
+    /// Custom extension (lowercase, owned) -> Content-Type (owned) mappings
+    mime_overrides: std.StringHashMapUnmanaged([]const u8),
+    /// Cache-Control sent with static files (owned); null means "no-cache"
+    cache_control: ?[]const u8,
+    /// Lowercase host name (owned) -> static root (owned) of each virtual host
+    virtual_hosts: std.StringHashMapUnmanaged([]const u8),
+    /// Status -> page (owned) the static server sends with it, from set_error_page!
+    error_pages: std.AutoHashMapUnmanaged(u16, []const u8),
+    /// Resolved file path (owned) -> contents of recently served static files
+    static_cache: std.StringHashMapUnmanaged(StaticCacheEntry),
+    /// Total bytes the static cache may hold (0 disables caching)
+    static_cache_limit: usize,
+    /// Files larger than this are streamed from disk rather than read into memory
+    static_buffer_limit: usize,
+    static_cache_bytes: usize,
+
+    fn init(allocator: std.mem.Allocator) WebSocketServer {
+        return .{
+            .allocator = allocator,
+            .listener = null,
+            .clients = std.AutoHashMap(u64, WebSocketClient).init(allocator),
+            .rooms = .{},
+            .room_logs = .{},
+            .room_histories = .{},
+            .history_size = 0,
+            .history_on_join = 0,
+            .history_store = null,
+            .next_client_id = 1,
+            .next_message_id = 1,
+            .timers = .{},
+            .watch_stdin = false,
+            .stdin_buf = .{},
+            .next_timer_id = 1,
+            .event_queue = .{},
+            .event_queue_limit = 0,
+            .event_overflow = .drop_oldest,
+            .events_dropped = 0,
+            .is_running = false,
+            .static_dir = null,
+            .index_file = null,
+            .spa_fallback = false,
+            .directory_listing = false,
+            .http_routes = .{},
+            .routes = .{},
+            .ws_endpoints = .{},
+            .webhooks = .{ .allocator = allocator },
+            .allowed_origins = .{},
+            .subprotocols = .{},
+            .handshake_policy = false,
+            .envelope_protocol = false,
+            .rpc_protocol = false,
+            .socketio = false,
+            .auth_secret = null,
+            .hook_secret = null,
+            .admin_token = null,
+            .config_path = null,
+            .admin_networks = .{},
+            .started_ms = 0,
+            .traffic = .{},
+            .session_secret = null,
+            .message_limit = .{},
+            .connect_limit = .{},
+            .connect_buckets = .{},
+            .allowed_networks = .{},
+            .denied_networks = .{},
+            .bans = .{},
+            .mutes = .{},
+            .content_filter = .{},
+            .room_policies = .{},
+            .base_path = null,
+            .trusted_proxies = .{},
+            .socket_options = .{},
+            .send_buf = .{},
+            .fanout_buf = .{},
+            .recent_notifications = .{},
+            .max_connections = 0,
+            .accept_paused_until_ms = 0,
+            .drain_until_ms = 0,
+            .max_frame_size = 64 * 1024,
+            .max_message_size = 1024 * 1024,
+            .max_body_size = 1024 * 1024,
+            .max_header_size = max_http_head_size,
+            .upload_dir = null,
+            .max_upload_size = 10 * 1024 * 1024,
+            .upload_types = .{},
+            .handshake_timeout_ms = default_handshake_timeout_ms,
+            .keepalive_interval_ms = 30_000,
+            .idle_timeout_ms = 75_000,
+            .resume_grace_ms = 0,
+            .max_outbox_bytes = 4 * 1024 * 1024,
+            .mime_overrides = .{},
+            .cache_control = null,
+            .virtual_hosts = .{},
+            .error_pages = .{},
+            .static_cache = .{},
+            .static_cache_limit = 0,
+            .static_cache_bytes = 0,
+            .static_buffer_limit = default_static_buffer,
+        };
+    }
+
+    fn deinit(self: *WebSocketServer) void {
+        if (self.listener) |*l| {
+            l.deinit();
+        }
+
+        var it = self.clients.valueIterator();
+        while (it.next()) |client| {
+            client.deinit(self.allocator);
+        }
+        self.clients.deinit();
+
+        self.clearRooms();
+        self.rooms.deinit(self.allocator);
+        var log_it = self.room_logs.iterator();
+        while (log_it.next()) |entry| {
+            entry.value_ptr.deinit(self.allocator);
+            self.allocator.free(entry.key_ptr.*);
+        }
+        self.room_logs.deinit(self.allocator);
+        self.clearHistories();
+        self.room_histories.deinit(self.allocator);
+        if (self.history_store) |*store| store.close();
+
+        var mime_it = self.mime_overrides.iterator();
+        while (mime_it.next()) |entry| {
+            self.allocator.free(entry.key_ptr.*);
+            self.allocator.free(entry.value_ptr.*);
+        }
+        self.mime_overrides.deinit(self.allocator);
+
+        if (self.cache_control) |value| self.allocator.free(value);
+        if (self.static_dir) |value| self.allocator.free(value);
+        if (self.index_file) |value| self.allocator.free(value);
+        for (self.http_routes.items) |prefix| self.allocator.free(prefix);
+        self.http_routes.deinit(self.allocator);
+        for (self.routes.items) |route| {
+            if (route.method) |method| self.allocator.free(method);
+            self.allocator.free(route.pattern);
+        }
+        self.routes.deinit(self.allocator);
+        for (self.ws_endpoints.items) |endpoint| {
+            self.allocator.free(endpoint.pattern);
+            self.allocator.free(endpoint.room);
+        }
+        self.ws_endpoints.deinit(self.allocator);
+        self.webhooks.deinit();
+        for (self.allowed_origins.items) |origin| self.allocator.free(origin);
+        self.allowed_origins.deinit(self.allocator);
+        for (self.subprotocols.items) |protocol| self.allocator.free(protocol);
+        self.subprotocols.deinit(self.allocator);
+        if (self.auth_secret) |secret| self.allocator.free(secret);
+        if (self.hook_secret) |secret| self.allocator.free(secret);
+        if (self.admin_token) |token| self.allocator.free(token);
+        if (self.config_path) |path| self.allocator.free(path);
+        self.admin_networks.deinit(self.allocator);
+        if (self.session_secret) |secret| self.allocator.free(secret);
+        self.connect_buckets.deinit(self.allocator);
+        self.allowed_networks.deinit(self.allocator);
+        self.denied_networks.deinit(self.allocator);
+        self.bans.deinit(self.allocator);
+        self.mutes.deinit(self.allocator);
+        self.content_filter.deinit(self.allocator);
+        var policy_it = self.room_policies.keyIterator();
+        while (policy_it.next()) |room| self.allocator.free(room.*);
+        self.room_policies.deinit(self.allocator);
+        if (self.base_path) |path| self.allocator.free(path);
+        self.trusted_proxies.deinit(self.allocator);
+        self.send_buf.deinit(self.allocator);
+        self.fanout_buf.deinit(self.allocator);
+        self.recent_notifications.deinit(self.allocator);
+
+        var host_it = self.virtual_hosts.iterator();
+        while (host_it.next()) |entry| {
+            self.allocator.free(entry.key_ptr.*);
+            self.allocator.free(entry.value_ptr.*);
+        }
+        self.virtual_hosts.deinit(self.allocator);
+        var page_it = self.error_pages.valueIterator();
+        while (page_it.next()) |page| self.allocator.free(page.*);
+        self.error_pages.deinit(self.allocator);
+        self.clearStaticCache();
+        self.static_cache.deinit(self.allocator);
+        if (self.upload_dir) |dir| self.allocator.free(dir);
+        for (self.upload_types.items) |mime| self.allocator.free(mime);
+        self.upload_types.deinit(self.allocator);
+
+        for (self.timers.items) |timer| self.allocator.free(timer.tag);
+        self.timers.deinit(self.allocator);
+        self.stdin_buf.deinit(self.allocator);
+
+        self.event_queue.deinit(self.allocator);
+    }
+
+    fn listen(self: *WebSocketServer, port: u16) !void {
+        const reuse_port = if (getenv("CHATSERVER_REUSE_PORT")) |value| std.mem.eql(u8, std.mem.span(value), "1") else false;
+        try self.listenOn(std.net.Address.initIp4(.{ 0, 0, 0, 0 }, port), reuse_port);
+    }
+
+    /// Bind where a configuration file says, then apply the rest of it
+    fn listenWithConfig(self: *WebSocketServer, path: []const u8, config: *const ServerConfig) !void {
+        const port = config.port orelse return error.MissingPort;
+        try self.listenOn(try std.net.Address.parseIp(config.bind orelse "0.0.0.0", port), config.reuse_port orelse false);
+        try self.applyConfig(config);
+        try self.watchConfigFile(path);
+    }
+
+    /// With `reuse_port`, other processes binding the same port with it share the
+    /// connections (SO_REUSEPORT); without it a second server fails to bind.
+    /// Windows has no SO_REUSEPORT, so asking for it there fails with Unsupported.
+    fn listenOn(self: *WebSocketServer, address: std.net.Address, reuse_port: bool) !void {
+        const posix = std.posix;
+        if (reuse_port and @import("builtin").os.tag == .windows) return error.Unsupported;
+        const sock = try posix.socket(address.any.family, posix.SOCK.STREAM | posix.SOCK.CLOEXEC, posix.IPPROTO.TCP);
+        errdefer posix.close(sock);
+
+        const enable: c_int = 1;
+        try posix.setsockopt(sock, posix.SOL.SOCKET, posix.SO.REUSEADDR, std.mem.asBytes(&enable));
+        if (@import("builtin").os.tag != .windows) {
+            if (reuse_port) try posix.setsockopt(sock, posix.SOL.SOCKET, posix.SO.REUSEPORT, std.mem.asBytes(&enable));
+        }
+
+        var socklen = address.getOsSockLen();
+        try posix.bind(sock, &address.any, socklen);
+        try posix.listen(sock, 128);
+        var listen_address: std.net.Address = undefined;
+        try posix.getsockname(sock, &listen_address.any, &socklen);
+
+        self.listener = .{ .listen_address = listen_address, .stream = .{ .handle = sock } };
+        if (reuse_port) logger.info(null, "Sharing port {d} with other processes (SO_REUSEPORT)", .{listen_address.getPort()});
+        self.is_running = true;
+
+        // Ctrl-C and orchestrator stops drain instead of killing the process
+        if (@import("builtin").os.tag != .windows) {
+            inline for (.{ posix.SIG.INT, posix.SIG.TERM }) |sig| {
+                watchSignal(sig) catch |err| {
+                    logger.warn(null, "Cannot handle signal {d}: {s}", .{ sig, @errorName(err) });
+                };
+            }
+        }
+        self.started_ms = std.time.milliTimestamp();
+    }
+
+    fn accept(self: *WebSocketServer) !WebSocketEvent {
+        while (true) {
+            // Poll with longer timeout (5 seconds) to avoid busy spinning
+            if (try self.pollEvent(5000)) |event| {
+                return event;
+            }
+        }
+    }
+
+    /// Wait at most `timeout_ms` for the next event, returning null once it has passed
+    fn acceptTimeout(self: *WebSocketServer, timeout_ms: u64) !?WebSocketEvent {
+        const deadline = std.time.milliTimestamp() + @as(i64, @intCast(@min(timeout_ms, std.math.maxInt(i32))));
+        while (true) {
+            const remaining = deadline - std.time.milliTimestamp();
+            const wait_ms: i32 = @intCast(std.math.clamp(remaining, 0, std.math.maxInt(i32)));
+            // Control frames and requests the host answers itself are not events
+            const maybe_event = self.pollEvent(wait_ms) catch |err| blk: {
+                if (err == error.ControlFrame or err == error.NotWebSocket) break :blk null;
+                return err;
+            };
+            if (maybe_event) |event| return event;
+            if (remaining <= 0) return null;
+        }
+    }
+
+    /// Wait up to `timeout_ms` for the next event, returning null if none arrived.
+    /// A timeout of 0 checks for ready sockets without blocking.
+    fn pollEvent(self: *WebSocketServer, timeout_ms: i32) !?WebSocketEvent {
+        const event = try self.nextEvent(timeout_ms) orelse return null;
+        self.queueWebhooks(event);
+        return event;
+    }
+
+    /// Hold an event for the application, taking ownership of it: an event that
+    /// cannot be queued is released here. Once event_queue_limit events are
+    /// waiting, the overflow policy decides what is discarded, and the count
+    /// is reported as an Overflow event ahead of the rest of the queue.
+    fn queueEvent(self: *WebSocketServer, event: WebSocketEvent) error{OutOfMemory}!void {
+        if (self.event_queue_limit == 0 or self.event_queue.items.len < self.event_queue_limit or event.isLifecycle()) {
+            self.event_queue.append(self.allocator, event) catch |err| {
+                event.deinit(self.allocator);
+                return err;
+            };
+            return;
+        }
+
+        self.events_dropped += 1;
+        switch (self.event_overflow) {
+            .drop_oldest => {
+                for (self.event_queue.items, 0..) |queued, i| {
+                    if (queued.isLifecycle()) continue;
+                    self.event_queue.orderedRemove(i).deinit(self.allocator);
+                    self.event_queue.appendAssumeCapacity(event);
+                    return;
+                }
+                // Only lifecycle events are waiting, so the new one goes instead
+                event.deinit(self.allocator);
+            },
+            .drop_newest => event.deinit(self.allocator),
+            .disconnect_producer => {
+                const producer = event.clientId();
+                event.deinit(self.allocator);
+                const client_id = producer orelse return;
+                if (!self.clients.contains(client_id)) return;
+                // Disconnected is a lifecycle event, so it is queued despite the limit
+                return self.queueEvent(self.dropClient(client_id, close_try_again_later, "Event queue full"));
+            },
+        }
+    }
+
+    /// An Error event owning its message, which is only logged if it cannot be allocated
+    fn errorEvent(self: *WebSocketServer, comptime fmt: []const u8, args: anytype) WebSocketEvent {
+        const msg = std.fmt.allocPrint(self.allocator, fmt, args) catch blk: {
+            logger.err(null, fmt, args);
+            break :blk "";
+        };
+        return .{ .err = msg };
+    }
+
+    fn nextEvent(self: *WebSocketServer, timeout_ms: i32) !?WebSocketEvent {
+        self.checkKeepalive();
+        self.dropSlowClients();
+        self.expireHandshakes();
+        self.expireDetached();
+        self.fireTimers();
+        self.checkDrain();
+
+        // Report discarded events before anything still queued
+        if (self.events_dropped > 0) {
+            const dropped = self.events_dropped;
+            self.events_dropped = 0;
+            return .{ .overflow = dropped };
+        }
+
+        // First check event queue
+        if (self.event_queue.items.len > 0) {
+            return self.event_queue.orderedRemove(0);
+        }
+
+        if (!self.is_running) {
+            return .shutdown;
+        }
+
+        // Frames left in read buffers by an earlier read will not wake poll
+        var buffered_it = self.clients.valueIterator();
+        while (buffered_it.next()) |client| {
+            if (!self.hasBufferedFrame(client)) continue;
+            const client_id = client.id;
+            if (self.frameEvent(client_id, self.nextFrame(client_id))) |event| return event;
+            // The map may have changed; look again on the next call
+            break;
+        }
+
+        var listener = &(self.listener.?);
+
+        // Set up poll to check for new connections and client data
+        var poll_fds = std.ArrayListUnmanaged(std.posix.pollfd){};
+        defer poll_fds.deinit(self.allocator);
+
+        // Add listener socket, unless backing off from descriptor exhaustion
+        const accepting = std.time.milliTimestamp() >= self.accept_paused_until_ms;
+        try poll_fds.append(self.allocator, .{
+            .fd = listener.stream.handle,
+            .events = if (accepting) std.posix.POLL.IN else 0,
+            .revents = 0,
+        });
+
+        // Add all client sockets
+        var client_ids = std.ArrayListUnmanaged(u64){};
+        defer client_ids.deinit(self.allocator);
+
+        var it = self.clients.iterator();
+        while (it.next()) |entry| {
+            // Clients awaiting respond! are not read from until answered
+            if (!entry.value_ptr.is_closed and !entry.value_ptr.http_pending and
+                !entry.value_ptr.handshake_pending and entry.value_ptr.detached_until_ms == 0)
+            {
+                // Ask for writability only while there is queued output
+                const events: i16 = if (entry.value_ptr.download != null or entry.value_ptr.closing)
+                    std.posix.POLL.OUT
+                else if (entry.value_ptr.outbox.items.len > 0)
+                    std.posix.POLL.IN | std.posix.POLL.OUT
+                else
+                    std.posix.POLL.IN;
+                try poll_fds.append(self.allocator, .{
+                    .fd = entry.value_ptr.stream.handle,
+                    .events = events,
+                    .revents = 0,
+                });
+                try client_ids.append(self.allocator, entry.key_ptr.*);
+            }
+        }
+
+        // Then the signal pipe, and the operator console last
+        const signal_index = poll_fds.items.len;
+        if (signal_pipe) |fds| {
+            try poll_fds.append(self.allocator, .{
+                .fd = fds[0],
+                .events = std.posix.POLL.IN,
+                .revents = 0,
+            });
+        }
+        if (self.watch_stdin) {
+            try poll_fds.append(self.allocator, .{
+                .fd = std.posix.STDIN_FILENO,
+                .events = std.posix.POLL.IN,
+                .revents = 0,
+            });
+        }
+
+        // Wake up in time for the next keepalive check
+        var wait_ms: i32 = if (self.keepalive_interval_ms > 0)
+            @intCast(@min(@as(i64, timeout_ms), self.keepalive_interval_ms))
+        else
+            timeout_ms;
+        if (!accepting) wait_ms = @min(wait_ms, accept_backoff_ms);
+        // Checking twice per interval keeps Engine.IO pings well inside the client's timeout
+        if (self.socketio) wait_ms = @min(wait_ms, socketio_ping_interval_ms / 2);
+        if (self.handshake_timeout_ms > 0) wait_ms = @intCast(@min(@as(i64, wait_ms), self.handshake_timeout_ms));
+        if (self.nextTimerDue()) |due| {
+            wait_ms = @intCast(std.math.clamp(due - std.time.milliTimestamp(), 0, @as(i64, wait_ms)));
+        }
+        if (self.drain_until_ms != 0) {
+            wait_ms = @intCast(std.math.clamp(self.drain_until_ms - std.time.milliTimestamp(), 0, @as(i64, wait_ms)));
+        }
+
+        const ready = std.posix.poll(poll_fds.items, wait_ms) catch |err| {
+            return self.errorEvent("Poll error: {}", .{err});
+        };
+
+        if (ready == 0) {
+            return null;
+        }
+
+        if (signal_pipe != null and poll_fds.items[signal_index].revents != 0) {
+            self.readSignals();
+        }
+
+        // Typed commands are queued and come out on the next call
+        if (self.watch_stdin and poll_fds.items[poll_fds.items.len - 1].revents != 0) {
+            self.readAdminCommands();
+        }
+
+        // Check listener for new connections
+        if (poll_fds.items[0].revents & std.posix.POLL.IN != 0) {
+            const connection = listener.accept() catch |err| switch (err) {
+                error.ProcessFdQuotaExceeded, error.SystemFdQuotaExceeded, error.SystemResources => {
+                    // Pending connections wait in the backlog until descriptors free up
+                    logger.warn(null, "Accept failed ({s}), pausing for {d}ms", .{ @errorName(err), accept_backoff_ms });
+                    self.accept_paused_until_ms = std.time.milliTimestamp() + accept_backoff_ms;
+                    return null;
+                },
+                else => return self.errorEvent("Accept error: {}", .{err}),
+            };
+
+            const client_id = self.next_client_id;
+            self.next_client_id += 1;
+
+            // Behind a proxy, the forwarded address is checked once the request arrives
+            if (!Cidr.anyContains(self.trusted_proxies.items, connection.address) and
+                !self.isAddressAllowed(connection.address))
+            {
+                logger.warn(client_id, "Refused connection from {f}", .{connection.address});
+                connection.stream.close();
+                return null;
+            }
+
+            if (!self.allowConnectAttempt(connection.address)) {
+                // Best effort: the connection is closed whether or not this fits the socket buffer
+                _ = writeNonBlocking(connection.stream, "HTTP/1.1 429 Too Many Requests\r\nRetry-After: 1\r\n" ++
+                    "Content-Length: 0\r\nConnection: close\r\n\r\n") catch {};
+                connection.stream.close();
+                logger.warn(client_id, "Too many connection attempts from {f}", .{connection.address});
+                const reason = std.fmt.allocPrint(self.allocator, "Too many connection attempts from {f}", .{connection.address}) catch "";
+                return .{ .rate_limited = .{ .client_id = client_id, .reason = reason } };
+            }
+
+            self.socket_options.apply(connection.stream);
+
+            // The HTTP request is read once the socket polls readable
+            try self.clients.put(client_id, .{
+                .id = client_id,
+                .stream = connection.stream,
+                .address = connection.address,
+                .is_websocket = false,
+                .last_activity_ms = std.time.milliTimestamp(),
+                .connected_ms = std.time.milliTimestamp(),
+                .over_capacity = self.drain_until_ms != 0 or
+                    (self.max_connections > 0 and self.clients.count() >= self.max_connections),
+            });
+        }
+
+        // Check clients for incoming data
+        for (poll_fds.items[1 .. 1 + client_ids.items.len], 0..) |pfd, i| {
+            if (pfd.revents & std.posix.POLL.OUT != 0) {
+                if (self.clients.getPtr(client_ids.items[i])) |client| {
+                    const pending = client.outbox.items.len;
+                    // Write errors surface as HUP/ERR on the next poll
+                    if (pending > 0) self.flushOutbox(client) catch {};
+                    // A response head goes out before the file body behind it
+                    if (client.download != null) {
+                        if (client.outbox.items.len == 0 and !self.pumpDownload(client)) self.removeClient(client_ids.items[i]);
+                        continue;
+                    }
+                    if (client.closing) {
+                        if (client.outbox.items.len == 0) {
+                            self.removeClient(client_ids.items[i]);
+                        } else if (client.outbox.items.len < pending) {
+                            client.last_activity_ms = std.time.milliTimestamp();
+                        }
+                        continue;
+                    }
+                }
+            }
+
+            if (pfd.revents & std.posix.POLL.IN != 0) {
+                const client_id = client_ids.items[i];
+                const client = self.clients.getPtr(client_id) orelse continue;
+                if (!client.is_websocket) {
+                    // Still reading the HTTP request; failures never reached Connected
+                    if (self.handleNewConnection(client_id)) |event| {
+                        return event;
+                    } else |err| switch (err) {
+                        error.PartialMessage, error.Streaming => continue,
+                        else => {
+                            self.finishHttp(client_id);
+                            continue;
+                        },
+                    }
+                }
+                if (self.frameEvent(client_id, self.handleClientData(client_id))) |event| return event;
+                continue;
+            }
+
+            if (pfd.revents & (std.posix.POLL.HUP | std.posix.POLL.ERR) != 0) {
+                const client_id = client_ids.items[i];
+                const client = self.clients.getPtr(client_id) orelse continue;
+                if (!client.is_websocket) {
+                    self.removeClient(client_id);
+                    continue;
+                }
+                if (self.connectionLost(client_id)) |event| return event;
+            }
+        }
+        // No events this poll cycle
+        return null;
+    }
+
+    /// Turn the outcome of decoding a frame into the event to report, if any,
+    /// closing the client on protocol violations and read errors
+    fn frameEvent(self: *WebSocketServer, client_id: u64, result: anyerror!WebSocketEvent) ?WebSocketEvent {
+        if (result) |event| {
+            var next = self.limitMessageRate(client_id, event);
+            next = self.socketioEvent(next) orelse return null;
+            next = self.graphqlEvent(next) orelse return null;
+            next = self.stompEvent(next) orelse return null;
+            next = self.envelopeEvent(next) orelse return null;
+            next = self.rpcEvent(next) orelse return null;
+            next = self.roleEvent(self.muteEvent(next));
+            return self.archiveEvent(self.filterEvent(next));
+        } else |err| switch (err) {
+            // Control frames and partial frames or messages produce no event
+            error.ControlFrame, error.PartialMessage, error.RejectedMessage => return null,
+            error.ProtocolError => return self.dropClient(client_id, close_protocol_error, "Protocol error"),
+            error.InvalidPayload => return self.dropClient(client_id, close_invalid_payload, "Invalid UTF-8"),
+            error.PayloadTooLarge => return self.dropOversizeClient(client_id),
+            else => {
+                // Error reading, client disconnected
+                logger.debug(client_id, "Read failed: {s}", .{@errorName(err)});
+                return self.connectionLost(client_id);
+            },
+        }
+    }
+
+    /// Read more of a connection's HTTP request and act on it once complete.
+    /// Returns error.PartialMessage while the request is still arriving.
+    fn handleNewConnection(self: *WebSocketServer, client_id: u64) !WebSocketEvent {
+        const client = self.clients.getPtr(client_id) orelse return error.ClientNotFound;
+
+        // Read straight into the request buffer's spare capacity
+        try client.request_buf.ensureUnusedCapacity(self.allocator, 4096);
+        const n = try client.stream.read(client.request_buf.unusedCapacitySlice());
+        if (n == 0) return error.ConnectionClosed;
+        client.request_buf.items.len += n;
+
+        const parsed = parseHttpHead(client.request_buf.items) catch |err| {
+            try self.sendRequestError(client, err);
+            return error.NotWebSocket;
+        };
+        var request = parsed orelse {
+            if (client.request_buf.items.len > self.max_header_size) {
+                try self.sendHttpError(client, 431, "Request Header Fields Too Large");
+                return error.NotWebSocket;
+            }
+            return error.PartialMessage;
+        };
+        const complete = readHttpBody(client.request_buf.items, &request, self.bodyLimitFor(&request)) catch |err| {
+            try self.sendRequestError(client, err);
+            return error.NotWebSocket;
+        };
+        if (!complete) {
+            // Clients sending Expect: 100-continue wait for this before the body
+            if (!client.sent_continue and request.headerHasToken("Expect", "100-continue")) {
+                try self.queueBytes(client, "HTTP/1.1 100 Continue\r\n\r\n");
+                client.sent_continue = true;
+            }
+            return error.PartialMessage;
+        }
+        defer client.request_buf.clearAndFree(self.allocator);
+        self.applyForwarded(client, &request);
+        self.stripBasePath(&request);
+
+        // Probes are answered even when the server is full
+        if (std.mem.eql(u8, request.path, health_path) or std.mem.eql(u8, request.path, ready_path)) {
+            try self.handleProbe(client, std.mem.eql(u8, request.path, ready_path));
+            return error.NotWebSocket;
+        }
+
+        if (client.over_capacity) {
+            try self.turnAway(client, &request);
+            return error.NotWebSocket;
+        }
+
+        if (client.address) |peer| {
+            const address = self.resolveClientAddress(peer, &request) orelse {
+                logger.warn(client_id, "Malformed forwarded address from {f}", .{peer});
+                try self.sendHttpError(client, 400, "Bad Request");
+                return error.NotWebSocket;
+            };
+            if (!self.isAddressAllowed(address)) {
+                logger.warn(client_id, "Refused forwarded address {f}", .{address});
+                try self.sendHttpError(client, 403, "Forbidden");
+                return error.NotWebSocket;
+            }
+            var ip_buf: [64]u8 = undefined;
+            try client.setData(self.allocator, "address", formatIp(address, &ip_buf));
+        }
+        if (self.virtualHost(&request)) |host| try client.setData(self.allocator, "vhost", host);
+
+        // Browsers always send Origin cross-site; reject pages we don't trust
+        const is_upload = self.upload_dir != null and std.mem.eql(u8, request.path, upload_path);
+        if (!self.isOriginAllowed(&request) and (request.isWebSocketUpgrade() or is_upload or
+            std.mem.eql(u8, request.path, sse_events_path) or std.mem.eql(u8, request.path, sse_send_path)))
+        {
+            logger.warn(client_id, "Rejected origin {s}", .{request.header("Origin") orelse ""});
+            try self.sendHttpError(client, 403, "Forbidden");
+            return error.NotWebSocket;
+        }
+
+        if ((request.isWebSocketUpgrade() or is_upload or std.mem.eql(u8, request.path, sse_events_path)) and
+            !try self.authenticate(client, &request))
+        {
+            return error.NotWebSocket;
+        }
+
+        if (request.isWebSocketUpgrade()) {
+            const accept_key = (try self.handleWebSocketUpgrade(client, &request)) orelse return error.NotWebSocket;
+            // Frames the client sent right behind its request are decoded after the handshake
+            try client.read_buf.appendSlice(self.allocator, client.request_buf.items[request.len..]);
+
+            if (queryParam(request.query, "resume")) |token| {
+                if (self.findDetached(token)) |detached| {
+                    try self.completeHandshake(client, &accept_key);
+                    try self.resumeClient(detached, client);
+                    // This connection's id is retired; the Resumed event is already queued
+                    return error.NotWebSocket;
+                }
+                logger.debug(client_id, "Unknown or expired resume token", .{});
+            }
+
+            const is_socketio = self.socketio and std.mem.eql(u8, request.path, socketio_path);
+            if (is_socketio and !std.mem.eql(u8, queryParam(request.query, "EIO") orelse "", "4")) {
+                try self.sendJson(client, 400, "{\"code\":5,\"message\":\"Unsupported protocol version\"}");
+                return error.NotWebSocket;
+            }
+            const room = if (is_socketio) "" else self.matchWsEndpoint(request.path) orelse {
+                logger.info(client_id, "No WebSocket endpoint at {s}", .{request.path});
+                try self.sendHttpError(client, 404, "Not Found");
+                return error.NotWebSocket;
+            };
+            client.is_socketio = is_socketio;
+            try client.setData(self.allocator, "path", request.path);
+            try client.setData(self.allocator, "query", request.query);
+            if (room.len > 0) self.joinRoom(client_id, room) catch |err| switch (err) {
+                error.PermissionDenied => {
+                    logger.info(client_id, "Not permitted to join {s}", .{room});
+                    try self.sendHttpError(client, 403, "Forbidden");
+                    return error.NotWebSocket;
+                },
+                else => return err,
+            };
+            if (self.handshake_policy) {
+                // The application decides with accept_handshake! or reject_handshake!
+                const event = try HttpRequestEvent.fromRequest(self.allocator, client_id, client.request_buf.items, &request);
+                client.handshake_accept = accept_key;
+                client.handshake_pending = true;
+                return .{ .handshake_request = event };
+            }
+            try self.completeHandshake(client, &accept_key);
+            self.issueResumeToken(client);
+            self.openSocketio(client);
+            self.replayJoinedRooms(client);
+            return .{ .connected = client_id };
+        } else if (std.mem.eql(u8, request.path, sse_events_path)) {
+            return self.openSseStream(client, &request);
+        } else if (std.mem.eql(u8, request.path, sse_send_path)) {
+            return self.handleSsePost(client, &request);
+        } else if (self.socketio and std.mem.eql(u8, request.path, socketio_path)) {
+            // Clients must be created with transports: ["websocket"]
+            try self.sendJson(client, 400, "{\"code\":0,\"message\":\"Transport unknown\"}");
+        } else if (is_upload) {
+            return self.handleUpload(client, &request);
+        } else if (self.upload_dir != null and std.mem.startsWith(u8, request.path, upload_path ++ "/")) {
+            try self.serveUpload(client, &request);
+        } else if (self.hook_secret != null and std.mem.startsWith(u8, request.path, hook_path_prefix)) {
+            return self.handleHookPost(client, &request);
+        } else if (self.admin_token != null and std.mem.startsWith(u8, request.path, admin_path_prefix)) {
+            try self.handleAdminRequest(client, &request);
+        } else {
+            const target = self.routeFor(client, &request) catch |err| switch (err) {
+                error.MethodNotAllowed => {
+                    try self.sendErrorPage(client, &request, 405, "Method Not Allowed");
+                    return error.NotWebSocket;
+                },
+                else => return err,
+            };
+            switch (target) {
+                .app => {
+                    // Application endpoint: the connection stays open until respond!
+                    const event = try HttpRequestEvent.fromRequest(self.allocator, client_id, client.request_buf.items, &request);
+                    client.http_pending = true;
+                    logger.debug(client_id, "{s} {s} -> application", .{ request.method, request.target });
+                    return .{ .http_request = event };
+                },
+                .static => try self.handleHttpRequest(client, &request),
+                .not_found => try self.sendErrorPage(client, &request, 404, "Not Found"),
+            }
+        }
+
+        // A streamed body keeps the connection until the poll loop has written it
+        if (client.download != null) return error.Streaming;
+        return error.NotWebSocket;
+    }
+
+    /// Serve everything under `path` (e.g. "/chat") as well as at the root; "" or "/" clears it
+    fn setBasePath(self: *WebSocketServer, path: []const u8) !void {
+        const trimmed = std.mem.trimRight(u8, path, "/");
+        if (trimmed.len > 0 and (trimmed[0] != '/' or std.mem.indexOfAny(u8, trimmed, "?#% \t") != null)) return error.InvalidBasePath;
+        const owned = if (trimmed.len > 0) try self.allocator.dupe(u8, trimmed) else null;
+        if (self.base_path) |old| self.allocator.free(old);
+        self.base_path = owned;
+    }
+
+    /// Route a request made under the base path as if it were made at the root.
+    /// Proxies that strip the prefix themselves send paths that are left alone.
+    fn stripBasePath(self: *const WebSocketServer, request: *HttpRequest) void {
+        const base = self.base_path orelse return;
+        if (!std.mem.startsWith(u8, request.path, base)) return;
+        const rest = request.path[base.len..];
+        if (rest.len == 0) {
+            request.path = "/";
+        } else if (rest[0] == '/') {
+            request.path = rest;
+        }
+    }
+
+    /// Whether a connection comes from a proxy set with trust_proxy!
+    fn isFromProxy(self: *const WebSocketServer, client: *const WebSocketClient) bool {
+        const peer = client.address orelse return false;
+        return Cidr.anyContains(self.trusted_proxies.items, peer);
+    }
+
+    /// For a request relayed by a trusted proxy, take the Host the client asked for
+    /// from X-Forwarded-Host and note whether it used HTTPS (X-Forwarded-Proto)
+    fn applyForwarded(self: *const WebSocketServer, client: *WebSocketClient, request: *HttpRequest) void {
+        if (!self.isFromProxy(client)) return;
+        if (request.header("X-Forwarded-Proto")) |proto| {
+            client.via_https = std.ascii.eqlIgnoreCase(firstForwarded(proto), "https");
+        }
+        const forwarded_host = firstForwarded(request.header("X-Forwarded-Host") orelse return);
+        if (forwarded_host.len == 0) return;
+        for (request.headers[0..request.header_count]) |*h| {
+            if (std.ascii.eqlIgnoreCase(h.name, "Host")) h.value = forwarded_host;
+        }
+    }
+
+    /// Write where `path` on this server is reached from outside: under the base
+    /// path, and as an absolute URL when a trusted proxy said how it was reached
+    fn writePublicUrl(self: *const WebSocketServer, writer: anytype, client: *const WebSocketClient, request: *const HttpRequest, path: []const u8) !void {
+        if (self.isFromProxy(client) and request.header("X-Forwarded-Proto") != null) {
+            if (request.header("Host")) |host| {
+                try writer.print("{s}://{s}", .{ if (client.via_https) "https" else "http", host });
+            }
+        }
+        try writer.print("{s}{s}", .{ self.base_path orelse "", path });
+    }
+
+    /// The client's real address: the peer itself or, for connections from a trusted
+    /// proxy, the nearest untrusted hop in X-Forwarded-For (falling back to X-Real-IP).
+    /// Null when a forwarded address is malformed; the proxy's own address must not
+    /// stand in for it, since that would pass loopback and admin network checks.
+    fn resolveClientAddress(self: *WebSocketServer, peer: std.net.Address, request: *const HttpRequest) ?std.net.Address {
+        if (!Cidr.anyContains(self.trusted_proxies.items, peer)) return peer;
+
+        if (request.header("X-Forwarded-For")) |forwarded| {
+            // Each proxy appends the address it received from, so read right to left
+            var nearest = peer;
+            var hops = std.mem.splitBackwardsScalar(u8, forwarded, ',');
+            while (hops.next()) |hop| {
+                nearest = std.net.Address.parseIp(std.mem.trim(u8, hop, " \t"), 0) catch return null;
+                if (!Cidr.anyContains(self.trusted_proxies.items, nearest)) break;
+            }
+            return nearest;
+        }
+        if (request.header("X-Real-IP")) |real_ip| {
+            return std.net.Address.parseIp(std.mem.trim(u8, real_ip, " \t"), 0) catch null;
+        }
+        return peer;
+    }
+
+    fn isAddressAllowed(self: *const WebSocketServer, address: std.net.Address) bool {
+        if (Cidr.anyContains(self.denied_networks.items, address)) return false;
+        if (Restriction.find(self.bans.items, address, std.time.milliTimestamp()) != null) return false;
+        return self.allowed_networks.items.len == 0 or Cidr.anyContains(self.allowed_networks.items, address);
+    }
+
+    /// With resuming enabled, give a new WebSocket client its resume token in a
+    /// {"type":"session","clientId","resumeToken"} frame
+    fn issueResumeToken(self: *WebSocketServer, client: *WebSocketClient) void {
+        if (self.resume_grace_ms <= 0 or client.is_sse or client.is_upstream or client.is_socketio) return;
+        if (client.resume_token == null) {
+            var secret: [16]u8 = undefined;
+            std.crypto.random.bytes(&secret);
+            client.resume_token = std.fmt.bytesToHex(secret, .lower);
+        }
+
+        var buf: [128]u8 = undefined;
+        const session = std.fmt.bufPrint(&buf, "{{\"type\":\"session\",\"clientId\":{d},\"resumeToken\":\"{s}\"}}", .{ client.id, &client.resume_token.? }) catch unreachable;
+        self.sendFrame(client, .text, session) catch {};
+    }
+
+    fn findDetached(self: *WebSocketServer, token: []const u8) ?*WebSocketClient {
+        if (token.len != 32) return null;
+        var it = self.clients.valueIterator();
+        while (it.next()) |candidate| {
+            if (candidate.detached_until_ms == 0) continue;
+            const candidate_token = candidate.resume_token orelse continue;
+            if (std.crypto.timing_safe.eql([32]u8, candidate_token, token[0..32].*)) return candidate;
+        }
+        return null;
+    }
+
+    /// Move a fresh connection's socket onto the detached client it resumes, send
+    /// what was queued meanwhile and queue Resumed. The fresh client is left holding
+    /// the dead socket and is removed by the caller.
+    fn resumeClient(self: *WebSocketServer, detached: *WebSocketClient, fresh: *WebSocketClient) !void {
+        // Whatever of the 101 the socket did not take goes out ahead of the backlog
+        try detached.outbox.insertSlice(self.allocator, 0, fresh.outbox.items);
+        detached.bytes_queued += fresh.outbox.items.len;
+        fresh.outbox.clearRetainingCapacity();
+        std.mem.swap(std.net.Stream, &detached.stream, &fresh.stream);
+        std.mem.swap(std.ArrayListUnmanaged(u8), &detached.read_buf, &fresh.read_buf);
+        detached.detached_until_ms = 0;
+        detached.fragment_opcode = null;
+        detached.fragment_buf.clearRetainingCapacity();
+        detached.last_activity_ms = std.time.milliTimestamp();
+        detached.last_ping_ms = detached.last_activity_ms;
+
+        logger.info(detached.id, "Resumed on connection #{d} ({d} bytes queued)", .{ fresh.id, detached.outbox.items.len });
+        self.issueResumeToken(detached);
+        self.flushOutbox(detached) catch {};
+        try self.queueEvent(.{ .resumed = detached.id });
+    }
+
+    /// A WebSocket client's connection dropped without a Close frame. Resumable
+    /// clients are detached quietly; others are removed with an abnormal closure.
+    fn connectionLost(self: *WebSocketServer, client_id: u64) ?WebSocketEvent {
+        const client = self.clients.getPtr(client_id) orelse return null;
+        if (client.resume_token != null and self.resume_grace_ms > 0) {
+            client.detached_until_ms = std.time.milliTimestamp() + self.resume_grace_ms;
+            client.fragment_opcode = null;
+            logger.info(client_id, "Connection lost, resumable for {d}ms", .{self.resume_grace_ms});
+            return null;
+        }
+        self.removeClient(client_id);
+        return abnormalClosure(client_id);
+    }
+
+    /// Remove detached clients whose grace period ran out, queueing their Disconnected
+    fn expireDetached(self: *WebSocketServer) void {
+        const now = std.time.milliTimestamp();
+
+        var expired = std.ArrayListUnmanaged(u64){};
+        defer expired.deinit(self.allocator);
+
+        var it = self.clients.valueIterator();
+        while (it.next()) |client| {
+            if (client.detached_until_ms != 0 and now >= client.detached_until_ms) {
+                expired.append(self.allocator, client.id) catch {};
+            }
+        }
+
+        for (expired.items) |client_id| {
+            logger.info(client_id, "Resume grace period expired", .{});
+            self.removeClient(client_id);
+            self.queueEvent(abnormalClosure(client_id)) catch {};
+        }
+    }
+
+    /// Refuse a connection accepted while the server was full: plain HTTP gets 503,
+    /// WebSocket clients are upgraded and closed with 1013 so browsers see a reason
+    fn turnAway(self: *WebSocketServer, client: *WebSocketClient, request: *const HttpRequest) !void {
+        const draining = self.drain_until_ms != 0;
+        if (draining) {
+            logger.info(client.id, "Draining, turning away", .{});
+        } else {
+            logger.warn(client.id, "Server full ({d} connections), turning away", .{self.max_connections});
+        }
+        if (request.isWebSocketUpgrade()) {
+            if (try self.handleWebSocketUpgrade(client, request)) |accept_key| {
+                try self.completeHandshake(client, &accept_key);
+                try self.sendClose(client, close_try_again_later, if (draining) "Server restarting" else "Server at capacity");
+            }
+            return;
+        }
+        try self.queueBytes(client, "HTTP/1.1 503 Service Unavailable\r\nRetry-After: 5\r\n" ++
+            "Content-Length: 0\r\nConnection: close\r\n\r\n");
+    }
+
+    /// Turn a GET /events request into an SSE pseudo-client. It then counts as
+    /// connected: send!, broadcast! and rooms reach it like a WebSocket client.
+    fn openSseStream(self: *WebSocketServer, client: *WebSocketClient, request: *const HttpRequest) !WebSocketEvent {
+        if (!std.mem.eql(u8, request.method, "GET")) {
+            try self.sendHttpError(client, 405, "Method Not Allowed");
+            return error.NotWebSocket;
+        }
+
+        var secret: [16]u8 = undefined;
+        std.crypto.random.bytes(&secret);
+        client.sse_token = std.fmt.bytesToHex(secret, .lower);
+        client.is_sse = true;
+        client.is_websocket = true;
+        client.last_ping_ms = std.time.milliTimestamp();
+
+        try self.queueBytes(client, "HTTP/1.1 200 OK\r\n" ++
+            "Content-Type: text/event-stream\r\n" ++
+            "Cache-Control: no-cache\r\n" ++
+            "X-Accel-Buffering: no\r\n\r\n");
+
+        // Tell the browser who it is and how to post
+        var session_buf: [128]u8 = undefined;
+        const session = std.fmt.bufPrint(&session_buf, "event: session\ndata: {{\"clientId\":{},\"token\":\"{s}\"}}\n\n", .{ client.id, client.sse_token }) catch unreachable;
+        try self.queueBytes(client, session);
+
+        logger.info(client.id, "SSE stream opened", .{});
+        return .{ .connected = client.id };
+    }
+
+    /// POST /send from an SSE client: the body becomes a Message from the client
+    /// whose token is given in X-SSE-Token
+    fn handleSsePost(self: *WebSocketServer, client: *WebSocketClient, request: *const HttpRequest) !WebSocketEvent {
+        if (!std.mem.eql(u8, request.method, "POST")) {
+            try self.sendHttpError(client, 405, "Method Not Allowed");
+            return error.NotWebSocket;
+        }
+        const token = request.header("X-SSE-Token") orelse "";
+        const sender = self.findSseClient(token) orelse {
+            try self.sendHttpError(client, 401, "Unauthorized");
+            return error.NotWebSocket;
+        };
+        if (!std.unicode.utf8ValidateSlice(request.body)) {
+            try self.sendHttpError(client, 400, "Bad Request");
+            return error.NotWebSocket;
+        }
+
+        if (!sender.message_bucket.take(self.message_limit, std.time.milliTimestamp())) {
+            try self.sendHttpError(client, 429, "Too Many Requests");
+            try self.queueEvent(self.rateLimitClient(sender.id));
+            return error.NotWebSocket;
+        }
+
+        try self.queueBytes(client, "HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n");
+
+        sender.last_activity_ms = std.time.milliTimestamp();
+        sender.traffic.bytes_in += request.body.len;
+        sender.traffic.messages_in += 1;
+        self.traffic.bytes_in += request.body.len;
+        self.traffic.messages_in += 1;
+        // Queued rather than returned: this connection is finished and gets removed
+        const text = try self.allocator.dupe(u8, request.body);
+        if (self.envelopeEvent(.{ .message = .{ .client_id = sender.id, .text = text } })) |event| {
+            if (self.rpcEvent(event)) |call| {
+                if (self.archiveEvent(self.filterEvent(self.roleEvent(self.muteEvent(call))))) |next| try self.queueEvent(next);
+            }
+        }
+        return error.NotWebSocket;
+    }
+
+    /// POST /hooks/{room} with `Authorization: Bearer <hook secret>` and a JSON body
+    /// {"text", "sender"?}: the message goes to the room as {"type":"hook","room",
+    /// "sender","text"} and a HookMessage event is queued
+    fn handleHookPost(self: *WebSocketServer, client: *WebSocketClient, request: *const HttpRequest) !WebSocketEvent {
+        if (!std.mem.eql(u8, request.method, "POST")) {
+            try self.sendHttpError(client, 405, "Method Not Allowed");
+            return error.NotWebSocket;
+        }
+
+        if (!bearerMatches(request, self.hook_secret.?)) {
+            try self.sendHttpError(client, 401, "Unauthorized");
+            return error.NotWebSocket;
+        }
+
+        const room = request.path[hook_path_prefix.len..];
+        if (room.len == 0 or std.mem.indexOfScalar(u8, room, '/') != null) {
+            try self.sendHttpError(client, 404, "Not Found");
+            return error.NotWebSocket;
+        }
+
+        const HookBody = struct { text: []const u8, sender: []const u8 = "webhook" };
+        const parsed = std.json.parseFromSlice(HookBody, self.allocator, request.body, .{ .ignore_unknown_fields = true }) catch {
+            try self.sendHttpError(client, 400, "Bad Request");
+            return error.NotWebSocket;
+        };
+        defer parsed.deinit();
+
+        var json = std.ArrayListUnmanaged(u8){};
+        defer json.deinit(self.allocator);
+        const w = json.writer(self.allocator);
+        try w.writeAll("{\"type\":\"hook\",\"room\":");
+        try writeJsonString(w, room);
+        try w.writeAll(",\"sender\":");
+        try writeJsonString(w, parsed.value.sender);
+        try w.writeAll(",\"text\":");
+        try writeJsonString(w, parsed.value.text);
+        try w.writeByte('}');
+        self.broadcastToRoom(room, json.items) catch |err| switch (err) {
+            error.RoomNotFound => {},
+            else => return err,
+        };
+
+        try self.queueBytes(client, "HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n");
+
+        logger.info(client.id, "Hook message for room {s} from {s}", .{ room, parsed.value.sender });
+        // Queued rather than returned: this connection is finished and gets removed
+        const event = try HookMessage.init(self.allocator, room, parsed.value.sender, parsed.value.text);
+        try self.queueEvent(.{ .hook_message = event });
+        return error.NotWebSocket;
+    }
+
+    /// Largest body accepted for a request: uploads have their own limit
+    fn bodyLimitFor(self: *const WebSocketServer, request: *const HttpRequest) usize {
+        if (self.upload_dir != null and std.mem.eql(u8, request.path, upload_path)) return self.max_upload_size;
+        return self.max_body_size;
+    }
+
+    /// Store a file posted to /upload, as multipart/form-data or as the raw body
+    /// (named by ?name= or typed by Content-Type), answer with its URL as JSON
+    /// and queue FileUploaded
+    fn handleUpload(self: *WebSocketServer, client: *WebSocketClient, request: *const HttpRequest) !WebSocketEvent {
+        if (!std.mem.eql(u8, request.method, "POST")) {
+            try self.sendHttpError(client, 405, "Method Not Allowed");
+            return error.NotWebSocket;
+        }
+
+        const content_type = request.header("Content-Type") orelse "application/octet-stream";
+        var filename: []const u8 = queryParam(request.query, "name") orelse "";
+        var content = request.body;
+        if (std.ascii.startsWithIgnoreCase(content_type, "multipart/form-data")) {
+            const file = multipartFile(request.body, content_type) catch |err| {
+                logger.debug(client.id, "Rejected upload: {s}", .{@errorName(err)});
+                try self.sendHttpError(client, 400, "Bad Request");
+                return error.NotWebSocket;
+            };
+            filename = file.filename;
+            content = file.content;
+        }
+        if (content.len == 0) {
+            try self.sendHttpError(client, 400, "Bad Request");
+            return error.NotWebSocket;
+        }
+
+        // Files are served by extension, so the extension decides the type
+        var ext_buf: [16]u8 = undefined;
+        const ext = uploadExtension(&ext_buf, filename) orelse extensionForMime(content_type) orelse {
+            try self.sendHttpError(client, 415, "Unsupported Media Type");
+            return error.NotWebSocket;
+        };
+        var name_buf: [64]u8 = undefined;
+        var id: [16]u8 = undefined;
+        std.crypto.random.bytes(&id);
+        const name = std.fmt.bufPrint(&name_buf, "{s}.{s}", .{ &std.fmt.bytesToHex(id, .lower), ext }) catch unreachable;
+        const mime = self.mimeTypeFor(name);
+        const allowed = if (self.upload_types.items.len > 0) self.upload_types.items else &default_upload_types;
+        if (!mimeAllowed(allowed, mime)) {
+            logger.info(client.id, "Refused upload of type {s}", .{mime});
+            try self.sendHttpError(client, 415, "Unsupported Media Type");
+            return error.NotWebSocket;
+        }
+
+        std.fs.cwd().makePath(self.upload_dir.?) catch {};
+        var dir = std.fs.cwd().openDir(self.upload_dir.?, .{}) catch |err| {
+            logger.err(client.id, "Cannot open upload directory {s}: {s}", .{ self.upload_dir.?, @errorName(err) });
+            try self.sendHttpError(client, 500, "Internal Server Error");
+            return error.NotWebSocket;
+        };
+        defer dir.close();
+        dir.writeFile(.{ .sub_path = name, .data = content, .flags = .{ .exclusive = true } }) catch |err| {
+            logger.err(client.id, "Cannot store upload {s}: {s}", .{ name, @errorName(err) });
+            try self.sendHttpError(client, 500, "Internal Server Error");
+            return error.NotWebSocket;
+        };
+
+        var path_buf: [96]u8 = undefined;
+        const path = std.fmt.bufPrint(&path_buf, "{s}/{s}", .{ upload_path, name }) catch unreachable;
+        var url_buf: [1024]u8 = undefined;
+        var url_stream = std.io.fixedBufferStream(&url_buf);
+        self.writePublicUrl(url_stream.writer(), client, request, path) catch {
+            try self.sendHttpError(client, 500, "Internal Server Error");
+            return error.NotWebSocket;
+        };
+        const url = url_stream.getWritten();
+        var json = std.ArrayListUnmanaged(u8){};
+        defer json.deinit(self.allocator);
+        const w = json.writer(self.allocator);
+        try w.writeAll("{\"url\":");
+        try writeJsonString(w, url);
+        try w.print(",\"size\":{d},\"mime\":", .{content.len});
+        try writeJsonString(w, mime);
+        try w.writeByte('}');
+        try self.sendJson(client, 201, json.items);
+
+        logger.info(client.id, "Stored upload {s} ({d} bytes, {s})", .{ name, content.len, mime });
+        // Queued rather than returned: this connection is finished and gets removed
+        const event = try FileUpload.init(self.allocator, client.data.get("user") orelse "", url, mime, content.len);
+        try self.queueEvent(.{ .file_uploaded = event });
+        return error.NotWebSocket;
+    }
+
+    /// Serve a file from the upload directory, as GET /upload/{name}
+    fn serveUpload(self: *WebSocketServer, client: *WebSocketClient, request: *const HttpRequest) !void {
+        if (!std.mem.eql(u8, request.method, "GET") and !std.mem.eql(u8, request.method, "HEAD")) {
+            return self.sendHttpError(client, 405, "Method Not Allowed");
+        }
+        // Stored names are generated, so anything else is not ours
+        const name = request.path[upload_path.len + 1 ..];
+        if (name.len == 0 or name[0] == '.' or std.mem.indexOfAny(u8, name, "/\\") != null) {
+            return self.sendHttpError(client, 404, "Not Found");
+        }
+
+        var dir = std.fs.cwd().openDir(self.upload_dir.?, .{}) catch return self.sendHttpError(client, 404, "Not Found");
+        defer dir.close();
+        const file = dir.openFile(name, .{}) catch return self.sendHttpError(client, 404, "Not Found");
+        defer file.close();
+        const stat = file.stat() catch return self.sendHttpError(client, 500, "Internal Server Error");
+
+        // Stored files never change
+        var etag_buf: [48]u8 = undefined;
+        const etag = std.fmt.bufPrint(&etag_buf, "\"{s}\"", .{name[0 .. std.mem.indexOfScalar(u8, name, '.') orelse name.len]}) catch return self.sendHttpError(client, 404, "Not Found");
+        var date_buf: [29]u8 = undefined;
+        const mtime_secs: i64 = @intCast(@divFloor(stat.mtime, std.time.ns_per_s));
+        const last_modified = formatHttpDate(&date_buf, mtime_secs);
+        if (isNotModified(request, etag, mtime_secs)) return self.sendNotModified(client, request, etag, last_modified);
+
+        if (stat.size > self.static_buffer_limit) {
+            return self.streamStaticFile(client, request, self.mimeTypeFor(name), dir, name, null, etag, last_modified);
+        }
+        const content = file.readToEndAlloc(self.allocator, self.static_buffer_limit) catch return self.sendHttpError(client, 500, "Internal Server Error");
+        defer self.allocator.free(content);
+        try self.sendStaticFile(client, request, self.mimeTypeFor(name), content, null, etag, last_modified);
+    }
+
+    /// Accept uploads into `dir` up to `max_size` bytes of request body, of the
+    /// given types ("image/*" covers a family; none for the defaults)
+    fn setUploads(self: *WebSocketServer, dir: []const u8, max_size: usize, types: []const []const u8) !void {
+        const owned_dir = try self.allocator.dupe(u8, dir);
+        errdefer self.allocator.free(owned_dir);
+        var owned_types = std.ArrayListUnmanaged([]const u8){};
+        errdefer {
+            for (owned_types.items) |mime| self.allocator.free(mime);
+            owned_types.deinit(self.allocator);
+        }
+        for (types) |mime| {
+            const owned = try self.allocator.dupe(u8, mime);
+            errdefer self.allocator.free(owned);
+            try owned_types.append(self.allocator, owned);
+        }
+
+        if (self.upload_dir) |old| self.allocator.free(old);
+        self.upload_dir = owned_dir;
+        if (max_size > 0) self.max_upload_size = max_size;
+        for (self.upload_types.items) |mime| self.allocator.free(mime);
+        self.upload_types.deinit(self.allocator);
+        self.upload_types = owned_types;
+    }
+
+    /// Serve the operator API. Requests must come from an admin network (loopback
+    /// unless allow_admin_ip! says otherwise) and carry the admin bearer token.
+    fn handleAdminRequest(self: *WebSocketServer, client: *WebSocketClient, request: *const HttpRequest) !void {
+        const peer = client.address orelse return self.sendHttpError(client, 403, "Forbidden");
+        const address = self.resolveClientAddress(peer, request) orelse return self.sendHttpError(client, 400, "Bad Request");
+        const allowed = if (self.admin_networks.items.len == 0) isLoopback(address) else Cidr.anyContains(self.admin_networks.items, address);
+        if (!allowed) {
+            logger.warn(client.id, "Admin request from {f} refused", .{address});
+            return self.sendHttpError(client, 403, "Forbidden");
+        }
+        if (!bearerMatches(request, self.admin_token.?)) return self.sendHttpError(client, 401, "Unauthorized");
+
+        const action = request.path[admin_path_prefix.len..];
+        const is_get = std.mem.eql(u8, request.method, "GET");
+        const is_post = std.mem.eql(u8, request.method, "POST");
+        logger.info(client.id, "Admin {s} {s}", .{ request.method, request.path });
+
+        var body = std.ArrayListUnmanaged(u8){};
+        defer body.deinit(self.allocator);
+        const w = body.writer(self.allocator);
+
+        if (std.mem.eql(u8, action, "clients")) {
+            if (!is_get) return self.sendHttpError(client, 405, "Method Not Allowed");
+            try w.writeByte('[');
+            var first = true;
+            var it = self.clients.valueIterator();
+            while (it.next()) |other| {
+                if (!other.is_websocket or other.is_closed) continue;
+                if (!first) try w.writeByte(',');
+                first = false;
+                const kind = if (other.is_sse) "sse" else if (other.is_upstream) "upstream" else "websocket";
+                try w.print("{{\"id\":{d},\"kind\":\"{s}\",\"address\":", .{ other.id, kind });
+                try writeJsonString(w, other.data.get("address") orelse "");
+                try w.writeAll(",\"user\":");
+                try writeJsonString(w, other.data.get("user") orelse "");
+                const counters = clientStats(other);
+                try w.print(",\"connectedMs\":{d},\"lastActivityMs\":{d},\"bytesIn\":{d},\"bytesOut\":{d},\"messagesIn\":{d},\"messagesOut\":{d},\"queuedBytes\":{d}}}", .{
+                    counters.connected_ms,
+                    counters.last_activity_ms,
+                    counters.bytes_in,
+                    counters.bytes_out,
+                    counters.messages_in,
+                    counters.messages_out,
+                    counters.queued_bytes,
+                });
+            }
+            try w.writeByte(']');
+        } else if (std.mem.eql(u8, action, "stats")) {
+            if (!is_get) return self.sendHttpError(client, 405, "Method Not Allowed");
+            try self.writeStatsJson(w);
+        } else if (std.mem.eql(u8, action, "broadcast")) {
+            if (!is_post) return self.sendHttpError(client, 405, "Method Not Allowed");
+            if (!std.unicode.utf8ValidateSlice(request.body)) return self.sendHttpError(client, 400, "Bad Request");
+            try self.broadcast(request.body);
+            return self.sendHttpError(client, 204, "No Content");
+        } else if (std.mem.startsWith(u8, action, "kick/")) {
+            if (!is_post) return self.sendHttpError(client, 405, "Method Not Allowed");
+            const target_id = std.fmt.parseInt(u64, action["kick/".len..], 10) catch return self.sendHttpError(client, 404, "Not Found");
+            const target = self.clients.getPtr(target_id) orelse return self.sendHttpError(client, 404, "Not Found");
+            if (!target.is_websocket or target.is_closed) return self.sendHttpError(client, 404, "Not Found");
+
+            const reason = "Kicked by admin";
+            self.closeClient(target_id, close_policy_violation, reason);
+            const owned_reason = try self.allocator.dupe(u8, reason);
+            self.queueEvent(.{ .disconnected = .{ .client_id = target_id, .code = close_policy_violation, .reason = owned_reason } }) catch {};
+            return self.sendHttpError(client, 204, "No Content");
+        } else {
+            return self.sendHttpError(client, 404, "Not Found");
+        }
+
+        try self.sendJson(client, 200, body.items);
+    }
+
+    /// Answer /healthz or /readyz with the listener state, client count and event
+    /// queue depth. Not ready (503) while shutting down or refusing new clients.
+    fn handleProbe(self: *WebSocketServer, client: *WebSocketClient, readiness: bool) !void {
+        const totals = self.stats();
+        const listening = self.is_running and self.listener != null;
+        const accepting = listening and self.drain_until_ms == 0 and
+            std.time.milliTimestamp() >= self.accept_paused_until_ms and
+            // The probe's own connection does not count
+            (self.max_connections == 0 or totals.connections - 1 < self.max_connections);
+        const ok = if (readiness) accepting else listening;
+
+        var buf: [256]u8 = undefined;
+        const body = try std.fmt.bufPrint(&buf, "{{\"status\":\"{s}\",\"listening\":{},\"accepting\":{},\"clients\":{d},\"queuedEvents\":{d}}}", .{
+            if (ok) "ok" else "unavailable",
+            listening,
+            accepting,
+            totals.clients,
+            totals.queued_events,
+        });
+        try self.sendJson(client, if (ok) 200 else 503, body);
+    }
+
+    /// Server-wide counters as a JSON object
+    fn writeStatsJson(self: *WebSocketServer, w: anytype) !void {
+        const totals = self.stats();
+        try w.print("{{\"clients\":{d},\"connections\":{d},\"rooms\":{d},\"queuedEvents\":{d},\"uptimeMs\":{d}," ++
+            "\"bytesIn\":{d},\"bytesOut\":{d},\"messagesIn\":{d},\"messagesOut\":{d}}}", .{
+            totals.clients,
+            totals.connections,
+            totals.rooms,
+            totals.queued_events,
+            totals.uptime_ms,
+            totals.bytes_in,
+            totals.bytes_out,
+            totals.messages_in,
+            totals.messages_out,
+        });
+    }
+
+    /// Aggregate counters; the field order matches the Roc record for stats!
+    fn stats(self: *const WebSocketServer) ServerStats {
+        var connected: u64 = 0;
+        var it = self.clients.valueIterator();
+        while (it.next()) |other| {
+            if (other.is_websocket and !other.is_closed) connected += 1;
+        }
+        return .{
+            .bytes_in = self.traffic.bytes_in,
+            .bytes_out = self.traffic.bytes_out,
+            .clients = connected,
+            .connections = self.clients.count(),
+            .messages_in = self.traffic.messages_in,
+            .messages_out = self.traffic.messages_out,
+            .queued_events = self.event_queue.items.len,
+            .rooms = self.rooms.count(),
+            .uptime_ms = @intCast(@max(std.time.milliTimestamp() - self.started_ms, 0)),
+        };
+    }
+
+    fn findSseClient(self: *WebSocketServer, token: []const u8) ?*WebSocketClient {
+        if (token.len != 32) return null;
+        var it = self.clients.valueIterator();
+        while (it.next()) |candidate| {
+            if (!candidate.is_sse or candidate.is_closed) continue;
+            if (std.crypto.timing_safe.eql([32]u8, candidate.sse_token, token[0..32].*)) return candidate;
+        }
+        return null;
+    }
+
+    /// Sec-WebSocket-Accept value for a handshake key
+    fn computeAcceptKey(key: []const u8) [28]u8 {
+        const magic = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+        var hasher = std.crypto.hash.Sha1.init(.{});
+        hasher.update(key);
+        hasher.update(magic);
+        const hash = hasher.finalResult();
+
+        var accept_key: [28]u8 = undefined;
+        _ = std.base64.standard.Encoder.encode(&accept_key, &hash);
+        return accept_key;
+    }
+
+    /// Validate an upgrade request, returning the Sec-WebSocket-Accept value,
+    /// or null after answering with an error status
+    fn handleWebSocketUpgrade(self: *WebSocketServer, client: *WebSocketClient, request: *const HttpRequest) !?[28]u8 {
+        const key = request.header("Sec-WebSocket-Key") orelse {
+            try self.sendHttpError(client, 400, "Bad Request");
+            return null;
+        };
+        const version = request.header("Sec-WebSocket-Version") orelse "";
+        if (!std.mem.eql(u8, version, "13")) {
+            try self.sendHttpError(client, 426, "Upgrade Required");
+            return null;
+        }
+
+        if (request.header("Sec-WebSocket-Protocol")) |offered| {
+            if (self.envelope_protocol and offersProtocol(offered, msgpack_protocol)) {
+                try client.setData(self.allocator, "protocol", msgpack_protocol);
+                client.msgpack = true;
+            } else if (self.selectSubprotocol(offered)) |protocol| {
+                try client.setData(self.allocator, "protocol", protocol);
+                if (std.mem.eql(u8, protocol, graphql_protocol)) client.graphql = .{};
+                if (std.mem.eql(u8, protocol, stomp_protocol)) client.stomp = .{};
+            } else {
+                // Without an echo the client decides whether to carry on
+                logger.debug(client.id, "No supported subprotocol in \"{s}\"", .{offered});
+            }
+        }
+
+        return computeAcceptKey(key);
+    }
+
+    /// The most preferred of our subprotocols that the client offered
+    fn selectSubprotocol(self: *const WebSocketServer, offered: []const u8) ?[]const u8 {
+        for (self.subprotocols.items) |protocol| {
+            if (offersProtocol(offered, protocol)) return protocol;
+        }
+        return null;
+    }
+
+    fn offersProtocol(offered: []const u8, protocol: []const u8) bool {
+        var it = std.mem.splitScalar(u8, offered, ',');
+        while (it.next()) |candidate| {
+            if (std.mem.eql(u8, std.mem.trim(u8, candidate, " \t"), protocol)) return true;
+        }
+        return false;
+    }
+
+    fn addSubprotocol(self: *WebSocketServer, protocol: []const u8) !void {
+        if (protocol.len == 0 or !isHttpToken(protocol)) return error.InvalidSubprotocol;
+        for (self.subprotocols.items) |existing| {
+            if (std.mem.eql(u8, existing, protocol)) return;
+        }
+        const owned = try self.allocator.dupe(u8, protocol);
+        errdefer self.allocator.free(owned);
+        try self.subprotocols.append(self.allocator, owned);
+    }
+
+    /// Send 101 Switching Protocols; the client is a WebSocket from here on
+    fn completeHandshake(self: *WebSocketServer, client: *WebSocketClient, accept_key: *const [28]u8) !void {
+        const response = "HTTP/1.1 101 Switching Protocols\r\n" ++
+            "Upgrade: websocket\r\n" ++
+            "Connection: Upgrade\r\n" ++
+            "Sec-WebSocket-Accept: ";
+
+        try self.queueBytes(client, response);
+        try self.queueBytes(client, accept_key);
+        if (client.data.get("protocol")) |protocol| {
+            try self.queueBytes(client, "\r\nSec-WebSocket-Protocol: ");
+            try self.queueBytes(client, protocol);
+        }
+        try self.queueBytes(client, "\r\n\r\n");
+
+        client.is_websocket = true;
+        client.handshake_pending = false;
+        logger.info(client.id, "WebSocket handshake completed", .{});
+    }
+
+    /// With an auth secret set, require a valid token and record its subject as the
+    /// client's "user" attribute. Answers 401 and returns false on failure.
+    fn authenticate(self: *WebSocketServer, client: *WebSocketClient, request: *const HttpRequest) !bool {
+        // A valid session cookie stands in for a token
+        if (try self.restoreSession(client, request)) return true;
+
+        const secret = self.auth_secret orelse return true;
+        const token = requestToken(request) orelse {
+            try self.sendHttpError(client, 401, "Unauthorized");
+            return false;
+        };
+        const user = verifyJwt(self.allocator, secret, token, std.time.timestamp()) catch |err| {
+            logger.warn(client.id, "Authentication failed: {s}", .{@errorName(err)});
+            try self.sendHttpError(client, 401, "Unauthorized");
+            return false;
+        };
+        defer self.allocator.free(user);
+        try client.setData(self.allocator, "user", user);
+        logger.info(client.id, "Authenticated as {s}", .{user});
+        return true;
+    }
+
+    /// Attach the request's session cookie, if valid, as the client's "session" and
+    /// "user" attributes
+    fn restoreSession(self: *WebSocketServer, client: *WebSocketClient, request: *const HttpRequest) !bool {
+        const cookie = requestCookie(request, session_cookie_name) orelse return false;
+        const secret = self.session_secret orelse return false;
+        const session = verifySessionCookie(self.allocator, secret, cookie, std.time.timestamp()) catch |err| {
+            logger.debug(client.id, "Ignoring session cookie: {s}", .{@errorName(err)});
+            return false;
+        };
+        defer self.allocator.free(session.user);
+
+        try client.setData(self.allocator, "session", session.session_id);
+        try client.setData(self.allocator, "user", session.user);
+        logger.info(client.id, "Resumed session for {s}", .{session.user});
+        return true;
+    }
+
+    /// Set-Cookie value for a new session; the app sends it from its login endpoint
+    fn createSession(self: *WebSocketServer, user: []const u8, ttl_secs: u64) ![]u8 {
+        if (user.len == 0) return error.EmptyUser;
+        if (self.session_secret == null) {
+            var key: [32]u8 = undefined;
+            std.crypto.random.bytes(&key);
+            self.session_secret = try self.allocator.dupe(u8, &key);
+        }
+        return createSessionCookie(self.allocator, self.session_secret.?, user, ttl_secs, std.time.timestamp(), self.base_path orelse "");
+    }
+
+    fn setSessionSecret(self: *WebSocketServer, secret: []const u8) !void {
+        if (secret.len < 16) return error.SecretTooShort;
+        const owned = try self.allocator.dupe(u8, secret);
+        if (self.session_secret) |old| self.allocator.free(old);
+        self.session_secret = owned;
+    }
+
+    fn setHookSecret(self: *WebSocketServer, secret: []const u8) !void {
+        const owned: ?[]const u8 = if (secret.len > 0) try self.allocator.dupe(u8, secret) else null;
+        if (self.hook_secret) |old| self.allocator.free(old);
+        self.hook_secret = owned;
+    }
+
+    fn setAdminToken(self: *WebSocketServer, token: []const u8) !void {
+        const owned: ?[]const u8 = if (token.len > 0) try self.allocator.dupe(u8, token) else null;
+        if (self.admin_token) |old| self.allocator.free(old);
+        self.admin_token = owned;
+    }
+
+    fn setAuthSecret(self: *WebSocketServer, secret: []const u8) !void {
+        const owned: ?[]const u8 = if (secret.len > 0) try self.allocator.dupe(u8, secret) else null;
+        if (self.auth_secret) |old| self.allocator.free(old);
+        self.auth_secret = owned;
+    }
+
+    /// Requests without an Origin header (non-browser clients) are always allowed
+    fn isOriginAllowed(self: *const WebSocketServer, request: *const HttpRequest) bool {
+        if (self.allowed_origins.items.len == 0) return true;
+        const origin = request.header("Origin") orelse return true;
+        for (self.allowed_origins.items) |allowed| {
+            if (std.ascii.eqlIgnoreCase(allowed, origin)) return true;
+        }
+        return false;
+    }
+
+    fn allowOrigin(self: *WebSocketServer, origin: []const u8) !void {
+        const trimmed = std.mem.trimRight(u8, origin, "/");
+        if (std.mem.indexOf(u8, trimmed, "://") == null) return error.InvalidOrigin;
+        for (self.allowed_origins.items) |allowed| {
+            if (std.ascii.eqlIgnoreCase(allowed, trimmed)) return;
+        }
+        const owned = try self.allocator.dupe(u8, trimmed);
+        errdefer self.allocator.free(owned);
+        try self.allowed_origins.append(self.allocator, owned);
+    }
+
+    /// Complete a handshake held for the application; Connected is queued
+    fn acceptHandshake(self: *WebSocketServer, client_id: u64) !void {
+        const client = self.clients.getPtr(client_id) orelse return error.ClientNotFound;
+        if (!client.handshake_pending) return error.NoPendingHandshake;
+        self.completeHandshake(client, &client.handshake_accept) catch |err| {
+            self.removeClient(client_id);
+            return err;
+        };
+        client.last_activity_ms = std.time.milliTimestamp();
+        self.issueResumeToken(client);
+        self.openSocketio(client);
+        self.replayJoinedRooms(client);
+        try self.queueEvent(.{ .connected = client_id });
+    }
+
+    /// Refuse a handshake held for the application with an HTTP error status
+    fn rejectHandshake(self: *WebSocketServer, client_id: u64, status: u16) !void {
+        const client = self.clients.getPtr(client_id) orelse return error.ClientNotFound;
+        if (!client.handshake_pending) return error.NoPendingHandshake;
+        if (status < 400 or status > 599) return error.InvalidStatus;
+        defer self.finishHttp(client_id);
+        const reason = httpReasonPhrase(status);
+        try self.sendHttpError(client, status, if (reason.len > 0) reason else "Rejected");
+    }
+
+    fn handleHttpRequest(self: *WebSocketServer, client: *WebSocketClient, request: *const HttpRequest) !void {
+        if (!std.mem.eql(u8, request.method, "GET") and !std.mem.eql(u8, request.method, "HEAD")) {
+            try self.sendErrorPage(client, request, 405, "Method Not Allowed");
+            return;
+        }
+
+        var rel_buf: [512]u8 = undefined;
+        var path = sanitizeRequestPath(&rel_buf, request.path) catch {
+            try self.sendErrorPage(client, request, 400, "Bad Request");
+            return;
+        };
+        if (embedded_static.files.len > 0) {
+            return self.serveEmbeddedFile(client, request, path);
+        }
+
+        // Serve static file, resolving symlinks so nothing outside the root is reachable
+        const static_dir = self.staticDirFor(request);
+        var root_buf: [std.fs.max_path_bytes]u8 = undefined;
+        const root = std.fs.cwd().realpath(static_dir, &root_buf) catch {
+            try self.sendErrorPage(client, request, 404, "Not Found");
+            return;
+        };
+        var root_dir = std.fs.cwd().openDir(root, .{}) catch {
+            try self.sendErrorPage(client, request, 404, "Not Found");
+            return;
+        };
+        defer root_dir.close();
+
+        var index_rel_buf: [600]u8 = undefined;
+        var file_path_buf: [std.fs.max_path_bytes]u8 = undefined;
+        const file_path = self.resolveRequestFile(root_dir, root, &path, &index_rel_buf, &file_path_buf) catch |err| {
+            if (err == error.IsDir and self.directory_listing) {
+                return self.sendDirectoryListing(client, request, root_dir, path);
+            }
+            if (err == error.Forbidden) {
+                try self.sendErrorPage(client, request, 403, "Forbidden");
+            } else {
+                try self.sendErrorPage(client, request, 404, "Not Found");
+            }
+            return;
+        };
+
+        const file = std.fs.cwd().openFile(file_path, .{}) catch {
+            try self.sendErrorPage(client, request, 404, "Not Found");
+            return;
+        };
+        defer file.close();
+
+        const stat = file.stat() catch {
+            try self.sendErrorPage(client, request, 500, "Internal Server Error");
+            return;
+        };
+        const mtime_secs: i64 = @intCast(@divFloor(stat.mtime, std.time.ns_per_s));
+        const content_type = self.mimeTypeFor(path);
+
+        // Prefer a pre-compressed sidecar (app.js.br, app.js.gz) the client accepts
+        var encoding: ?[]const u8 = null;
+        var body_file = file;
+        var body_path = file_path;
+        var sidecar_path_buf: [std.fs.max_path_bytes]u8 = undefined;
+        defer if (encoding != null) body_file.close();
+        if (stat.size >= min_compress_size and isCompressibleMime(content_type)) {
+            const accept_encoding = request.header("Accept-Encoding") orelse "";
+            for (compressed_sidecars) |sidecar| {
+                if (!acceptsEncoding(accept_encoding, sidecar.coding)) continue;
+                var rel_sidecar_buf: [520]u8 = undefined;
+                const rel_sidecar = std.fmt.bufPrint(&rel_sidecar_buf, "{s}{s}", .{ path, sidecar.suffix }) catch continue;
+                const sidecar_path = resolveStaticPath(root_dir, root, rel_sidecar, &sidecar_path_buf) catch continue;
+                body_file = std.fs.cwd().openFile(sidecar_path, .{}) catch continue;
+                body_path = sidecar_path;
+                encoding = sidecar.coding;
+                break;
+            }
+        }
+
+        // Validators: size and mtime identify a version of the file, per encoding
+        var etag_buf: [64]u8 = undefined;
+        const etag = std.fmt.bufPrint(&etag_buf, "\"{x}-{x}{s}{s}\"", .{
+            stat.size,
+            @as(u64, @intCast(@max(@divFloor(stat.mtime, std.time.ns_per_ms), 0))),
+            if (encoding != null) "-" else "",
+            encoding orelse "",
+        }) catch unreachable;
+        var date_buf: [29]u8 = undefined;
+        const last_modified = formatHttpDate(&date_buf, mtime_secs);
+
+        if (isNotModified(request, etag, mtime_secs)) {
+            try self.sendNotModified(client, request, etag, last_modified);
+            return;
+        }
+
+        // Beyond the buffer limit the file is written from disk as the socket drains
+        const body_size = if (encoding != null) (body_file.stat() catch {
+            try self.sendErrorPage(client, request, 500, "Internal Server Error");
+            return;
+        }).size else stat.size;
+        if (body_size > self.static_buffer_limit) {
+            return self.streamStaticFile(client, request, content_type, std.fs.cwd(), body_path, encoding, etag, last_modified);
+        }
+
+        const loaded = self.loadStaticFile(body_path, body_file) catch {
+            try self.sendErrorPage(client, request, 500, "Internal Server Error");
+            return;
+        };
+        defer if (loaded.owned) self.allocator.free(loaded.content);
+
+        try self.sendStaticFile(client, request, content_type, loaded.content, encoding, etag, last_modified);
+    }
+
+    /// Map a sanitized request path to a file under the static root. Directories
+    /// resolve to their index file; with SPA fallback, a missing path without an
+    /// extension resolves to the root index. `path` is updated to the file served.
+    fn resolveRequestFile(self: *WebSocketServer, root_dir: std.fs.Dir, root: []const u8, path: *[]const u8, index_rel_buf: []u8, out_buf: []u8) ![]const u8 {
+        if (path.*.len == 0) return self.resolveIndex(root_dir, root, path, index_rel_buf, out_buf);
+        const resolved = resolveStaticPath(root_dir, root, path.*, out_buf) catch |err| {
+            if (err == error.FileNotFound and self.isClientRoute(path.*)) {
+                path.* = "";
+                return self.resolveIndex(root_dir, root, path, index_rel_buf, out_buf) catch |index_err| switch (index_err) {
+                    error.IsDir => error.FileNotFound,
+                    else => index_err,
+                };
+            }
+            return err;
+        };
+
+        const stat = std.fs.cwd().statFile(resolved) catch return resolved;
+        if (stat.kind != .directory) return resolved;
+        return self.resolveIndex(root_dir, root, path, index_rel_buf, out_buf);
+    }
+
+    /// Resolve the first index file present in directory `path` ("" for the root)
+    /// and point `path` at it. Fails with error.IsDir when there is none.
+    fn resolveIndex(self: *WebSocketServer, root_dir: std.fs.Dir, root: []const u8, path: *[]const u8, index_rel_buf: []u8, out_buf: []u8) ![]const u8 {
+        var names = indexCandidates(self.index_file orelse default_index_files);
+        while (names.next()) |name| {
+            const rel = if (path.*.len == 0)
+                name
+            else
+                std.fmt.bufPrint(index_rel_buf, "{s}/{s}", .{ path.*, name }) catch return error.NameTooLong;
+            const resolved = resolveStaticPath(root_dir, root, rel, out_buf) catch |err| switch (err) {
+                error.FileNotFound => continue,
+                else => return err,
+            };
+            path.* = rel;
+            return resolved;
+        }
+        return error.IsDir;
+    }
+
+    /// First index file compiled in for directory `dir` ("" for the root)
+    fn findEmbeddedIndex(self: *const WebSocketServer, dir: []const u8, buf: []u8) ?struct { path: []const u8, content: []const u8 } {
+        var names = indexCandidates(self.index_file orelse default_index_files);
+        while (names.next()) |name| {
+            const path = if (dir.len == 0) name else std.fmt.bufPrint(buf, "{s}/{s}", .{ dir, name }) catch return null;
+            if (findEmbeddedFile(path)) |content| return .{ .path = path, .content = content };
+        }
+        return null;
+    }
+
+    /// Answer a directory without an index with an HTML list of its entries (dot
+    /// files left out), first redirecting to the slash-terminated URL so the
+    /// relative links resolve
+    fn sendDirectoryListing(self: *WebSocketServer, client: *WebSocketClient, request: *const HttpRequest, root_dir: std.fs.Dir, path: []const u8) !void {
+        if (!std.mem.endsWith(u8, request.path, "/")) {
+            var location_buf: [1024]u8 = undefined;
+            var location = std.io.fixedBufferStream(&location_buf);
+            self.writePublicUrl(location.writer(), client, request, request.path) catch {
+                return self.sendErrorPage(client, request, 414, "URI Too Long");
+            };
+            var buf: [1280]u8 = undefined;
+            const response = std.fmt.bufPrint(&buf, "HTTP/1.1 301 Moved Permanently\r\nLocation: {s}/{s}{s}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", .{
+                location.getWritten(),
+                if (request.query.len > 0) "?" else "",
+                request.query,
+            }) catch return self.sendErrorPage(client, request, 414, "URI Too Long");
+            try self.queueBytes(client, response);
+            logger.info(client.id, "{s} {s} 301", .{ request.method, request.target });
+            return;
+        }
+
+        var dir = root_dir.openDir(if (path.len == 0) "." else path, .{ .iterate = true }) catch {
+            return self.sendErrorPage(client, request, 404, "Not Found");
+        };
+        defer dir.close();
+
+        var names = std.ArrayListUnmanaged([]const u8){};
+        defer {
+            for (names.items) |name| self.allocator.free(name);
+            names.deinit(self.allocator);
+        }
+        var it = dir.iterate();
+        while (try it.next()) |entry| {
+            if (entry.name[0] == '.') continue;
+            // Directories keep a trailing slash, which also sorts them apart from files
+            const is_dir = entry.kind == .directory or
+                (entry.kind == .sym_link and (dir.statFile(entry.name) catch continue).kind == .directory);
+            const name = try std.fmt.allocPrint(self.allocator, "{s}{s}", .{ entry.name, if (is_dir) "/" else "" });
+            errdefer self.allocator.free(name);
+            try names.append(self.allocator, name);
+        }
+        std.mem.sort([]const u8, names.items, {}, struct {
+            fn lessThan(_: void, a: []const u8, b: []const u8) bool {
+                return std.mem.order(u8, a, b) == .lt;
+            }
+        }.lessThan);
+
+        var body = std.ArrayListUnmanaged(u8){};
+        defer body.deinit(self.allocator);
+        const w = body.writer(self.allocator);
+        try w.writeAll("<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Index of /");
+        try writeHtmlEscaped(w, path);
+        if (path.len > 0) try w.writeByte('/');
+        try w.writeAll("</title></head>\n<body><h1>Index of /");
+        try writeHtmlEscaped(w, path);
+        if (path.len > 0) try w.writeByte('/');
+        try w.writeAll("</h1>\n<ul>\n");
+        if (path.len > 0) try w.writeAll("<li><a href=\"../\">../</a></li>\n");
+        for (names.items) |name| {
+            const is_dir = std.mem.endsWith(u8, name, "/");
+            try w.writeAll("<li><a href=\"");
+            try writePathSegment(w, if (is_dir) name[0 .. name.len - 1] else name);
+            if (is_dir) try w.writeByte('/');
+            try w.writeAll("\">");
+            try writeHtmlEscaped(w, name);
+            try w.writeAll("</a></li>\n");
+        }
+        try w.writeAll("</ul></body></html>\n");
+
+        var header_buf: [256]u8 = undefined;
+        const header = std.fmt.bufPrint(&header_buf, "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {d}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n", .{body.items.len}) catch unreachable;
+        try self.queueBytes(client, header);
+        if (!std.mem.eql(u8, request.method, "HEAD")) try self.queueBytes(client, body.items);
+        logger.info(client.id, "{s} {s} 200 listing, {d} entries", .{ request.method, request.target, names.items.len });
+    }
+
+    /// Whether a missing path should fall back to the index under SPA routing
+    fn isClientRoute(self: *const WebSocketServer, path: []const u8) bool {
+        return self.spa_fallback and std.mem.indexOfScalar(u8, std.fs.path.basename(path), '.') == null;
+    }
+
+    /// Serve a file compiled into the host with -Dembed-static
+    fn serveEmbeddedFile(self: *WebSocketServer, client: *WebSocketClient, request: *const HttpRequest, requested: []const u8) !void {
+        var path = requested;
+        var index_rel_buf: [600]u8 = undefined;
+        const original = (if (path.len > 0) findEmbeddedFile(path) else null) orelse blk: {
+            // A directory (or the root): serve its index
+            if (self.findEmbeddedIndex(path, &index_rel_buf)) |index| {
+                path = index.path;
+                break :blk index.content;
+            }
+            if (self.isClientRoute(path)) {
+                if (self.findEmbeddedIndex("", &index_rel_buf)) |index| {
+                    path = index.path;
+                    break :blk index.content;
+                }
+            }
+            try self.sendErrorPage(client, request, 404, "Not Found");
+            return;
+        };
+        const content_type = self.mimeTypeFor(path);
+
+        var content = original;
+        var encoding: ?[]const u8 = null;
+        if (original.len >= min_compress_size and isCompressibleMime(content_type)) {
+            const accept_encoding = request.header("Accept-Encoding") orelse "";
+            for (compressed_sidecars) |sidecar| {
+                if (!acceptsEncoding(accept_encoding, sidecar.coding)) continue;
+                var sidecar_buf: [520]u8 = undefined;
+                const sidecar_path = std.fmt.bufPrint(&sidecar_buf, "{s}{s}", .{ path, sidecar.suffix }) catch continue;
+                content = findEmbeddedFile(sidecar_path) orelse continue;
+                encoding = sidecar.coding;
+                break;
+            }
+        }
+
+        // Embedded files never change, so a content hash is a stable validator
+        var etag_buf: [48]u8 = undefined;
+        const etag = std.fmt.bufPrint(&etag_buf, "\"{x}{s}{s}\"", .{
+            std.hash.Wyhash.hash(0, original),
+            if (encoding != null) "-" else "",
+            encoding orelse "",
+        }) catch unreachable;
+
+        if (isNotModified(request, etag, null)) {
+            try self.sendNotModified(client, request, etag, null);
+            return;
+        }
+        try self.sendStaticFile(client, request, content_type, content, encoding, etag, null);
+    }
+
+    fn sendNotModified(self: *WebSocketServer, client: *WebSocketClient, request: *const HttpRequest, etag: []const u8, last_modified: ?[]const u8) !void {
+        var last_modified_buf: [64]u8 = undefined;
+        const last_modified_header = if (last_modified) |date|
+            std.fmt.bufPrint(&last_modified_buf, "Last-Modified: {s}\r\n", .{date}) catch unreachable
+        else
+            "";
+
+        var buf: [512]u8 = undefined;
+        const response = std.fmt.bufPrint(&buf, "HTTP/1.1 304 Not Modified\r\nETag: {s}\r\n{s}Cache-Control: {s}\r\nVary: Accept-Encoding\r\nConnection: close\r\n\r\n", .{ etag, last_modified_header, self.cache_control orelse "no-cache" }) catch return;
+        try self.queueBytes(client, response);
+        logger.info(client.id, "{s} {s} 304", .{ request.method, request.target });
+    }
+
+    /// Answer a static request with an error status and, when there is one, a page
+    /// for it: the app's (set_error_page!), else `<code>.html` or `40x.html`/`50x.html`
+    /// from the static root. Without a page the body is empty, as with sendHttpError.
+    fn sendErrorPage(self: *WebSocketServer, client: *WebSocketClient, request: *const HttpRequest, code: u16, message: []const u8) !void {
+        const registered = self.error_pages.get(code);
+        const loaded = if (registered == null) self.loadErrorPage(request, code) else null;
+        defer if (loaded) |content| self.allocator.free(content);
+        const page = registered orelse loaded orelse return self.sendHttpError(client, code, message);
+
+        var header_buf: [256]u8 = undefined;
+        const header = std.fmt.bufPrint(&header_buf, "HTTP/1.1 {d} {s}\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {d}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n", .{ code, message, page.len }) catch return;
+        try self.queueBytes(client, header);
+        if (!std.mem.eql(u8, request.method, "HEAD")) try self.queueBytes(client, page);
+        logger.info(client.id, "HTTP {d} {s} (error page)", .{ code, message });
+    }
+
+    /// Read the static root's page for an error status, caller frees
+    fn loadErrorPage(self: *WebSocketServer, request: *const HttpRequest, code: u16) ?[]u8 {
+        var exact_buf: [16]u8 = undefined;
+        var family_buf: [16]u8 = undefined;
+        const names = [_][]const u8{
+            std.fmt.bufPrint(&exact_buf, "{d}.html", .{code}) catch unreachable,
+            std.fmt.bufPrint(&family_buf, "{d}0x.html", .{code / 100}) catch unreachable,
+        };
+
+        if (embedded_static.files.len > 0) {
+            for (names) |name| {
+                const content = findEmbeddedFile(name) orelse continue;
+                return self.allocator.dupe(u8, content) catch null;
+            }
+            return null;
+        }
+
+        var root_buf: [std.fs.max_path_bytes]u8 = undefined;
+        const root = std.fs.cwd().realpath(self.staticDirFor(request), &root_buf) catch return null;
+        var root_dir = std.fs.cwd().openDir(root, .{}) catch return null;
+        defer root_dir.close();
+        for (names) |name| {
+            var path_buf: [std.fs.max_path_bytes]u8 = undefined;
+            const file_path = resolveStaticPath(root_dir, root, name, &path_buf) catch continue;
+            const file = std.fs.cwd().openFile(file_path, .{}) catch continue;
+            defer file.close();
+            return file.readToEndAlloc(self.allocator, self.static_buffer_limit) catch continue;
+        }
+        return null;
+    }
+
+    /// Serve `content` as the page for an error status; empty removes it
+    fn setErrorPage(self: *WebSocketServer, code: u16, content: []const u8) !void {
+        if (code < 400 or code > 599) return error.InvalidStatus;
+        if (content.len == 0) {
+            if (self.error_pages.fetchRemove(code)) |kv| self.allocator.free(kv.value);
+            return;
+        }
+        const owned = try self.allocator.dupe(u8, content);
+        errdefer self.allocator.free(owned);
+        const entry = try self.error_pages.getOrPut(self.allocator, code);
+        if (entry.found_existing) self.allocator.free(entry.value_ptr.*);
+        entry.value_ptr.* = owned;
+    }
+
+    /// Write a 200 response for static content (headers only for HEAD), or 206
+    /// with the part a GET's Range header asks for
+    fn sendStaticFile(
+        self: *WebSocketServer,
+        client: *WebSocketClient,
+        request: *const HttpRequest,
+        content_type: []const u8,
+        content: []const u8,
+        encoding: ?[]const u8,
+        etag: []const u8,
+        last_modified: ?[]const u8,
+    ) !void {
+        const part = try self.writeStaticHead(client, request, content_type, content.len, encoding, etag, last_modified) orelse return;
+        try self.queueBytes(client, content[part.start..part.end]);
+    }
+
+    /// Like sendStaticFile for a file too big to buffer: the head goes out now and
+    /// the poll loop writes the body from disk as the socket drains
+    fn streamStaticFile(
+        self: *WebSocketServer,
+        client: *WebSocketClient,
+        request: *const HttpRequest,
+        content_type: []const u8,
+        dir: std.fs.Dir,
+        sub_path: []const u8,
+        encoding: ?[]const u8,
+        etag: []const u8,
+        last_modified: ?[]const u8,
+    ) !void {
+        const file = try dir.openFile(sub_path, .{});
+        errdefer file.close();
+        const size = (try file.stat()).size;
+        const part = try self.writeStaticHead(client, request, content_type, @intCast(size), encoding, etag, last_modified) orelse {
+            file.close();
+            return;
+        };
+        if (part.start == part.end) {
+            file.close();
+            return;
+        }
+        try self.startDownload(client, file, part.start, part.end);
+    }
+
+    /// Write the head of a static response for `size` bytes of content: 200, 206
+    /// for the part a GET's Range header asks for, or 416. Returns the span the
+    /// body carries, null when there is none (HEAD and 416).
+    fn writeStaticHead(
+        self: *WebSocketServer,
+        client: *WebSocketClient,
+        request: *const HttpRequest,
+        content_type: []const u8,
+        size: usize,
+        encoding: ?[]const u8,
+        etag: []const u8,
+        last_modified: ?[]const u8,
+    ) !?struct { start: usize, end: usize } {
+        const charset = if (isTextMime(content_type)) "; charset=utf-8" else "";
+
+        var encoding_buf: [48]u8 = undefined;
+        const encoding_header = if (encoding) |coding|
+            std.fmt.bufPrint(&encoding_buf, "Content-Encoding: {s}\r\n", .{coding}) catch unreachable
+        else
+            "";
+        var last_modified_buf: [64]u8 = undefined;
+        const last_modified_header = if (last_modified) |date|
+            std.fmt.bufPrint(&last_modified_buf, "Last-Modified: {s}\r\n", .{date}) catch unreachable
+        else
+            "";
+
+        // Ranges index the representation sent, so a compressed sidecar's own bytes
+        const range_header = request.header("Range");
+        const range = if (range_header != null and std.mem.eql(u8, request.method, "GET") and rangeApplies(request, etag, last_modified))
+            parseByteRange(range_header.?, size) catch {
+                var buf: [160]u8 = undefined;
+                const response = std.fmt.bufPrint(&buf, "HTTP/1.1 416 Range Not Satisfiable\r\nContent-Range: bytes */{d}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", .{size}) catch return null;
+                try self.queueBytes(client, response);
+                logger.info(client.id, "{s} {s} 416", .{ request.method, request.target });
+                return null;
+            }
+        else
+            null;
+        const start = if (range) |span| span.start else 0;
+        const end = if (range) |span| span.end + 1 else size;
+        var content_range_buf: [80]u8 = undefined;
+        const content_range_header = if (range) |span|
+            std.fmt.bufPrint(&content_range_buf, "Content-Range: bytes {d}-{d}/{d}\r\n", .{ span.start, span.end, size }) catch unreachable
+        else
+            "";
+        const status = if (range != null) "206 Partial Content" else "200 OK";
+
+        var header_buf: [1024]u8 = undefined;
+        const header = std.fmt.bufPrint(&header_buf, "HTTP/1.1 {s}\r\nContent-Type: {s}{s}\r\nContent-Length: {d}\r\n{s}{s}Accept-Ranges: bytes\r\nVary: Accept-Encoding\r\nETag: {s}\r\n{s}Cache-Control: {s}\r\nConnection: close\r\n\r\n", .{ status, content_type, charset, end - start, content_range_header, encoding_header, etag, last_modified_header, self.cache_control orelse "no-cache" }) catch return null;
+
+        try self.queueBytes(client, header);
+        logger.info(client.id, "{s} {s} {s} {d} bytes", .{ request.method, request.target, status[0..3], end - start });
+        if (std.mem.eql(u8, request.method, "HEAD")) return null;
+        return .{ .start = start, .end = end };
+    }
+
+    /// Hand the rest of a response body to the poll loop, which writes it with
+    /// sendfile as the socket drains. Takes the file.
+    fn startDownload(self: *WebSocketServer, client: *WebSocketClient, file: std.fs.File, start: u64, end: u64) !void {
+        _ = self;
+        // Nothing else is read from this connection, so it may as well not block
+        const flags = try std.posix.fcntl(client.stream.handle, std.posix.F.GETFL, 0);
+        _ = try std.posix.fcntl(client.stream.handle, std.posix.F.SETFL, flags | @as(usize, @as(u32, @bitCast(std.posix.O{ .NONBLOCK = true }))));
+        client.download = .{ .file = file, .offset = start, .end = end };
+        client.last_activity_ms = std.time.milliTimestamp();
+    }
+
+    /// Write more of a client's download without blocking. Returns false once it
+    /// is complete or the connection failed, either way done with the client.
+    fn pumpDownload(self: *WebSocketServer, client: *WebSocketClient) bool {
+        _ = self;
+        const download = &client.download.?;
+        var budget: u64 = download_chunk_size;
+        while (download.offset < download.end and budget > 0) {
+            const len = @min(download.end - download.offset, budget);
+            const n = std.posix.sendfile(client.stream.handle, download.file.handle, download.offset, len, &.{}, &.{}, 0) catch |err| switch (err) {
+                error.WouldBlock => break,
+                else => {
+                    logger.debug(client.id, "Download failed: {s}", .{@errorName(err)});
+                    return false;
+                },
+            };
+            // The file shrank underneath us
+            if (n == 0) return false;
+            download.offset += n;
+            budget -= n;
+            client.last_activity_ms = std.time.milliTimestamp();
+        }
+        return download.offset < download.end;
+    }
+
+    /// Read a static file, serving it from the in-memory cache when the cached copy is
+    /// still current. Returned content is owned by the caller only if `owned` is set.
+    fn loadStaticFile(self: *WebSocketServer, file_path: []const u8, file: std.fs.File) !struct { content: []const u8, owned: bool } {
+        const stat = try file.stat();
+
+        if (self.static_cache_limit > 0) {
+            if (self.static_cache.getPtr(file_path)) |entry| {
+                if (entry.mtime == stat.mtime and entry.size == stat.size) {
+                    return .{ .content = entry.content, .owned = false };
+                }
+                // Edited on disk since it was cached
+                self.evictStaticFile(file_path);
+            }
+        }
+
+        const content = try file.readToEndAlloc(self.allocator, self.static_buffer_limit);
+        if (self.static_cache_limit == 0 or self.static_cache_bytes + content.len > self.static_cache_limit) {
+            return .{ .content = content, .owned = true };
+        }
+
+        const key = self.allocator.dupe(u8, file_path) catch return .{ .content = content, .owned = true };
+        self.static_cache.put(self.allocator, key, .{ .content = content, .mtime = stat.mtime, .size = stat.size }) catch {
+            self.allocator.free(key);
+            return .{ .content = content, .owned = true };
+        };
+        self.static_cache_bytes += content.len;
+        return .{ .content = content, .owned = false };
+    }
+
+    fn evictStaticFile(self: *WebSocketServer, file_path: []const u8) void {
+        if (self.static_cache.fetchRemove(file_path)) |kv| {
+            self.static_cache_bytes -= kv.value.content.len;
+            self.allocator.free(kv.key);
+            self.allocator.free(kv.value.content);
+        }
+    }
+
+    fn clearStaticCache(self: *WebSocketServer) void {
+        var it = self.static_cache.iterator();
+        while (it.next()) |entry| {
+            self.allocator.free(entry.key_ptr.*);
+            self.allocator.free(entry.value_ptr.content);
+        }
+        self.static_cache.clearRetainingCapacity();
+        self.static_cache_bytes = 0;
+    }
+
+    /// Content-Type for a file path, by extension
+    fn mimeTypeFor(self: *const WebSocketServer, path: []const u8) []const u8 {
+        const base = std.fs.path.basename(path);
+        const dot = std.mem.lastIndexOfScalar(u8, base, '.') orelse return "application/octet-stream";
+        const ext = base[dot + 1 ..];
+
+        var lower_buf: [32]u8 = undefined;
+        if (ext.len > lower_buf.len) return "application/octet-stream";
+        const lower = std.ascii.lowerString(&lower_buf, ext);
+
+        if (self.mime_overrides.get(lower)) |mime| return mime;
+        for (mime_types) |entry| {
+            if (std.mem.eql(u8, entry.ext, lower)) return entry.mime;
+        }
+        return "application/octet-stream";
+    }
+
+    /// Register or replace the Content-Type served for an extension
+    fn setMimeType(self: *WebSocketServer, ext: []const u8, mime: []const u8) !void {
+        const trimmed = std.mem.trimLeft(u8, ext, ".");
+        if (trimmed.len == 0 or trimmed.len > 32) return error.InvalidExtension;
+        if (mime.len == 0 or std.mem.indexOfAny(u8, mime, "\r\n") != null) return error.InvalidMimeType;
+
+        const owned_mime = try self.allocator.dupe(u8, mime);
+        errdefer self.allocator.free(owned_mime);
+
+        var lower_buf: [32]u8 = undefined;
+        const lower = std.ascii.lowerString(&lower_buf, trimmed);
+        const gop = try self.mime_overrides.getOrPut(self.allocator, lower);
+        if (gop.found_existing) {
+            self.allocator.free(gop.value_ptr.*);
+        } else {
+            gop.key_ptr.* = self.allocator.dupe(u8, lower) catch |err| {
+                _ = self.mime_overrides.remove(lower);
+                return err;
+            };
+        }
+        gop.value_ptr.* = owned_mime;
+    }
+
+    fn isHttpRoute(self: *const WebSocketServer, path: []const u8) bool {
+        for (self.http_routes.items) |prefix| {
+            if (std.mem.startsWith(u8, path, prefix)) return true;
+        }
+        return false;
+    }
+
+    /// Room an upgrade to `path` joins ("" for none), or null if no endpoint accepts it
+    fn matchWsEndpoint(self: *const WebSocketServer, path: []const u8) ?[]const u8 {
+        if (self.ws_endpoints.items.len == 0) return "";
+        for (self.ws_endpoints.items) |endpoint| {
+            if (endpoint.match(path)) |room| return room;
+        }
+        return null;
+    }
+
+    /// Accept WebSocket upgrades on `pattern`, joining clients to `room`
+    fn addWsEndpoint(self: *WebSocketServer, pattern: []const u8, room: []const u8) !void {
+        if (pattern.len == 0 or pattern[0] != '/') return error.InvalidPath;
+        if (std.mem.eql(u8, room, "*") and !std.mem.endsWith(u8, pattern, "/*")) return error.InvalidRoom;
+
+        const owned_pattern = try self.allocator.dupe(u8, pattern);
+        errdefer self.allocator.free(owned_pattern);
+        const owned_room = try self.allocator.dupe(u8, room);
+        errdefer self.allocator.free(owned_room);
+        try self.ws_endpoints.append(self.allocator, .{ .pattern = owned_pattern, .room = owned_room });
+    }
+
+    /// Send requests for `method` ("" or "*" for any) whose path fits `pattern` to
+    /// `target`. Routes are tried in the order added; adding one again retargets it.
+    fn addRoute(self: *WebSocketServer, method: []const u8, pattern: []const u8, target: RouteTarget) !void {
+        try HttpRoute.validate(pattern);
+        const any_method = method.len == 0 or std.mem.eql(u8, method, "*");
+        if (!any_method and !isHttpToken(method)) return error.InvalidMethod;
+        for (self.routes.items) |*route| {
+            const same_method = if (route.method) |existing| !any_method and std.mem.eql(u8, existing, method) else any_method;
+            if (same_method and std.mem.eql(u8, route.pattern, pattern)) {
+                route.target = target;
+                return;
+            }
+        }
+
+        const owned_pattern = try self.allocator.dupe(u8, pattern);
+        errdefer self.allocator.free(owned_pattern);
+        const owned_method = if (any_method) null else try self.allocator.dupe(u8, method);
+        errdefer if (owned_method) |m| self.allocator.free(m);
+        try self.routes.append(self.allocator, .{ .method = owned_method, .pattern = owned_pattern, .target = target });
+    }
+
+    /// Where a request goes: the first route that fits it (its captures stored as
+    /// client data "param:<name>"), a route_http! prefix, or, while no routes are
+    /// defined, the static server. A path some route fits for other methods gets
+    /// error.MethodNotAllowed.
+    fn routeFor(self: *WebSocketServer, client: *WebSocketClient, request: *const HttpRequest) !RouteTarget {
+        var params: RouteParams = .{};
+        var path_matched = false;
+        for (self.routes.items) |route| {
+            if (!route.match(request.path, &params)) continue;
+            if (!route.allowsMethod(request.method)) {
+                path_matched = true;
+                continue;
+            }
+            for (params.names[0..params.len], params.values[0..params.len]) |name, value| {
+                var key_buf: [max_route_param_name + 8]u8 = undefined;
+                const key = std.fmt.bufPrint(&key_buf, "param:{s}", .{name}) catch unreachable;
+                try client.setData(self.allocator, key, value);
+            }
+            try client.setData(self.allocator, "route", route.pattern);
+            return route.target;
+        }
+        if (self.isHttpRoute(request.path)) return .app;
+        if (path_matched) return error.MethodNotAllowed;
+        return if (self.routes.items.len > 0) .not_found else .static;
+    }
+
+    /// Deliver requests under `prefix` to the application as HttpRequest events
+    fn addHttpRoute(self: *WebSocketServer, prefix: []const u8) !void {
+        if (prefix.len == 0 or prefix[0] != '/') return error.InvalidPrefix;
+        if (self.isHttpRoute(prefix)) return;
+        const owned = try self.allocator.dupe(u8, prefix);
+        errdefer self.allocator.free(owned);
+        try self.http_routes.append(self.allocator, owned);
+    }
+
+    /// Answer a request delivered as an HttpRequest event and close the connection
+    fn respond(self: *WebSocketServer, client_id: u64, status: u16, headers: []const HttpHeader, body: []const u8) !void {
+        const client = self.clients.getPtr(client_id) orelse return error.ClientNotFound;
+        if (!client.http_pending) return error.NoPendingRequest;
+        if (status < 100 or status > 999) return error.InvalidStatus;
+        defer self.finishHttp(client_id);
+
+        var head = std.ArrayListUnmanaged(u8){};
+        defer head.deinit(self.allocator);
+        const w = head.writer(self.allocator);
+
+        try w.print("HTTP/1.1 {d} {s}\r\n", .{ status, httpReasonPhrase(status) });
+        var has_length = false;
+        for (headers) |h| {
+            // Header injection guard
+            if (std.mem.indexOfAny(u8, h.name, ":\r\n ") != null or std.mem.indexOfAny(u8, h.value, "\r\n") != null) {
+                return error.InvalidHeader;
+            }
+            if (std.ascii.eqlIgnoreCase(h.name, "Connection")) continue;
+            if (std.ascii.eqlIgnoreCase(h.name, "Content-Length")) has_length = true;
+            try w.print("{s}: {s}", .{ h.name, h.value });
+            // Cookies set for a page the client loaded over HTTPS stay on HTTPS
+            if (client.via_https and std.ascii.eqlIgnoreCase(h.name, "Set-Cookie") and
+                std.ascii.indexOfIgnoreCase(h.value, "secure") == null)
+            {
+                try w.writeAll("; Secure");
+            }
+            try w.writeAll("\r\n");
+        }
+        if (!has_length) try w.print("Content-Length: {d}\r\n", .{body.len});
+        try w.writeAll("Connection: close\r\n\r\n");
+
+        try self.queueBytes(client, head.items);
+        if (body.len > 0) try self.queueBytes(client, body);
+        logger.info(client_id, "HTTP {d} {d} bytes (application)", .{ status, body.len });
+    }
+
+    /// Set the static root, index file names and SPA fallback
+    fn configure(self: *WebSocketServer, static_dir: []const u8, index_file: []const u8, spa_fallback: bool) !void {
+        var names = indexCandidates(index_file);
+        if (names.peek() == null) return error.InvalidIndexFile;
+        while (names.next()) |name| {
+            if (std.mem.indexOfAny(u8, name, "/\\") != null) return error.InvalidIndexFile;
+        }
+        if (embedded_static.files.len == 0) {
+            var dir = try std.fs.cwd().openDir(static_dir, .{});
+            dir.close();
+        }
+
+        const owned_dir = try self.allocator.dupe(u8, static_dir);
+        errdefer self.allocator.free(owned_dir);
+        const owned_index = try self.allocator.dupe(u8, index_file);
+
+        if (self.static_dir) |old| self.allocator.free(old);
+        if (self.index_file) |old| self.allocator.free(old);
+        self.static_dir = owned_dir;
+        self.index_file = owned_index;
+        self.spa_fallback = spa_fallback;
+        // Cached paths may belong to the old root
+        self.clearStaticCache();
+    }
+
+    fn setCacheControl(self: *WebSocketServer, value: []const u8) !void {
+        if (std.mem.indexOfAny(u8, value, "\r\n") != null) return error.InvalidHeaderValue;
+        const owned = try self.allocator.dupe(u8, value);
+        if (self.cache_control) |old| self.allocator.free(old);
+        self.cache_control = owned;
+    }
+
+    /// Answer a request that failed to parse
+    fn sendRequestError(self: *WebSocketServer, client: *WebSocketClient, err: anyerror) !void {
+        switch (err) {
+            error.UnsupportedVersion => try self.sendHttpError(client, 505, "HTTP Version Not Supported"),
+            error.UnsupportedTransferEncoding => try self.sendHttpError(client, 501, "Not Implemented"),
+            error.TooManyHeaders => try self.sendHttpError(client, 431, "Request Header Fields Too Large"),
+            error.BodyTooLarge => try self.sendHttpError(client, 413, "Content Too Large"),
+            else => try self.sendHttpError(client, 400, "Bad Request"),
+        }
+    }
+
+    /// Queue a complete JSON response; the connection is closed afterwards
+    fn sendJson(self: *WebSocketServer, client: *WebSocketClient, status: u16, body: []const u8) !void {
+        var head_buf: [160]u8 = undefined;
+        const head = try std.fmt.bufPrint(&head_buf, "HTTP/1.1 {d} {s}\r\nContent-Type: application/json\r\nCache-Control: no-store\r\nContent-Length: {d}\r\nConnection: close\r\n\r\n", .{ status, httpReasonPhrase(status), body.len });
+        try self.queueBytes(client, head);
+        try self.queueBytes(client, body);
+    }
+
+    fn sendHttpError(self: *WebSocketServer, client: *WebSocketClient, code: u16, message: []const u8) !void {
+        logger.info(client.id, "HTTP {d} {s}", .{ code, message });
+        var buf: [256]u8 = undefined;
+        const response = std.fmt.bufPrint(&buf, "HTTP/1.1 {d} {s}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", .{ code, message }) catch return;
+        try self.queueBytes(client, response);
+    }
+
+    /// Read whatever the socket has and decode the next frame from the client's
+    /// read buffer. Returns error.PartialMessage until a whole frame is buffered.
+    fn handleClientData(self: *WebSocketServer, client_id: u64) !WebSocketEvent {
+        const client = self.clients.getPtr(client_id) orelse return error.ClientNotFound;
+
+        if (!client.is_websocket) {
+            return error.NotWebSocket;
+        }
+
+        if (client.is_sse) {
+            // SSE streams are one-way; a read only tells us the browser went away
+            var scratch: [256]u8 = undefined;
+            if (try client.stream.read(&scratch) == 0) return error.ConnectionClosed;
+            return error.ControlFrame;
+        }
+
+        try client.read_buf.ensureUnusedCapacity(self.allocator, 4096);
+        const n = try client.stream.read(client.read_buf.unusedCapacitySlice());
+        if (n == 0) return error.ConnectionClosed;
+        client.read_buf.items.len += n;
+        client.last_activity_ms = std.time.milliTimestamp();
+        client.traffic.bytes_in += n;
+        self.traffic.bytes_in += n;
+
+        return self.nextFrame(client_id);
+    }
+
+    /// Whether the client's read buffer holds a frame ready for nextFrame,
+    /// which poll would not report since the bytes are already off the socket
+    fn hasBufferedFrame(self: *const WebSocketServer, client: *const WebSocketClient) bool {
+        if (!client.is_websocket or client.is_closed or client.is_sse or client.detached_until_ms != 0) return false;
+        const header = codec.parseHeader(client.read_buf.items) orelse return false;
+        // Oversize frames are reported without waiting for their payload
+        if (header.payload_len > self.max_frame_size) return true;
+        return client.read_buf.items.len - header.header_len >= header.payload_len;
+    }
+
+    /// Decode one frame from the front of the client's read buffer
+    fn nextFrame(self: *WebSocketServer, client_id: u64) !WebSocketEvent {
+        const client = self.clients.getPtr(client_id) orelse return error.ClientNotFound;
+        // Upstream connections read a server's frames, which must not be masked
+        const frame = try codec.decode(client.read_buf.items, .{
+            .masked = !client.is_upstream,
+            .max_payload = @min(self.max_frame_size, self.max_message_size),
+        }) orelse return error.PartialMessage;
+        const opcode = frame.opcode;
+        const frame_len = frame.len;
+        const payload = frame.payload;
+
+        // The payload slice is only valid until the frame is consumed, so each
+        // branch copies what it keeps before calling consumeFrame
+        switch (opcode) {
+            .text, .binary => {
+                // A new data frame may not start while a fragmented message is pending
+                if (client.fragment_opcode != null) return error.ProtocolError;
+                if (!frame.fin) {
+                    client.fragment_opcode = opcode;
+                    try self.appendFragment(client, payload);
+                    consumeFrame(client, frame_len);
+                    return error.PartialMessage;
+                }
+                const message = try self.allocator.dupe(u8, payload);
+                consumeFrame(client, frame_len);
+                return self.dataEvent(client, opcode, message);
+            },
+            .continuation => {
+                const fragment_opcode = client.fragment_opcode orelse return error.ProtocolError;
+                try self.appendFragment(client, payload);
+                consumeFrame(client, frame_len);
+                if (!frame.fin) return error.PartialMessage;
+
+                client.fragment_opcode = null;
+                const message = try client.fragment_buf.toOwnedSlice(self.allocator);
+                return self.dataEvent(client, fragment_opcode, message);
+            },
+            .close => {
+                var control_buf: [125]u8 = undefined;
+                const body = control_buf[0..payload.len];
+                @memcpy(body, payload);
+                consumeFrame(client, frame_len);
+
+                // Echo the peer's status code to complete the closing handshake
+                const close = try codec.parseClose(body);
+                const code = close.code orelse close_no_status;
+                var reason: []const u8 = "";
+                if (close.code != null) {
+                    reason = self.allocator.dupe(u8, close.reason) catch "";
+                    self.sendClose(client, code, "") catch {};
+                } else {
+                    self.sendFrame(client, .close, "") catch {};
+                }
+
+                logger.info(client_id, "Closed by peer ({d})", .{code});
+                client.is_closed = true;
+                self.removeClient(client_id);
+                return .{ .disconnected = .{ .client_id = client_id, .code = code, .reason = reason } };
+            },
+            .ping => {
+                var control_buf: [125]u8 = undefined;
+                const body = control_buf[0..payload.len];
+                @memcpy(body, payload);
+                consumeFrame(client, frame_len);
+
+                // Send pong
+                try self.sendFrame(client, .pong, body);
+                return error.ControlFrame;
+            },
+            .pong => {
+                consumeFrame(client, frame_len);
+                return error.ControlFrame;
+            },
+            _ => unreachable,
+        }
+    }
+
+    /// Drop a processed frame from the front of the client's read buffer
+    fn consumeFrame(client: *WebSocketClient, frame_len: usize) void {
+        const rest = client.read_buf.items.len - frame_len;
+        std.mem.copyForwards(u8, client.read_buf.items[0..rest], client.read_buf.items[frame_len..]);
+        client.read_buf.shrinkRetainingCapacity(rest);
+    }
+
+    /// Append a fragment's payload to the client's reassembly buffer
+    fn appendFragment(self: *WebSocketServer, client: *WebSocketClient, payload: []const u8) !void {
+        if (client.fragment_buf.items.len + payload.len > self.max_message_size) {
+            return error.PayloadTooLarge;
+        }
+        try client.fragment_buf.appendSlice(self.allocator, payload);
+    }
+
+    /// Close a client immediately with the given status and build its Disconnected event
+    fn dropClient(self: *WebSocketServer, client_id: u64, code: u16, reason: []const u8) WebSocketEvent {
+        logger.warn(client_id, "Dropping connection ({d} {s})", .{ code, reason });
+        if (self.clients.getPtr(client_id)) |client| {
+            self.sendClose(client, code, reason) catch {};
+        }
+        self.removeClient(client_id);
+        const owned_reason = self.allocator.dupe(u8, reason) catch "";
+        return .{ .disconnected = .{ .client_id = client_id, .code = code, .reason = owned_reason } };
+    }
+
+    /// Close a client that sent a frame or message over the size limits with 1009.
+    /// The app hears about it as an Error naming the client, then Disconnected.
+    fn dropOversizeClient(self: *WebSocketServer, client_id: u64) WebSocketEvent {
+        const event = self.errorEvent("Client #{d} exceeded the message size limit ({d} bytes per frame, {d} per message)", .{ client_id, self.max_frame_size, self.max_message_size });
+        const disconnected = self.dropClient(client_id, close_message_too_big, "Message too big");
+        self.queueEvent(disconnected) catch {};
+        return event;
+    }
+
+    /// Take a connection attempt from the address's allowance
+    fn allowConnectAttempt(self: *WebSocketServer, address: std.net.Address) bool {
+        if (self.connect_limit.rate == 0) return true;
+        const now = std.time.milliTimestamp();
+
+        if (self.connect_buckets.count() >= max_connect_buckets) self.pruneConnectBuckets(now);
+        const gop = self.connect_buckets.getOrPut(self.allocator, addressKey(address)) catch return true;
+        if (!gop.found_existing) gop.value_ptr.* = .{};
+        return gop.value_ptr.take(self.connect_limit, now);
+    }
+
+    /// Forget addresses whose allowance has refilled completely
+    fn pruneConnectBuckets(self: *WebSocketServer, now_ms: i64) void {
+        var full = std.ArrayListUnmanaged(u128){};
+        defer full.deinit(self.allocator);
+
+        var it = self.connect_buckets.iterator();
+        while (it.next()) |entry| {
+            entry.value_ptr.refill(self.connect_limit, now_ms);
+            if (entry.value_ptr.tokens >= @as(f64, @floatFromInt(@max(self.connect_limit.burst, 1)))) {
+                full.append(self.allocator, entry.key_ptr.*) catch break;
+            }
+        }
+        for (full.items) |key| _ = self.connect_buckets.remove(key);
+    }
+
+    /// Pass a data message through the client's rate limit. Over the limit, the
+    /// message is discarded and the client closed with 1008.
+    /// Under the envelope protocol, turn a text message into its typed event.
+    /// A malformed envelope is answered with an "error" envelope instead.
+    fn envelopeEvent(self: *WebSocketServer, event: WebSocketEvent) ?WebSocketEvent {
+        if (!self.envelope_protocol) return event;
+        const msg = switch (event) {
+            .message => |msg| msg,
+            else => return event,
+        };
+        defer self.allocator.free(msg.text);
+
+        if (Envelope.parse(self.allocator, msg.client_id, msg.text)) |parsed| {
+            var envelope = parsed;
+            // A virtual host's clients name rooms within its namespace
+            if (envelope.room.len > 0) {
+                if (self.clients.getPtr(msg.client_id)) |client| {
+                    if (client.data.get("vhost") != null) {
+                        var room_buf: [512]u8 = undefined;
+                        const room = clientRoom(client, envelope.room, &room_buf) catch envelope.room;
+                        if (room.ptr != envelope.room.ptr) {
+                            const owned = self.allocator.dupe(u8, room) catch {
+                                envelope.deinit(self.allocator);
+                                return null;
+                            };
+                            self.allocator.free(envelope.room);
+                            envelope.room = owned;
+                        }
+                    }
+                }
+            }
+            return .{ .envelope = envelope };
+        } else |err| {
+            logger.debug(msg.client_id, "Rejected envelope: {s}", .{@errorName(err)});
+            var buf: [128]u8 = undefined;
+            var reply = std.io.fixedBufferStream(&buf);
+            writeEnvelope(reply.writer(), "error", "", "", @errorName(err)) catch return null;
+            self.send(msg.client_id, reply.getWritten()) catch {};
+            return null;
+        }
+    }
+
+    /// Send the Engine.IO open packet to a client that connected on /socket.io/
+    fn openSocketio(self: *WebSocketServer, client: *WebSocketClient) void {
+        if (!client.is_socketio) return;
+        var sid: [16]u8 = undefined;
+        std.crypto.random.bytes(&sid);
+        var buf: [192]u8 = undefined;
+        const open = std.fmt.bufPrint(&buf, "0{{\"sid\":\"{s}\",\"upgrades\":[],\"pingInterval\":{d},\"pingTimeout\":{d},\"maxPayload\":{d}}}", .{
+            std.fmt.bytesToHex(sid, .lower), socketio_ping_interval_ms, socketio_ping_timeout_ms, self.max_message_size,
+        }) catch unreachable;
+        self.writeFrame(client, .text, open) catch {};
+        client.last_ping_ms = std.time.milliTimestamp();
+    }
+
+    /// Unwrap Engine.IO and Socket.IO packets from clients on /socket.io/. The
+    /// namespace handshake and heartbeats are handled here. A "message" event with
+    /// one string argument arrives as Message, any other event as Command.
+    fn socketioEvent(self: *WebSocketServer, event: WebSocketEvent) ?WebSocketEvent {
+        const msg = switch (event) {
+            .message => |msg| msg,
+            else => return event,
+        };
+        const client = self.clients.getPtr(msg.client_id) orelse return event;
+        if (!client.is_socketio) return event;
+        defer self.allocator.free(msg.text);
+
+        return self.socketioPacket(client, msg.text) catch |err| switch (err) {
+            error.OutOfMemory => null,
+            else => self.dropClient(msg.client_id, close_protocol_error, "Invalid Socket.IO packet"),
+        };
+    }
+
+    fn socketioPacket(self: *WebSocketServer, client: *WebSocketClient, text: []const u8) !?WebSocketEvent {
+        if (text.len == 0) return error.InvalidPacket;
+        switch (text[0]) {
+            '1' => return self.dropClient(client.id, close_normal, "Engine.IO close"),
+            '2' => {
+                try self.writeFrame(client, .text, "3");
+                return null;
+            },
+            // Pongs, upgrade confirmations and noops need no answer
+            '3', '5', '6' => return null,
+            '4' => {},
+            else => return error.InvalidPacket,
+        }
+
+        const packet = try SocketioPacket.parse(text[1..]);
+        switch (packet.kind) {
+            '0' => {
+                if (!std.mem.eql(u8, packet.namespace, "/")) {
+                    var buf: [256]u8 = undefined;
+                    const refusal = std.fmt.bufPrint(&buf, "44{s},{{\"message\":\"Invalid namespace\"}}", .{packet.namespace}) catch return error.InvalidPacket;
+                    try self.writeFrame(client, .text, refusal);
+                    return null;
+                }
+                var sid: [16]u8 = undefined;
+                std.crypto.random.bytes(&sid);
+                var buf: [64]u8 = undefined;
+                const connect = std.fmt.bufPrint(&buf, "40{{\"sid\":\"{s}\"}}", .{std.fmt.bytesToHex(sid, .lower)}) catch unreachable;
+                try self.writeFrame(client, .text, connect);
+                client.socketio_connected = true;
+                return null;
+            },
+            '1' => return self.dropClient(client.id, close_normal, "Socket.IO disconnect"),
+            '2' => {
+                if (!client.socketio_connected or !std.mem.eql(u8, packet.namespace, "/")) return error.NotConnected;
+                return try self.socketioEmitEvent(client.id, packet);
+            },
+            // Acks answer emits with ack ids, which the host never sends
+            '3' => return null,
+            // Binary attachments are not supported; clients never send CONNECT_ERROR
+            else => return error.InvalidPacket,
+        }
+    }
+
+    /// The event for a Socket.IO EVENT packet ["name", ...args]
+    fn socketioEmitEvent(self: *WebSocketServer, client_id: u64, packet: SocketioPacket) !WebSocketEvent {
+        const parsed = std.json.parseFromSlice(std.json.Value, self.allocator, packet.data, .{}) catch return error.InvalidPacket;
+        defer parsed.deinit();
+        if (parsed.value != .array) return error.InvalidPacket;
+        const items = parsed.value.array.items;
+        if (items.len == 0 or items[0] != .string) return error.InvalidPacket;
+        const name = items[0].string;
+
+        if (std.mem.eql(u8, name, "message") and items.len == 2 and items[1] == .string and packet.ack_id.len == 0) {
+            return .{ .message = .{ .client_id = client_id, .text = try self.allocator.dupe(u8, items[1].string) } };
+        }
+
+        const type_name = try self.allocator.dupe(u8, name);
+        errdefer self.allocator.free(type_name);
+        const room = try self.allocator.dupe(u8, "");
+        errdefer self.allocator.free(room);
+        const id = try self.allocator.dupe(u8, packet.ack_id);
+        errdefer self.allocator.free(id);
+        const payload = try std.json.Stringify.valueAlloc(self.allocator, items[1..], .{});
+        return .{ .envelope = .{
+            .client_id = client_id,
+            .kind = .command,
+            .type_name = type_name,
+            .room = room,
+            .id = id,
+            .payload = payload,
+        } };
+    }
+
+    /// Send a Socket.IO packet whose data is `prefix` followed by the elements of
+    /// the JSON array `args`: an EVENT ("42[\"name\"") or an ACK ("43<id>[")
+    fn sendSocketio(self: *WebSocketServer, client_id: u64, prefix: []const u8, args: []const u8) !void {
+        const client = self.clients.getPtr(client_id) orelse return error.ClientNotFound;
+        if (client.is_closed) return error.ConnectionClosed;
+        if (!client.is_socketio) return error.NotSocketioClient;
+
+        const parsed = std.json.parseFromSlice(std.json.Value, self.allocator, args, .{}) catch return error.InvalidArguments;
+        defer parsed.deinit();
+        if (parsed.value != .array) return error.InvalidArguments;
+        const trimmed = std.mem.trim(u8, args, " \t\r\n");
+        const elements = std.mem.trim(u8, trimmed[1 .. trimmed.len - 1], " \t\r\n");
+
+        var packet = std.ArrayListUnmanaged(u8){};
+        defer packet.deinit(self.allocator);
+        try packet.appendSlice(self.allocator, prefix);
+        if (elements.len > 0) {
+            if (prefix[prefix.len - 1] != '[') try packet.append(self.allocator, ',');
+            try packet.appendSlice(self.allocator, elements);
+        }
+        try packet.append(self.allocator, ']');
+        try self.writeFrame(client, .text, packet.items);
+    }
+
+    /// Emit a Socket.IO event with a JSON array of arguments
+    fn socketioEmit(self: *WebSocketServer, client_id: u64, name: []const u8, args: []const u8) !void {
+        var prefix = std.ArrayListUnmanaged(u8){};
+        defer prefix.deinit(self.allocator);
+        try prefix.appendSlice(self.allocator, "42[");
+        try writeJsonString(prefix.writer(self.allocator), name);
+        try self.sendSocketio(client_id, prefix.items, args);
+    }
+
+    /// Answer a Socket.IO event that asked for an ack, with a JSON array of arguments
+    fn socketioAck(self: *WebSocketServer, client_id: u64, ack_id: []const u8, args: []const u8) !void {
+        if (ack_id.len == 0 or ack_id.len > 16) return error.InvalidAckId;
+        for (ack_id) |c| {
+            if (!std.ascii.isDigit(c)) return error.InvalidAckId;
+        }
+        var buf: [24]u8 = undefined;
+        try self.sendSocketio(client_id, std.fmt.bufPrint(&buf, "43{s}[", .{ack_id}) catch unreachable, args);
+    }
+
+    /// Speak STOMP 1.2 with clients that negotiated it. SUBSCRIBE and UNSUBSCRIBE
+    /// join and leave the destination's room, SEND arrives as a ChatMessage for
+    /// that room. A bad frame is answered with ERROR and the connection closed.
+    fn stompEvent(self: *WebSocketServer, event: WebSocketEvent) ?WebSocketEvent {
+        const msg = switch (event) {
+            .message => |msg| msg,
+            else => return event,
+        };
+        const client = self.clients.getPtr(msg.client_id) orelse return event;
+        const session = if (client.stomp) |*state| state else return event;
+        defer self.allocator.free(msg.text);
+
+        var arena = std.heap.ArenaAllocator.init(self.allocator);
+        defer arena.deinit();
+        const frame = StompFrame.parse(arena.allocator(), msg.text) catch |err| switch (err) {
+            error.HeartBeat, error.OutOfMemory => return null,
+            else => return self.stompError(msg.client_id, "Malformed frame"),
+        };
+        return self.stompFrame(client, session, &frame) catch |err| switch (err) {
+            error.OutOfMemory => null,
+            else => self.stompError(msg.client_id, @errorName(err)),
+        };
+    }
+
+    fn stompFrame(self: *WebSocketServer, client: *WebSocketClient, session: *StompSession, frame: *const StompFrame) !?WebSocketEvent {
+        const command = frame.command;
+        if (std.mem.eql(u8, command, "CONNECT") or std.mem.eql(u8, command, "STOMP")) {
+            if (session.connected) return error.AlreadyConnected;
+            var versions = std.mem.splitScalar(u8, frame.header("accept-version") orelse "1.0", ',');
+            while (versions.next()) |version| {
+                if (std.mem.eql(u8, std.mem.trim(u8, version, " "), "1.2")) break;
+            } else return error.UnsupportedVersion;
+            // Heart-beating is left to WebSocket pings
+            try self.writeFrame(client, .text, "CONNECTED\nversion:1.2\nheart-beat:0,0\nserver:chatserver\n\n\x00");
+            session.connected = true;
+            return null;
+        }
+        if (!session.connected) return error.NotConnected;
+
+        var event: ?WebSocketEvent = null;
+        if (std.mem.eql(u8, command, "SUBSCRIBE")) {
+            const id = frame.header("id") orelse return error.MissingId;
+            const destination = frame.header("destination") orelse return error.MissingDestination;
+            if (session.subscriptions.contains(id)) return error.DuplicateSubscription;
+            const owned_id = try self.allocator.dupe(u8, id);
+            errdefer self.allocator.free(owned_id);
+            const owned_destination = try self.allocator.dupe(u8, destination);
+            errdefer self.allocator.free(owned_destination);
+            try session.subscriptions.put(self.allocator, owned_id, owned_destination);
+            errdefer _ = session.subscriptions.swapRemove(owned_id);
+            // Subscribed first so that replayed history finds the subscription
+            try self.joinRoom(client.id, destination);
+        } else if (std.mem.eql(u8, command, "UNSUBSCRIBE")) {
+            const id = frame.header("id") orelse return error.MissingId;
+            const entry = session.subscriptions.fetchSwapRemove(id) orelse return error.UnknownSubscription;
+            defer self.allocator.free(entry.key);
+            defer self.allocator.free(entry.value);
+            if (session.subscriptionFor(entry.value) == null) self.leaveRoom(client.id, entry.value);
+        } else if (std.mem.eql(u8, command, "SEND")) {
+            const destination = frame.header("destination") orelse return error.MissingDestination;
+            if (!std.unicode.utf8ValidateSlice(frame.body)) return error.InvalidBody;
+            const type_name = try self.allocator.dupe(u8, "message");
+            errdefer self.allocator.free(type_name);
+            const room = try self.allocator.dupe(u8, destination);
+            errdefer self.allocator.free(room);
+            const id = try self.allocator.dupe(u8, "");
+            errdefer self.allocator.free(id);
+            event = .{ .envelope = .{
+                .client_id = client.id,
+                .kind = .chat_message,
+                .type_name = type_name,
+                .room = room,
+                .id = id,
+                .payload = try self.allocator.dupe(u8, frame.body),
+            } };
+        } else if (std.mem.eql(u8, command, "ACK") or std.mem.eql(u8, command, "NACK")) {
+            // Messages go out once and are never redelivered, so there is nothing to settle
+            _ = frame.header("id") orelse return error.MissingId;
+        } else if (std.mem.eql(u8, command, "DISCONNECT")) {
+            if (frame.header("receipt")) |receipt| try self.sendStompReceipt(client, receipt);
+            return self.dropClient(client.id, close_normal, "STOMP disconnect");
+        } else if (std.mem.eql(u8, command, "BEGIN") or std.mem.eql(u8, command, "COMMIT") or std.mem.eql(u8, command, "ABORT")) {
+            return error.TransactionsNotSupported;
+        } else {
+            return error.UnknownCommand;
+        }
+        errdefer if (event) |e| e.envelope.deinit(self.allocator);
+        if (frame.header("receipt")) |receipt| try self.sendStompReceipt(client, receipt);
+        return event;
+    }
+
+    fn sendStompReceipt(self: *WebSocketServer, client: *WebSocketClient, receipt: []const u8) !void {
+        var frame = std.ArrayListUnmanaged(u8){};
+        defer frame.deinit(self.allocator);
+        const w = frame.writer(self.allocator);
+        try w.writeAll("RECEIPT\n");
+        try writeStompHeader(w, "receipt-id", receipt);
+        try w.writeAll("\n\x00");
+        try self.writeFrame(client, .text, frame.items);
+    }
+
+    /// Send ERROR and close the connection, as STOMP requires after an error
+    fn stompError(self: *WebSocketServer, client_id: u64, message: []const u8) WebSocketEvent {
+        if (self.clients.getPtr(client_id)) |client| {
+            var buf: [256]u8 = undefined;
+            var frame = std.io.fixedBufferStream(&buf);
+            const w = frame.writer();
+            w.writeAll("ERROR\n") catch {};
+            writeStompHeader(w, "message", message) catch {};
+            w.writeAll("\n\x00") catch {};
+            self.writeFrame(client, .text, frame.getWritten()) catch {};
+        }
+        return self.dropClient(client_id, close_protocol_error, message);
+    }
+
+    /// Send one room message to one member in the form its protocol needs
+    fn sendRoomMessage(self: *WebSocketServer, client: *WebSocketClient, room: []const u8, message: []const u8) !void {
+        if (client.stomp) |*session| return self.sendStompMessage(client, session, room, message);
+        try self.sendFrame(client, .text, message);
+    }
+
+    /// Deliver text to a STOMP client as a MESSAGE frame, if it subscribed to the destination
+    fn sendStompMessage(self: *WebSocketServer, client: *WebSocketClient, session: *StompSession, destination: []const u8, body: []const u8) !void {
+        const subscription = session.subscriptionFor(destination) orelse {
+            logger.debug(client.id, "Not subscribed to {s}, message dropped", .{destination});
+            return;
+        };
+
+        var frame = std.ArrayListUnmanaged(u8){};
+        defer frame.deinit(self.allocator);
+        const w = frame.writer(self.allocator);
+        try w.writeAll("MESSAGE\n");
+        try writeStompHeader(w, "subscription", subscription);
+        try w.print("message-id:{d}\n", .{session.next_message_id});
+        try writeStompHeader(w, "destination", destination);
+        try w.print("content-length:{d}\n\n", .{body.len});
+        try w.writeAll(body);
+        try w.writeByte(0);
+        session.next_message_id += 1;
+        try self.writeFrame(client, .text, frame.items);
+    }
+
+    /// Speak graphql-transport-ws with clients that negotiated it: connection_init
+    /// and ping are answered here, subscribe and complete become events, and
+    /// anything else closes the connection with the protocol's 44xx codes
+    fn graphqlEvent(self: *WebSocketServer, event: WebSocketEvent) ?WebSocketEvent {
+        const msg = switch (event) {
+            .message => |msg| msg,
+            else => return event,
+        };
+        const client = self.clients.getPtr(msg.client_id) orelse return event;
+        const session = if (client.graphql) |*state| state else return event;
+        defer self.allocator.free(msg.text);
+
+        return self.graphqlMessage(client, session, msg.text) catch |err| switch (err) {
+            error.OutOfMemory => null,
+            error.Unauthorized => self.dropClient(msg.client_id, graphql_close_unauthorized, "Unauthorized"),
+            error.SubscriberExists => self.dropClient(msg.client_id, graphql_close_subscriber_exists, "Subscriber already exists"),
+            error.TooManyInits => self.dropClient(msg.client_id, graphql_close_too_many_inits, "Too many initialisation requests"),
+            else => self.dropClient(msg.client_id, graphql_close_bad_request, "Invalid message"),
+        };
+    }
+
+    fn graphqlMessage(self: *WebSocketServer, client: *WebSocketClient, session: *GraphqlSession, text: []const u8) !?WebSocketEvent {
+        const parsed = std.json.parseFromSlice(std.json.Value, self.allocator, text, .{}) catch return error.InvalidMessage;
+        defer parsed.deinit();
+        if (parsed.value != .object) return error.InvalidMessage;
+        const fields = &parsed.value.object;
+        const message_type = try Envelope.stringField(fields, "type") orelse return error.InvalidMessage;
+
+        if (std.mem.eql(u8, message_type, "connection_init")) {
+            if (session.acknowledged) return error.TooManyInits;
+            session.acknowledged = true;
+            try self.sendFrame(client, .text, "{\"type\":\"connection_ack\"}");
+            return null;
+        }
+        if (std.mem.eql(u8, message_type, "ping")) {
+            try self.sendFrame(client, .text, "{\"type\":\"pong\"}");
+            return null;
+        }
+        if (std.mem.eql(u8, message_type, "pong")) return null;
+        if (!session.acknowledged) return error.Unauthorized;
+
+        const id = try Envelope.stringField(fields, "id") orelse return error.InvalidMessage;
+        if (std.mem.eql(u8, message_type, "subscribe")) {
+            const payload = fields.get("payload") orelse return error.InvalidMessage;
+            if (payload != .object) return error.InvalidMessage;
+            if (session.operations.contains(id)) return error.SubscriberExists;
+
+            const payload_json = try std.json.Stringify.valueAlloc(self.allocator, payload, .{});
+            errdefer self.allocator.free(payload_json);
+            const event_id = try self.allocator.dupe(u8, id);
+            errdefer self.allocator.free(event_id);
+            const key = try self.allocator.dupe(u8, id);
+            errdefer self.allocator.free(key);
+            try session.operations.put(self.allocator, key, {});
+            return .{ .graphql_subscribe = .{ .client_id = client.id, .id = event_id, .payload = payload_json } };
+        }
+        if (std.mem.eql(u8, message_type, "complete")) {
+            // Operations the server already completed may still be cancelled by the client
+            if (!session.finish(self.allocator, id)) return null;
+            return .{ .graphql_complete = .{ .client_id = client.id, .id = try self.allocator.dupe(u8, id) } };
+        }
+        return error.InvalidMessage;
+    }
+
+    /// Send a graphql-transport-ws message for a running operation. "error" and
+    /// "complete" end the operation.
+    fn sendGraphql(self: *WebSocketServer, client_id: u64, message_type: []const u8, id: []const u8, payload: ?[]const u8) !void {
+        const client = self.clients.getPtr(client_id) orelse return error.ClientNotFound;
+        if (client.is_closed) return error.ConnectionClosed;
+        const session = if (client.graphql) |*state| state else return error.NotGraphqlClient;
+        if (!session.operations.contains(id)) return error.UnknownOperation;
+        if (payload) |value| {
+            if (!try std.json.validate(self.allocator, value)) return error.InvalidPayload;
+        }
+
+        var json = std.ArrayListUnmanaged(u8){};
+        defer json.deinit(self.allocator);
+        const w = json.writer(self.allocator);
+        try w.writeAll("{\"id\":");
+        try writeJsonString(w, id);
+        try w.writeAll(",\"type\":");
+        try writeJsonString(w, message_type);
+        if (payload) |value| try w.print(",\"payload\":{s}", .{value});
+        try w.writeByte('}');
+        try self.sendFrame(client, .text, json.items);
+
+        if (!std.mem.eql(u8, message_type, "next")) _ = session.finish(self.allocator, id);
+    }
+
+    /// Under the JSON-RPC protocol, turn a text message into an RpcCall event.
+    /// An invalid request is answered with a JSON-RPC error instead.
+    fn rpcEvent(self: *WebSocketServer, event: WebSocketEvent) ?WebSocketEvent {
+        if (!self.rpc_protocol) return event;
+        const msg = switch (event) {
+            .message => |msg| msg,
+            else => return event,
+        };
+        defer self.allocator.free(msg.text);
+
+        if (RpcCall.parse(self.allocator, msg.client_id, msg.text)) |call| {
+            return .{ .rpc_call = call };
+        } else |err| {
+            logger.debug(msg.client_id, "Rejected JSON-RPC request: {s}", .{@errorName(err)});
+            const code: i64 = switch (err) {
+                error.ParseError => rpc_parse_error,
+                error.InvalidRequest => rpc_invalid_request,
+                else => return null,
+            };
+            const message = if (code == rpc_parse_error) "Parse error" else "Invalid Request";
+            var buf: [128]u8 = undefined;
+            var reply = std.io.fixedBufferStream(&buf);
+            writeRpcError(reply.writer(), "", code, message) catch return null;
+            self.send(msg.client_id, reply.getWritten()) catch {};
+            return null;
+        }
+    }
+
+    /// Answer a JSON-RPC request with a result, both given as JSON
+    fn rpcRespond(self: *WebSocketServer, client_id: u64, id: []const u8, result: []const u8) !void {
+        if (id.len == 0) return error.NotificationHasNoResponse;
+        if (!try std.json.validate(self.allocator, id)) return error.InvalidId;
+        if (!try std.json.validate(self.allocator, result)) return error.InvalidResult;
+
+        var json = std.ArrayListUnmanaged(u8){};
+        defer json.deinit(self.allocator);
+        try json.writer(self.allocator).print("{{\"jsonrpc\":\"2.0\",\"id\":{s},\"result\":{s}}}", .{ id, result });
+        try self.send(client_id, json.items);
+    }
+
+    /// Answer a JSON-RPC request with an error object
+    fn rpcError(self: *WebSocketServer, client_id: u64, id: []const u8, code: i64, message: []const u8) !void {
+        if (id.len == 0) return error.NotificationHasNoResponse;
+        if (!try std.json.validate(self.allocator, id)) return error.InvalidId;
+
+        var json = std.ArrayListUnmanaged(u8){};
+        defer json.deinit(self.allocator);
+        try writeRpcError(json.writer(self.allocator), id, code, message);
+        try self.send(client_id, json.items);
+    }
+
+    /// A client's role in a room; rooms named too long for a key of their own use the global role
+    fn roleOf(client: *const WebSocketClient, room: []const u8) Role {
+        var key_buf: [256]u8 = undefined;
+        if (std.fmt.bufPrint(&key_buf, "role:{s}", .{room})) |key| {
+            if (client.data.get(key)) |name| return Role.parse(name) orelse .member;
+        } else |_| {}
+        const name = client.data.get("role") orelse return .member;
+        return Role.parse(name) orelse .member;
+    }
+
+    /// Give a client a role in one room, or in every room when `room` is empty
+    fn setRole(self: *WebSocketServer, client_id: u64, room: []const u8, role: Role) !void {
+        const client = self.clients.getPtr(client_id) orelse return error.ClientNotFound;
+        if (room.len == 0) return client.setData(self.allocator, "role", @tagName(role));
+        var key_buf: [256]u8 = undefined;
+        const key = std.fmt.bufPrint(&key_buf, "role:{s}", .{room}) catch return error.RoomNameTooLong;
+        try client.setData(self.allocator, key, @tagName(role));
+    }
+
+    fn setRoomPolicy(self: *WebSocketServer, room: []const u8, policy: RoomPolicy) !void {
+        const gop = try self.room_policies.getOrPut(self.allocator, room);
+        if (!gop.found_existing) {
+            gop.key_ptr.* = self.allocator.dupe(u8, room) catch |err| {
+                _ = self.room_policies.remove(room);
+                return err;
+            };
+        }
+        gop.value_ptr.* = policy;
+    }
+
+    fn permits(self: *const WebSocketServer, client: *const WebSocketClient, room: []const u8, action: RoomAction) bool {
+        const policy = self.room_policies.get(room) orelse RoomPolicy{};
+        const required = switch (action) {
+            .join => policy.join,
+            .send => policy.send,
+        };
+        return @intFromEnum(roleOf(client, room)) >= @intFromEnum(required);
+    }
+
+    /// Refuse envelope joins and room messages the sender's role does not
+    /// allow, reporting PermissionDenied in their place
+    fn roleEvent(self: *WebSocketServer, event: WebSocketEvent) WebSocketEvent {
+        const envelope = switch (event) {
+            .envelope => |envelope| envelope,
+            else => return event,
+        };
+        const action: RoomAction = switch (envelope.kind) {
+            .join_request => .join,
+            .chat_message => .send,
+            .command => return event,
+        };
+        const client = self.clients.getPtr(envelope.client_id) orelse return event;
+        if (self.permits(client, envelope.room, action)) return event;
+
+        logger.debug(envelope.client_id, "Not permitted to {s} {s}", .{ @tagName(action), envelope.room });
+        // The room moves into the event
+        self.allocator.free(envelope.type_name);
+        self.allocator.free(envelope.id);
+        self.allocator.free(envelope.payload);
+        return .{ .permission_denied = .{ .client_id = envelope.client_id, .room = envelope.room, .action = action } };
+    }
+
+    /// Run chat text through the content filters, then deliver it masked, drop
+    /// it or report Flagged in its place as configured
+    fn filterEvent(self: *WebSocketServer, event: WebSocketEvent) ?WebSocketEvent {
+        if (self.content_filter.isEmpty()) return event;
+        switch (event) {
+            .message => |msg| {
+                var next = msg;
+                switch (self.filterText(msg.client_id, msg.text)) {
+                    .keep => return event,
+                    .replace => |text| {
+                        self.allocator.free(msg.text);
+                        next.text = text;
+                        return .{ .message = next };
+                    },
+                    .drop => {
+                        self.allocator.free(msg.text);
+                        return null;
+                    },
+                    .flag => |reason| return .{ .flagged = .{ .client_id = msg.client_id, .reason = reason, .text = msg.text } },
+                }
+            },
+            .envelope => |envelope| {
+                if (envelope.kind != .chat_message) return event;
+                var next = envelope;
+                switch (self.filterText(envelope.client_id, envelope.payload)) {
+                    .keep => return event,
+                    .replace => |text| {
+                        self.allocator.free(envelope.payload);
+                        next.payload = text;
+                        return .{ .envelope = next };
+                    },
+                    .drop => {
+                        envelope.deinit(self.allocator);
+                        return null;
+                    },
+                    .flag => |reason| {
+                        // The text moves into the event
+                        self.allocator.free(envelope.type_name);
+                        self.allocator.free(envelope.room);
+                        self.allocator.free(envelope.id);
+                        return .{ .flagged = .{ .client_id = envelope.client_id, .reason = reason, .text = envelope.payload } };
+                    },
+                }
+            },
+            else => return event,
+        }
+    }
+
+    fn filterText(self: *WebSocketServer, client_id: u64, text: []const u8) FilterOutcome {
+        const result = self.content_filter.apply(self.allocator, text) catch |err| {
+            logger.warn(client_id, "Content filter failed: {s}", .{@errorName(err)});
+            return .keep;
+        };
+        const reason = result.reason orelse {
+            if (!std.mem.eql(u8, result.text, text)) return .{ .replace = result.text };
+            self.allocator.free(result.text);
+            return .keep;
+        };
+        switch (self.content_filter.action) {
+            .mask => {
+                defer self.allocator.free(reason);
+                logger.debug(client_id, "Masked by content filter ({s})", .{reason});
+                return .{ .replace = result.text };
+            },
+            .drop => {
+                defer self.allocator.free(reason);
+                self.allocator.free(result.text);
+                logger.debug(client_id, "Dropped by content filter ({s})", .{reason});
+                return .drop;
+            },
+            .flag => {
+                self.allocator.free(result.text);
+                return .{ .flag = reason };
+            },
+        }
+    }
+
+    /// Drop chat from a muted client, reporting Muted in its place. Commands,
+    /// joins and RPC calls still go through.
+    fn muteEvent(self: *WebSocketServer, event: WebSocketEvent) WebSocketEvent {
+        if (self.mutes.items.len == 0) return event;
+        const client_id = switch (event) {
+            .message => |msg| msg.client_id,
+            .binary_message => |msg| msg.client_id,
+            .envelope => |envelope| if (envelope.kind == .chat_message) envelope.client_id else return event,
+            else => return event,
+        };
+        const client = self.clients.getPtr(client_id) orelse return event;
+        const address = clientAddress(client) orelse return event;
+        const now = std.time.milliTimestamp();
+        const mute = Restriction.find(self.mutes.items, address, now) orelse return event;
+
+        switch (event) {
+            .message => |msg| self.allocator.free(msg.text),
+            .binary_message => |msg| self.allocator.free(msg.data),
+            .envelope => |envelope| envelope.deinit(self.allocator),
+            else => unreachable,
+        }
+        logger.debug(client_id, "Muted, message dropped", .{});
+        const remaining_ms: u64 = if (mute.until_ms == 0) 0 else @intCast(mute.until_ms - now);
+        return .{ .muted = .{ .client_id = client_id, .remaining_ms = remaining_ms } };
+    }
+
+    /// Refuse an address or network for `duration_ms` (0 until unban_ip!). Connected
+    /// clients from it are closed with 1008, each reported as Banned then Disconnected.
+    fn banNetwork(self: *WebSocketServer, network: Cidr, duration_ms: u64) !void {
+        try Restriction.add(&self.bans, self.allocator, network, duration_ms);
+
+        // Collect first, removing while iterating invalidates the iterator
+        var banned = std.ArrayListUnmanaged(u64){};
+        defer banned.deinit(self.allocator);
+        var it = self.clients.valueIterator();
+        while (it.next()) |client| {
+            if (!client.is_websocket or client.is_closed) continue;
+            const address = clientAddress(client) orelse continue;
+            if (network.contains(address)) try banned.append(self.allocator, client.id);
+        }
+
+        for (banned.items) |client_id| {
+            const client = self.clients.getPtr(client_id).?;
+            const address = try self.allocator.dupe(u8, client.data.get("address").?);
+            try self.queueEvent(.{ .banned = .{ .client_id = client_id, .address = address } });
+            // A full queue may already have disconnected it
+            if (self.clients.contains(client_id)) try self.queueEvent(self.dropClient(client_id, close_policy_violation, "Banned"));
+        }
+    }
+
+    /// Ban the address a client connected from
+    fn banClient(self: *WebSocketServer, client_id: u64, duration_ms: u64) !void {
+        const client = self.clients.getPtr(client_id) orelse return error.ClientNotFound;
+        const address = clientAddress(client) orelse return error.NoClientAddress;
+        try self.banNetwork(hostNetwork(address), duration_ms);
+    }
+
+    /// Drop chat from the address a client connected from, across reconnects,
+    /// for `duration_ms` (0 until unmute_client!)
+    fn muteClient(self: *WebSocketServer, client_id: u64, duration_ms: u64) !void {
+        const client = self.clients.getPtr(client_id) orelse return error.ClientNotFound;
+        const address = clientAddress(client) orelse return error.NoClientAddress;
+        try Restriction.add(&self.mutes, self.allocator, hostNetwork(address), duration_ms);
+    }
+
+    fn unmuteClient(self: *WebSocketServer, client_id: u64) !void {
+        const client = self.clients.getPtr(client_id) orelse return error.ClientNotFound;
+        const address = clientAddress(client) orelse return error.NoClientAddress;
+        Restriction.remove(&self.mutes, hostNetwork(address));
+    }
+
+    fn limitMessageRate(self: *WebSocketServer, client_id: u64, event: WebSocketEvent) WebSocketEvent {
+        const client = self.clients.getPtr(client_id) orelse return event;
+        if (client.is_upstream) return event;
+        switch (event) {
+            .message => |msg| {
+                if (client.message_bucket.take(self.message_limit, std.time.milliTimestamp())) return event;
+                self.allocator.free(msg.text);
+            },
+            .binary_message => |msg| {
+                if (client.message_bucket.take(self.message_limit, std.time.milliTimestamp())) return event;
+                self.allocator.free(msg.data);
+            },
+            else => return event,
+        }
+        return self.rateLimitClient(client_id);
+    }
+
+    /// Close a client that exceeded its message rate, queueing its Disconnected event
+    fn rateLimitClient(self: *WebSocketServer, client_id: u64) WebSocketEvent {
+        const reason = std.fmt.allocPrint(self.allocator, "More than {d} messages/s", .{self.message_limit.rate}) catch "";
+        const disconnected = self.dropClient(client_id, close_policy_violation, "Rate limit exceeded");
+        self.queueEvent(disconnected) catch {};
+        return .{ .rate_limited = .{ .client_id = client_id, .reason = reason } };
+    }
+
+    /// Disconnect event for a connection that dropped without a Close frame
+    fn abnormalClosure(client_id: u64) WebSocketEvent {
+        return .{ .disconnected = .{ .client_id = client_id, .code = close_abnormal, .reason = "" } };
+    }
+
+    /// Build the event for a complete data message, taking ownership of the payload
+    fn dataEvent(self: *WebSocketServer, client: *WebSocketClient, opcode: WebSocketOpcode, payload: []u8) !WebSocketEvent {
+        if (opcode == .text and !std.unicode.utf8ValidateSlice(payload)) {
+            self.allocator.free(payload);
+            return error.InvalidPayload;
+        }
+        client.traffic.messages_in += 1;
+        self.traffic.messages_in += 1;
+        if (opcode == .binary and client.msgpack) {
+            defer self.allocator.free(payload);
+            const text = msgpackToJson(self.allocator, payload) catch |err| {
+                logger.debug(client.id, "Rejected MessagePack envelope: {s}", .{@errorName(err)});
+                var buf: [128]u8 = undefined;
+                var reply = std.io.fixedBufferStream(&buf);
+                writeEnvelope(reply.writer(), "error", "", "", @errorName(err)) catch return error.RejectedMessage;
+                self.sendFrame(client, .text, reply.getWritten()) catch {};
+                return error.RejectedMessage;
+            };
+            return .{ .message = .{ .client_id = client.id, .text = text } };
+        }
+        if (opcode == .binary) {
+            return .{ .binary_message = .{ .client_id = client.id, .data = payload } };
+        }
+        if (client.is_upstream) {
+            return .{ .upstream_message = .{ .client_id = client.id, .text = payload } };
+        }
+        return .{ .message = .{ .client_id = client.id, .text = payload } };
+    }
+
+    fn sendFrame(self: *WebSocketServer, client: *WebSocketClient, opcode: WebSocketOpcode, payload: []const u8) !void {
+        // Socket.IO clients get text as a "message" event
+        if (client.is_socketio and opcode == .text) {
+            var packet = std.ArrayListUnmanaged(u8){};
+            defer packet.deinit(self.allocator);
+            try packet.appendSlice(self.allocator, "42[\"message\",");
+            try writeJsonString(packet.writer(self.allocator), payload);
+            try packet.append(self.allocator, ']');
+            return self.writeFrame(client, .text, packet.items);
+        }
+        if (opcode == .text) {
+            if (client.stomp) |*session| return self.sendStompMessage(client, session, stomp_direct_destination, payload);
+        }
+        // MessagePack clients get JSON text as binary; anything else passes through
+        if (client.msgpack and opcode == .text) {
+            if (jsonToMsgpack(self.allocator, payload)) |packed_payload| {
+                defer self.allocator.free(packed_payload);
+                return self.writeFrame(client, .binary, packed_payload);
+            }
+        }
+        return self.writeFrame(client, opcode, payload);
+    }
+
+    fn writeFrame(self: *WebSocketServer, client: *WebSocketClient, opcode: WebSocketOpcode, payload: []const u8) !void {
+        self.countMessageOut(client, opcode);
+        if (client.is_sse) return self.sendSseEvent(client, opcode, payload);
+
+        // Frames sent to a server must be masked (RFC 6455 section 5.3)
+        var mask: ?[4]u8 = null;
+        if (client.is_upstream) {
+            var key: [4]u8 = undefined;
+            std.crypto.random.bytes(&key);
+            mask = key;
+        }
+        var header: [codec.max_header_len]u8 = undefined;
+        const header_len = codec.writeHeader(&header, opcode, payload.len, mask);
+
+        // Header and payload go out in one write; a separate header segment
+        // would sit behind Nagle's algorithm waiting for an ACK
+        var small: [small_frame_size]u8 = undefined;
+        const frame_len = header_len + payload.len;
+        const frame = if (frame_len <= small.len) small[0..frame_len] else blk: {
+            try self.send_buf.resize(self.allocator, frame_len);
+            break :blk self.send_buf.items;
+        };
+        defer if (frame_len > small.len) releasePooled(self.allocator, &self.send_buf);
+
+        @memcpy(frame[0..header_len], header[0..header_len]);
+        @memcpy(frame[header_len..], payload);
+        if (mask) |key| codec.applyMask(frame[header_len..], key);
+        try self.queueBytes(client, frame);
+    }
+
+    /// Queue a frame built by codec.encode. SSE, upstream, MessagePack, Socket.IO and
+    /// STOMP clients need their own encoding, so they get the payload re-framed instead.
+    fn sendEncoded(self: *WebSocketServer, client: *WebSocketClient, encoded: []const u8, opcode: WebSocketOpcode, payload: []const u8) !void {
+        if (client.is_sse or client.is_upstream or client.msgpack or client.is_socketio or client.stomp != null) return self.sendFrame(client, opcode, payload);
+        self.countMessageOut(client, opcode);
+        try self.queueBytes(client, encoded);
+    }
+
+    fn countMessageOut(self: *WebSocketServer, client: *WebSocketClient, opcode: WebSocketOpcode) void {
+        if (opcode != .text and opcode != .binary) return;
+        client.traffic.messages_out += 1;
+        self.traffic.messages_out += 1;
+    }
+
+    /// SSE encoding of a frame: text as data lines, binary as base64 under
+    /// "event: binary", pings as comments, close as "event: close"
+    fn sendSseEvent(self: *WebSocketServer, client: *WebSocketClient, opcode: WebSocketOpcode, payload: []const u8) !void {
+        var out = std.ArrayListUnmanaged(u8){};
+        defer out.deinit(self.allocator);
+
+        switch (opcode) {
+            .text => {
+                var lines = std.mem.splitScalar(u8, payload, '\n');
+                while (lines.next()) |line| {
+                    try out.appendSlice(self.allocator, "data: ");
+                    try out.appendSlice(self.allocator, std.mem.trimRight(u8, line, "\r"));
+                    try out.append(self.allocator, '\n');
+                }
+            },
+            .binary => {
+                const encoder = std.base64.standard.Encoder;
+                try out.appendSlice(self.allocator, "event: binary\ndata: ");
+                const start = out.items.len;
+                try out.resize(self.allocator, start + encoder.calcSize(payload.len));
+                _ = encoder.encode(out.items[start..], payload);
+                try out.append(self.allocator, '\n');
+            },
+            .ping => try out.appendSlice(self.allocator, ": ping\n"),
+            .close => {
+                const code = if (payload.len >= 2) std.mem.readInt(u16, payload[0..2], .big) else close_no_status;
+                try out.writer(self.allocator).print("event: close\ndata: {d}\n", .{code});
+            },
+            else => return,
+        }
+        try out.append(self.allocator, '\n');
+        try self.queueBytes(client, out.items);
+    }
+
+    /// Write as much as the socket accepts without blocking and queue the rest,
+    /// so one slow reader cannot stall the event loop
+    fn queueBytes(self: *WebSocketServer, client: *WebSocketClient, bytes: []const u8) !void {
+        var remaining = bytes;
+        // Output for a detached client waits in the outbox for it to resume
+        if (client.outbox.items.len == 0 and client.detached_until_ms == 0) {
+            const written = try writeNonBlocking(client.stream, remaining);
+            client.bytes_queued += written;
+            client.traffic.bytes_out += written;
+            self.traffic.bytes_out += written;
+            remaining = remaining[written..];
+            if (remaining.len == 0) return;
+        }
+
+        // An HTTP response is bounded by its own size, so only streams are held to the limit
+        if (client.is_websocket and client.outbox.items.len + remaining.len > self.max_outbox_bytes) {
+            client.outbox_overflowed = true;
+            return error.SlowConsumer;
+        }
+        try client.outbox.appendSlice(self.allocator, remaining);
+        client.bytes_queued += remaining.len;
+        client.traffic.bytes_out += remaining.len;
+        self.traffic.bytes_out += remaining.len;
+    }
+
+    fn flushOutbox(self: *WebSocketServer, client: *WebSocketClient) !void {
+        const pending = client.outbox.items;
+        const written = try writeNonBlocking(client.stream, pending);
+        const rest = pending.len - written;
+        std.mem.copyForwards(u8, pending[0..rest], pending[written..]);
+        client.outbox.shrinkRetainingCapacity(rest);
+        self.settleAcks(client);
+    }
+
+    /// Send a text message and return an id; a Delivered event follows once all
+    /// of it has been written to the socket, DeliveryFailed if the client goes first
+    fn sendWithAck(self: *WebSocketServer, client_id: u64, message: []const u8) !u64 {
+        const client = self.clients.getPtr(client_id) orelse return error.ClientNotFound;
+        if (client.is_closed or !client.is_websocket) return error.ConnectionClosed;
+        try self.sendFrame(client, .text, message);
+
+        const message_id = self.next_message_id;
+        self.next_message_id += 1;
+        try client.pending_acks.append(self.allocator, .{ .message_id = message_id, .end = client.bytes_queued });
+        self.settleAcks(client);
+        return message_id;
+    }
+
+    /// Queue Delivered for acked messages whose bytes have all left the outbox
+    fn settleAcks(self: *WebSocketServer, client: *WebSocketClient) void {
+        const written = client.bytes_queued - client.outbox.items.len;
+        var settled: usize = 0;
+        for (client.pending_acks.items) |ack| {
+            if (ack.end > written) break;
+            self.queueEvent(.{ .delivered = ack.message_id }) catch {};
+            settled += 1;
+        }
+        if (settled == 0) return;
+        const acks = client.pending_acks.items;
+        std.mem.copyForwards(PendingAck, acks[0 .. acks.len - settled], acks[settled..]);
+        client.pending_acks.shrinkRetainingCapacity(acks.len - settled);
+    }
+
+    /// Queue DeliveryFailed for acked messages still pending on a departing client
+    fn failPendingAcks(self: *WebSocketServer, client: *WebSocketClient, reason: []const u8) void {
+        self.settleAcks(client);
+        for (client.pending_acks.items) |ack| {
+            self.queueEvent(.{ .delivery_failed = .{ .message_id = ack.message_id, .reason = reason } }) catch {};
+        }
+        client.pending_acks.clearRetainingCapacity();
+    }
+
+    /// Drop clients whose outbox overflowed, queueing Disconnected events for them
+    fn dropSlowClients(self: *WebSocketServer) void {
+        var slow = std.ArrayListUnmanaged(u64){};
+        defer slow.deinit(self.allocator);
+
+        var it = self.clients.valueIterator();
+        while (it.next()) |client| {
+            if (client.outbox_overflowed) {
+                slow.append(self.allocator, client.id) catch {};
+            }
+        }
+
+        for (slow.items) |client_id| {
+            const event = self.dropClient(client_id, close_policy_violation, "Send queue overflow");
+            self.queueEvent(event) catch {};
+        }
+    }
+
+    /// Drop connections still trickling in their request after the handshake timeout.
+    /// They never reached Connected, so no event is produced.
+    fn expireHandshakes(self: *WebSocketServer) void {
+        if (self.handshake_timeout_ms <= 0) return;
+        const now = std.time.milliTimestamp();
+
+        var expired = std.ArrayListUnmanaged(u64){};
+        defer expired.deinit(self.allocator);
+
+        var it = self.clients.valueIterator();
+        while (it.next()) |client| {
+            // Requests handed to the application wait on it, not on the peer
+            if (client.is_websocket or client.is_upstream or client.is_closed or
+                client.http_pending or client.handshake_pending) continue;
+            if (now - client.last_activity_ms >= self.handshake_timeout_ms) {
+                expired.append(self.allocator, client.id) catch {};
+            }
+        }
+
+        for (expired.items) |client_id| {
+            const client = self.clients.getPtr(client_id) orelse continue;
+            // A download's head is already out, so a stalled one just closes
+            if (client.download) |download| {
+                logger.warn(client_id, "Download stalled for {d}ms ({d} bytes left)", .{ self.handshake_timeout_ms, download.end - download.offset });
+            } else if (client.closing) {
+                logger.warn(client_id, "Response stalled for {d}ms ({d} bytes left)", .{ self.handshake_timeout_ms, client.outbox.items.len });
+            } else {
+                logger.warn(client_id, "Request not completed within {d}ms ({d} bytes received)", .{ self.handshake_timeout_ms, client.request_buf.items.len });
+                self.sendHttpError(client, 408, "Request Timeout") catch {};
+            }
+            self.removeClient(client_id);
+        }
     }
 
     fn send(self: *WebSocketServer, client_id: u64, message: []const u8) !void {
@@ -550,297 +6572,5428 @@ const WebSocketServer = struct {
         try self.sendFrame(client, .text, message);
     }
 
-    fn broadcast(self: *WebSocketServer, message: []const u8) !void {
-        var it = self.clients.valueIterator();
-        while (it.next()) |client| {
-            if (client.is_websocket and !client.is_closed) {
-                self.sendFrame(client, .text, message) catch {};
-            }
-        }
+    fn sendBinary(self: *WebSocketServer, client_id: u64, data: []const u8) !void {
+        const client = self.clients.getPtr(client_id) orelse return error.ClientNotFound;
+        if (client.is_closed) return error.ConnectionClosed;
+        try self.sendFrame(client, .binary, data);
+    }
+
+    fn broadcast(self: *WebSocketServer, message: []const u8) !void {
+        try self.broadcastFrame(.text, message);
+    }
+
+    fn broadcastBinary(self: *WebSocketServer, data: []const u8) !void {
+        try self.broadcastFrame(.binary, data);
+    }
+
+    /// Serve `host`'s requests from `static_dir` and keep its clients' rooms apart
+    /// from everyone else's; an empty dir removes the host
+    fn setVirtualHost(self: *WebSocketServer, host: []const u8, static_dir: []const u8) !void {
+        if (host.len == 0 or host.len > max_host_name or std.mem.indexOfAny(u8, host, "/: ") != null) return error.InvalidHost;
+        var name_buf: [max_host_name]u8 = undefined;
+        const name = std.ascii.lowerString(&name_buf, host);
+
+        if (static_dir.len == 0) {
+            if (self.virtual_hosts.fetchRemove(name)) |kv| {
+                self.allocator.free(kv.key);
+                self.allocator.free(kv.value);
+            }
+            return;
+        }
+        if (embedded_static.files.len == 0) {
+            var dir = try std.fs.cwd().openDir(static_dir, .{});
+            dir.close();
+        }
+
+        const owned_dir = try self.allocator.dupe(u8, static_dir);
+        errdefer self.allocator.free(owned_dir);
+        const entry = try self.virtual_hosts.getOrPut(self.allocator, name);
+        if (entry.found_existing) {
+            self.allocator.free(entry.value_ptr.*);
+        } else {
+            entry.key_ptr.* = self.allocator.dupe(u8, name) catch |err| {
+                _ = self.virtual_hosts.remove(name);
+                return err;
+            };
+        }
+        entry.value_ptr.* = owned_dir;
+    }
+
+    /// The registered virtual host a request's Host header names (the stored key), if any
+    fn virtualHost(self: *const WebSocketServer, request: *const HttpRequest) ?[]const u8 {
+        if (self.virtual_hosts.count() == 0) return null;
+        const host = requestHostName(request) orelse return null;
+        if (host.len > max_host_name) return null;
+        var name_buf: [max_host_name]u8 = undefined;
+        return self.virtual_hosts.getKey(std.ascii.lowerString(&name_buf, host));
+    }
+
+    /// Static root for a request: its virtual host's, else the configured one
+    fn staticDirFor(self: *const WebSocketServer, request: *const HttpRequest) []const u8 {
+        if (self.virtualHost(request)) |host| return self.virtual_hosts.get(host).?;
+        return self.static_dir orelse "static";
+    }
+
+    /// Send a text message to every client that connected through `host`
+    fn broadcastToHost(self: *WebSocketServer, host: []const u8, message: []const u8) !void {
+        const encoded = try codec.encode(self.allocator, &self.fanout_buf, .text, message, null);
+        defer releasePooled(self.allocator, &self.fanout_buf);
+
+        var it = self.clients.valueIterator();
+        while (it.next()) |client| {
+            if (!client.is_websocket or client.is_closed or client.is_upstream) continue;
+            const client_host = client.data.get("vhost") orelse continue;
+            if (std.ascii.eqlIgnoreCase(client_host, host)) self.sendEncoded(client, encoded, .text, message) catch {};
+        }
+    }
+
+    /// Send a frame to every client, encoding it once: each client then costs one
+    /// write of the shared bytes (or a copy into its outbox)
+    fn broadcastFrame(self: *WebSocketServer, opcode: WebSocketOpcode, payload: []const u8) !void {
+        const encoded = try codec.encode(self.allocator, &self.fanout_buf, opcode, payload, null);
+        defer releasePooled(self.allocator, &self.fanout_buf);
+
+        var it = self.clients.valueIterator();
+        while (it.next()) |client| {
+            if (client.is_websocket and !client.is_closed and !client.is_upstream) {
+                self.sendEncoded(client, encoded, opcode, payload) catch {};
+            }
+        }
+    }
+
+    /// Send a text message to each listed client, encoding the frame once.
+    /// Recipients that could not be sent to are appended to `failures`.
+    fn sendMany(self: *WebSocketServer, client_ids: []const u64, message: []const u8, failures: *std.ArrayListUnmanaged(SendFailure)) !void {
+        const encoded = try codec.encode(self.allocator, &self.fanout_buf, .text, message, null);
+        defer releasePooled(self.allocator, &self.fanout_buf);
+
+        for (client_ids) |client_id| {
+            const client = self.clients.getPtr(client_id) orelse {
+                try failures.append(self.allocator, .{ .client_id = client_id, .err = error.ClientNotFound });
+                continue;
+            };
+            if (!client.is_websocket or client.is_closed) {
+                try failures.append(self.allocator, .{ .client_id = client_id, .err = error.ConnectionClosed });
+                continue;
+            }
+            self.sendEncoded(client, encoded, .text, message) catch |err| {
+                try failures.append(self.allocator, .{ .client_id = client_id, .err = err });
+            };
+        }
+    }
+
+    fn setClientData(self: *WebSocketServer, client_id: u64, key: []const u8, value: []const u8) !void {
+        const client = self.clients.getPtr(client_id) orelse return error.ClientNotFound;
+        try client.setData(self.allocator, key, value);
+    }
+
+    fn getClientData(self: *WebSocketServer, client_id: u64, key: []const u8) ?[]const u8 {
+        const client = self.clients.getPtr(client_id) orelse return null;
+        return client.data.get(key);
+    }
+
+    fn joinRoom(self: *WebSocketServer, client_id: u64, name: []const u8) !void {
+        const client = self.clients.getPtr(client_id) orelse return error.ClientNotFound;
+        var room_buf: [512]u8 = undefined;
+        const room = try clientRoom(client, name, &room_buf);
+        if (!self.permits(client, room, .join)) return error.PermissionDenied;
+
+        const gop = try self.rooms.getOrPut(self.allocator, room);
+        if (!gop.found_existing) {
+            gop.key_ptr.* = self.allocator.dupe(u8, room) catch |err| {
+                _ = self.rooms.remove(room);
+                return err;
+            };
+            gop.value_ptr.* = .{};
+        }
+        const member = try gop.value_ptr.getOrPut(self.allocator, client_id);
+        if (member.found_existing) return;
+
+        // Clients still completing their handshake get the history once connected
+        if (client.is_websocket) self.replayHistory(client, room);
+    }
+
+    /// Send a client the newest of a room's remembered messages, oldest first
+    fn replayHistory(self: *WebSocketServer, client: *WebSocketClient, room: []const u8) void {
+        if (self.history_on_join == 0 or client.is_closed) return;
+        const history = self.room_histories.getPtr(room) orelse return;
+        const count = @min(self.history_on_join, history.messages.items.len);
+        for (history.messages.items.len - count..history.messages.items.len) |i| {
+            self.sendRoomMessage(client, room, history.get(i)) catch return;
+        }
+    }
+
+    /// Replay the history of every room a newly connected client was put in
+    fn replayJoinedRooms(self: *WebSocketServer, client: *WebSocketClient) void {
+        if (self.history_on_join == 0) return;
+        var it = self.rooms.iterator();
+        while (it.next()) |entry| {
+            if (entry.value_ptr.contains(client.id)) self.replayHistory(client, entry.key_ptr.*);
+        }
+    }
+
+    /// Remember a message sent to a room, creating its history on first use
+    fn recordHistory(self: *WebSocketServer, room: []const u8, message: []const u8) !void {
+        if (self.history_size == 0) return;
+        const gop = try self.room_histories.getOrPut(self.allocator, room);
+        if (!gop.found_existing) {
+            gop.key_ptr.* = self.allocator.dupe(u8, room) catch |err| {
+                _ = self.room_histories.remove(room);
+                return err;
+            };
+            gop.value_ptr.* = .{};
+        }
+        try gop.value_ptr.push(self.allocator, self.history_size, message);
+    }
+
+    /// Change how much history is kept and replayed. A new size starts every room afresh.
+    fn setRoomHistory(self: *WebSocketServer, size: usize, on_join: usize) void {
+        if (size != self.history_size) self.clearHistories();
+        self.history_size = size;
+        self.history_on_join = @min(on_join, size);
+    }
+
+    fn clearHistories(self: *WebSocketServer) void {
+        var it = self.room_histories.iterator();
+        while (it.next()) |entry| {
+            entry.value_ptr.deinit(self.allocator);
+            self.allocator.free(entry.key_ptr.*);
+        }
+        self.room_histories.clearRetainingCapacity();
+    }
+
+    /// Write every chat message to files under `path` from now on; "" stops
+    fn setHistoryStore(self: *WebSocketServer, path: []const u8) !void {
+        const store = if (path.len > 0) try HistoryStore.open(path) else null;
+        if (self.history_store) |*old| old.close();
+        self.history_store = store;
+    }
+
+    /// Write a chat message envelope to the history store, if there is one.
+    /// Runs after mutes, room policies and filters, so it keeps what the app sees.
+    fn archiveEvent(self: *WebSocketServer, event: ?WebSocketEvent) ?WebSocketEvent {
+        if (self.history_store == null) return event;
+        const envelope = switch (event orelse return null) {
+            .envelope => |envelope| envelope,
+            else => return event,
+        };
+        if (envelope.kind != .chat_message) return event;
+        self.history_store.?.append(self.allocator, .{
+            .client_id = envelope.client_id,
+            .received_ms = @intCast(@max(std.time.milliTimestamp(), 0)),
+            .room = envelope.room,
+            .text = envelope.payload,
+        }) catch |err| {
+            logger.warn(envelope.client_id, "Could not store message for {s}: {s}", .{ envelope.room, @errorName(err) });
+        };
+        return event;
+    }
+
+    fn leaveRoom(self: *WebSocketServer, client_id: u64, name: []const u8) void {
+        var room_buf: [512]u8 = undefined;
+        const room = if (self.clients.getPtr(client_id)) |client|
+            clientRoom(client, name, &room_buf) catch return
+        else
+            name;
+        const members = self.rooms.getPtr(room) orelse return;
+        _ = members.remove(client_id);
+        if (members.count() == 0) {
+            self.deleteRoom(room);
+        }
+    }
+
+    fn leaveAllRooms(self: *WebSocketServer, client_id: u64) void {
+        // Collect emptied rooms first, removing while iterating invalidates the iterator
+        var empty_rooms = std.ArrayListUnmanaged([]const u8){};
+        defer empty_rooms.deinit(self.allocator);
+
+        var it = self.rooms.iterator();
+        while (it.next()) |entry| {
+            _ = entry.value_ptr.remove(client_id);
+            if (entry.value_ptr.count() == 0) {
+                empty_rooms.append(self.allocator, entry.key_ptr.*) catch {};
+            }
+        }
+
+        for (empty_rooms.items) |room| {
+            self.deleteRoom(room);
+        }
+    }
+
+    fn clearRooms(self: *WebSocketServer) void {
+        var it = self.rooms.iterator();
+        while (it.next()) |entry| {
+            entry.value_ptr.deinit(self.allocator);
+            self.allocator.free(entry.key_ptr.*);
+        }
+        self.rooms.clearRetainingCapacity();
+    }
+
+    fn deleteRoom(self: *WebSocketServer, room: []const u8) void {
+        if (self.rooms.fetchRemove(room)) |kv| {
+            var members = kv.value;
+            members.deinit(self.allocator);
+            self.allocator.free(kv.key);
+        }
+    }
+
+    /// Send a message to a room's members. It is remembered in the room's history
+    /// even when nobody is there.
+    fn broadcastToRoom(self: *WebSocketServer, room: []const u8, message: []const u8) !void {
+        self.recordHistory(room, message) catch |err| {
+            logger.warn(null, "Could not record history for {s}: {s}", .{ room, @errorName(err) });
+        };
+        const members = self.rooms.getPtr(room) orelse return error.RoomNotFound;
+
+        const encoded = try codec.encode(self.allocator, &self.fanout_buf, .text, message, null);
+        defer releasePooled(self.allocator, &self.fanout_buf);
+
+        var it = members.keyIterator();
+        while (it.next()) |client_id| {
+            const client = self.clients.getPtr(client_id.*) orelse continue;
+            if (client.is_websocket and !client.is_closed) {
+                if (client.stomp != null) {
+                    self.sendRoomMessage(client, room, message) catch {};
+                    continue;
+                }
+                self.sendEncoded(client, encoded, .text, message) catch {};
+            }
+        }
+    }
+
+    /// Number a message with the room's next sequence number and send it to the
+    /// room as {"type":"message","room","seq","text"}. The envelope is logged for
+    /// replay_since! even when nobody is in the room.
+    fn publishToRoom(self: *WebSocketServer, room: []const u8, message: []const u8) !u64 {
+        const gop = try self.room_logs.getOrPut(self.allocator, room);
+        if (!gop.found_existing) {
+            gop.key_ptr.* = self.allocator.dupe(u8, room) catch |err| {
+                _ = self.room_logs.remove(room);
+                return err;
+            };
+            gop.value_ptr.* = .{};
+        }
+        const room_log = gop.value_ptr;
+        const seq = room_log.next_seq;
+
+        var json = std.ArrayListUnmanaged(u8){};
+        errdefer json.deinit(self.allocator);
+        const w = json.writer(self.allocator);
+        try w.writeAll("{\"type\":\"message\",\"room\":");
+        try writeJsonString(w, room);
+        try w.print(",\"seq\":{d},\"text\":", .{seq});
+        try writeJsonString(w, message);
+        try w.writeByte('}');
+
+        const envelope = try json.toOwnedSlice(self.allocator);
+        if (room_log.entries.items.len >= max_replay_messages) {
+            self.allocator.free(room_log.entries.orderedRemove(0).envelope);
+        }
+        room_log.entries.append(self.allocator, .{ .seq = seq, .envelope = envelope }) catch |err| {
+            self.allocator.free(envelope);
+            return err;
+        };
+        room_log.next_seq += 1;
+
+        self.broadcastToRoom(room, envelope) catch |err| switch (err) {
+            error.RoomNotFound => {},
+            else => return err,
+        };
+        return seq;
+    }
+
+    /// Resend a room's logged messages numbered after `since` to one client.
+    /// Messages already dropped from the log are announced first as
+    /// {"type":"gap","room","from","to"}. Returns how many messages were resent.
+    fn replaySince(self: *WebSocketServer, client_id: u64, room: []const u8, since: u64) !u64 {
+        const client = self.clients.getPtr(client_id) orelse return error.ClientNotFound;
+        if (!client.is_websocket or client.is_closed) return error.NotConnected;
+        const room_log = self.room_logs.getPtr(room) orelse return 0;
+        if (since >= room_log.next_seq - 1) return 0;
+
+        const entries = room_log.entries.items;
+        const oldest = if (entries.len > 0) entries[0].seq else room_log.next_seq;
+        if (oldest > since + 1) {
+            var json = std.ArrayListUnmanaged(u8){};
+            defer json.deinit(self.allocator);
+            const w = json.writer(self.allocator);
+            try w.writeAll("{\"type\":\"gap\",\"room\":");
+            try writeJsonString(w, room);
+            try w.print(",\"from\":{d},\"to\":{d}}}", .{ since + 1, oldest - 1 });
+            try self.sendFrame(client, .text, json.items);
+        }
+
+        var replayed: u64 = 0;
+        for (entries) |entry| {
+            if (entry.seq <= since) continue;
+            try self.sendFrame(client, .text, entry.envelope);
+            replayed += 1;
+        }
+        return replayed;
+    }
+
+    /// Fan an ephemeral signal (typing, presence) out to a room as
+    /// {"type":"notify","room","kind","payload"}; SSE clients get it as "event: notify".
+    /// Unlike messages these are throttled: a signal identical to one sent in the
+    /// last notify_min_interval_ms is dropped with error.Throttled.
+    fn notifyRoom(self: *WebSocketServer, room: []const u8, kind: []const u8, payload: []const u8) !void {
+        const members = self.rooms.getPtr(room) orelse return error.RoomNotFound;
+        if (kind.len == 0) return error.InvalidKind;
+
+        const now = std.time.milliTimestamp();
+        var hasher = std.hash.Wyhash.init(0);
+        for ([_][]const u8{ room, kind, payload }) |part| {
+            hasher.update(part);
+            hasher.update(&[_]u8{0});
+        }
+        if (self.recent_notifications.count() >= max_recent_notifications) self.pruneNotifications(now);
+        const gop = try self.recent_notifications.getOrPut(self.allocator, hasher.final());
+        if (gop.found_existing and now - gop.value_ptr.* < notify_min_interval_ms) return error.Throttled;
+        gop.value_ptr.* = now;
+
+        var json = std.ArrayListUnmanaged(u8){};
+        defer json.deinit(self.allocator);
+        const w = json.writer(self.allocator);
+        try w.writeAll("{\"type\":\"notify\",\"room\":");
+        try writeJsonString(w, room);
+        try w.writeAll(",\"kind\":");
+        try writeJsonString(w, kind);
+        try w.writeAll(",\"payload\":");
+        try writeJsonString(w, payload);
+        try w.writeByte('}');
+
+        const encoded = try codec.encode(self.allocator, &self.fanout_buf, .text, json.items, null);
+        defer releasePooled(self.allocator, &self.fanout_buf);
+
+        var it = members.keyIterator();
+        while (it.next()) |client_id| {
+            const client = self.clients.getPtr(client_id.*) orelse continue;
+            if (!client.is_websocket or client.is_closed) continue;
+            if (client.is_sse) {
+                self.queueBytes(client, "event: notify\ndata: ") catch continue;
+                self.queueBytes(client, json.items) catch continue;
+                self.queueBytes(client, "\n\n") catch {};
+            } else {
+                self.sendEncoded(client, encoded, .text, json.items) catch {};
+            }
+        }
+    }
+
+    fn pruneNotifications(self: *WebSocketServer, now_ms: i64) void {
+        var stale = std.ArrayListUnmanaged(u64){};
+        defer stale.deinit(self.allocator);
+
+        var it = self.recent_notifications.iterator();
+        while (it.next()) |entry| {
+            if (now_ms - entry.value_ptr.* >= notify_min_interval_ms) {
+                stale.append(self.allocator, entry.key_ptr.*) catch break;
+            }
+        }
+        for (stale.items) |key| _ = self.recent_notifications.remove(key);
+    }
+
+    /// Dial a ws:// URL and register it as an upstream connection.
+    /// Text frames it sends arrive as upstream_message events; send() and close() work on the returned id.
+    fn connectUpstream(self: *WebSocketServer, url: []const u8) !u64 {
+        const target = try parseWsUrl(url);
+
+        const stream = try std.net.tcpConnectToHost(self.allocator, target.host, target.port);
+        errdefer stream.close();
+
+        var key_bytes: [16]u8 = undefined;
+        std.crypto.random.bytes(&key_bytes);
+        var key: [24]u8 = undefined;
+        _ = std.base64.standard.Encoder.encode(&key, &key_bytes);
+
+        var request_buf: [1024]u8 = undefined;
+        const request = try std.fmt.bufPrint(&request_buf, "GET {s} HTTP/1.1\r\nHost: {s}:{d}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {s}\r\nSec-WebSocket-Version: 13\r\n\r\n", .{ target.path, target.host, target.port, key });
+        _ = try stream.write(request);
+
+        // Read the response one byte at a time so no frame data is consumed
+        var response_buf: [4096]u8 = undefined;
+        var response_len: usize = 0;
+        while (!std.mem.endsWith(u8, response_buf[0..response_len], "\r\n\r\n")) {
+            if (response_len == response_buf.len) return error.HandshakeFailed;
+            const n = try stream.read(response_buf[response_len .. response_len + 1]);
+            if (n == 0) return error.HandshakeFailed;
+            response_len += n;
+        }
+        const response = response_buf[0..response_len];
+
+        if (!std.mem.startsWith(u8, response, "HTTP/1.1 101")) return error.HandshakeFailed;
+        const expected = computeAcceptKey(&key);
+        const accept_header = "Sec-WebSocket-Accept: ";
+        const accept_start = std.ascii.indexOfIgnoreCase(response, accept_header) orelse return error.HandshakeFailed;
+        const accept_value = response[accept_start + accept_header.len ..];
+        if (!std.mem.startsWith(u8, accept_value, &expected)) return error.HandshakeFailed;
+
+        const client_id = self.next_client_id;
+        self.next_client_id += 1;
+
+        try self.clients.put(client_id, .{
+            .id = client_id,
+            .stream = stream,
+            .is_websocket = true,
+            .is_upstream = true,
+            .last_activity_ms = std.time.milliTimestamp(),
+            .connected_ms = std.time.milliTimestamp(),
+        });
+        return client_id;
+    }
+
+    /// Read what stdin has and queue an AdminCommand for each complete line.
+    /// Watching stops at end of input.
+    fn readAdminCommands(self: *WebSocketServer) void {
+        var buf: [1024]u8 = undefined;
+        const n = std.posix.read(std.posix.STDIN_FILENO, &buf) catch |err| {
+            logger.warn(null, "Reading stdin failed, no longer watching it: {s}", .{@errorName(err)});
+            self.watch_stdin = false;
+            return;
+        };
+        if (n == 0) {
+            logger.info(null, "Stdin closed, no longer watching it", .{});
+            self.watch_stdin = false;
+            return;
+        }
+        self.stdin_buf.appendSlice(self.allocator, buf[0..n]) catch return;
+
+        while (std.mem.indexOfScalar(u8, self.stdin_buf.items, '\n')) |end| {
+            const line = std.mem.trimRight(u8, self.stdin_buf.items[0..end], "\r");
+            if (line.len > 0) {
+                if (self.allocator.dupe(u8, line)) |command| {
+                    self.queueEvent(.{ .admin_command = command }) catch {};
+                } else |_| {}
+            }
+            self.stdin_buf.replaceRangeAssumeCapacity(0, end + 1, &.{});
+        }
+        if (self.stdin_buf.items.len > max_admin_line) {
+            logger.warn(null, "Discarding over-long stdin line", .{});
+            self.stdin_buf.clearRetainingCapacity();
+        }
+    }
+
+    /// Act on signals the handler wrote to the signal pipe (never on Windows,
+    /// where watchSignal installs nothing)
+    fn readSignals(self: *WebSocketServer) void {
+        if (@import("builtin").os.tag != .windows) {
+            const fds = signal_pipe orelse return;
+            var buf: [16]u8 = undefined;
+            const n = std.posix.read(fds[0], &buf) catch return;
+            for (buf[0..n]) |sig| {
+                switch (sig) {
+                    std.posix.SIG.HUP => self.reloadConfig(),
+                    std.posix.SIG.INT, std.posix.SIG.TERM => {
+                        // A second signal skips the rest of the grace period
+                        if (self.drain_until_ms != 0) {
+                            logger.info(null, "Signal {d} while draining, shutting down now", .{sig});
+                            self.shutdown();
+                        } else {
+                            logger.info(null, "Signal {d}, draining", .{sig});
+                            self.drain(signal_drain_grace_ms, "Server shutting down");
+                        }
+                    },
+                    else => {},
+                }
+            }
+        }
+    }
+
+    /// Read a configuration file and apply it, remembering the path for SIGHUP.
+    /// Nothing changes if the file cannot be read, parsed or applied.
+    fn loadConfig(self: *WebSocketServer, path: []const u8, err_line: *usize) !void {
+        var config = try ServerConfig.fromFile(self.allocator, path, err_line);
+        defer config.deinit();
+        try self.applyConfig(&config);
+        try self.watchConfigFile(path);
+        logger.info(null, "Loaded configuration from {s}", .{path});
+    }
+
+    /// Remember the file SIGHUP rereads
+    fn watchConfigFile(self: *WebSocketServer, path: []const u8) !void {
+        if (self.config_path) |current| {
+            if (std.mem.eql(u8, current, path)) return;
+        } else if (@import("builtin").os.tag != .windows) {
+            watchSignal(std.posix.SIG.HUP) catch |err| {
+                logger.warn(null, "Cannot reload on SIGHUP: {s}", .{@errorName(err)});
+            };
+        }
+        const owned = try self.allocator.dupe(u8, path);
+        if (self.config_path) |old| self.allocator.free(old);
+        self.config_path = owned;
+    }
+
+    /// Reread the configuration file after SIGHUP; a broken file is reported as
+    /// an Error event and the current settings stay
+    fn reloadConfig(self: *WebSocketServer) void {
+        const path = self.config_path orelse return;
+        var err_line: usize = 0;
+        self.loadConfig(path, &err_line) catch |err| {
+            const event = if (err_line > 0)
+                self.errorEvent("Reloading {s} failed on line {d}: {s}", .{ path, err_line, @errorName(err) })
+            else
+                self.errorEvent("Reloading {s} failed: {s}", .{ path, @errorName(err) });
+            self.queueEvent(event) catch {};
+        };
+    }
+
+    /// Apply the settings a configuration file sets. Open connections keep what
+    /// they negotiated; limits apply to everything read from now on.
+    fn applyConfig(self: *WebSocketServer, config: *const ServerConfig) !void {
+        // The origin list is rebuilt aside and swapped back if anything fails
+        var previous_origins = std.ArrayListUnmanaged([]const u8){};
+        defer {
+            for (previous_origins.items) |origin| self.allocator.free(origin);
+            previous_origins.deinit(self.allocator);
+        }
+        if (config.allowed_origins != null) std.mem.swap(std.ArrayListUnmanaged([]const u8), &self.allowed_origins, &previous_origins);
+        errdefer if (config.allowed_origins != null) std.mem.swap(std.ArrayListUnmanaged([]const u8), &self.allowed_origins, &previous_origins);
+        if (config.allowed_origins) |origins| {
+            for (origins) |origin| try self.allowOrigin(origin);
+        }
+
+        if (config.static_dir != null or config.index_file != null or config.spa_fallback != null) {
+            try self.configure(
+                config.static_dir orelse self.static_dir orelse "static",
+                config.index_file orelse self.index_file orelse default_index_files,
+                config.spa_fallback orelse self.spa_fallback,
+            );
+        }
+        if (config.static_cache_bytes) |limit| {
+            self.clearStaticCache();
+            self.static_cache_limit = @intCast(limit);
+        }
+        if (config.static_buffer_bytes) |limit| {
+            self.clearStaticCache();
+            self.static_buffer_limit = @intCast(limit);
+        }
+        if (config.directory_listing) |enabled| self.directory_listing = enabled;
+
+        for (config.rooms.items) |route| {
+            // A reload lists the routes added last time again
+            const known = for (self.ws_endpoints.items) |endpoint| {
+                if (std.mem.eql(u8, endpoint.pattern, route.pattern)) break true;
+            } else false;
+            if (!known) try self.addWsEndpoint(route.pattern, route.room);
+        }
+        for (config.vhosts.items) |vhost| try self.setVirtualHost(vhost.host, vhost.static_dir);
+        for (config.routes.items) |route| try self.addRoute(route.method, route.pattern, route.target);
+        if (config.base_path) |path| try self.setBasePath(path);
+        if (config.jwt_secret) |secret| try self.setAuthSecret(secret);
+        if (config.session_secret) |secret| try self.setSessionSecret(secret);
+        if (config.admin_token) |token| try self.setAdminToken(token);
+        if (config.hook_secret) |secret| try self.setHookSecret(secret);
+
+        if (config.log_level) |level| logger.level = level;
+        if (config.log_json) |json| logger.json = json;
+        if (config.max_connections) |limit| self.max_connections = @intCast(limit);
+        if (config.max_frame_size) |limit| self.max_frame_size = @intCast(limit);
+        if (config.max_message_size) |limit| self.max_message_size = @intCast(limit);
+        if (config.max_body_size) |limit| self.max_body_size = @intCast(limit);
+        if (config.max_header_size) |limit| self.max_header_size = @intCast(limit);
+        if (config.send_queue_bytes) |limit| self.max_outbox_bytes = @intCast(limit);
+        if (config.event_queue_limit) |limit| self.event_queue_limit = @intCast(limit);
+        if (config.event_overflow) |policy| self.event_overflow = policy;
+        if (config.history_size != null or config.history_on_join != null) {
+            self.setRoomHistory(
+                @intCast(config.history_size orelse self.history_size),
+                @intCast(config.history_on_join orelse self.history_on_join),
+            );
+        }
+        if (config.history_dir) |dir| try self.setHistoryStore(dir);
+        if (config.filter_words) |words| try self.content_filter.setWords(self.allocator, words);
+        if (config.filter_patterns) |patterns| try self.content_filter.setPatterns(self.allocator, patterns);
+        if (config.filter_max_length) |limit| self.content_filter.max_length = @intCast(limit);
+        if (config.filter_strip_urls) |strip| self.content_filter.strip_urls = strip;
+        if (config.filter_action) |action| self.content_filter.action = action;
+        if (config.upload_dir != null or config.upload_max_size != null or config.upload_types != null) {
+            try self.setUploads(
+                config.upload_dir orelse self.upload_dir orelse return error.MissingUploadDir,
+                @intCast(config.upload_max_size orelse self.max_upload_size),
+                config.upload_types orelse self.upload_types.items,
+            );
+        }
+    }
+
+    /// Queue a Timer event carrying `tag` once `delay_ms` has passed
+    fn setTimer(self: *WebSocketServer, delay_ms: u64, tag: []const u8) !u64 {
+        const owned_tag = try self.allocator.dupe(u8, tag);
+        errdefer self.allocator.free(owned_tag);
+
+        const timer_id = self.next_timer_id;
+        const delay: i64 = @intCast(@min(delay_ms, std.math.maxInt(i32)));
+        try self.timers.append(self.allocator, .{
+            .id = timer_id,
+            .due_ms = std.time.milliTimestamp() + delay,
+            .tag = owned_tag,
+        });
+        self.next_timer_id += 1;
+        return timer_id;
+    }
+
+    /// Unknown or already fired timers are ignored
+    fn cancelTimer(self: *WebSocketServer, timer_id: u64) void {
+        for (self.timers.items, 0..) |timer, index| {
+            if (timer.id != timer_id) continue;
+            self.allocator.free(timer.tag);
+            _ = self.timers.swapRemove(index);
+            return;
+        }
+    }
+
+    /// Queue Timer events for every deadline that has passed, earliest first
+    fn fireTimers(self: *WebSocketServer) void {
+        const now = std.time.milliTimestamp();
+        while (true) {
+            var earliest: ?usize = null;
+            for (self.timers.items, 0..) |timer, index| {
+                if (timer.due_ms > now) continue;
+                if (earliest == null or timer.due_ms < self.timers.items[earliest.?].due_ms) earliest = index;
+            }
+            const index = earliest orelse return;
+            // The event takes over the tag
+            const timer = self.timers.swapRemove(index);
+            self.queueEvent(.{ .timer = .{ .timer_id = timer.id, .tag = timer.tag } }) catch {};
+        }
+    }
+
+    fn nextTimerDue(self: *const WebSocketServer) ?i64 {
+        var due: ?i64 = null;
+        for (self.timers.items) |timer| {
+            if (due == null or timer.due_ms < due.?) due = timer.due_ms;
+        }
+        return due;
+    }
+
+    /// POST `events` to an http:// URL, signed with `secret` when it is not empty
+    fn addWebhook(self: *WebSocketServer, url: []const u8, events: WebhookEvents, secret: []const u8) !void {
+        const owned_url = try self.allocator.dupe(u8, url);
+        errdefer self.allocator.free(owned_url);
+        const target = try splitUrl(owned_url, "http://");
+        const owned_secret = try self.allocator.dupe(u8, secret);
+        errdefer self.allocator.free(owned_secret);
+
+        try self.webhooks.add(.{
+            .url = owned_url,
+            .host = target.host,
+            .port = target.port,
+            .path = target.path,
+            .secret = owned_secret,
+            .events = events,
+        });
+    }
+
+    /// Queue a POST of this event to every webhook subscribed to it
+    fn queueWebhooks(self: *WebSocketServer, event: WebSocketEvent) void {
+        if (self.webhooks.hooks.items.len == 0) return;
+
+        var json = std.ArrayListUnmanaged(u8){};
+        defer json.deinit(self.allocator);
+        const w = json.writer(self.allocator);
+        const kind: WebhookEvents = switch (event) {
+            .message => |msg| blk: {
+                w.print("{{\"event\":\"message\",\"clientId\":{d},\"text\":", .{msg.client_id}) catch return;
+                writeJsonString(w, msg.text) catch return;
+                w.writeByte('}') catch return;
+                break :blk .{ .message = true };
+            },
+            .envelope => |envelope| blk: {
+                if (envelope.kind != .chat_message) return;
+                w.print("{{\"event\":\"message\",\"clientId\":{d},\"room\":", .{envelope.client_id}) catch return;
+                writeJsonString(w, envelope.room) catch return;
+                w.writeAll(",\"text\":") catch return;
+                writeJsonString(w, envelope.payload) catch return;
+                w.writeByte('}') catch return;
+                break :blk .{ .message = true };
+            },
+            .connected => |client_id| blk: {
+                w.print("{{\"event\":\"connect\",\"clientId\":{d}}}", .{client_id}) catch return;
+                break :blk .{ .connect = true };
+            },
+            .disconnected => |info| blk: {
+                w.print("{{\"event\":\"disconnect\",\"clientId\":{d},\"code\":{d}}}", .{ info.client_id, info.code }) catch return;
+                break :blk .{ .disconnect = true };
+            },
+            else => return,
+        };
+
+        for (self.webhooks.hooks.items, 0..) |hook, index| {
+            if (!hook.events.has(kind)) continue;
+            const body = self.allocator.dupe(u8, json.items) catch return;
+            self.webhooks.enqueue(index, body) catch |err| switch (err) {
+                error.QueueFull => {
+                    logger.warn(null, "Webhook queue full, dropping event for {s}", .{hook.url});
+                    return;
+                },
+                error.OutOfMemory => return,
+            };
+        }
+    }
+
+    /// Send a Close frame carrying a status code and optional reason
+    fn sendClose(self: *WebSocketServer, client: *WebSocketClient, code: u16, reason: []const u8) !void {
+        var buf: [codec.max_control_payload]u8 = undefined;
+        try self.sendFrame(client, .close, codec.writeClose(&buf, code, reason));
+    }
+
+    /// Stop accepting connections, close every client with 1001 (Going Away)
+    /// and queue a Shutdown event so the application's event loop can exit
+    fn shutdown(self: *WebSocketServer) void {
+        if (!self.is_running) return;
+        self.closeAll(close_going_away, "Server shutting down");
+        self.queueEvent(.shutdown) catch {};
+    }
+
+    /// Turn new connections away, tell connected clients `message`, then shut down
+    /// once every outbox has been written or `grace_ms` has passed
+    fn drain(self: *WebSocketServer, grace_ms: u64, message: []const u8) void {
+        if (!self.is_running or self.drain_until_ms != 0) return;
+        logger.info(null, "Draining, closing clients within {d}ms", .{grace_ms});
+        if (message.len > 0) self.broadcast(message) catch {};
+        const grace: i64 = @intCast(@min(grace_ms, std.math.maxInt(i32)));
+        self.drain_until_ms = std.time.milliTimestamp() + grace;
+    }
+
+    fn checkDrain(self: *WebSocketServer) void {
+        if (self.drain_until_ms == 0 or !self.is_running) return;
+        if (std.time.milliTimestamp() < self.drain_until_ms) {
+            var it = self.clients.valueIterator();
+            while (it.next()) |client| {
+                if (client.outbox.items.len > 0) return;
+            }
+        }
+        self.shutdown();
+    }
+
+    /// Stop listening and close every client with the given status
+    fn closeAll(self: *WebSocketServer, code: u16, reason: []const u8) void {
+        self.is_running = false;
+
+        if (self.listener) |*l| {
+            l.deinit();
+            self.listener = null;
+        }
+
+        var it = self.clients.valueIterator();
+        while (it.next()) |client| {
+            if (client.is_websocket and !client.is_closed) {
+                self.sendClose(client, code, reason) catch {};
+            }
+            self.failPendingAcks(client, "Server shutting down");
+            client.deinit(self.allocator);
+        }
+        self.clients.clearRetainingCapacity();
+        self.clearRooms();
+    }
+
+    /// Close an answered HTTP connection once the poll loop has written the rest
+    /// of its response, or right away if the socket took all of it
+    fn finishHttp(self: *WebSocketServer, client_id: u64) void {
+        const client = self.clients.getPtr(client_id) orelse return;
+        if (client.download == null and (client.outbox.items.len == 0 or client.outbox_overflowed)) {
+            return self.removeClient(client_id);
+        }
+        // Clients turned away after the upgrade get nothing but the rest of it
+        client.is_websocket = false;
+        client.closing = true;
+        client.http_pending = false;
+        client.handshake_pending = false;
+        client.last_activity_ms = std.time.milliTimestamp();
+    }
+
+    fn removeClient(self: *WebSocketServer, client_id: u64) void {
+        self.leaveAllRooms(client_id);
+        if (self.clients.fetchRemove(client_id)) |kv| {
+            var client = kv.value;
+            self.failPendingAcks(&client, "Connection lost");
+            client.deinit(self.allocator);
+        }
+    }
+
+    /// Ping quiet clients and drop those that stopped answering.
+    /// Timed-out clients are queued as Disconnected events.
+    fn checkKeepalive(self: *WebSocketServer) void {
+        const now = std.time.milliTimestamp();
+
+        // Collect first, removing while iterating invalidates the iterator
+        var timed_out = std.ArrayListUnmanaged(u64){};
+        defer timed_out.deinit(self.allocator);
+
+        var it = self.clients.valueIterator();
+        while (it.next()) |client| {
+            if (!client.is_websocket or client.is_closed or client.detached_until_ms != 0) continue;
+
+            if (client.is_socketio and now - client.last_ping_ms >= socketio_ping_interval_ms) {
+                // Engine.IO clients give up on a server whose "2" is late, busy or not
+                self.writeFrame(client, .text, "2") catch {};
+                client.last_ping_ms = now;
+            }
+
+            if (client.is_sse) {
+                // SSE clients cannot answer pings; a comment keeps proxies from timing out
+                // and dead connections surface as write errors
+                if (self.keepalive_interval_ms > 0 and now - client.last_ping_ms >= self.keepalive_interval_ms) {
+                    self.sendFrame(client, .ping, "") catch {};
+                    client.last_ping_ms = now;
+                }
+                continue;
+            }
+
+            const quiet_ms = now - client.last_activity_ms;
+            if (self.idle_timeout_ms > 0 and quiet_ms >= self.idle_timeout_ms) {
+                timed_out.append(self.allocator, client.id) catch {};
+            } else if (self.keepalive_interval_ms > 0 and
+                quiet_ms >= self.keepalive_interval_ms and
+                now - client.last_ping_ms >= self.keepalive_interval_ms)
+            {
+                self.sendFrame(client, .ping, "") catch {};
+                client.last_ping_ms = now;
+            }
+        }
+
+        for (timed_out.items) |client_id| {
+            const event = self.dropClient(client_id, close_going_away, "Idle timeout");
+            self.queueEvent(event) catch {};
+        }
+    }
+
+    /// Close a client with the given status code, giving the peer a short window
+    /// to echo the Close frame before the socket is dropped
+    fn closeClient(self: *WebSocketServer, client_id: u64, code: u16, reason: []const u8) void {
+        logger.info(client_id, "Closing connection ({d})", .{code});
+        self.leaveAllRooms(client_id);
+        if (self.clients.fetchRemove(client_id)) |kv| {
+            var client = kv.value;
+            if (client.is_websocket and !client.is_closed) {
+                self.sendClose(&client, code, reason) catch {};
+                if (!client.is_sse) awaitCloseEcho(&client);
+            }
+            self.failPendingAcks(&client, "Connection closed");
+            client.deinit(self.allocator);
+        }
+    }
+
+    fn awaitCloseEcho(client: *WebSocketClient) void {
+        var fds = [_]std.posix.pollfd{.{
+            .fd = client.stream.handle,
+            .events = std.posix.POLL.IN,
+            .revents = 0,
+        }};
+        const deadline = std.time.milliTimestamp() + close_echo_timeout_ms;
+        var buf: [256]u8 = undefined;
+
+        while (true) {
+            const remaining = deadline - std.time.milliTimestamp();
+            if (remaining <= 0) return;
+
+            const ready = std.posix.poll(&fds, @intCast(remaining)) catch return;
+            if (ready == 0) return;
+
+            // Peer closed the TCP connection or answered with its own Close frame
+            const n = client.stream.read(&buf) catch return;
+            if (n == 0) return;
+            if (buf[0] & 0x0F == @intFromEnum(WebSocketOpcode.close)) return;
+        }
+    }
+};
+
+/// Frames up to this size are assembled on the stack before sending
+const small_frame_size = 4096;
+
+/// Options applied to each accepted socket
+const SocketOptions = struct {
+    /// Send small frames immediately instead of coalescing them (TCP_NODELAY)
+    no_delay: bool = true,
+    /// Let the kernel probe idle connections (SO_KEEPALIVE)
+    keepalive: bool = false,
+    /// Kernel send/receive buffer sizes in bytes; 0 keeps the system default
+    send_buffer: u32 = 0,
+    recv_buffer: u32 = 0,
+
+    fn apply(self: SocketOptions, stream: std.net.Stream) void {
+        const fd = stream.handle;
+        setIntOption(fd, std.posix.IPPROTO.TCP, std.posix.TCP.NODELAY, @intFromBool(self.no_delay));
+        setIntOption(fd, std.posix.SOL.SOCKET, std.posix.SO.KEEPALIVE, @intFromBool(self.keepalive));
+        if (self.send_buffer > 0) setIntOption(fd, std.posix.SOL.SOCKET, std.posix.SO.SNDBUF, self.send_buffer);
+        if (self.recv_buffer > 0) setIntOption(fd, std.posix.SOL.SOCKET, std.posix.SO.RCVBUF, self.recv_buffer);
+    }
+
+    fn setIntOption(fd: std.posix.socket_t, level: i32, name: u32, value: u32) void {
+        const int_value: c_int = @intCast(@min(value, std.math.maxInt(c_int)));
+        std.posix.setsockopt(fd, level, name, std.mem.asBytes(&int_value)) catch |err| {
+            logger.debug(null, "setsockopt({d}, {d}) failed: {s}", .{ level, name, @errorName(err) });
+        };
+    }
+};
+
+/// Roc { bytes_in, bytes_out, clients, connections, messages_in, messages_out,
+/// queued_events, rooms, uptime_ms }; all U64, so fields are in name order
+const ServerStats = extern struct {
+    bytes_in: u64,
+    bytes_out: u64,
+    /// Connected WebSocket and SSE clients
+    clients: u64,
+    /// Open sockets, including HTTP requests in progress
+    connections: u64,
+    messages_in: u64,
+    messages_out: u64,
+    queued_events: u64,
+    rooms: u64,
+    uptime_ms: u64,
+};
+
+/// Roc { bytes_in, bytes_out, connected_ms, last_activity_ms, messages_in,
+/// messages_out, queued_bytes }; all U64, so fields are in name order
+const ClientStats = extern struct {
+    bytes_in: u64,
+    bytes_out: u64,
+    /// Accept time, ms since epoch
+    connected_ms: u64,
+    last_activity_ms: u64,
+    messages_in: u64,
+    messages_out: u64,
+    /// Outbox depth: bytes accepted but not yet written to the socket
+    queued_bytes: u64,
+};
+
+fn clientStats(client: *const WebSocketClient) ClientStats {
+    return .{
+        .bytes_in = client.traffic.bytes_in,
+        .bytes_out = client.traffic.bytes_out,
+        .connected_ms = @intCast(@max(client.connected_ms, 0)),
+        .last_activity_ms = @intCast(@max(client.last_activity_ms, 0)),
+        .messages_in = client.traffic.messages_in,
+        .messages_out = client.traffic.messages_out,
+        .queued_bytes = client.outbox.items.len,
+    };
+}
+
+/// Whether the request's `Authorization: Bearer` token equals `expected`.
+/// Digests are compared so neither the length nor the content leaks through timing.
+fn bearerMatches(request: *const HttpRequest, expected: []const u8) bool {
+    const authorization = request.header("Authorization") orelse "";
+    const bearer = "Bearer ";
+    const presented = if (std.ascii.startsWithIgnoreCase(authorization, bearer)) authorization[bearer.len..] else "";
+    var presented_digest: [32]u8 = undefined;
+    var expected_digest: [32]u8 = undefined;
+    std.crypto.hash.sha2.Sha256.hash(presented, &presented_digest, .{});
+    std.crypto.hash.sha2.Sha256.hash(expected, &expected_digest, .{});
+    return std.crypto.timing_safe.eql([32]u8, presented_digest, expected_digest);
+}
+
+fn isLoopback(address: std.net.Address) bool {
+    return switch (address.any.family) {
+        // 127.0.0.0/8; the address is stored in network byte order
+        std.posix.AF.INET => std.mem.asBytes(&address.in.sa.addr)[0] == 127,
+        std.posix.AF.INET6 => std.mem.eql(u8, &address.in6.sa.addr, &([_]u8{0} ** 15 ++ [_]u8{1})),
+        else => false,
+    };
+}
+
+/// Events a webhook can subscribe to
+const WebhookEvents = packed struct(u8) {
+    message: bool = false,
+    connect: bool = false,
+    disconnect: bool = false,
+    _padding: u5 = 0,
+
+    fn add(self: *WebhookEvents, name: []const u8) !void {
+        if (std.mem.eql(u8, name, "message")) {
+            self.message = true;
+        } else if (std.mem.eql(u8, name, "connect")) {
+            self.connect = true;
+        } else if (std.mem.eql(u8, name, "disconnect")) {
+            self.disconnect = true;
+        } else return error.UnknownEvent;
+    }
+
+    fn has(self: WebhookEvents, kind: WebhookEvents) bool {
+        return @as(u8, @bitCast(self)) & @as(u8, @bitCast(kind)) != 0;
+    }
+};
+
+/// An endpoint that receives events as JSON POSTs
+const Webhook = struct {
+    /// http:// URL (owned); host and path point into it
+    url: []u8,
+    host: []const u8,
+    port: u16,
+    path: []const u8,
+    /// HMAC-SHA256 key for X-Webhook-Signature (owned); empty sends no signature
+    secret: []u8,
+    events: WebhookEvents,
+};
+
+/// One event's POST to one webhook, retried until it succeeds or runs out of attempts
+const WebhookDelivery = struct {
+    /// Index into the server's webhooks
+    hook: usize,
+    /// JSON body (owned)
+    body: []u8,
+    attempts: u8 = 0,
+    next_attempt_ms: i64 = 0,
+};
+
+/// Webhook endpoints and their pending POSTs. A thread of its own delivers them,
+/// so DNS lookups, connects and slow endpoints never hold up the poll loop.
+const WebhookWorker = struct {
+    allocator: std.mem.Allocator,
+    /// Guards queue and stopping, and hooks while they are appended to
+    mutex: std.Thread.Mutex = .{},
+    /// Signalled when a POST is queued or the thread should stop
+    wake: std.Thread.Condition = .{},
+    /// Registered endpoints; only the poll loop's thread adds them and none are removed
+    hooks: std.ArrayListUnmanaged(Webhook) = .{},
+    /// POSTs not yet accepted by their endpoint, oldest first
+    queue: std.ArrayListUnmanaged(WebhookDelivery) = .{},
+    thread: ?std.Thread = null,
+    stopping: bool = false,
+
+    /// Stop the thread, waiting out any POST in flight, and free what is left
+    fn deinit(self: *WebhookWorker) void {
+        if (self.thread) |thread| {
+            self.mutex.lock();
+            self.stopping = true;
+            self.mutex.unlock();
+            self.wake.signal();
+            thread.join();
+        }
+        for (self.hooks.items) |hook| {
+            self.allocator.free(hook.url);
+            self.allocator.free(hook.secret);
+        }
+        self.hooks.deinit(self.allocator);
+        for (self.queue.items) |delivery| self.allocator.free(delivery.body);
+        self.queue.deinit(self.allocator);
+    }
+
+    /// Register an endpoint, starting the thread along with the first one. Takes
+    /// the hook's strings only on success.
+    fn add(self: *WebhookWorker, hook: Webhook) !void {
+        if (self.thread == null) self.thread = try std.Thread.spawn(.{}, run, .{self});
+        self.mutex.lock();
+        defer self.mutex.unlock();
+        try self.hooks.append(self.allocator, hook);
+    }
+
+    /// Queue a POST of `body` to the hook at `index`. Takes the body.
+    fn enqueue(self: *WebhookWorker, index: usize, body: []u8) error{ QueueFull, OutOfMemory }!void {
+        self.mutex.lock();
+        defer self.mutex.unlock();
+        errdefer self.allocator.free(body);
+        if (self.queue.items.len >= max_webhook_queue) return error.QueueFull;
+        try self.queue.append(self.allocator, .{ .hook = index, .body = body });
+        self.wake.signal();
+    }
+
+    /// Deliver due POSTs one at a time until stopped. Failed POSTs are retried
+    /// with exponential backoff and dropped after max_webhook_attempts.
+    fn run(self: *WebhookWorker) void {
+        self.mutex.lock();
+        defer self.mutex.unlock();
+        while (!self.stopping) {
+            const now = std.time.milliTimestamp();
+            var due: ?usize = null;
+            var next_due: ?i64 = null;
+            for (self.queue.items, 0..) |delivery, index| {
+                if (delivery.next_attempt_ms <= now) {
+                    due = index;
+                    break;
+                }
+                if (next_due == null or delivery.next_attempt_ms < next_due.?) next_due = delivery.next_attempt_ms;
+            }
+            const index = due orelse {
+                if (next_due) |at| {
+                    self.wake.timedWait(&self.mutex, @as(u64, @intCast(at - now)) * std.time.ns_per_ms) catch {};
+                } else {
+                    self.wake.wait(&self.mutex);
+                }
+                continue;
+            };
+
+            // The hook's strings stay put even if hooks grows while unlocked
+            var delivery = self.queue.orderedRemove(index);
+            const hook = self.hooks.items[delivery.hook];
+            self.mutex.unlock();
+            const result = postWebhook(self.allocator, hook, delivery.body);
+            self.mutex.lock();
+
+            if (result) |_| {
+                self.allocator.free(delivery.body);
+            } else |err| {
+                delivery.attempts += 1;
+                if (delivery.attempts >= max_webhook_attempts) {
+                    logger.warn(null, "Webhook {s} failed {d} times, dropping event: {s}", .{ hook.url, delivery.attempts, @errorName(err) });
+                    self.allocator.free(delivery.body);
+                    continue;
+                }
+                const backoff = webhook_retry_base_ms * (@as(i64, 1) << @intCast(delivery.attempts - 1));
+                delivery.next_attempt_ms = std.time.milliTimestamp() + backoff;
+                logger.debug(null, "Webhook {s} failed ({s}), retrying in {d}ms", .{ hook.url, @errorName(err), backoff });
+                // Back where it was, ahead of anything queued meanwhile
+                self.queue.insert(self.allocator, @min(index, self.queue.items.len), delivery) catch {
+                    self.allocator.free(delivery.body);
+                };
+            }
+        }
+    }
+};
+
+const max_webhook_attempts = 5;
+/// Delay before the first retry; doubles with each further failure
+const webhook_retry_base_ms = 1000;
+/// Connect, send and receive timeout for one webhook POST
+const webhook_timeout_secs = 2;
+/// Undelivered POSTs kept before new events are dropped
+const max_webhook_queue = 1024;
+
+/// POST a webhook body and wait for the status line. Anything but 2xx is an error.
+fn postWebhook(allocator: std.mem.Allocator, hook: Webhook, body: []const u8) !void {
+    const stream = try connectWebhook(allocator, hook.host, hook.port);
+    defer stream.close();
+
+    const timeout = std.posix.timeval{ .sec = webhook_timeout_secs, .usec = 0 };
+    try std.posix.setsockopt(stream.handle, std.posix.SOL.SOCKET, std.posix.SO.SNDTIMEO, std.mem.asBytes(&timeout));
+    try std.posix.setsockopt(stream.handle, std.posix.SOL.SOCKET, std.posix.SO.RCVTIMEO, std.mem.asBytes(&timeout));
+
+    // GitHub-style signature of the raw body: "sha256=<hex>"
+    var signature_buf: [96]u8 = undefined;
+    var signature: []const u8 = "";
+    if (hook.secret.len > 0) {
+        var mac: [HmacSha256.mac_length]u8 = undefined;
+        HmacSha256.create(&mac, body, hook.secret);
+        signature = std.fmt.bufPrint(&signature_buf, "X-Webhook-Signature: sha256={s}\r\n", .{std.fmt.bytesToHex(mac, .lower)}) catch unreachable;
+    }
+
+    var head_buf: [1024]u8 = undefined;
+    const head = try std.fmt.bufPrint(&head_buf, "POST {s} HTTP/1.1\r\nHost: {s}:{d}\r\nContent-Type: application/json\r\nContent-Length: {d}\r\n{s}Connection: close\r\n\r\n", .{ hook.path, hook.host, hook.port, body.len, signature });
+    try stream.writeAll(head);
+    try stream.writeAll(body);
+
+    // "HTTP/1.1 200" is all that matters
+    var status_buf: [16]u8 = undefined;
+    var status_len: usize = 0;
+    while (status_len < 12) {
+        const n = try stream.read(status_buf[status_len..]);
+        if (n == 0) return error.NoResponse;
+        status_len += n;
+    }
+    if (!std.mem.startsWith(u8, &status_buf, "HTTP/1.")) return error.InvalidResponse;
+    if (status_buf[9] != '2') return error.UnsuccessfulStatus;
+}
+
+/// Connect to the first address of `host` that answers within webhook_timeout_secs
+fn connectWebhook(allocator: std.mem.Allocator, host: []const u8, port: u16) !std.net.Stream {
+    const list = try std.net.getAddressList(allocator, host, port);
+    defer list.deinit();
+    if (list.addrs.len == 0) return error.UnknownHostName;
+
+    var last_err: anyerror = error.ConnectionRefused;
+    for (list.addrs) |address| {
+        return connectWithTimeout(address, webhook_timeout_secs * 1000) catch |err| {
+            last_err = err;
+            continue;
+        };
+    }
+    return last_err;
+}
+
+/// Connect without waiting longer than `timeout_ms`; the stream comes back blocking
+fn connectWithTimeout(address: std.net.Address, timeout_ms: i32) !std.net.Stream {
+    const nonblock: usize = @as(u32, @bitCast(std.posix.O{ .NONBLOCK = true }));
+    const fd = try std.posix.socket(address.any.family, std.posix.SOCK.STREAM | std.posix.SOCK.NONBLOCK | std.posix.SOCK.CLOEXEC, std.posix.IPPROTO.TCP);
+    errdefer std.posix.close(fd);
+
+    std.posix.connect(fd, &address.any, address.getOsSockLen()) catch |err| switch (err) {
+        error.WouldBlock => {
+            var fds = [_]std.posix.pollfd{.{ .fd = fd, .events = std.posix.POLL.OUT, .revents = 0 }};
+            if (try std.posix.poll(&fds, timeout_ms) == 0) return error.ConnectionTimedOut;
+            try std.posix.getsockoptError(fd);
+        },
+        else => return err,
+    };
+
+    const flags = try std.posix.fcntl(fd, std.posix.F.GETFL, 0);
+    _ = try std.posix.fcntl(fd, std.posix.F.SETFL, flags & ~nonblock);
+    return .{ .handle = fd };
+}
+
+/// A recipient send_many! could not deliver to
+const SendFailure = struct {
+    client_id: u64,
+    err: anyerror,
+};
+
+/// Pooled buffers keep at most this much capacity between uses
+const max_pooled_capacity = 64 * 1024;
+
+/// Empty a pooled buffer, keeping its memory unless it grew unusually large
+fn releasePooled(allocator: std.mem.Allocator, buf: *std.ArrayListUnmanaged(u8)) void {
+    if (buf.capacity > max_pooled_capacity) {
+        buf.clearAndFree(allocator);
+    } else {
+        buf.clearRetainingCapacity();
+    }
+}
+
+/// Send without blocking, returning how many bytes the kernel accepted
+fn writeNonBlocking(stream: std.net.Stream, bytes: []const u8) !usize {
+    var total: usize = 0;
+    while (total < bytes.len) {
+        const n = std.posix.send(stream.handle, bytes[total..], std.posix.MSG.DONTWAIT) catch |err| switch (err) {
+            error.WouldBlock => return total,
+            else => return err,
+        };
+        total += n;
+    }
+    return total;
+}
+
+/// Split a ws:// URL into host, port and request path
+fn parseWsUrl(url: []const u8) !UrlParts {
+    // wss:// would need a TLS client stream, which the frame code does not support yet
+    return splitUrl(url, "ws://");
+}
+
+const UrlParts = struct { host: []const u8, port: u16, path: []const u8 };
+
+/// Split a URL with the given scheme into host, port (default 80) and request path
+fn splitUrl(url: []const u8, scheme: []const u8) !UrlParts {
+    if (!std.mem.startsWith(u8, url, scheme)) return error.UnsupportedScheme;
+
+    const rest = url[scheme.len..];
+    const path_start = std.mem.indexOfAny(u8, rest, "/?") orelse rest.len;
+    const authority = rest[0..path_start];
+    const path = if (path_start < rest.len) rest[path_start..] else "/";
+    if (authority.len == 0) return error.MissingHost;
+
+    if (std.mem.lastIndexOfScalar(u8, authority, ':')) |colon| {
+        const port = try std.fmt.parseInt(u16, authority[colon + 1 ..], 10);
+        return .{ .host = authority[0..colon], .port = port, .path = path };
+    }
+    return .{ .host = authority, .port = 80, .path = path };
+}
+
+// Global server instance
+var global_server: ?*WebSocketServer = null;
+
+// ============================================================================
+// Hosted Functions
+// ============================================================================
+
+fn getAsSlice(roc_str: *const RocStr) []const u8 {
+    if (roc_str.len() == 0) return "";
+    return roc_str.asSlice();
+}
+
+fn getListAsSlice(roc_list: *const RocList) []const u8 {
+    if (roc_list.len() == 0) return "";
+    const bytes = roc_list.elements(u8) orelse return "";
+    return bytes[0..roc_list.len()];
+}
+
+/// WebServer.listen! : U16 => Result({}, Str)
+fn hostedWebServerListen(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct { port: u16 };
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    if (host.server) |_| {
+        const msg = "Server already running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0; // Err
+        return;
+    }
+
+    const server = host.gpa.allocator().create(WebSocketServer) catch {
+        const msg = "Failed to allocate server";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+    server.* = WebSocketServer.init(host.gpa.allocator());
+
+    server.listen(args.port) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "Failed to listen: {}", .{err}) catch "Listen failed";
+        if (RocStr.fitsInSmallStr(msg.len)) {
+            result.payload = RocStr.fromSliceSmall(msg);
+        } else {
+            result.payload = RocStr.init(msg.ptr, msg.len, ops);
+        }
+        result.discriminant = 0;
+        return;
+    };
+
+    host.server = server;
+    global_server = server;
+
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.listen_with_config! : Str => Result({}, Str)
+/// Start the server on the address and port a TOML or JSON file gives, with its settings
+fn hostedWebServerListenWithConfig(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        path: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    if (host.server) |_| {
+        const msg = "Server already running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0; // Err
+        return;
+    }
+
+    const allocator = host.gpa.allocator();
+    const path = getAsSlice(&args.path);
+    var err_line: usize = 0;
+    var config = ServerConfig.fromFile(allocator, path, &err_line) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = if (err_line > 0)
+            std.fmt.bufPrint(&buf, "Config error on line {d}: {}", .{ err_line, err }) catch "Config error"
+        else
+            std.fmt.bufPrint(&buf, "Load config failed: {}", .{err}) catch "Load config failed";
+        result.payload = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+    defer config.deinit();
+
+    const server = allocator.create(WebSocketServer) catch {
+        const msg = "Failed to allocate server";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+    server.* = WebSocketServer.init(allocator);
+
+    server.listenWithConfig(path, &config) catch |err| {
+        server.deinit();
+        allocator.destroy(server);
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "Failed to listen: {}", .{err}) catch "Listen failed";
+        result.payload = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+
+    host.server = server;
+    global_server = server;
+
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.route_websocket! : Str, Str => Result({}, Str)
+/// Accept upgrades on a path, joining clients to a room ("" for none). "/ws/*" with
+/// room "*" joins the room named by the last segment. Once any route exists,
+/// upgrades elsewhere get 404.
+fn hostedWebServerRouteWebsocket(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        path: RocStr,
+        room: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    server.addWsEndpoint(getAsSlice(&args.path), getAsSlice(&args.room)) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "Route websocket failed: {}", .{err}) catch "Route websocket failed";
+        result.payload = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.run! : () => Result({}, Str)
+/// Runs the event loop entirely in Zig - no Roc recursion needed
+fn hostedWebServerRun(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    _ = args_ptr;
+
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0; // Err
+        return;
+    };
+
+    // Event loop runs entirely in Zig
+    while (true) {
+        const event = server.accept() catch |err| {
+            if (err == error.ControlFrame or err == error.NotWebSocket) {
+                continue;
+            }
+            logger.warn(null, "Accept error, continuing: {s}", .{@errorName(err)});
+            continue;
+        };
+
+        switch (event) {
+            .connected => |client_id| {
+                logger.info(client_id, "Connected", .{});
+
+                // Send welcome message
+                var welcome_buf: [256]u8 = undefined;
+                const welcome = std.fmt.bufPrint(&welcome_buf, "{{\"type\": \"system\", \"text\": \"Welcome to the chat! You are client #{}\"}}", .{client_id}) catch continue;
+                server.send(client_id, welcome) catch {};
+
+                // Broadcast join message
+                var join_buf: [256]u8 = undefined;
+                const join = std.fmt.bufPrint(&join_buf, "{{\"type\": \"system\", \"text\": \"Client #{} joined the chat\"}}", .{client_id}) catch continue;
+                server.broadcast(join) catch {};
+            },
+            .disconnected => |info| {
+                defer server.allocator.free(info.reason);
+
+                logger.info(info.client_id, "Disconnected ({d})", .{info.code});
+
+                // Broadcast leave message
+                var leave_buf: [256]u8 = undefined;
+                const leave = std.fmt.bufPrint(&leave_buf, "{{\"type\": \"system\", \"text\": \"Client #{} left the chat\"}}", .{info.client_id}) catch continue;
+                server.broadcast(leave) catch {};
+            },
+            .message => |msg| {
+                defer server.allocator.free(msg.text);
+                logger.debug(msg.client_id, "Message: {s}", .{msg.text});
+
+                // Broadcast message - need to escape text for JSON
+                var json_buf: [4096]u8 = undefined;
+                var writer = std.io.fixedBufferStream(&json_buf);
+                writer.writer().print("{{\"type\": \"message\", \"clientId\": {}, \"text\": \"", .{msg.client_id}) catch continue;
+                for (msg.text) |c| {
+                    switch (c) {
+                        '"' => writer.writer().writeAll("\\\"") catch {},
+                        '\\' => writer.writer().writeAll("\\\\") catch {},
+                        '\n' => writer.writer().writeAll("\\n") catch {},
+                        '\r' => writer.writer().writeAll("\\r") catch {},
+                        '\t' => writer.writer().writeAll("\\t") catch {},
+                        else => writer.writer().writeByte(c) catch {},
+                    }
+                }
+                writer.writer().writeAll("\"}") catch continue;
+                server.broadcast(json_buf[0..writer.pos]) catch {};
+            },
+            .upstream_message => |msg| {
+                defer server.allocator.free(msg.text);
+                logger.debug(msg.client_id, "Upstream message: {s}", .{msg.text});
+            },
+            .envelope => |envelope| {
+                // Joins and room messages need no application; commands are ignored
+                defer envelope.deinit(server.allocator);
+                switch (envelope.kind) {
+                    .join_request => server.joinRoom(envelope.client_id, envelope.room) catch {},
+                    .chat_message => {
+                        var json = std.ArrayListUnmanaged(u8){};
+                        defer json.deinit(server.allocator);
+                        writeEnvelope(json.writer(server.allocator), "message", envelope.room, envelope.id, envelope.payload) catch continue;
+                        server.broadcastToRoom(envelope.room, json.items) catch {};
+                    },
+                    .command => {},
+                }
+            },
+            .graphql_subscribe => |op| {
+                // The built-in loop has no schema
+                defer server.allocator.free(op.payload);
+                defer server.allocator.free(op.id);
+                server.sendGraphql(op.client_id, "error", op.id, "[{\"message\":\"No schema\"}]") catch {};
+            },
+            .graphql_complete => |op| {
+                server.allocator.free(op.id);
+            },
+            .rpc_call => |call| {
+                // The built-in loop implements no methods
+                defer call.deinit(server.allocator);
+                server.rpcError(call.client_id, call.id, rpc_method_not_found, "Method not found") catch {};
+            },
+            .handshake_request => |req| {
+                // The built-in loop has no policy of its own
+                defer req.deinit(server.allocator);
+                server.acceptHandshake(req.client_id) catch {};
+            },
+            .hook_message => |hook| {
+                // Already delivered to the room
+                defer hook.deinit(server.allocator);
+                logger.debug(null, "Hook message for {s}: {s}", .{ hook.room, hook.text });
+            },
+            .http_request => |req| {
+                // The built-in loop has no application endpoints
+                defer req.deinit(server.allocator);
+                server.respond(req.client_id, 404, &.{}, "") catch {};
+            },
+            .resumed => |client_id| {
+                logger.info(client_id, "Resumed", .{});
+            },
+            .admin_command => |command| {
+                defer server.allocator.free(command);
+                if (std.mem.eql(u8, command, "shutdown")) server.shutdown();
+            },
+            .timer => |info| {
+                // The built-in loop sets no timers
+                server.allocator.free(info.tag);
+            },
+            .delivered, .delivery_failed => {
+                // The built-in loop never sends with acknowledgement
+            },
+            .overflow => |dropped| {
+                logger.warn(null, "Event queue full, dropped {d} events", .{dropped});
+            },
+            .rate_limited => |info| {
+                defer server.allocator.free(info.reason);
+                logger.warn(info.client_id, "Rate limited: {s}", .{info.reason});
+            },
+            .banned => |info| {
+                defer server.allocator.free(info.address);
+                logger.warn(info.client_id, "Banned: {s}", .{info.address});
+            },
+            .file_uploaded => |upload| {
+                // The built-in loop leaves sharing the URL to the uploader
+                defer upload.deinit(server.allocator);
+                logger.debug(null, "Uploaded {s} ({d} bytes)", .{ upload.url, upload.size });
+            },
+            .flagged => |info| {
+                // The built-in loop holds flagged messages back for good
+                defer server.allocator.free(info.reason);
+                defer server.allocator.free(info.text);
+                logger.warn(info.client_id, "Flagged ({s}): {s}", .{ info.reason, info.text });
+            },
+            .permission_denied => |info| {
+                defer server.allocator.free(info.room);
+                logger.info(info.client_id, "Not permitted to {s} {s}", .{ @tagName(info.action), info.room });
+            },
+            .muted => |info| {
+                logger.debug(info.client_id, "Muted for {d}ms more", .{info.remaining_ms});
+            },
+            .binary_message => |msg| {
+                logger.debug(msg.client_id, "Binary message: {d} bytes", .{msg.data.len});
+
+                // Relay binary payloads untouched
+                server.broadcastBinary(msg.data) catch {};
+                server.allocator.free(msg.data);
+            },
+            .err => |msg| {
+                defer server.allocator.free(msg);
+                logger.err(null, "{s}", .{msg});
+            },
+            .shutdown => {
+                logger.info(null, "Server shutting down", .{});
+                result.payload = RocStr.empty();
+                result.discriminant = 1; // Ok
+                return;
+            },
+        }
+    }
+}
+
+/// Roc `Event` tag union shared by accept! and try_accept!
+/// Event is [AdminCommand(Str), Banned(U64, Str), BinaryMessage(U64, List(U8)), ChatMessage(U64, Str, Str, Str),
+///           Command(U64, Str, Str, Str, Str), Connected(U64), Delivered(U64), DeliveryFailed(U64, Str),
+///           Disconnected(U64, U16, Str), Error(Str), FileUploaded(Str, Str, U64, Str),
+///           Flagged(U64, Str, Str), GraphqlComplete(U64, Str), GraphqlSubscribe(U64, Str, Str),
+///           HandshakeRequest(U64, Str, List((Str, Str))), HookMessage(Str, Str, Str),
+///           HttpRequest(U64, Str, Str, List((Str, Str)), List(U8)),
+///           Idle, JoinRequest(U64, Str, Str), Message(U64, Str), Muted(U64, U64), Overflow(U64),
+///           PermissionDenied(U64, Str, Str), RateLimited(U64, Str), Resumed(U64),
+///           RpcCall(U64, Str, Str, Str), Shutdown, Timer(U64, Str), UpstreamMessage(U64, Str)]
+///
+/// Roc tag union layout: payload first (sized to largest), discriminant at end
+/// Largest payload = HttpRequest(U64, Str, Str, List, List) = 8 + 4 * 24 = 104 bytes
+/// discriminant_offset = 104, total size = 112 bytes (padded to 8-byte alignment)
+const RocEvent = extern struct {
+    payload: extern union {
+        // Connected/Delivered/Overflow/Resumed: U64 at offset 0
+        client_id: u64,
+        // Disconnected: fields sorted by alignment, so U64 at 0, Str at 8, U16 at 32
+        disconnected: extern struct {
+            client_id: u64,
+            reason: RocStr,
+            code: u16,
+        },
+        // AdminCommand/Error: Str at offset 0
+        err_str: RocStr,
+        // Banned/DeliveryFailed/GraphqlComplete/Message/RateLimited/Timer/UpstreamMessage: U64 at offset 0, Str at offset 8
+        message: extern struct {
+            client_id: u64,
+            text: RocStr,
+        },
+        // Muted: U64 client, then U64 remaining milliseconds
+        muted: extern struct {
+            client_id: u64,
+            remaining_ms: u64,
+        },
+        // BinaryMessage: U64 at offset 0, List(U8) at offset 8
+        binary_message: extern struct {
+            client_id: u64,
+            data: RocList,
+        },
+        // HandshakeRequest: U64 at 0, Str at 8, List((Str, Str)) at 32
+        handshake_request: extern struct {
+            client_id: u64,
+            target: RocStr,
+            headers: RocList,
+        },
+        // ChatMessage: U64 client, then room, id, text
+        chat_message: extern struct {
+            client_id: u64,
+            room: RocStr,
+            id: RocStr,
+            text: RocStr,
+        },
+        // Command: U64 client, then type, room, id, payload
+        command: extern struct {
+            client_id: u64,
+            type_name: RocStr,
+            room: RocStr,
+            id: RocStr,
+            payload: RocStr,
+        },
+        // JoinRequest: U64 client, then room, id
+        join_request: extern struct {
+            client_id: u64,
+            room: RocStr,
+            id: RocStr,
+        },
+        // FileUploaded: all fields 8-aligned, so user, url, size, mime in order
+        file_uploaded: extern struct {
+            user: RocStr,
+            url: RocStr,
+            size: u64,
+            mime: RocStr,
+        },
+        // Flagged: U64 client, then reason, text
+        flagged: extern struct {
+            client_id: u64,
+            reason: RocStr,
+            text: RocStr,
+        },
+        // PermissionDenied: U64 client, then room, action
+        permission_denied: extern struct {
+            client_id: u64,
+            room: RocStr,
+            action: RocStr,
+        },
+        // GraphqlSubscribe: U64 client, then id, payload
+        graphql_subscribe: extern struct {
+            client_id: u64,
+            id: RocStr,
+            payload: RocStr,
+        },
+        // RpcCall: U64 client, then id, method, params
+        rpc_call: extern struct {
+            client_id: u64,
+            id: RocStr,
+            method: RocStr,
+            params: RocStr,
+        },
+        // HookMessage: room, sender, text in order
+        hook_message: extern struct {
+            room: RocStr,
+            sender: RocStr,
+            text: RocStr,
+        },
+        // HttpRequest: all fields 8-aligned, so declaration order
+        http_request: extern struct {
+            client_id: u64,
+            method: RocStr,
+            target: RocStr,
+            headers: RocList,
+            body: RocList,
+        },
+        // Idle/Shutdown: no payload
+    },
+    discriminant: Tag,
+
+    /// Tags sorted alphabetically
+    const Tag = enum(u8) {
+        admin_command = 0,
+        banned = 1,
+        binary_message = 2,
+        chat_message = 3,
+        command = 4,
+        connected = 5,
+        delivered = 6,
+        delivery_failed = 7,
+        disconnected = 8,
+        err = 9,
+        file_uploaded = 10,
+        flagged = 11,
+        graphql_complete = 12,
+        graphql_subscribe = 13,
+        handshake_request = 14,
+        hook_message = 15,
+        http_request = 16,
+        idle = 17,
+        join_request = 18,
+        message = 19,
+        muted = 20,
+        overflow = 21,
+        permission_denied = 22,
+        rate_limited = 23,
+        resumed = 24,
+        rpc_call = 25,
+        shutdown = 26,
+        timer = 27,
+        upstream_message = 28,
+    };
+
+    comptime {
+        std.debug.assert(@offsetOf(RocEvent, "discriminant") == 104);
+        std.debug.assert(@sizeOf(RocEvent) == 112);
+    }
+};
+
+fn toRocStr(slice: []const u8, ops: *builtins.host_abi.RocOps) RocStr {
+    if (RocStr.fitsInSmallStr(slice.len)) {
+        return RocStr.fromSliceSmall(slice);
+    }
+    return RocStr.init(slice.ptr, slice.len, ops);
+}
+
+/// Convert a server event into its Roc representation, releasing host-owned payloads
+fn writeRocEvent(ops: *builtins.host_abi.RocOps, server: *WebSocketServer, result: *RocEvent, event: WebSocketEvent) void {
+    switch (event) {
+        .connected => |client_id| {
+            result.payload.client_id = client_id;
+            result.discriminant = .connected;
+        },
+        .disconnected => |info| {
+            result.payload.disconnected.client_id = info.client_id;
+            result.payload.disconnected.reason = toRocStr(info.reason, ops);
+            result.payload.disconnected.code = info.code;
+            server.allocator.free(info.reason);
+            result.discriminant = .disconnected;
+        },
+        .message => |msg| {
+            result.payload.message.client_id = msg.client_id;
+            result.payload.message.text = toRocStr(msg.text, ops);
+            server.allocator.free(msg.text);
+            result.discriminant = .message;
+        },
+        .upstream_message => |msg| {
+            result.payload.message.client_id = msg.client_id;
+            result.payload.message.text = toRocStr(msg.text, ops);
+            server.allocator.free(msg.text);
+            result.discriminant = .upstream_message;
+        },
+        .resumed => |client_id| {
+            result.payload.client_id = client_id;
+            result.discriminant = .resumed;
+        },
+        .admin_command => |command| {
+            result.payload.err_str = toRocStr(command, ops);
+            server.allocator.free(command);
+            result.discriminant = .admin_command;
+        },
+        .timer => |info| {
+            result.payload.message.client_id = info.timer_id;
+            result.payload.message.text = toRocStr(info.tag, ops);
+            server.allocator.free(info.tag);
+            result.discriminant = .timer;
+        },
+        .delivered => |message_id| {
+            result.payload.client_id = message_id;
+            result.discriminant = .delivered;
+        },
+        .overflow => |dropped| {
+            result.payload.client_id = dropped;
+            result.discriminant = .overflow;
+        },
+        .delivery_failed => |info| {
+            result.payload.message.client_id = info.message_id;
+            result.payload.message.text = toRocStr(info.reason, ops);
+            result.discriminant = .delivery_failed;
+        },
+        .rate_limited => |info| {
+            result.payload.message.client_id = info.client_id;
+            result.payload.message.text = toRocStr(info.reason, ops);
+            server.allocator.free(info.reason);
+            result.discriminant = .rate_limited;
+        },
+        .banned => |info| {
+            result.payload.message.client_id = info.client_id;
+            result.payload.message.text = toRocStr(info.address, ops);
+            server.allocator.free(info.address);
+            result.discriminant = .banned;
+        },
+        .file_uploaded => |upload| {
+            defer upload.deinit(server.allocator);
+            const out = &result.payload.file_uploaded;
+            out.user = toRocStr(upload.user, ops);
+            out.url = toRocStr(upload.url, ops);
+            out.size = upload.size;
+            out.mime = toRocStr(upload.mime, ops);
+            result.discriminant = .file_uploaded;
+        },
+        .flagged => |info| {
+            const out = &result.payload.flagged;
+            out.client_id = info.client_id;
+            out.reason = toRocStr(info.reason, ops);
+            out.text = toRocStr(info.text, ops);
+            server.allocator.free(info.reason);
+            server.allocator.free(info.text);
+            result.discriminant = .flagged;
+        },
+        .permission_denied => |info| {
+            const out = &result.payload.permission_denied;
+            out.client_id = info.client_id;
+            out.room = toRocStr(info.room, ops);
+            out.action = toRocStr(@tagName(info.action), ops);
+            server.allocator.free(info.room);
+            result.discriminant = .permission_denied;
+        },
+        .muted => |info| {
+            result.payload.muted.client_id = info.client_id;
+            result.payload.muted.remaining_ms = info.remaining_ms;
+            result.discriminant = .muted;
+        },
+        .binary_message => |msg| {
+            result.payload.binary_message.client_id = msg.client_id;
+            result.payload.binary_message.data = RocList.fromSlice(u8, msg.data, false, ops);
+            server.allocator.free(msg.data);
+            result.discriminant = .binary_message;
+        },
+        .handshake_request => |req| {
+            defer req.deinit(server.allocator);
+            const out = &result.payload.handshake_request;
+            out.client_id = req.client_id;
+            out.target = toRocStr(req.target, ops);
+            out.headers = toRocHeaders(req.headers, ops);
+            result.discriminant = .handshake_request;
+        },
+        .hook_message => |hook| {
+            defer hook.deinit(server.allocator);
+            const out = &result.payload.hook_message;
+            out.room = toRocStr(hook.room, ops);
+            out.sender = toRocStr(hook.sender, ops);
+            out.text = toRocStr(hook.text, ops);
+            result.discriminant = .hook_message;
+        },
+        .envelope => |envelope| {
+            defer envelope.deinit(server.allocator);
+            switch (envelope.kind) {
+                .chat_message => {
+                    const out = &result.payload.chat_message;
+                    out.client_id = envelope.client_id;
+                    out.room = toRocStr(envelope.room, ops);
+                    out.id = toRocStr(envelope.id, ops);
+                    out.text = toRocStr(envelope.payload, ops);
+                    result.discriminant = .chat_message;
+                },
+                .join_request => {
+                    const out = &result.payload.join_request;
+                    out.client_id = envelope.client_id;
+                    out.room = toRocStr(envelope.room, ops);
+                    out.id = toRocStr(envelope.id, ops);
+                    result.discriminant = .join_request;
+                },
+                .command => {
+                    const out = &result.payload.command;
+                    out.client_id = envelope.client_id;
+                    out.type_name = toRocStr(envelope.type_name, ops);
+                    out.room = toRocStr(envelope.room, ops);
+                    out.id = toRocStr(envelope.id, ops);
+                    out.payload = toRocStr(envelope.payload, ops);
+                    result.discriminant = .command;
+                },
+            }
+        },
+        .graphql_subscribe => |op| {
+            const out = &result.payload.graphql_subscribe;
+            out.client_id = op.client_id;
+            out.id = toRocStr(op.id, ops);
+            out.payload = toRocStr(op.payload, ops);
+            server.allocator.free(op.id);
+            server.allocator.free(op.payload);
+            result.discriminant = .graphql_subscribe;
+        },
+        .graphql_complete => |op| {
+            result.payload.message.client_id = op.client_id;
+            result.payload.message.text = toRocStr(op.id, ops);
+            server.allocator.free(op.id);
+            result.discriminant = .graphql_complete;
+        },
+        .rpc_call => |call| {
+            defer call.deinit(server.allocator);
+            const out = &result.payload.rpc_call;
+            out.client_id = call.client_id;
+            out.id = toRocStr(call.id, ops);
+            out.method = toRocStr(call.method, ops);
+            out.params = toRocStr(call.params, ops);
+            result.discriminant = .rpc_call;
+        },
+        .http_request => |req| {
+            defer req.deinit(server.allocator);
+            const out = &result.payload.http_request;
+            out.client_id = req.client_id;
+            out.method = toRocStr(req.method, ops);
+            out.target = toRocStr(req.target, ops);
+            out.headers = toRocHeaders(req.headers, ops);
+            out.body = RocList.fromSlice(u8, req.body, false, ops);
+            result.discriminant = .http_request;
+        },
+        .err => |msg| {
+            result.payload.err_str = toRocStr(msg, ops);
+            server.allocator.free(msg);
+            result.discriminant = .err;
+        },
+        .shutdown => {
+            result.discriminant = .shutdown;
+        },
+    }
+}
+
+/// Roc (Str, Str) tuple; both fields share alignment so they stay in order
+const RocHeader = extern struct {
+    name: RocStr,
+    value: RocStr,
+};
+
+fn toRocHeaders(headers: []const HttpHeader, ops: *builtins.host_abi.RocOps) RocList {
+    if (headers.len == 0) return RocList.empty();
+    var pairs: [max_http_headers]RocHeader = undefined;
+    for (headers, 0..) |h, i| {
+        pairs[i] = .{ .name = toRocStr(h.name, ops), .value = toRocStr(h.value, ops) };
+    }
+    return RocList.fromSlice(RocHeader, pairs[0..headers.len], true, ops);
+}
+
+/// WebServer.accept! : () => Event
+/// Blocks until the next event arrives
+fn hostedWebServerAccept(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    _ = args_ptr;
+
+    const result: *RocEvent = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        result.discriminant = .shutdown;
+        return;
+    };
+
+    // Loop until we get a real event (skip ControlFrame and NotWebSocket errors)
+    while (true) {
+        const event = server.accept() catch |err| {
+            if (err == error.ControlFrame or err == error.NotWebSocket) {
+                // Skip these non-events and continue polling
+                continue;
+            }
+            const msg = "Accept error";
+            result.payload.err_str = RocStr.fromSliceSmall(msg);
+            result.discriminant = .err;
+            return;
+        };
+
+        writeRocEvent(ops, server, result, event);
+        return;
+    }
+}
+
+/// WebServer.accept_timeout! : U64 => Event
+/// Waits at most this many milliseconds, returning Idle if no event arrived
+fn hostedWebServerAcceptTimeout(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Args = extern struct {
+        timeout_ms: u64,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *RocEvent = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        result.discriminant = .shutdown;
+        return;
+    };
+
+    const maybe_event = server.acceptTimeout(args.timeout_ms) catch {
+        const msg = "Accept error";
+        result.payload.err_str = RocStr.fromSliceSmall(msg);
+        result.discriminant = .err;
+        return;
+    };
+
+    if (maybe_event) |event| {
+        writeRocEvent(ops, server, result, event);
+    } else {
+        result.discriminant = .idle;
+    }
+}
+
+/// WebServer.watch_stdin! : Bool => {}
+/// Deliver lines typed on stdin as AdminCommand events
+fn hostedWebServerWatchStdin(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    _ = ret_ptr;
+
+    const Args = extern struct {
+        enabled: bool,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    if (host.server) |server| {
+        server.watch_stdin = args.enabled;
+    }
+}
+
+/// WebServer.try_accept! : () => Event
+/// Returns Idle immediately when no event is ready
+fn hostedWebServerTryAccept(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    _ = args_ptr;
+
+    const result: *RocEvent = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        result.discriminant = .shutdown;
+        return;
+    };
+
+    const maybe_event = server.pollEvent(0) catch |err| {
+        if (err == error.ControlFrame or err == error.NotWebSocket) {
+            result.discriminant = .idle;
+            return;
+        }
+        const msg = "Accept error";
+        result.payload.err_str = RocStr.fromSliceSmall(msg);
+        result.discriminant = .err;
+        return;
+    };
+
+    if (maybe_event) |event| {
+        writeRocEvent(ops, server, result, event);
+    } else {
+        result.discriminant = .idle;
+    }
+}
+
+/// WebServer.send! : U64, Str => Result({}, Str)
+fn hostedWebServerSend(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        client_id: u64,
+        message: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    const message = getAsSlice(&args.message);
+    server.send(args.client_id, message) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "Send failed: {}", .{err}) catch "Send failed";
+        if (RocStr.fitsInSmallStr(msg.len)) {
+            result.payload = RocStr.fromSliceSmall(msg);
+        } else {
+            result.payload = RocStr.init(msg.ptr, msg.len, ops);
+        }
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.broadcast! : Str => Result({}, Str)
+fn hostedWebServerBroadcast(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        message: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    const message = getAsSlice(&args.message);
+    server.broadcast(message) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "Broadcast failed: {}", .{err}) catch "Broadcast failed";
+        if (RocStr.fitsInSmallStr(msg.len)) {
+            result.payload = RocStr.fromSliceSmall(msg);
+        } else {
+            result.payload = RocStr.init(msg.ptr, msg.len, ops);
+        }
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
+
+/// Roc (U64, Str) tuple; both fields are 8-aligned so they stay in order
+const RocSendFailure = extern struct {
+    client_id: u64,
+    reason: RocStr,
+};
+
+/// WebServer.send_many! : List(U64), Str => List((U64, Str))
+/// Send one message to several clients; returns the ids that failed with the reason
+fn hostedWebServerSendMany(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Args = extern struct {
+        client_ids: RocList,
+        message: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *RocList = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const client_ids: []const u64 = if (args.client_ids.elements(u64)) |ids| ids[0..args.client_ids.len()] else &.{};
+
+    const server = host.server orelse {
+        var failed: [1]RocSendFailure = undefined;
+        failed[0] = .{ .client_id = 0, .reason = RocStr.fromSliceSmall("Server not running") };
+        result.* = RocList.fromSlice(RocSendFailure, &failed, true, ops);
+        return;
+    };
+
+    var failures = std.ArrayListUnmanaged(SendFailure){};
+    defer failures.deinit(server.allocator);
+
+    server.sendMany(client_ids, getAsSlice(&args.message), &failures) catch |err| {
+        // Nothing was sent: report every recipient
+        failures.clearRetainingCapacity();
+        for (client_ids) |client_id| {
+            failures.append(server.allocator, .{ .client_id = client_id, .err = err }) catch break;
+        }
+    };
+
+    if (failures.items.len == 0) {
+        result.* = RocList.empty();
+        return;
+    }
+
+    var failed = std.ArrayListUnmanaged(RocSendFailure){};
+    defer failed.deinit(server.allocator);
+    for (failures.items) |failure| {
+        failed.append(server.allocator, .{
+            .client_id = failure.client_id,
+            .reason = toRocStr(@errorName(failure.err), ops),
+        }) catch break;
+    }
+    result.* = RocList.fromSlice(RocSendFailure, failed.items, true, ops);
+}
+
+/// WebServer.send_with_ack! : U64, Str => Result(U64, Str)
+/// Send a message and get its id; Delivered(id) or DeliveryFailed(id, reason) follows
+fn hostedWebServerSendWithAck(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: extern union {
+            message_id: u64,
+            err_str: RocStr,
+        },
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        client_id: u64,
+        message: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload.err_str = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    const message_id = server.sendWithAck(args.client_id, getAsSlice(&args.message)) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "Send failed: {}", .{err}) catch "Send failed";
+        result.payload.err_str = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload.message_id = message_id;
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.send_binary! : U64, List(U8) => Result({}, Str)
+fn hostedWebServerSendBinary(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        client_id: u64,
+        data: RocList,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    const data = getListAsSlice(&args.data);
+    server.sendBinary(args.client_id, data) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "Send failed: {}", .{err}) catch "Send failed";
+        if (RocStr.fitsInSmallStr(msg.len)) {
+            result.payload = RocStr.fromSliceSmall(msg);
+        } else {
+            result.payload = RocStr.init(msg.ptr, msg.len, ops);
+        }
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.broadcast_binary! : List(U8) => Result({}, Str)
+fn hostedWebServerBroadcastBinary(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        data: RocList,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    const data = getListAsSlice(&args.data);
+    server.broadcastBinary(data) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "Broadcast failed: {}", .{err}) catch "Broadcast failed";
+        if (RocStr.fitsInSmallStr(msg.len)) {
+            result.payload = RocStr.fromSliceSmall(msg);
+        } else {
+            result.payload = RocStr.init(msg.ptr, msg.len, ops);
+        }
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.connect! : Str => Result(U64, Str)
+fn hostedWebServerConnect(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: extern union {
+            client_id: u64,
+            err_str: RocStr,
+        },
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        url: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload.err_str = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    const client_id = server.connectUpstream(getAsSlice(&args.url)) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "Connect failed: {}", .{err}) catch "Connect failed";
+        result.payload.err_str = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload.client_id = client_id;
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.allow_origin! : Str => Result({}, Str)
+/// Add an origin (e.g. "https://chat.example.com") browsers may connect from
+fn hostedWebServerAllowOrigin(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        origin: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    server.allowOrigin(getAsSlice(&args.origin)) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "Allow origin failed: {}", .{err}) catch "Allow origin failed";
+        result.payload = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.add_route! : Str, Str, Str => Result({}, Str)
+/// Route method ("" for any) and path pattern to "app", "static" or "not_found"
+fn hostedWebServerAddRoute(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        method: RocStr,
+        pattern: RocStr,
+        target: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    const target = std.meta.stringToEnum(RouteTarget, getAsSlice(&args.target)) orelse {
+        const msg = "Unknown route target";
+        result.payload = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+    server.addRoute(getAsSlice(&args.method), getAsSlice(&args.pattern), target) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "Add route failed: {}", .{err}) catch "Add route failed";
+        result.payload = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.add_subprotocol! : Str => Result({}, Str)
+/// Offer a Sec-WebSocket-Protocol; earlier additions are preferred when a client offers several
+fn hostedWebServerAddSubprotocol(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        protocol: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    server.addSubprotocol(getAsSlice(&args.protocol)) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "Add subprotocol failed: {}", .{err}) catch "Add subprotocol failed";
+        result.payload = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.add_webhook! : Str, List(Str), Str => Result({}, Str)
+/// POST "message", "connect" and/or "disconnect" events to an http:// URL,
+/// signed with the secret unless it is empty
+fn hostedWebServerAddWebhook(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        url: RocStr,
+        events: RocList,
+        secret: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    var events = WebhookEvents{};
+    if (args.events.elements(RocStr)) |names| {
+        for (names[0..args.events.len()]) |*name| {
+            events.add(getAsSlice(name)) catch {
+                var buf: [128]u8 = undefined;
+                const msg = std.fmt.bufPrint(&buf, "Unknown webhook event: {s}", .{getAsSlice(name)}) catch "Unknown webhook event";
+                result.payload = toRocStr(msg, ops);
+                result.discriminant = 0;
+                return;
+            };
+        }
+    }
+
+    server.addWebhook(getAsSlice(&args.url), events, getAsSlice(&args.secret)) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "Add webhook failed: {}", .{err}) catch "Add webhook failed";
+        result.payload = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.allow_admin_ip! : Str => Result({}, Str)
+/// Let an address or CIDR network use the admin API; by default only loopback may
+fn hostedWebServerAllowAdminIp(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        network: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    const network = Cidr.parse(getAsSlice(&args.network)) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "Allow admin IP failed: {}", .{err}) catch "Allow admin IP failed";
+        result.payload = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+    server.admin_networks.append(server.allocator, network) catch {
+        const msg = "Out of memory";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.allow_ip! : Str => Result({}, Str)
+/// Only accept clients from this address or CIDR network (repeatable)
+fn hostedWebServerAllowIp(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        network: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    const network = Cidr.parse(getAsSlice(&args.network)) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "Allow IP failed: {}", .{err}) catch "Allow IP failed";
+        result.payload = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+    server.allowed_networks.append(server.allocator, network) catch {
+        const msg = "Out of memory";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.deny_ip! : Str => Result({}, Str)
+/// Refuse clients from this address or CIDR network
+fn hostedWebServerDenyIp(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        network: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    const network = Cidr.parse(getAsSlice(&args.network)) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "Deny IP failed: {}", .{err}) catch "Deny IP failed";
+        result.payload = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+    server.denied_networks.append(server.allocator, network) catch {
+        const msg = "Out of memory";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.ban_ip! : Str, U64 => Result({}, Str)
+/// Refuse an address or network for a number of milliseconds (0 until unban_ip!), closing its clients
+fn hostedWebServerBanIp(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        network: RocStr,
+        duration_ms: u64,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    const network = Cidr.parse(getAsSlice(&args.network)) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "Ban IP failed: {}", .{err}) catch "Ban IP failed";
+        result.payload = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+    server.banNetwork(network, args.duration_ms) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "Ban IP failed: {}", .{err}) catch "Ban IP failed";
+        result.payload = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.unban_ip! : Str => Result({}, Str)
+/// Lift a ban placed with ban_ip! on exactly this address or network
+fn hostedWebServerUnbanIp(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        network: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    const network = Cidr.parse(getAsSlice(&args.network)) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "Unban IP failed: {}", .{err}) catch "Unban IP failed";
+        result.payload = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+    Restriction.remove(&server.bans, network);
+
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.ban_client! : U64, U64 => Result({}, Str)
+/// Ban the address a client connected from, so reconnects are refused too
+fn hostedWebServerBanClient(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        client_id: u64,
+        duration_ms: u64,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    server.banClient(args.client_id, args.duration_ms) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "Ban client failed: {}", .{err}) catch "Ban client failed";
+        result.payload = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.mute_client! : U64, U64 => Result({}, Str)
+/// Drop chat from a client's address for a number of milliseconds (0 until unmute_client!)
+fn hostedWebServerMuteClient(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        client_id: u64,
+        duration_ms: u64,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    server.muteClient(args.client_id, args.duration_ms) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "Mute client failed: {}", .{err}) catch "Mute client failed";
+        result.payload = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.unmute_client! : U64 => Result({}, Str)
+/// Lift a mute placed with mute_client!
+fn hostedWebServerUnmuteClient(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        client_id: u64,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    server.unmuteClient(args.client_id) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "Unmute client failed: {}", .{err}) catch "Unmute client failed";
+        result.payload = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.trust_proxy! : Str => Result({}, Str)
+/// Believe X-Forwarded-For and X-Real-IP on connections from this address or network
+fn hostedWebServerTrustProxy(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        network: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    const network = Cidr.parse(getAsSlice(&args.network)) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "Trust proxy failed: {}", .{err}) catch "Trust proxy failed";
+        result.payload = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+    server.trusted_proxies.append(server.allocator, network) catch {
+        const msg = "Out of memory";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.require_auth! : Str => {}
+/// Require an HS256 JWT signed with this secret to connect; "" turns auth off
+fn hostedWebServerRequireAuth(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    _ = ret_ptr;
+
+    const Args = extern struct {
+        secret: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    if (host.server) |server| {
+        server.setAuthSecret(getAsSlice(&args.secret)) catch |err| {
+            logger.err(null, "require_auth! failed: {s}", .{@errorName(err)});
+        };
+    }
+}
+
+/// WebServer.set_envelope_protocol! : Bool => {}
+/// When enabled, text messages must be JSON envelopes and arrive as ChatMessage,
+/// JoinRequest or Command events
+fn hostedWebServerSetEnvelopeProtocol(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    _ = ret_ptr;
+
+    const Args = extern struct {
+        enabled: bool,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    if (host.server) |server| {
+        server.envelope_protocol = args.enabled;
+    }
+}
+
+/// WebServer.envelope! : Str, Str, Str, Str => Str
+/// Encode {"type", "room", "id", "ts", "payload"} stamped with the current time;
+/// an empty room or id is left out
+fn hostedWebServerEnvelope(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Args = extern struct {
+        type_name: RocStr,
+        room: RocStr,
+        id: RocStr,
+        payload: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *RocStr = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+    const allocator = host.gpa.allocator();
+
+    var json = std.ArrayListUnmanaged(u8){};
+    defer json.deinit(allocator);
+    writeEnvelope(json.writer(allocator), getAsSlice(&args.type_name), getAsSlice(&args.room), getAsSlice(&args.id), getAsSlice(&args.payload)) catch {
+        result.* = RocStr.empty();
+        return;
+    };
+    result.* = toRocStr(json.items, ops);
+}
+
+/// WebServer.graphql_next! : U64, Str, Str => Result({}, Str)
+/// Send an operation's next execution result (JSON)
+fn hostedWebServerGraphqlNext(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        client_id: u64,
+        id: RocStr,
+        payload: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    server.sendGraphql(args.client_id, "next", getAsSlice(&args.id), getAsSlice(&args.payload)) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "GraphQL next failed: {}", .{err}) catch "GraphQL next failed";
+        result.payload = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.graphql_error! : U64, Str, Str => Result({}, Str)
+/// End an operation with a JSON array of GraphQL errors
+fn hostedWebServerGraphqlError(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        client_id: u64,
+        id: RocStr,
+        payload: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    server.sendGraphql(args.client_id, "error", getAsSlice(&args.id), getAsSlice(&args.payload)) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "GraphQL error failed: {}", .{err}) catch "GraphQL error failed";
+        result.payload = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.graphql_complete! : U64, Str => Result({}, Str)
+/// End an operation that has no more results
+fn hostedWebServerGraphqlComplete(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        client_id: u64,
+        id: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    server.sendGraphql(args.client_id, "complete", getAsSlice(&args.id), null) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "GraphQL complete failed: {}", .{err}) catch "GraphQL complete failed";
+        result.payload = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.set_rpc_protocol! : Bool => {}
+/// When enabled, text messages must be JSON-RPC 2.0 requests and arrive as RpcCall events
+fn hostedWebServerSetRpcProtocol(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    _ = ret_ptr;
+
+    const Args = extern struct {
+        enabled: bool,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    if (host.server) |server| {
+        server.rpc_protocol = args.enabled;
+    }
+}
+
+/// WebServer.rpc_respond! : U64, Str, Str => Result({}, Str)
+/// Answer the RpcCall with this id; the result must be JSON
+fn hostedWebServerRpcRespond(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        client_id: u64,
+        id: RocStr,
+        result: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    server.rpcRespond(args.client_id, getAsSlice(&args.id), getAsSlice(&args.result)) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "RPC respond failed: {}", .{err}) catch "RPC respond failed";
+        result.payload = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.rpc_error! : U64, Str, I64, Str => Result({}, Str)
+/// Answer the RpcCall with this id with a JSON-RPC error code and message
+fn hostedWebServerRpcError(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        client_id: u64,
+        id: RocStr,
+        code: i64,
+        message: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    server.rpcError(args.client_id, getAsSlice(&args.id), args.code, getAsSlice(&args.message)) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "RPC error failed: {}", .{err}) catch "RPC error failed";
+        result.payload = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.set_socketio! : Bool => {}
+/// When enabled, Socket.IO clients can connect over WebSocket at /socket.io/
+fn hostedWebServerSetSocketio(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    _ = ret_ptr;
+
+    const Args = extern struct {
+        enabled: bool,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    if (host.server) |server| {
+        server.socketio = args.enabled;
+    }
+}
+
+/// WebServer.socketio_emit! : U64, Str, Str => Result({}, Str)
+/// Emit a Socket.IO event to a client; the arguments are a JSON array
+fn hostedWebServerSocketioEmit(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        client_id: u64,
+        name: RocStr,
+        args: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    server.socketioEmit(args.client_id, getAsSlice(&args.name), getAsSlice(&args.args)) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "Socket.IO emit failed: {}", .{err}) catch "Socket.IO emit failed";
+        result.payload = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.socketio_ack! : U64, Str, Str => Result({}, Str)
+/// Acknowledge a Socket.IO event (the Command's id) with a JSON array of arguments
+fn hostedWebServerSocketioAck(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        client_id: u64,
+        ack_id: RocStr,
+        args: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    server.socketioAck(args.client_id, getAsSlice(&args.ack_id), getAsSlice(&args.args)) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "Socket.IO ack failed: {}", .{err}) catch "Socket.IO ack failed";
+        result.payload = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.set_handshake_policy! : Bool => {}
+/// When enabled, upgrades arrive as HandshakeRequest events instead of connecting
+fn hostedWebServerSetHandshakePolicy(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    _ = ret_ptr;
+
+    const Args = extern struct {
+        enabled: bool,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    if (host.server) |server| {
+        server.handshake_policy = args.enabled;
+    }
+}
+
+/// WebServer.accept_handshake! : U64 => Result({}, Str)
+fn hostedWebServerAcceptHandshake(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        client_id: u64,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    server.acceptHandshake(args.client_id) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "Accept handshake failed: {}", .{err}) catch "Accept handshake failed";
+        result.payload = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.reject_handshake! : U64, U16 => Result({}, Str)
+/// Refuse a pending upgrade with a 4xx/5xx status
+fn hostedWebServerRejectHandshake(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        client_id: u64,
+        status: u16,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    server.rejectHandshake(args.client_id, args.status) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "Reject handshake failed: {}", .{err}) catch "Reject handshake failed";
+        result.payload = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.route_http! : Str => Result({}, Str)
+/// Requests whose path starts with the prefix arrive as HttpRequest events
+fn hostedWebServerRouteHttp(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        prefix: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    server.addHttpRoute(getAsSlice(&args.prefix)) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "Route failed: {}", .{err}) catch "Route failed";
+        result.payload = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.respond! : U64, U16, List((Str, Str)), List(U8) => Result({}, Str)
+/// Answer an HttpRequest event; Content-Length is added unless given
+fn hostedWebServerRespond(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    // Arguments are laid out by alignment, so the U16 status comes last
+    const Args = extern struct {
+        client_id: u64,
+        headers: RocList,
+        body: RocList,
+        status: u16,
+    };
+    comptime std.debug.assert(@offsetOf(Args, "status") == 56);
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    var headers: [max_http_headers]HttpHeader = undefined;
+    const count = @min(args.headers.len(), max_http_headers);
+    if (args.headers.elements(RocHeader)) |pairs| {
+        for (pairs[0..count], headers[0..count]) |*pair, *out| {
+            out.* = .{ .name = getAsSlice(&pair.name), .value = getAsSlice(&pair.value) };
+        }
+    }
+
+    server.respond(args.client_id, args.status, headers[0..count], getListAsSlice(&args.body)) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "Respond failed: {}", .{err}) catch "Respond failed";
+        result.payload = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.set_admin_token! : Str => {}
+/// Enable the /admin/ API for requests bearing this token; "" disables it
+fn hostedWebServerSetAdminToken(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    _ = ret_ptr;
+
+    const Args = extern struct {
+        token: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    if (host.server) |server| {
+        server.setAdminToken(getAsSlice(&args.token)) catch |err| {
+            logger.err(null, "set_admin_token! failed: {s}", .{@errorName(err)});
+        };
+    }
+}
+
+/// WebServer.reload_config! : Str => Result({}, Str)
+/// Apply a TOML configuration file now and again on every SIGHUP
+fn hostedWebServerReloadConfig(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        path: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    var err_line: usize = 0;
+    server.loadConfig(getAsSlice(&args.path), &err_line) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = if (err_line > 0)
+            std.fmt.bufPrint(&buf, "Config error on line {d}: {}", .{ err_line, err }) catch "Config error"
+        else
+            std.fmt.bufPrint(&buf, "Load config failed: {}", .{err}) catch "Load config failed";
+        result.payload = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.set_directory_listing! : Bool => {}
+/// List directories under the static root that have no index file
+fn hostedWebServerSetDirectoryListing(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    _ = ret_ptr;
+
+    const Args = extern struct {
+        enabled: bool,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    if (host.server) |server| {
+        server.directory_listing = args.enabled;
+    }
+}
+
+/// WebServer.set_base_path! : Str => Result({}, Str)
+/// Also serve everything under a URL prefix such as "/chat"; "" clears it
+fn hostedWebServerSetBasePath(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        path: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    server.setBasePath(getAsSlice(&args.path)) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "Set base path failed: {}", .{err}) catch "Set base path failed";
+        result.payload = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.set_cache_control! : Str => Result({}, Str)
+/// Cache-Control header value for static files (default "no-cache")
+fn hostedWebServerSetCacheControl(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        value: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    server.setCacheControl(getAsSlice(&args.value)) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "Set Cache-Control failed: {}", .{err}) catch "Set Cache-Control failed";
+        result.payload = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.set_error_page! : U16, Str => Result({}, Str)
+/// HTML the static server sends with an error status (400-599); empty removes it
+fn hostedWebServerSetErrorPage(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    // Arguments are laid out by alignment, so the Str comes before the U16 status
+    const Args = extern struct {
+        content: RocStr,
+        status: u16,
+    };
+    comptime std.debug.assert(@offsetOf(Args, "status") == 24);
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    server.setErrorPage(args.status, getAsSlice(&args.content)) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "Set error page failed: {}", .{err}) catch "Set error page failed";
+        result.payload = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.set_event_queue_limit! : U64, Str => Result({}, Str)
+/// Hold at most this many events for the application (0 for no limit). The policy for
+/// a full queue is "drop_oldest", "drop_newest" or "disconnect_producer".
+fn hostedWebServerSetEventQueueLimit(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        limit: u64,
+        policy: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    const policy = std.meta.stringToEnum(OverflowPolicy, getAsSlice(&args.policy)) orelse {
+        const msg = "Unknown overflow policy";
+        result.payload = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+    server.event_queue_limit = @intCast(args.limit);
+    server.event_overflow = policy;
+
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.set_client_data! : U64, Str, Str => Result({}, Str)
+fn hostedWebServerSetClientData(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        client_id: u64,
+        key: RocStr,
+        value: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    server.setClientData(args.client_id, getAsSlice(&args.key), getAsSlice(&args.value)) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "Set failed: {}", .{err}) catch "Set failed";
+        result.payload = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.get_client_data! : U64, Str => Result(Str, Str)
+fn hostedWebServerGetClientData(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        client_id: u64,
+        key: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    const value = server.getClientData(args.client_id, getAsSlice(&args.key)) orelse {
+        const msg = "Not found";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload = toRocStr(value, ops);
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.set_role! : U64, Str, Str => Result({}, Str)
+/// Give a client a role (owner, moderator, member or readonly) in a room, or in every room for ""
+fn hostedWebServerSetRole(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        client_id: u64,
+        room: RocStr,
+        role: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    const role = Role.parse(getAsSlice(&args.role)) orelse {
+        const msg = "Unknown role";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+    server.setRole(args.client_id, getAsSlice(&args.room), role) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "Set role failed: {}", .{err}) catch "Set role failed";
+        result.payload = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.get_role! : U64, Str => Result(Str, Str)
+/// A client's effective role in a room
+fn hostedWebServerGetRole(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        client_id: u64,
+        room: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    const client = server.clients.getPtr(args.client_id) orelse {
+        const msg = "Client not found";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload = toRocStr(@tagName(WebSocketServer.roleOf(client, getAsSlice(&args.room))), ops);
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.set_room_policy! : Str, Str, Str => Result({}, Str)
+/// The least role a room requires to join and to send messages to it
+fn hostedWebServerSetRoomPolicy(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        room: RocStr,
+        join: RocStr,
+        send: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    const join = Role.parse(getAsSlice(&args.join));
+    const send = Role.parse(getAsSlice(&args.send));
+    if (join == null or send == null) {
+        const msg = "Unknown role";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    }
+    server.setRoomPolicy(getAsSlice(&args.room), .{ .join = join.?, .send = send.? }) catch {
+        const msg = "Out of memory";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.create_session! : Str, U64 => Result(Str, Str)
+/// Sign a session for a user, valid for `ttl_secs`; returns a Set-Cookie header value
+fn hostedWebServerCreateSession(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        user: RocStr,
+        ttl_secs: u64,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    const cookie = server.createSession(getAsSlice(&args.user), args.ttl_secs) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "Create session failed: {}", .{err}) catch "Create session failed";
+        result.payload = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+    defer server.allocator.free(cookie);
+
+    result.payload = toRocStr(cookie, ops);
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.cancel_timer! : U64 => {}
+fn hostedWebServerCancelTimer(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    _ = ret_ptr;
+
+    const Args = extern struct {
+        timer_id: u64,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    if (host.server) |server| {
+        server.cancelTimer(args.timer_id);
+    }
+}
+
+/// WebServer.client_stats! : U64 => Result({ bytes_in : U64, bytes_out : U64, connected_ms : U64, last_activity_ms : U64, messages_in : U64, messages_out : U64, queued_bytes : U64 }, Str)
+fn hostedWebServerClientStats(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: extern union {
+            stats: ClientStats,
+            err_str: RocStr,
+        },
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        client_id: u64,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload.err_str = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    const client = server.clients.getPtr(args.client_id) orelse {
+        const msg = "Client not found";
+        result.payload.err_str = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload.stats = clientStats(client);
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.client_session! : U64 => Result(Str, Str)
+/// Session id the client connected with; its user is the "user" attribute
+fn hostedWebServerClientSession(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        client_id: u64,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    const session_id = server.getClientData(args.client_id, "session") orelse {
+        const msg = "No session";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload = toRocStr(session_id, ops);
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.set_session_secret! : Str => Result({}, Str)
+/// Key for signing session cookies (at least 16 bytes); without one, a random key
+/// is generated and sessions do not survive a restart
+fn hostedWebServerSetSessionSecret(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        secret: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    server.setSessionSecret(getAsSlice(&args.secret)) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "Set session secret failed: {}", .{err}) catch "Set session secret failed";
+        result.payload = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.join_room! : U64, Str => Result({}, Str)
+fn hostedWebServerJoinRoom(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        client_id: u64,
+        room: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    const room = getAsSlice(&args.room);
+    server.joinRoom(args.client_id, room) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "Join failed: {}", .{err}) catch "Join failed";
+        result.payload = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.leave_room! : U64, Str => {}
+fn hostedWebServerLeaveRoom(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    _ = ret_ptr;
+
+    const Args = extern struct {
+        client_id: u64,
+        room: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    if (host.server) |server| {
+        server.leaveRoom(args.client_id, getAsSlice(&args.room));
+    }
+}
+
+/// WebServer.broadcast_host! : Str, Str => Result({}, Str)
+/// Send to every client that connected through the given virtual host
+fn hostedWebServerBroadcastHost(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        host: RocStr,
+        message: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    server.broadcastToHost(getAsSlice(&args.host), getAsSlice(&args.message)) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "Broadcast failed: {}", .{err}) catch "Broadcast failed";
+        result.payload = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.broadcast_room! : Str, Str => Result({}, Str)
+fn hostedWebServerBroadcastRoom(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        room: RocStr,
+        message: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    server.broadcastToRoom(getAsSlice(&args.room), getAsSlice(&args.message)) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "Broadcast failed: {}", .{err}) catch "Broadcast failed";
+        result.payload = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.publish_room! : Str, Str => Result(U64, Str)
+/// Send a sequenced {"type":"message","room","seq","text"} envelope to a room; returns its seq
+fn hostedWebServerPublishRoom(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: extern union {
+            seq: u64,
+            err_str: RocStr,
+        },
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        room: RocStr,
+        message: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload.err_str = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    const seq = server.publishToRoom(getAsSlice(&args.room), getAsSlice(&args.message)) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "Publish failed: {}", .{err}) catch "Publish failed";
+        result.payload.err_str = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload.seq = seq;
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.replay_since! : U64, Str, U64 => Result(U64, Str)
+/// Resend a room's published messages after seq to a client; returns how many were sent
+fn hostedWebServerReplaySince(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: extern union {
+            count: u64,
+            err_str: RocStr,
+        },
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        client_id: u64,
+        room: RocStr,
+        since: u64,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload.err_str = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    const count = server.replaySince(args.client_id, getAsSlice(&args.room), args.since) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "Replay failed: {}", .{err}) catch "Replay failed";
+        result.payload.err_str = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload.count = count;
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.notify_room! : Str, Str, Str => Result({}, Str)
+/// Send an ephemeral signal (kind such as "typing") to a room; repeats within 250ms are dropped
+fn hostedWebServerNotifyRoom(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        room: RocStr,
+        kind: RocStr,
+        payload: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    server.notifyRoom(getAsSlice(&args.room), getAsSlice(&args.kind), getAsSlice(&args.payload)) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "Notify failed: {}", .{err}) catch "Notify failed";
+        result.payload = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.list_rooms! : () => List(Str)
+fn hostedWebServerListRooms(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    _ = args_ptr;
+
+    const result: *RocList = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        result.* = RocList.empty();
+        return;
+    };
+
+    var names = std.ArrayListUnmanaged(RocStr){};
+    defer names.deinit(server.allocator);
+
+    var it = server.rooms.keyIterator();
+    while (it.next()) |room| {
+        names.append(server.allocator, toRocStr(room.*, ops)) catch break;
+    }
+
+    result.* = RocList.fromSlice(RocStr, names.items, true, ops);
+}
+
+/// WebServer.clients! : () => List(U64)
+/// Ids of the connected WebSocket and SSE clients, in no particular order
+fn hostedWebServerClients(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    _ = args_ptr;
+
+    const result: *RocList = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        result.* = RocList.empty();
+        return;
+    };
+
+    var ids = std.ArrayListUnmanaged(u64){};
+    defer ids.deinit(server.allocator);
+
+    var it = server.clients.valueIterator();
+    while (it.next()) |client| {
+        // Upstream connections and requests still being read are not chat clients
+        if (!client.is_websocket or client.is_upstream or client.is_closed) continue;
+        ids.append(server.allocator, client.id) catch break;
+    }
+
+    result.* = RocList.fromSlice(u64, ids.items, false, ops);
+}
+
+/// WebServer.close! : U64 => {}
+fn hostedWebServerClose(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    _ = ret_ptr;
+
+    const Args = extern struct {
+        client_id: u64,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    if (host.server) |server| {
+        server.closeClient(args.client_id, close_normal, "");
+    }
+}
+
+/// WebServer.configure! : { static_dir : Str, index_file : Str, spa_fallback : Bool } => Result({}, Str)
+fn hostedWebServerConfigure(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    // Record fields sorted by alignment, then name
+    const Config = extern struct {
+        index_file: RocStr,
+        static_dir: RocStr,
+        spa_fallback: bool,
+    };
+
+    const Args = extern struct {
+        config: Config,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    const config = &args.config;
+    server.configure(getAsSlice(&config.static_dir), getAsSlice(&config.index_file), config.spa_fallback) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "Configure failed: {}", .{err}) catch "Configure failed";
+        result.payload = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.set_handshake_timeout! : U64 => {}
+/// Milliseconds a new connection has to send its complete request; 0 disables
+fn hostedWebServerSetHandshakeTimeout(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    _ = ret_ptr;
+
+    const Args = extern struct {
+        timeout_ms: u64,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    if (host.server) |server| {
+        server.handshake_timeout_ms = @intCast(@min(args.timeout_ms, std.math.maxInt(i32)));
+    }
+}
+
+/// WebServer.set_hook_secret! : Str => {}
+/// Enable POST /hooks/{room} for requests bearing this token; "" disables it
+fn hostedWebServerSetHookSecret(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    _ = ret_ptr;
+
+    const Args = extern struct {
+        secret: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    if (host.server) |server| {
+        server.setHookSecret(getAsSlice(&args.secret)) catch |err| {
+            logger.err(null, "set_hook_secret! failed: {s}", .{@errorName(err)});
+        };
+    }
+}
+
+/// WebServer.set_keepalive! : U64, U64 => {}
+/// Ping interval and idle timeout in milliseconds; 0 disables either
+fn hostedWebServerSetKeepalive(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    _ = ret_ptr;
+
+    const Args = extern struct {
+        interval_ms: u64,
+        idle_timeout_ms: u64,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    if (host.server) |server| {
+        server.keepalive_interval_ms = @intCast(@min(args.interval_ms, std.math.maxInt(i32)));
+        server.idle_timeout_ms = @intCast(@min(args.idle_timeout_ms, std.math.maxInt(i32)));
+    }
+}
+
+/// WebServer.set_room_history! : U64, U64 => {}
+/// Keep the last `size` messages of every room and send up to `on_join` of them
+/// to clients joining it; a new size clears what was kept
+fn hostedWebServerSetRoomHistory(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    _ = ret_ptr;
+
+    const Args = extern struct {
+        size: u64,
+        on_join: u64,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    if (host.server) |server| {
+        server.setRoomHistory(@intCast(args.size), @intCast(args.on_join));
+    }
+}
+
+/// WebServer.set_history_store! : Str => Result({}, Str)
+/// Also write every chat message to files under a directory; "" stops
+fn hostedWebServerSetHistoryStore(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        dir: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    server.setHistoryStore(getAsSlice(&args.dir)) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "Cannot open history store: {}", .{err}) catch "Cannot open history store";
+        result.payload = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.room_history! : Str, U64 => Result(List({ client_id : U64, received_ms : U64, room : Str, text : Str }), Str)
+/// The last `limit` stored messages of a room (at most 10,000), oldest first
+fn hostedWebServerRoomHistory(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    // Record fields sorted by alignment, then name
+    const RocHistoryEntry = extern struct {
+        client_id: u64,
+        received_ms: u64,
+        room: RocStr,
+        text: RocStr,
+    };
+
+    const Result = extern struct {
+        payload: extern union {
+            entries: RocList,
+            err_str: RocStr,
+        },
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        room: RocStr,
+        limit: u64,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload.err_str = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+    if (server.history_store == null) {
+        const msg = "No history store; call set_history_store! first";
+        result.payload.err_str = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    }
+
+    var arena_state = std.heap.ArenaAllocator.init(server.allocator);
+    defer arena_state.deinit();
+    const arena = arena_state.allocator();
+
+    const limit: usize = @intCast(@min(args.limit, max_history_query));
+    const entries = server.history_store.?.history(arena, getAsSlice(&args.room), limit) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "History read failed: {}", .{err}) catch "History read failed";
+        result.payload.err_str = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+
+    var items = std.ArrayListUnmanaged(RocHistoryEntry){};
+    for (entries) |entry| {
+        items.append(arena, .{
+            .client_id = entry.client_id,
+            .received_ms = entry.received_ms,
+            .room = toRocStr(entry.room, ops),
+            .text = toRocStr(entry.text, ops),
+        }) catch break;
+    }
+
+    result.payload.entries = RocList.fromSlice(RocHistoryEntry, items.items, true, ops);
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.set_message_rate! : U64, U64 => {}
+/// Messages per second each client may send, with bursts up to `burst`; 0 disables
+fn hostedWebServerSetMessageRate(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    _ = ret_ptr;
+
+    const Args = extern struct {
+        per_second: u64,
+        burst: u64,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    if (host.server) |server| {
+        server.message_limit = .{
+            .rate = @intCast(@min(args.per_second, std.math.maxInt(u32))),
+            .burst = @intCast(@min(args.burst, std.math.maxInt(u32))),
+        };
+    }
+}
+
+/// WebServer.set_connect_rate! : U64, U64 => {}
+/// Connection attempts per second each IP address may make, with bursts up to `burst`; 0 disables
+fn hostedWebServerSetConnectRate(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    _ = ret_ptr;
+
+    const Args = extern struct {
+        per_second: u64,
+        burst: u64,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    if (host.server) |server| {
+        server.connect_limit = .{
+            .rate = @intCast(@min(args.per_second, std.math.maxInt(u32))),
+            .burst = @intCast(@min(args.burst, std.math.maxInt(u32))),
+        };
+        server.connect_buckets.clearRetainingCapacity();
+    }
+}
+
+/// WebServer.set_log_level! : Str => Result({}, Str)
+/// Accepts "debug", "info", "warn", "error" or "off"
+fn hostedWebServerSetLogLevel(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        level: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+
+    logger.level = LogLevel.parse(getAsSlice(&args.level)) orelse {
+        const msg = "Unknown log level";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.set_uploads! : Str, U64, List(Str) => Result({}, Str)
+/// Accept POST /upload into a directory, with a body limit (0 keeps 10 MiB) and allowed types ([] for the defaults)
+fn hostedWebServerSetUploads(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        dir: RocStr,
+        max_size: u64,
+        types: RocList,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    var types = std.ArrayListUnmanaged([]const u8){};
+    defer types.deinit(server.allocator);
+    if (args.types.elements(RocStr)) |names| {
+        for (names[0..args.types.len()]) |*name| {
+            types.append(server.allocator, getAsSlice(name)) catch {
+                const msg = "Out of memory";
+                result.payload = RocStr.fromSliceSmall(msg);
+                result.discriminant = 0;
+                return;
+            };
+        }
+    }
+
+    server.setUploads(getAsSlice(&args.dir), @intCast(args.max_size), types.items) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "Set uploads failed: {}", .{err}) catch "Set uploads failed";
+        result.payload = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.set_max_body_size! : U64 => {}
+/// Larger HTTP request bodies are rejected with 413
+fn hostedWebServerSetMaxBodySize(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    _ = ret_ptr;
+
+    const Args = extern struct {
+        max_bytes: u64,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    if (host.server) |server| {
+        server.max_body_size = @intCast(args.max_bytes);
+    }
+}
+
+/// WebServer.set_max_connections! : U64 => {}
+/// Open connections beyond which new ones get 503 or a 1013 close; 0 is unlimited
+fn hostedWebServerSetMaxConnections(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    _ = ret_ptr;
+
+    const Args = extern struct {
+        max_connections: u64,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    if (host.server) |server| {
+        server.max_connections = @intCast(args.max_connections);
+    }
+}
+
+/// WebServer.set_max_frame_size! : U64 => {}
+/// Largest frame payload accepted; bigger frames close the client with 1009
+fn hostedWebServerSetMaxFrameSize(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    _ = ret_ptr;
+
+    const Args = extern struct {
+        max_bytes: u64,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    if (host.server) |server| {
+        server.max_frame_size = @intCast(args.max_bytes);
+    }
+}
+
+/// WebServer.set_max_message_size! : U64 => {}
+/// Largest message accepted, including reassembled fragments; bigger ones close the client with 1009
+fn hostedWebServerSetMaxMessageSize(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    _ = ret_ptr;
+
+    const Args = extern struct {
+        max_bytes: u64,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    if (host.server) |server| {
+        server.max_message_size = @intCast(args.max_bytes);
+    }
+}
+
+/// WebServer.set_max_header_size! : U64 => {}
+/// Largest request line plus headers accepted; bigger requests get 431
+fn hostedWebServerSetMaxHeaderSize(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    _ = ret_ptr;
+
+    const Args = extern struct {
+        max_bytes: u64,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    if (host.server) |server| {
+        server.max_header_size = @intCast(args.max_bytes);
+    }
+}
+
+/// WebServer.set_mime_type! : Str, Str => Result({}, Str)
+/// Map a file extension (with or without the dot) to a Content-Type
+fn hostedWebServerSetMimeType(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        ext: RocStr,
+        mime: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    server.setMimeType(getAsSlice(&args.ext), getAsSlice(&args.mime)) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "Set MIME type failed: {}", .{err}) catch "Set MIME type failed";
+        result.payload = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.set_resume_grace! : U64 => {}
+/// Milliseconds a dropped WebSocket client may reconnect with ?resume=<token>
+/// and keep its id; 0 disables resuming
+fn hostedWebServerSetResumeGrace(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    _ = ret_ptr;
+
+    const Args = extern struct {
+        grace_ms: u64,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    if (host.server) |server| {
+        server.resume_grace_ms = @intCast(@min(args.grace_ms, std.math.maxInt(i32)));
+    }
+}
+
+/// WebServer.set_send_queue_limit! : U64 => {}
+/// Clients with more unsent bytes than this are disconnected with 1008
+fn hostedWebServerSetSendQueueLimit(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    _ = ret_ptr;
+
+    const Args = extern struct {
+        max_bytes: u64,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    if (host.server) |server| {
+        server.max_outbox_bytes = @intCast(args.max_bytes);
+    }
+}
+
+/// WebServer.set_socket_options! : { no_delay : Bool, keepalive : Bool, send_buffer : U64, recv_buffer : U64 } => {}
+/// TCP options for connections accepted from now on; buffer sizes of 0 keep the system default
+fn hostedWebServerSetSocketOptions(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    _ = ret_ptr;
+
+    // Record fields sorted by alignment, then name
+    const Options = extern struct {
+        recv_buffer: u64,
+        send_buffer: u64,
+        keepalive: bool,
+        no_delay: bool,
+    };
+
+    const Args = extern struct {
+        options: Options,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    if (host.server) |server| {
+        const options = &args.options;
+        server.socket_options = .{
+            .no_delay = options.no_delay,
+            .keepalive = options.keepalive,
+            .send_buffer = @intCast(@min(options.send_buffer, std.math.maxInt(u32))),
+            .recv_buffer = @intCast(@min(options.recv_buffer, std.math.maxInt(u32))),
+        };
+    }
+}
+
+/// WebServer.set_static_buffer! : U64 => {}
+/// Largest static file read into memory; bigger ones are streamed from disk
+fn hostedWebServerSetStaticBuffer(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    _ = ret_ptr;
+
+    const Args = extern struct {
+        max_bytes: u64,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    if (host.server) |server| {
+        // Cached entries above the new limit would no longer be re-read
+        server.clearStaticCache();
+        server.static_buffer_limit = @intCast(args.max_bytes);
+    }
+}
+
+/// WebServer.set_static_cache! : U64 => {}
+/// Memory budget in bytes for caching static files; 0 disables and empties the cache
+fn hostedWebServerSetStaticCache(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    _ = ret_ptr;
+
+    const Args = extern struct {
+        max_bytes: u64,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    if (host.server) |server| {
+        server.clearStaticCache();
+        server.static_cache_limit = @intCast(args.max_bytes);
+    }
+}
+
+/// WebServer.set_virtual_host! : Str, Str => Result({}, Str)
+/// Serve a Host name from its own static root with its own rooms; an empty dir removes it
+fn hostedWebServerSetVirtualHost(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        host: RocStr,
+        static_dir: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    server.setVirtualHost(getAsSlice(&args.host), getAsSlice(&args.static_dir)) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "Set virtual host failed: {}", .{err}) catch "Set virtual host failed";
+        result.payload = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.set_timer! : U64, Str => Result(U64, Str)
+/// Deliver Timer(id, tag) after the given milliseconds; returns the timer id
+fn hostedWebServerSetTimer(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: extern union {
+            timer_id: u64,
+            err_str: RocStr,
+        },
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        delay_ms: u64,
+        tag: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        const msg = "Server not running";
+        result.payload.err_str = RocStr.fromSliceSmall(msg);
+        result.discriminant = 0;
+        return;
+    };
+
+    const timer_id = server.setTimer(args.delay_ms, getAsSlice(&args.tag)) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "Set timer failed: {}", .{err}) catch "Set timer failed";
+        result.payload.err_str = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload.timer_id = timer_id;
+    result.discriminant = 1; // Ok
+}
+
+/// WebServer.stats! : () => { bytes_in : U64, bytes_out : U64, clients : U64, connections : U64, messages_in : U64, messages_out : U64, queued_events : U64, rooms : U64, uptime_ms : U64 }
+fn hostedWebServerStats(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    _ = args_ptr;
+
+    const result: *ServerStats = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    const server = host.server orelse {
+        result.* = std.mem.zeroes(ServerStats);
+        return;
+    };
+    result.* = server.stats();
+}
+
+/// WebServer.drain! : U64, Str => {}
+fn hostedWebServerDrain(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    _ = ret_ptr;
+    const Args = extern struct {
+        grace_ms: u64,
+        message: RocStr,
+    };
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    if (host.server) |server| {
+        server.drain(args.grace_ms, getAsSlice(&args.message));
     }
+}
 
-    fn closeClient(self: *WebSocketServer, client_id: u64) void {
-        if (self.clients.fetchRemove(client_id)) |kv| {
-            // Send close frame
-            self.sendFrame(@constCast(&kv.value), .close, "") catch {};
-            kv.value.stream.close();
-        }
+/// WebServer.shutdown! : () => {}
+fn hostedWebServerShutdown(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    _ = ret_ptr;
+    _ = args_ptr;
+
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    if (host.server) |server| {
+        server.shutdown();
     }
-};
+}
 
-// Global server instance
-var global_server: ?*WebSocketServer = null;
+/// Reference point for Clock.monotonic_millis!, taken on first use
+var monotonic_start: ?std.time.Instant = null;
 
-// ============================================================================
-// Hosted Functions
-// ============================================================================
+/// Clock.now_utc! : () => U64
+/// Milliseconds since the Unix epoch (wall clock, may jump)
+fn hostedClockNowUtc(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    _ = ops;
+    _ = args_ptr;
 
-fn getAsSlice(roc_str: *const RocStr) []const u8 {
-    if (roc_str.len() == 0) return "";
-    return roc_str.asSlice();
+    const result: *u64 = @ptrCast(@alignCast(ret_ptr));
+    result.* = @intCast(@max(std.time.milliTimestamp(), 0));
 }
 
-/// WebServer.listen! : U16 => Result({}, Str)
-fn hostedWebServerListen(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+/// Clock.monotonic_millis! : () => U64
+/// Milliseconds from an arbitrary start that never goes backwards; for measuring durations
+fn hostedClockMonotonicMillis(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    _ = ops;
+    _ = args_ptr;
+
+    const result: *u64 = @ptrCast(@alignCast(ret_ptr));
+    const now = std.time.Instant.now() catch {
+        result.* = 0;
+        return;
+    };
+    const start = monotonic_start orelse blk: {
+        monotonic_start = now;
+        break :blk now;
+    };
+    result.* = now.since(start) / std.time.ns_per_ms;
+}
+
+/// Env.get! : Str => Result(Str, Str)
+fn hostedEnvGet(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
     const Result = extern struct {
         payload: RocStr,
         discriminant: u8,
     };
 
-    const Args = extern struct { port: u16 };
+    const Args = extern struct {
+        name: RocStr,
+    };
+
     const args: *Args = @ptrCast(@alignCast(args_ptr));
     const result: *Result = @ptrCast(@alignCast(ret_ptr));
 
-    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
-
-    if (host.server) |_| {
-        const msg = "Server already running";
+    const name = getAsSlice(&args.name);
+    var name_buf: [256]u8 = undefined;
+    if (name.len == 0 or name.len >= name_buf.len or std.mem.indexOfAny(u8, name, "=\x00") != null) {
+        const msg = "Invalid variable name";
         result.payload = RocStr.fromSliceSmall(msg);
-        result.discriminant = 0; // Err
+        result.discriminant = 0;
         return;
     }
+    @memcpy(name_buf[0..name.len], name);
+    name_buf[name.len] = 0;
 
-    const server = host.gpa.allocator().create(WebSocketServer) catch {
-        const msg = "Failed to allocate server";
-        result.payload = RocStr.fromSliceSmall(msg);
+    const value = getenv(name_buf[0..name.len :0]) orelse {
+        var buf: [300]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "{s} is not set", .{name}) catch "Not set";
+        result.payload = toRocStr(msg, ops);
         result.discriminant = 0;
         return;
     };
-    server.* = WebSocketServer.init(host.gpa.allocator());
 
-    server.listen(args.port) catch |err| {
+    result.payload = toRocStr(std.mem.span(value), ops);
+    result.discriminant = 1; // Ok
+}
+
+/// Env.args! : () => List(Str)
+/// Command-line arguments, starting with the program name
+fn hostedEnvArgs(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    _ = args_ptr;
+
+    const result: *RocList = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+    const allocator = host.gpa.allocator();
+
+    var items = std.ArrayListUnmanaged(RocStr){};
+    defer items.deinit(allocator);
+
+    for (process_args) |arg| {
+        items.append(allocator, toRocStr(std.mem.span(arg), ops)) catch break;
+    }
+
+    result.* = RocList.fromSlice(RocStr, items.items, true, ops);
+}
+
+/// Largest file File.read_utf8! loads
+const max_file_read = 16 * 1024 * 1024;
+
+/// File.read_utf8! : Str => Result(Str, Str)
+/// Whole file as text; Err for missing files, files over 16 MiB or invalid UTF-8
+fn hostedFileReadUtf8(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        path: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+    const allocator = host.gpa.allocator();
+
+    const content = std.fs.cwd().readFileAlloc(allocator, getAsSlice(&args.path), max_file_read) catch |err| {
         var buf: [128]u8 = undefined;
-        const msg = std.fmt.bufPrint(&buf, "Failed to listen: {}", .{err}) catch "Listen failed";
-        if (RocStr.fitsInSmallStr(msg.len)) {
-            result.payload = RocStr.fromSliceSmall(msg);
-        } else {
-            result.payload = RocStr.init(msg.ptr, msg.len, ops);
-        }
+        const msg = std.fmt.bufPrint(&buf, "Read failed: {}", .{err}) catch "Read failed";
+        result.payload = toRocStr(msg, ops);
         result.discriminant = 0;
         return;
     };
+    defer allocator.free(content);
 
-    host.server = server;
-    global_server = server;
+    if (!std.unicode.utf8ValidateSlice(content)) {
+        const msg = "Read failed: invalid UTF-8";
+        result.payload = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    }
 
-    result.payload = RocStr.empty();
+    result.payload = toRocStr(content, ops);
     result.discriminant = 1; // Ok
 }
 
-/// WebServer.run! : () => Result({}, Str)
-/// Runs the event loop entirely in Zig - no Roc recursion needed
-fn hostedWebServerRun(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
-    const stderr = std.fs.File.stderr();
-    const stdout = std.fs.File.stdout();
-    _ = args_ptr;
+/// File.write_utf8! : Str, Str => Result({}, Str)
+/// Create or replace a file with the given text
+fn hostedFileWriteUtf8(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
 
+    const Args = extern struct {
+        path: RocStr,
+        content: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+
+    std.fs.cwd().writeFile(.{ .sub_path = getAsSlice(&args.path), .data = getAsSlice(&args.content) }) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "Write failed: {}", .{err}) catch "Write failed";
+        result.payload = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
+    };
+
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
+
+/// File.append! : Str, Str => Result({}, Str)
+/// Add text to the end of a file, creating it if needed (transcripts, logs)
+fn hostedFileAppend(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
     const Result = extern struct {
         payload: RocStr,
         discriminant: u8,
     };
 
+    const Args = extern struct {
+        path: RocStr,
+        content: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
     const result: *Result = @ptrCast(@alignCast(ret_ptr));
-    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
 
-    const server = host.server orelse {
-        const msg = "Server not running";
-        result.payload = RocStr.fromSliceSmall(msg);
-        result.discriminant = 0; // Err
+    appendToFile(getAsSlice(&args.path), getAsSlice(&args.content)) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "Append failed: {}", .{err}) catch "Append failed";
+        result.payload = toRocStr(msg, ops);
+        result.discriminant = 0;
         return;
     };
 
-    // Event loop runs entirely in Zig
-    while (true) {
-        const event = server.accept() catch |err| {
-            if (err == error.ControlFrame or err == error.NotWebSocket) {
-                continue;
-            }
-            stderr.writeAll("Accept error, continuing...\n") catch {};
-            continue;
-        };
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
 
-        switch (event) {
-            .connected => |client_id| {
-                var buf: [128]u8 = undefined;
-                const log_msg = std.fmt.bufPrint(&buf, "Client {} connected\n", .{client_id}) catch "Client connected\n";
-                stdout.writeAll(log_msg) catch {};
+fn appendToFile(path: []const u8, content: []const u8) !void {
+    const file = try std.fs.cwd().createFile(path, .{ .truncate = false });
+    defer file.close();
+    try file.seekFromEnd(0);
+    try file.writeAll(content);
+}
 
-                // Send welcome message
-                var welcome_buf: [256]u8 = undefined;
-                const welcome = std.fmt.bufPrint(&welcome_buf, "{{\"type\": \"system\", \"text\": \"Welcome to the chat! You are client #{}\"}}", .{client_id}) catch continue;
-                server.send(client_id, welcome) catch {};
+/// Directory the Kv module keeps its files in unless CHATSERVER_KV_DIR names another
+const default_kv_dir = "kv";
 
-                // Broadcast join message
-                var join_buf: [256]u8 = undefined;
-                const join = std.fmt.bufPrint(&join_buf, "{{\"type\": \"system\", \"text\": \"Client #{} joined the chat\"}}", .{client_id}) catch continue;
-                server.broadcast(join) catch {};
-            },
-            .disconnected => |client_id| {
-                var buf: [128]u8 = undefined;
-                const log_msg = std.fmt.bufPrint(&buf, "Client {} disconnected\n", .{client_id}) catch "Client disconnected\n";
-                stdout.writeAll(log_msg) catch {};
+/// Longest key in bytes; its hex file name and a temporary suffix must fit the
+/// usual 255-byte limit on file names
+const max_kv_key = 100;
 
-                // Broadcast leave message
-                var leave_buf: [256]u8 = undefined;
-                const leave = std.fmt.bufPrint(&leave_buf, "{{\"type\": \"system\", \"text\": \"Client #{} left the chat\"}}", .{client_id}) catch continue;
-                server.broadcast(leave) catch {};
-            },
-            .message => |msg| {
-                var buf: [4096]u8 = undefined;
-                const log_msg = std.fmt.bufPrint(&buf, "Client {}: {s}\n", .{ msg.client_id, msg.text }) catch "Client message\n";
-                stdout.writeAll(log_msg) catch {};
+/// Largest value Kv.get! loads
+const max_kv_value = 16 * 1024 * 1024;
 
-                // Broadcast message - need to escape text for JSON
-                var json_buf: [4096]u8 = undefined;
-                var writer = std.io.fixedBufferStream(&json_buf);
-                writer.writer().print("{{\"type\": \"message\", \"clientId\": {}, \"text\": \"", .{msg.client_id}) catch continue;
-                for (msg.text) |c| {
-                    switch (c) {
-                        '"' => writer.writer().writeAll("\\\"") catch {},
-                        '\\' => writer.writer().writeAll("\\\\") catch {},
-                        '\n' => writer.writer().writeAll("\\n") catch {},
-                        '\r' => writer.writer().writeAll("\\r") catch {},
-                        '\t' => writer.writer().writeAll("\\t") catch {},
-                        else => writer.writer().writeByte(c) catch {},
-                    }
-                }
-                writer.writer().writeAll("\"}") catch continue;
-                server.broadcast(json_buf[0..writer.pos]) catch {};
-            },
-            .err => |msg| {
-                stderr.writeAll("Error: ") catch {};
-                stderr.writeAll(msg) catch {};
-                stderr.writeAll("\n") catch {};
-            },
-            .shutdown => {
-                stdout.writeAll("Server shutting down\n") catch {};
-                result.payload = RocStr.empty();
-                result.discriminant = 1; // Ok
-                return;
-            },
-        }
+/// Durable key-value pairs, one file per key. The file name is the key in hex, so
+/// any key makes a valid name and a key prefix is a name prefix. A value is
+/// written to a temporary file and renamed over the old one, so a crash leaves
+/// either the old value or the new one.
+const KvStore = struct {
+    dir: std.fs.Dir,
+
+    /// Open the store under `path`, creating the directory if needed
+    fn open(path: []const u8) !KvStore {
+        return .{ .dir = try std.fs.cwd().makeOpenPath(path, .{ .iterate = true }) };
     }
-}
 
-/// WebServer.accept! : () => Event
-/// Event is [Connected(U64), Disconnected(U64), Message(U64, Str), Error(Str), Shutdown]
-fn hostedWebServerAccept(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
-    _ = args_ptr;
+    /// The store CHATSERVER_KV_DIR names, or `kv` under the working directory
+    fn openDefault() !KvStore {
+        const path = if (getenv("CHATSERVER_KV_DIR")) |dir| std.mem.span(dir) else default_kv_dir;
+        return open(path);
+    }
 
-    // Roc tag union layout: payload first (sized to largest), discriminant at end
-    // Alphabetical order: Connected=0, Disconnected=1, Error=2, Message=3, Shutdown=4
-    // Largest payload = Message(U64, Str) = 8 + 24 = 32 bytes
-    // discriminant_offset = 32, total size = 40 bytes (padded to 8-byte alignment)
-    const EventPayload = extern union {
-        // Connected/Disconnected: U64 at offset 0
-        client_id: u64,
-        // Error: Str at offset 0
-        err_str: RocStr,
-        // Message: U64 at offset 0, Str at offset 8
-        message: extern struct {
-            client_id: u64,
-            text: RocStr,
-        },
-        // Shutdown: no payload
-    };
+    fn close(self: *KvStore) void {
+        self.dir.close();
+    }
+
+    /// Value stored under `key`, owned by the caller; error.NotFound if there is none
+    fn get(self: *KvStore, allocator: std.mem.Allocator, key: []const u8) ![]u8 {
+        var name_buf: [2 * max_kv_key]u8 = undefined;
+        const name = try kvFileName(&name_buf, key);
+        return self.dir.readFileAlloc(allocator, name, max_kv_value) catch |err| switch (err) {
+            error.FileNotFound => return error.NotFound,
+            else => return err,
+        };
+    }
+
+    fn set(self: *KvStore, key: []const u8, value: []const u8) !void {
+        var name_buf: [2 * max_kv_key]u8 = undefined;
+        const name = try kvFileName(&name_buf, key);
+        // Random, so processes sharing the directory never write the same temporary file
+        var tmp_buf: [2 * max_kv_key + 24]u8 = undefined;
+        const tmp = std.fmt.bufPrint(&tmp_buf, "{s}.{d}.tmp", .{ name, std.crypto.random.int(u64) }) catch unreachable;
+        try self.dir.writeFile(.{ .sub_path = tmp, .data = value });
+        errdefer self.dir.deleteFile(tmp) catch {};
+        try self.dir.rename(tmp, name);
+    }
+
+    /// Remove `key`; a key that is not there is already deleted
+    fn delete(self: *KvStore, key: []const u8) !void {
+        var name_buf: [2 * max_kv_key]u8 = undefined;
+        const name = try kvFileName(&name_buf, key);
+        self.dir.deleteFile(name) catch |err| switch (err) {
+            error.FileNotFound => {},
+            else => return err,
+        };
+    }
+
+    /// Keys starting with `prefix`, sorted; each key and the slice are owned by the caller
+    fn listPrefix(self: *KvStore, allocator: std.mem.Allocator, prefix: []const u8) ![][]u8 {
+        // No key is longer than max_kv_key, so neither is a prefix that matches one
+        if (prefix.len > max_kv_key) return allocator.alloc([]u8, 0);
+        var prefix_buf: [2 * max_kv_key]u8 = undefined;
+        const name_prefix = hexEncode(&prefix_buf, prefix);
+
+        var keys = std.ArrayListUnmanaged([]u8){};
+        errdefer {
+            for (keys.items) |key| allocator.free(key);
+            keys.deinit(allocator);
+        }
+        var it = self.dir.iterate();
+        while (try it.next()) |entry| {
+            // Temporary files have a '.', which no hex name does
+            if (entry.kind != .file or std.mem.indexOfScalar(u8, entry.name, '.') != null) continue;
+            if (!std.mem.startsWith(u8, entry.name, name_prefix)) continue;
+            if (entry.name.len % 2 != 0) continue;
+            const key = try allocator.alloc(u8, entry.name.len / 2);
+            _ = std.fmt.hexToBytes(key, entry.name) catch {
+                allocator.free(key);
+                continue;
+            };
+            keys.append(allocator, key) catch |err| {
+                allocator.free(key);
+                return err;
+            };
+        }
+        std.mem.sort([]u8, keys.items, {}, struct {
+            fn lessThan(_: void, a: []u8, b: []u8) bool {
+                return std.mem.lessThan(u8, a, b);
+            }
+        }.lessThan);
+        return keys.toOwnedSlice(allocator);
+    }
+};
 
-    const Event = extern struct {
-        payload: EventPayload,
+/// File name for a Kv key: its bytes in lowercase hex
+fn kvFileName(buf: *[2 * max_kv_key]u8, key: []const u8) ![]const u8 {
+    if (key.len == 0) return error.EmptyKey;
+    if (key.len > max_kv_key) return error.KeyTooLong;
+    return hexEncode(buf, key);
+}
+
+fn hexEncode(buf: []u8, bytes: []const u8) []const u8 {
+    const digits = "0123456789abcdef";
+    for (bytes, 0..) |byte, i| {
+        buf[2 * i] = digits[byte >> 4];
+        buf[2 * i + 1] = digits[byte & 0x0F];
+    }
+    return buf[0 .. 2 * bytes.len];
+}
+
+/// Kv.get! : Str => Result(Str, Str)
+/// Value stored under a key; Err if there is none or it cannot be read
+fn hostedKvGet(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
         discriminant: u8,
     };
 
-    const result: *Event = @ptrCast(@alignCast(ret_ptr));
+    const Args = extern struct {
+        key: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
     const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+    const allocator = host.gpa.allocator();
 
-    const server = host.server orelse {
-        result.discriminant = 4; // Shutdown
+    const value = kvGet(allocator, getAsSlice(&args.key)) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = switch (err) {
+            error.NotFound => "No value for this key",
+            else => std.fmt.bufPrint(&buf, "Read failed: {}", .{err}) catch "Read failed",
+        };
+        result.payload = toRocStr(msg, ops);
+        result.discriminant = 0;
         return;
     };
+    defer allocator.free(value);
 
-    // Loop until we get a real event (skip ControlFrame and NotWebSocket errors)
-    while (true) {
-        const event = server.accept() catch |err| {
-            if (err == error.ControlFrame or err == error.NotWebSocket) {
-                // Skip these non-events and continue polling
-                continue;
-            }
-            const msg = "Accept error";
-            result.payload.err_str = RocStr.fromSliceSmall(msg);
-            result.discriminant = 2; // Error
-            return;
-        };
-
-        switch (event) {
-            .connected => |client_id| {
-                result.payload.client_id = client_id;
-                result.discriminant = 0; // Connected
-                return;
-            },
-            .disconnected => |client_id| {
-                result.payload.client_id = client_id;
-                result.discriminant = 1; // Disconnected
-                return;
-            },
-            .message => |msg| {
-                result.payload.message.client_id = msg.client_id;
-                // Create RocStr from message text
-                if (RocStr.fitsInSmallStr(msg.text.len)) {
-                    result.payload.message.text = RocStr.fromSliceSmall(msg.text);
-                } else {
-                    result.payload.message.text = RocStr.init(msg.text.ptr, msg.text.len, ops);
-                }
-                result.discriminant = 3; // Message
-                return;
-            },
-            .err => |msg| {
-                if (RocStr.fitsInSmallStr(msg.len)) {
-                    result.payload.err_str = RocStr.fromSliceSmall(msg);
-                } else {
-                    result.payload.err_str = RocStr.init(msg.ptr, msg.len, ops);
-                }
-                result.discriminant = 2; // Error
-                return;
-            },
-            .shutdown => {
-                result.discriminant = 4; // Shutdown
-                return;
-            },
-        }
+    if (!std.unicode.utf8ValidateSlice(value)) {
+        const msg = "Read failed: invalid UTF-8";
+        result.payload = toRocStr(msg, ops);
+        result.discriminant = 0;
+        return;
     }
+
+    result.payload = toRocStr(value, ops);
+    result.discriminant = 1; // Ok
 }
 
-/// WebServer.send! : U64, Str => Result({}, Str)
-fn hostedWebServerSend(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+fn kvGet(allocator: std.mem.Allocator, key: []const u8) ![]u8 {
+    var store = try KvStore.openDefault();
+    defer store.close();
+    return store.get(allocator, key);
+}
+
+/// Kv.set! : Str, Str => Result({}, Str)
+/// Store a value under a key, replacing any earlier one
+fn hostedKvSet(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
     const Result = extern struct {
         payload: RocStr,
         discriminant: u8,
     };
 
     const Args = extern struct {
-        client_id: u64,
-        message: RocStr,
+        key: RocStr,
+        value: RocStr,
     };
 
     const args: *Args = @ptrCast(@alignCast(args_ptr));
     const result: *Result = @ptrCast(@alignCast(ret_ptr));
-    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
 
-    const server = host.server orelse {
-        const msg = "Server not running";
-        result.payload = RocStr.fromSliceSmall(msg);
+    kvSet(getAsSlice(&args.key), getAsSlice(&args.value)) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "Write failed: {}", .{err}) catch "Write failed";
+        result.payload = toRocStr(msg, ops);
         result.discriminant = 0;
         return;
     };
 
-    const message = getAsSlice(&args.message);
-    server.send(args.client_id, message) catch |err| {
+    result.payload = RocStr.empty();
+    result.discriminant = 1; // Ok
+}
+
+fn kvSet(key: []const u8, value: []const u8) !void {
+    var store = try KvStore.openDefault();
+    defer store.close();
+    try store.set(key, value);
+}
+
+/// Kv.delete! : Str => Result({}, Str)
+/// Remove a key; deleting a key that is not there succeeds
+fn hostedKvDelete(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        key: RocStr,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+
+    kvDelete(getAsSlice(&args.key)) catch |err| {
         var buf: [128]u8 = undefined;
-        const msg = std.fmt.bufPrint(&buf, "Send failed: {}", .{err}) catch "Send failed";
-        if (RocStr.fitsInSmallStr(msg.len)) {
-            result.payload = RocStr.fromSliceSmall(msg);
-        } else {
-            result.payload = RocStr.init(msg.ptr, msg.len, ops);
-        }
+        const msg = std.fmt.bufPrint(&buf, "Delete failed: {}", .{err}) catch "Delete failed";
+        result.payload = toRocStr(msg, ops);
         result.discriminant = 0;
         return;
     };
@@ -849,59 +12002,209 @@ fn hostedWebServerSend(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args
     result.discriminant = 1; // Ok
 }
 
-/// WebServer.broadcast! : Str => Result({}, Str)
-fn hostedWebServerBroadcast(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+fn kvDelete(key: []const u8) !void {
+    var store = try KvStore.openDefault();
+    defer store.close();
+    try store.delete(key);
+}
+
+/// Kv.list_prefix! : Str => Result(List(Str), Str)
+/// Keys starting with a prefix, sorted; "" lists every key
+fn hostedKvListPrefix(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
     const Result = extern struct {
-        payload: RocStr,
+        payload: extern union {
+            keys: RocList,
+            err_str: RocStr,
+        },
         discriminant: u8,
     };
 
     const Args = extern struct {
-        message: RocStr,
+        prefix: RocStr,
     };
 
     const args: *Args = @ptrCast(@alignCast(args_ptr));
     const result: *Result = @ptrCast(@alignCast(ret_ptr));
     const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+    const allocator = host.gpa.allocator();
 
-    const server = host.server orelse {
-        const msg = "Server not running";
-        result.payload = RocStr.fromSliceSmall(msg);
+    const keys = kvListPrefix(allocator, getAsSlice(&args.prefix)) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "List failed: {}", .{err}) catch "List failed";
+        result.payload.err_str = toRocStr(msg, ops);
         result.discriminant = 0;
         return;
     };
+    defer {
+        for (keys) |key| allocator.free(key);
+        allocator.free(keys);
+    }
 
-    const message = getAsSlice(&args.message);
-    server.broadcast(message) catch |err| {
-        var buf: [128]u8 = undefined;
-        const msg = std.fmt.bufPrint(&buf, "Broadcast failed: {}", .{err}) catch "Broadcast failed";
-        if (RocStr.fitsInSmallStr(msg.len)) {
-            result.payload = RocStr.fromSliceSmall(msg);
-        } else {
-            result.payload = RocStr.init(msg.ptr, msg.len, ops);
+    var names = std.ArrayListUnmanaged(RocStr){};
+    defer names.deinit(allocator);
+    for (keys) |key| {
+        // Only files put there by hand can hold a key that is not text
+        if (!std.unicode.utf8ValidateSlice(key)) continue;
+        names.append(allocator, toRocStr(key, ops)) catch break;
+    }
+
+    result.payload.keys = RocList.fromSlice(RocStr, names.items, true, ops);
+    result.discriminant = 1; // Ok
+}
+
+fn kvListPrefix(allocator: std.mem.Allocator, prefix: []const u8) ![][]u8 {
+    var store = try KvStore.openDefault();
+    defer store.close();
+    return store.listPrefix(allocator, prefix);
+}
+
+/// Largest response body Http.fetch! reads
+const max_fetch_body = 16 * 1024 * 1024;
+
+const FetchResponse = struct {
+    status: u16,
+    headers: []const HttpHeader,
+    body: []const u8,
+};
+
+/// Make one HTTP/1.1 request over http:// or https:// (TLS with the system's CA
+/// bundle), following up to three redirects. Everything returned lives in `arena`.
+/// Blocks the calling thread until the response is read.
+fn httpFetch(arena: std.mem.Allocator, method_name: []const u8, url: []const u8, headers: []const std.http.Header, body: []const u8) !FetchResponse {
+    const method = std.meta.stringToEnum(std.http.Method, method_name) orelse return error.UnsupportedMethod;
+    const uri = try std.Uri.parse(url);
+
+    var client: std.http.Client = .{ .allocator = arena };
+    defer client.deinit();
+
+    var req = try client.request(method, uri, .{
+        .extra_headers = headers,
+        .keep_alive = false,
+        .redirect_behavior = .init(3),
+    });
+    defer req.deinit();
+
+    if (method.requestHasBody()) {
+        try req.sendBodyComplete(try arena.dupe(u8, body));
+    } else {
+        try req.sendBodiless();
+    }
+
+    var redirect_buf: [8 * 1024]u8 = undefined;
+    var response = try req.receiveHead(&redirect_buf);
+
+    // Header slices point into the connection buffer; copy them before reading the body
+    var response_headers = std.ArrayListUnmanaged(HttpHeader){};
+    var it = response.head.iterateHeaders();
+    while (it.next()) |h| {
+        if (response_headers.items.len == max_http_headers) break;
+        try response_headers.append(arena, .{ .name = try arena.dupe(u8, h.name), .value = try arena.dupe(u8, h.value) });
+    }
+    const status: u16 = @intFromEnum(response.head.status);
+
+    var transfer_buf: [64]u8 = undefined;
+    const reader = response.reader(&transfer_buf);
+    const response_body = reader.allocRemaining(arena, .limited(max_fetch_body)) catch |err| switch (err) {
+        error.ReadFailed => return response.bodyErr().?,
+        else => |e| return e,
+    };
+
+    return .{ .status = status, .headers = response_headers.items, .body = response_body };
+}
+
+/// Http.fetch! : Str, Str, List((Str, Str)), List(U8) => Result({ status : U16, headers : List((Str, Str)), body : List(U8) }, Str)
+/// Method, URL, request headers and body. Any status counts as Ok; Err is for
+/// requests that got no response at all.
+fn hostedHttpFetch(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    const Result = extern struct {
+        payload: extern union {
+            // Record fields sorted by alignment, then name
+            response: extern struct {
+                body: RocList,
+                headers: RocList,
+                status: u16,
+            },
+            err_str: RocStr,
+        },
+        discriminant: u8,
+    };
+
+    const Args = extern struct {
+        method: RocStr,
+        url: RocStr,
+        headers: RocList,
+        body: RocList,
+    };
+
+    const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+
+    var arena_state = std.heap.ArenaAllocator.init(host.gpa.allocator());
+    defer arena_state.deinit();
+    const arena = arena_state.allocator();
+
+    var request_headers: [max_http_headers]std.http.Header = undefined;
+    var header_count: usize = 0;
+    if (args.headers.elements(RocHeader)) |pairs| {
+        for (pairs[0..@min(args.headers.len(), max_http_headers)]) |*pair| {
+            request_headers[header_count] = .{ .name = getAsSlice(&pair.name), .value = getAsSlice(&pair.value) };
+            header_count += 1;
         }
+    }
+
+    const response = httpFetch(arena, getAsSlice(&args.method), getAsSlice(&args.url), request_headers[0..header_count], getListAsSlice(&args.body)) catch |err| {
+        var buf: [128]u8 = undefined;
+        const msg = std.fmt.bufPrint(&buf, "Fetch failed: {}", .{err}) catch "Fetch failed";
+        result.payload.err_str = toRocStr(msg, ops);
         result.discriminant = 0;
         return;
     };
 
-    result.payload = RocStr.empty();
+    result.payload.response.status = response.status;
+    result.payload.response.headers = toRocHeaders(response.headers, ops);
+    result.payload.response.body = RocList.fromSlice(u8, response.body, false, ops);
     result.discriminant = 1; // Ok
 }
 
-/// WebServer.close! : U64 => {}
-fn hostedWebServerClose(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
-    _ = ret_ptr;
+/// Largest Random.bytes! request
+const max_random_bytes = 1024 * 1024;
+
+/// Random.u64! : () => U64
+/// From the operating system's CSPRNG, so fine for ids, invite codes and tokens
+fn hostedRandomU64(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    _ = ops;
+    _ = args_ptr;
+
+    const result: *u64 = @ptrCast(@alignCast(ret_ptr));
+    result.* = std.crypto.random.int(u64);
+}
 
+/// Random.bytes! : U64 => List(U8)
+/// Cryptographically secure random bytes, at most 1 MiB per call
+fn hostedRandomBytes(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
     const Args = extern struct {
-        client_id: u64,
+        count: u64,
     };
 
     const args: *Args = @ptrCast(@alignCast(args_ptr));
+    const result: *RocList = @ptrCast(@alignCast(ret_ptr));
     const host: *HostEnv = @ptrCast(@alignCast(ops.env));
 
-    if (host.server) |server| {
-        server.closeClient(args.client_id);
+    const count: usize = @intCast(@min(args.count, max_random_bytes));
+    if (count == 0) {
+        result.* = RocList.empty();
+        return;
     }
+
+    const allocator = host.gpa.allocator();
+    const bytes = allocator.alloc(u8, count) catch {
+        result.* = RocList.empty();
+        return;
+    };
+    defer allocator.free(bytes);
+    std.crypto.random.bytes(bytes);
+    result.* = RocList.fromSlice(u8, bytes, false, ops);
 }
 
 /// Stderr.line! : Str => {}
@@ -920,6 +12223,48 @@ fn hostedStderrLine(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_pt
     stderr.writeAll("\n") catch {};
 }
 
+/// Stdin.line! : () => Result(Str, Str)
+/// Block until a line arrives on stdin; Err at end of input
+fn hostedStdinLine(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
+    _ = args_ptr;
+
+    const Result = extern struct {
+        payload: RocStr,
+        discriminant: u8,
+    };
+
+    const result: *Result = @ptrCast(@alignCast(ret_ptr));
+    const host: *HostEnv = @ptrCast(@alignCast(ops.env));
+    const allocator = host.gpa.allocator();
+
+    var line = std.ArrayListUnmanaged(u8){};
+    defer line.deinit(allocator);
+
+    // One byte at a time so nothing past the newline is consumed
+    const stdin = std.fs.File.stdin();
+    var byte: [1]u8 = undefined;
+    while (true) {
+        const n = stdin.read(&byte) catch |err| {
+            var buf: [128]u8 = undefined;
+            const msg = std.fmt.bufPrint(&buf, "Read failed: {}", .{err}) catch "Read failed";
+            result.payload = toRocStr(msg, ops);
+            result.discriminant = 0;
+            return;
+        };
+        if (n == 0) {
+            if (line.items.len > 0) break;
+            result.payload = RocStr.fromSliceSmall("End of input");
+            result.discriminant = 0;
+            return;
+        }
+        if (byte[0] == '\n') break;
+        line.append(allocator, byte[0]) catch break;
+    }
+
+    result.payload = toRocStr(std.mem.trimRight(u8, line.items, "\r"), ops);
+    result.discriminant = 1; // Ok
+}
+
 /// Stdout.line! : Str => {}
 fn hostedStdoutLine(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_ptr: *anyopaque) callconv(.c) void {
     _ = ops;
@@ -939,18 +12284,133 @@ fn hostedStdoutLine(ops: *builtins.host_abi.RocOps, ret_ptr: *anyopaque, args_pt
 /// Array of hosted function pointers, sorted by module name alphabetically,
 /// then by function name alphabetically within each module.
 const hosted_function_ptrs = [_]builtins.host_abi.HostedFn{
+    hostedClockMonotonicMillis,
+    hostedClockNowUtc,
+    hostedEnvArgs,
+    hostedEnvGet,
+    hostedFileAppend,
+    hostedFileReadUtf8,
+    hostedFileWriteUtf8,
+    hostedHttpFetch,
+    hostedKvDelete,
+    hostedKvGet,
+    hostedKvListPrefix,
+    hostedKvSet,
+    hostedRandomBytes,
+    hostedRandomU64,
     hostedStderrLine,
+    hostedStdinLine,
     hostedStdoutLine,
     hostedWebServerAccept,
+    hostedWebServerAcceptHandshake,
+    hostedWebServerAcceptTimeout,
+    hostedWebServerAddRoute,
+    hostedWebServerAddSubprotocol,
+    hostedWebServerAddWebhook,
+    hostedWebServerAllowAdminIp,
+    hostedWebServerAllowIp,
+    hostedWebServerAllowOrigin,
+    hostedWebServerBanClient,
+    hostedWebServerBanIp,
     hostedWebServerBroadcast,
+    hostedWebServerBroadcastBinary,
+    hostedWebServerBroadcastHost,
+    hostedWebServerBroadcastRoom,
+    hostedWebServerCancelTimer,
+    hostedWebServerClientSession,
+    hostedWebServerClientStats,
+    hostedWebServerClients,
     hostedWebServerClose,
+    hostedWebServerConfigure,
+    hostedWebServerConnect,
+    hostedWebServerCreateSession,
+    hostedWebServerDenyIp,
+    hostedWebServerDrain,
+    hostedWebServerEnvelope,
+    hostedWebServerGetClientData,
+    hostedWebServerGetRole,
+    hostedWebServerGraphqlComplete,
+    hostedWebServerGraphqlError,
+    hostedWebServerGraphqlNext,
+    hostedWebServerJoinRoom,
+    hostedWebServerLeaveRoom,
+    hostedWebServerListRooms,
     hostedWebServerListen,
+    hostedWebServerListenWithConfig,
+    hostedWebServerMuteClient,
+    hostedWebServerNotifyRoom,
+    hostedWebServerPublishRoom,
+    hostedWebServerRejectHandshake,
+    hostedWebServerReloadConfig,
+    hostedWebServerReplaySince,
+    hostedWebServerRequireAuth,
+    hostedWebServerRespond,
+    hostedWebServerRoomHistory,
+    hostedWebServerRouteHttp,
+    hostedWebServerRouteWebsocket,
+    hostedWebServerRpcError,
+    hostedWebServerRpcRespond,
     hostedWebServerRun,
     hostedWebServerSend,
+    hostedWebServerSendBinary,
+    hostedWebServerSendMany,
+    hostedWebServerSendWithAck,
+    hostedWebServerSetAdminToken,
+    hostedWebServerSetBasePath,
+    hostedWebServerSetCacheControl,
+    hostedWebServerSetClientData,
+    hostedWebServerSetConnectRate,
+    hostedWebServerSetDirectoryListing,
+    hostedWebServerSetEnvelopeProtocol,
+    hostedWebServerSetErrorPage,
+    hostedWebServerSetEventQueueLimit,
+    hostedWebServerSetHandshakePolicy,
+    hostedWebServerSetHandshakeTimeout,
+    hostedWebServerSetHistoryStore,
+    hostedWebServerSetHookSecret,
+    hostedWebServerSetKeepalive,
+    hostedWebServerSetLogLevel,
+    hostedWebServerSetMaxBodySize,
+    hostedWebServerSetMaxConnections,
+    hostedWebServerSetMaxFrameSize,
+    hostedWebServerSetMaxHeaderSize,
+    hostedWebServerSetMaxMessageSize,
+    hostedWebServerSetMessageRate,
+    hostedWebServerSetMimeType,
+    hostedWebServerSetResumeGrace,
+    hostedWebServerSetRole,
+    hostedWebServerSetRoomHistory,
+    hostedWebServerSetRoomPolicy,
+    hostedWebServerSetRpcProtocol,
+    hostedWebServerSetSendQueueLimit,
+    hostedWebServerSetSessionSecret,
+    hostedWebServerSetSocketOptions,
+    hostedWebServerSetSocketio,
+    hostedWebServerSetStaticBuffer,
+    hostedWebServerSetStaticCache,
+    hostedWebServerSetTimer,
+    hostedWebServerSetUploads,
+    hostedWebServerSetVirtualHost,
+    hostedWebServerShutdown,
+    hostedWebServerSocketioAck,
+    hostedWebServerSocketioEmit,
+    hostedWebServerStats,
+    hostedWebServerTrustProxy,
+    hostedWebServerTryAccept,
+    hostedWebServerUnbanIp,
+    hostedWebServerUnmuteClient,
+    hostedWebServerWatchStdin,
 };
 
 /// Platform host entrypoint
 fn platform_main() c_int {
+    if (getenv("CHATSERVER_LOG")) |level| {
+        logger.level = LogLevel.parse(std.mem.span(level)) orelse .info;
+    }
+    if (getenv("CHATSERVER_LOG_FORMAT")) |format| {
+        logger.json = std.mem.eql(u8, std.mem.span(format), "json");
+    }
+
     var host_env = HostEnv{
         .gpa = std.heap.GeneralPurposeAllocator(.{}){},
         .server = null,
@@ -988,3 +12448,330 @@ fn platform_main() c_int {
 
     return exit_code;
 }
+
+const testing = std.testing;
+
+/// A client attached to `server` through a socketpair instead of the listener.
+/// The test holds the other end and speaks HTTP and frames over it, so the
+/// handshake, framing, send and broadcast paths run without a network or a port.
+const Loopback = struct {
+    server: *WebSocketServer,
+    client_id: u64,
+    peer: std.net.Stream,
+    /// Bytes from the server not consumed yet
+    inbox: std.ArrayListUnmanaged(u8) = .{},
+
+    fn open(server: *WebSocketServer) !Loopback {
+        var fds: [2]std.posix.fd_t = undefined;
+        if (std.c.socketpair(std.posix.AF.UNIX, std.posix.SOCK.STREAM, 0, &fds) != 0) return error.SystemResources;
+        errdefer {
+            std.posix.close(fds[0]);
+            std.posix.close(fds[1]);
+        }
+        // A test waiting on output that never comes fails instead of hanging
+        const timeout = std.posix.timeval{ .sec = 1, .usec = 0 };
+        try std.posix.setsockopt(fds[1], std.posix.SOL.SOCKET, std.posix.SO.RCVTIMEO, std.mem.asBytes(&timeout));
+
+        const client_id = server.next_client_id;
+        try server.clients.put(client_id, .{
+            .id = client_id,
+            .stream = .{ .handle = fds[0] },
+            .is_websocket = false,
+            .last_activity_ms = std.time.milliTimestamp(),
+            .connected_ms = std.time.milliTimestamp(),
+        });
+        server.next_client_id += 1;
+        return .{ .server = server, .client_id = client_id, .peer = .{ .handle = fds[1] } };
+    }
+
+    /// Close the peer end; the server end goes with its client
+    fn deinit(self: *Loopback) void {
+        self.peer.close();
+        self.inbox.deinit(testing.allocator);
+    }
+
+    /// Upgrade the connection as a browser would and check the 101 that comes back
+    fn handshake(self: *Loopback) !WebSocketEvent {
+        // The sample key and accept value from RFC 6455 section 1.3
+        try self.peer.writeAll("GET /chat HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n" ++
+            "Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n");
+        const event = try self.server.handleNewConnection(self.client_id);
+        const head = try self.readHead();
+        try testing.expect(std.mem.startsWith(u8, head, "HTTP/1.1 101 "));
+        try testing.expect(std.mem.indexOf(u8, head, "Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n") != null);
+        self.consume(head.len);
+        return event;
+    }
+
+    /// Send a frame masked, as clients must
+    fn sendFrame(self: *Loopback, opcode: codec.Opcode, payload: []const u8) !void {
+        var out = std.ArrayListUnmanaged(u8){};
+        defer out.deinit(testing.allocator);
+        try self.peer.writeAll(try codec.encode(testing.allocator, &out, opcode, payload, .{ 0x12, 0x34, 0x56, 0x78 }));
+    }
+
+    /// Read the server's next frame, which must be unmasked, and check it
+    fn expectFrame(self: *Loopback, opcode: codec.Opcode, payload: []const u8) !void {
+        while (true) {
+            if (try codec.decode(self.inbox.items, .{ .masked = false, .max_payload = 1 << 20 })) |frame| {
+                try testing.expectEqual(opcode, frame.opcode);
+                try testing.expectEqualStrings(payload, frame.payload);
+                self.consume(frame.len);
+                return;
+            }
+            try self.fill();
+        }
+    }
+
+    /// Read until a whole HTTP response head is buffered and return it
+    fn readHead(self: *Loopback) ![]const u8 {
+        while (true) {
+            if (std.mem.indexOf(u8, self.inbox.items, "\r\n\r\n")) |end| return self.inbox.items[0 .. end + 4];
+            try self.fill();
+        }
+    }
+
+    fn fill(self: *Loopback) !void {
+        try self.inbox.ensureUnusedCapacity(testing.allocator, 4096);
+        const n = try self.peer.read(self.inbox.unusedCapacitySlice());
+        if (n == 0) return error.ConnectionClosed;
+        self.inbox.items.len += n;
+    }
+
+    fn consume(self: *Loopback, len: usize) void {
+        const rest = self.inbox.items.len - len;
+        std.mem.copyForwards(u8, self.inbox.items[0..rest], self.inbox.items[len..]);
+        self.inbox.shrinkRetainingCapacity(rest);
+    }
+};
+
+test "loopback handshake, message and send" {
+    var server = WebSocketServer.init(testing.allocator);
+    defer server.deinit();
+    var client = try Loopback.open(&server);
+    defer client.deinit();
+
+    try testing.expectEqual(client.client_id, (try client.handshake()).connected);
+
+    try client.sendFrame(.text, "hello");
+    const event = try server.handleClientData(client.client_id);
+    defer event.deinit(testing.allocator);
+    try testing.expectEqual(client.client_id, event.message.client_id);
+    try testing.expectEqualStrings("hello", event.message.text);
+
+    try server.send(client.client_id, "hi there");
+    try client.expectFrame(.text, "hi there");
+}
+
+test "loopback ping is answered with the same payload" {
+    var server = WebSocketServer.init(testing.allocator);
+    defer server.deinit();
+    var client = try Loopback.open(&server);
+    defer client.deinit();
+    _ = try client.handshake();
+
+    try client.sendFrame(.ping, "are you there");
+    try testing.expectError(error.ControlFrame, server.handleClientData(client.client_id));
+    try client.expectFrame(.pong, "are you there");
+}
+
+test "loopback broadcast reaches every client" {
+    var server = WebSocketServer.init(testing.allocator);
+    defer server.deinit();
+    var first = try Loopback.open(&server);
+    defer first.deinit();
+    var second = try Loopback.open(&server);
+    defer second.deinit();
+    _ = try first.handshake();
+    _ = try second.handshake();
+
+    try server.broadcast("everyone");
+    try first.expectFrame(.text, "everyone");
+    try second.expectFrame(.text, "everyone");
+}
+
+test "loopback close is echoed and removes the client" {
+    var server = WebSocketServer.init(testing.allocator);
+    defer server.deinit();
+    var client = try Loopback.open(&server);
+    defer client.deinit();
+    _ = try client.handshake();
+
+    try client.sendFrame(.close, "\x03\xe8bye");
+    const event = try server.handleClientData(client.client_id);
+    defer event.deinit(testing.allocator);
+    try testing.expectEqual(@as(u16, 1000), event.disconnected.code);
+    try testing.expectEqualStrings("bye", event.disconnected.reason);
+    try testing.expect(!server.clients.contains(client.client_id));
+    try client.expectFrame(.close, "\x03\xe8");
+}
+
+test "loopback request head split across reads" {
+    var server = WebSocketServer.init(testing.allocator);
+    defer server.deinit();
+    var client = try Loopback.open(&server);
+    defer client.deinit();
+
+    try client.peer.writeAll("GET /healthz HTTP/1.1\r\nHo");
+    try testing.expectError(error.PartialMessage, server.handleNewConnection(client.client_id));
+    try client.peer.writeAll("st: localhost\r\n\r\n");
+    try testing.expectError(error.NotWebSocket, server.handleNewConnection(client.client_id));
+    // Nothing is listening, so the liveness probe fails
+    const head = try client.readHead();
+    try testing.expect(std.mem.startsWith(u8, head, "HTTP/1.1 503 "));
+    try testing.expect(std.mem.indexOf(u8, head, "Content-Type: application/json\r\n") != null);
+}
+
+test "loopback malformed request gets 400" {
+    var server = WebSocketServer.init(testing.allocator);
+    defer server.deinit();
+    var client = try Loopback.open(&server);
+    defer client.deinit();
+
+    try client.peer.writeAll("get / HTTP/1.1\r\n\r\n");
+    try testing.expectError(error.NotWebSocket, server.handleNewConnection(client.client_id));
+    try testing.expect(std.mem.startsWith(u8, try client.readHead(), "HTTP/1.1 400 Bad Request\r\n"));
+}
+
+test "naturally aligned Roc blocks pair with realloc and free" {
+    const alignment = std.mem.Alignment.of(usize);
+    const block = allocBlock(16, alignment) orelse return error.OutOfMemory;
+    @memcpy(block[0..5], "hello");
+    // What rocReallocFn does for these blocks
+    const grown: [*]u8 = @ptrCast(std.c.realloc(block, 4096) orelse return error.OutOfMemory);
+    try testing.expectEqualStrings("hello", grown[0..5]);
+    freeBlock(grown, alignment);
+}
+
+test "over-aligned Roc blocks are aligned and remember their length" {
+    const alignment = std.mem.Alignment.fromByteUnits(64);
+    const block = allocBlock(100, alignment) orelse return error.OutOfMemory;
+    defer freeBlock(block, alignment);
+    try testing.expect(alignment.check(@intFromPtr(block)));
+    try testing.expectEqual(@as(usize, 100), blockLength(block));
+}
+
+test "sanitizeRequestPath normalizes plain paths" {
+    var buf: [256]u8 = undefined;
+    try testing.expectEqualStrings("css/site.css", try sanitizeRequestPath(&buf, "/css/./site.css"));
+    try testing.expectEqualStrings("a b/c", try sanitizeRequestPath(&buf, "//a%20b//c/"));
+    try testing.expectEqualStrings("", try sanitizeRequestPath(&buf, "/"));
+}
+
+test "sanitizeRequestPath rejects encoded traversal" {
+    var buf: [256]u8 = undefined;
+    for ([_][]const u8{
+        "/../etc/passwd",
+        "/%2e%2e/etc/passwd",
+        "/%2E%2E/etc/passwd",
+        "/..%2fetc/passwd",
+        "/%2e%2e%5cetc",
+        "/a/..\\b",
+    }) |raw| {
+        try testing.expectError(error.InvalidPath, sanitizeRequestPath(&buf, raw));
+    }
+}
+
+test "sanitizeRequestPath rejects NUL" {
+    var buf: [256]u8 = undefined;
+    try testing.expectError(error.InvalidPath, sanitizeRequestPath(&buf, "/index.html%00.png"));
+}
+
+test "sanitizeRequestPath decodes only once" {
+    var buf: [256]u8 = undefined;
+    // %25 is a literal '%', so this names a file called "%2e%2e", not ".."
+    try testing.expectEqualStrings("%2e%2e/x", try sanitizeRequestPath(&buf, "/%252e%252e/x"));
+}
+
+test "sanitizeRequestPath rejects malformed escapes" {
+    var buf: [256]u8 = undefined;
+    for ([_][]const u8{ "/a%", "/a%2", "/a%zz", "/a%+f", "/a%-1", "/a%2g" }) |raw| {
+        try testing.expectError(error.InvalidPath, sanitizeRequestPath(&buf, raw));
+    }
+}
+
+test "KvStore sets, replaces, lists and deletes keys" {
+    var tmp = testing.tmpDir(.{ .iterate = true });
+    defer tmp.cleanup();
+    var store = KvStore{ .dir = tmp.dir };
+
+    try store.set("user:alice", "admin");
+    try store.set("user:bob", "member");
+    try store.set("room:general/topic", "Say hi");
+    try store.set("user:alice", "owner");
+
+    const alice = try store.get(testing.allocator, "user:alice");
+    defer testing.allocator.free(alice);
+    try testing.expectEqualStrings("owner", alice);
+
+    const users = try store.listPrefix(testing.allocator, "user:");
+    defer {
+        for (users) |key| testing.allocator.free(key);
+        testing.allocator.free(users);
+    }
+    try testing.expectEqual(@as(usize, 2), users.len);
+    try testing.expectEqualStrings("user:alice", users[0]);
+    try testing.expectEqualStrings("user:bob", users[1]);
+
+    try store.delete("user:bob");
+    try store.delete("user:bob");
+    try testing.expectError(error.NotFound, store.get(testing.allocator, "user:bob"));
+}
+
+test "KvStore rejects empty and over-long keys" {
+    var tmp = testing.tmpDir(.{ .iterate = true });
+    defer tmp.cleanup();
+    var store = KvStore{ .dir = tmp.dir };
+
+    try testing.expectError(error.EmptyKey, store.set("", "x"));
+    try testing.expectError(error.KeyTooLong, store.set("k" ** (max_kv_key + 1), "x"));
+    try store.set("k" ** max_kv_key, "x");
+}
+
+test "HistoryStore returns a room's last messages, oldest first" {
+    var tmp = testing.tmpDir(.{});
+    defer tmp.cleanup();
+    var store = HistoryStore{ .dir = tmp.dir };
+    var arena_state = std.heap.ArenaAllocator.init(testing.allocator);
+    defer arena_state.deinit();
+    const arena = arena_state.allocator();
+
+    try store.append(testing.allocator, .{ .client_id = 1, .received_ms = 1000, .room = "general", .text = "first" });
+    try store.append(testing.allocator, .{ .client_id = 2, .received_ms = 2000, .room = "random", .text = "elsewhere" });
+    try store.append(testing.allocator, .{ .client_id = 2, .received_ms = 3000, .room = "general", .text = "say \"hi\"\nthere" });
+    try store.append(testing.allocator, .{ .client_id = 3, .received_ms = 4000, .room = "general", .text = "third" });
+
+    const last_two = try store.history(arena, "general", 2);
+    try testing.expectEqual(@as(usize, 2), last_two.len);
+    try testing.expectEqual(@as(u64, 2), last_two[0].client_id);
+    try testing.expectEqual(@as(u64, 3000), last_two[0].received_ms);
+    try testing.expectEqualStrings("say \"hi\"\nthere", last_two[0].text);
+    try testing.expectEqualStrings("third", last_two[1].text);
+
+    const all = try store.history(arena, "general", 100);
+    try testing.expectEqual(@as(usize, 3), all.len);
+    try testing.expectEqualStrings("first", all[0].text);
+    try testing.expectEqualStrings("general", all[0].room);
+
+    try testing.expectEqual(@as(usize, 0), (try store.history(arena, "general", 0)).len);
+    try testing.expectEqual(@as(usize, 0), (try store.history(arena, "empty", 10)).len);
+}
+
+test "HistoryStore skips a torn last line" {
+    var tmp = testing.tmpDir(.{});
+    defer tmp.cleanup();
+    var store = HistoryStore{ .dir = tmp.dir };
+    var arena_state = std.heap.ArenaAllocator.init(testing.allocator);
+    defer arena_state.deinit();
+
+    try store.append(testing.allocator, .{ .client_id = 1, .received_ms = 1000, .room = "general", .text = "kept" });
+    var name_buf: [history_file_name_len]u8 = undefined;
+    const file = try tmp.dir.openFile(historyFileName(&name_buf, "general"), .{ .mode = .write_only });
+    defer file.close();
+    try file.seekFromEnd(0);
+    try file.writeAll("{\"ts\":2000,\"clientId\":1,\"ro");
+
+    const entries = try store.history(arena_state.allocator(), "general", 10);
+    try testing.expectEqual(@as(usize, 1), entries.len);
+    try testing.expectEqualStrings("kept", entries[0].text);
+}
@@ -0,0 +1,320 @@
+//! HTTP/1.x request parsing (RFC 9112)
+//! Reads a request head and its Content-Length or chunked body out of a byte
+//! buffer, with no knowledge of sockets or clients
+const std = @import("std");
+
+pub const Header = struct {
+    name: []const u8,
+    value: []const u8,
+};
+
+/// Most headers accepted in one request
+pub const max_headers = 64;
+
+/// A parsed HTTP/1.x request. Slices point into the buffer it was parsed from.
+pub const Request = struct {
+    method: []const u8,
+    /// Request target as sent, including any query string
+    target: []const u8,
+    /// Target without the query string
+    path: []const u8,
+    /// Text after '?', empty if there is none
+    query: []const u8,
+    version: []const u8,
+    headers: [max_headers]Header = undefined,
+    header_count: usize = 0,
+    body: []const u8 = "",
+    /// Bytes of the buffer this request occupies
+    len: usize = 0,
+
+    /// First value of a header, matched case-insensitively
+    pub fn header(self: *const Request, name: []const u8) ?[]const u8 {
+        for (self.headers[0..self.header_count]) |h| {
+            if (std.ascii.eqlIgnoreCase(h.name, name)) return h.value;
+        }
+        return null;
+    }
+
+    /// How many times a header appears
+    pub fn headerCount(self: *const Request, name: []const u8) usize {
+        var count: usize = 0;
+        for (self.headers[0..self.header_count]) |h| {
+            if (std.ascii.eqlIgnoreCase(h.name, name)) count += 1;
+        }
+        return count;
+    }
+
+    /// Whether a comma-separated header contains `token` (case-insensitive)
+    pub fn headerHasToken(self: *const Request, name: []const u8, token: []const u8) bool {
+        for (self.headers[0..self.header_count]) |h| {
+            if (!std.ascii.eqlIgnoreCase(h.name, name)) continue;
+            var it = std.mem.splitScalar(u8, h.value, ',');
+            while (it.next()) |part| {
+                if (std.ascii.eqlIgnoreCase(std.mem.trim(u8, part, " \t"), token)) return true;
+            }
+        }
+        return false;
+    }
+
+    pub fn isWebSocketUpgrade(self: *const Request) bool {
+        return std.mem.eql(u8, self.method, "GET") and
+            self.headerHasToken("Upgrade", "websocket") and
+            self.headerHasToken("Connection", "upgrade");
+    }
+};
+
+/// Parse the request line and headers of an HTTP/1.x request at the start of `buf`.
+/// Returns null until the blank line ending the head has arrived; the body is read
+/// separately by readBody.
+pub fn parseHead(buf: []const u8) !?Request {
+    const head_end = std.mem.indexOf(u8, buf, "\r\n\r\n") orelse return null;
+    const head = buf[0..head_end];
+
+    var lines = std.mem.splitSequence(u8, head, "\r\n");
+    const request_line = lines.next() orelse return error.BadRequest;
+
+    // Request line: METHOD SP TARGET SP VERSION
+    var parts = std.mem.splitScalar(u8, request_line, ' ');
+    const method = parts.next() orelse return error.BadRequest;
+    const target = parts.next() orelse return error.BadRequest;
+    const version = parts.next() orelse return error.BadRequest;
+    if (parts.next() != null) return error.BadRequest;
+    if (method.len == 0 or target.len == 0) return error.BadRequest;
+    for (method) |c| {
+        if (!std.ascii.isUpper(c)) return error.BadRequest;
+    }
+    if (!std.mem.eql(u8, version, "HTTP/1.1") and !std.mem.eql(u8, version, "HTTP/1.0")) {
+        return error.UnsupportedVersion;
+    }
+
+    var request = Request{
+        .method = method,
+        .target = target,
+        .path = target,
+        .query = "",
+        .version = version,
+    };
+    if (std.mem.indexOfScalar(u8, target, '?')) |q| {
+        request.path = target[0..q];
+        request.query = target[q + 1 ..];
+    }
+
+    while (lines.next()) |line| {
+        // Obsolete line folding is rejected (RFC 9112 section 5.2)
+        if (line.len == 0 or line[0] == ' ' or line[0] == '\t') return error.BadRequest;
+        const colon = std.mem.indexOfScalar(u8, line, ':') orelse return error.BadRequest;
+        const name = line[0..colon];
+        if (name.len == 0) return error.BadRequest;
+        for (name) |c| {
+            if (c <= ' ' or c >= 0x7F) return error.BadRequest;
+        }
+        if (request.header_count == max_headers) return error.TooManyHeaders;
+        request.headers[request.header_count] = .{
+            .name = name,
+            .value = std.mem.trim(u8, line[colon + 1 ..], " \t"),
+        };
+        request.header_count += 1;
+    }
+
+    request.len = head_end + 4;
+    return request;
+}
+
+/// Complete `request` with its body, framed by Content-Length or chunked
+/// Transfer-Encoding. Returns false while the body is still arriving. Chunked
+/// bodies are decoded in place once complete, so `buf` must not be re-parsed after.
+pub fn readBody(buf: []u8, request: *Request, max_body_size: usize) !bool {
+    const body_start = request.len;
+    const content_length = request.header("Content-Length");
+    // A repeated framing header may be read differently by a proxy in front
+    if (request.headerCount("Content-Length") > 1 or request.headerCount("Transfer-Encoding") > 1) {
+        return error.BadRequest;
+    }
+
+    if (request.header("Transfer-Encoding")) |encoding| {
+        // Both framings at once is a request smuggling vector (RFC 9112 section 6.3)
+        if (content_length != null) return error.BadRequest;
+        if (!std.ascii.eqlIgnoreCase(std.mem.trim(u8, encoding, " \t"), "chunked")) {
+            return error.UnsupportedTransferEncoding;
+        }
+        const framing = (try scanChunkedBody(buf, body_start, max_body_size)) orelse return false;
+        request.body = decodeChunkedBody(buf, body_start, framing.body_len);
+        request.len = framing.end;
+        return true;
+    }
+
+    var body_len: usize = 0;
+    if (content_length) |value| {
+        body_len = parseNumber(value, 10) orelse return error.BadRequest;
+    }
+    if (body_len > max_body_size) return error.BodyTooLarge;
+    if (buf.len - body_start < body_len) return false;
+
+    request.body = buf[body_start .. body_start + body_len];
+    request.len = body_start + body_len;
+    return true;
+}
+
+/// A Content-Length or chunk size: digits of `base` only. std.fmt.parseInt also
+/// takes a sign and '_' separators, which a proxy in front would read differently.
+fn parseNumber(text: []const u8, base: u8) ?usize {
+    if (text.len == 0) return null;
+    var value: usize = 0;
+    for (text) |c| {
+        const digit = std.fmt.charToDigit(c, base) catch return null;
+        value = std.math.mul(usize, value, base) catch return null;
+        value = std.math.add(usize, value, digit) catch return null;
+    }
+    return value;
+}
+
+/// Walk a chunked body without modifying it. Returns null if it is incomplete,
+/// otherwise where the message ends and the decoded body length.
+fn scanChunkedBody(buf: []const u8, start: usize, max_body_size: usize) !?struct { end: usize, body_len: usize } {
+    var pos = start;
+    var total: usize = 0;
+    while (true) {
+        const line_end = std.mem.indexOfPos(u8, buf, pos, "\r\n") orelse {
+            if (buf.len - pos > 1024) return error.BadRequest; // runaway chunk-size line
+            return null;
+        };
+        const size_field = buf[pos..line_end];
+        const size_text = size_field[0 .. std.mem.indexOfScalar(u8, size_field, ';') orelse size_field.len];
+        const size = parseNumber(std.mem.trim(u8, size_text, " \t"), 16) orelse return error.BadRequest;
+        pos = line_end + 2;
+        if (size == 0) break;
+
+        if (size > max_body_size - total) return error.BodyTooLarge;
+        total += size;
+        // Subtract rather than add, so a huge size cannot wrap around
+        if (buf.len - pos < size or buf.len - pos - size < 2) return null;
+        if (!std.mem.eql(u8, buf[pos + size .. pos + size + 2], "\r\n")) return error.BadRequest;
+        pos += size + 2;
+    }
+
+    // Trailer fields are skipped up to the terminating blank line
+    while (true) {
+        const line_end = std.mem.indexOfPos(u8, buf, pos, "\r\n") orelse return null;
+        if (line_end == pos) return .{ .end = pos + 2, .body_len = total };
+        pos = line_end + 2;
+    }
+}
+
+/// Compact a complete, already scanned chunked body to `buf[start..start + body_len]`
+fn decodeChunkedBody(buf: []u8, start: usize, body_len: usize) []const u8 {
+    var read = start;
+    var write = start;
+    while (write < start + body_len) {
+        const line_end = std.mem.indexOfPos(u8, buf, read, "\r\n").?;
+        const size_field = buf[read..line_end];
+        const size_text = size_field[0 .. std.mem.indexOfScalar(u8, size_field, ';') orelse size_field.len];
+        const size = parseNumber(std.mem.trim(u8, size_text, " \t"), 16).?;
+        read = line_end + 2;
+        std.mem.copyForwards(u8, buf[write .. write + size], buf[read .. read + size]);
+        write += size;
+        read += size + 2;
+    }
+    return buf[start .. start + body_len];
+}
+
+const testing = std.testing;
+
+test "readBody decodes a chunked body in place" {
+    var buf = "POST /send HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5;x=y\r\nhello\r\n6\r\n world\r\n0\r\nX-Trailer: 1\r\n\r\nnext".*;
+    var request = (try parseHead(&buf)).?;
+    try testing.expect(try readBody(&buf, &request, 1024));
+    try testing.expectEqualStrings("hello world", request.body);
+    try testing.expectEqualStrings("next", buf[request.len..]);
+}
+
+test "scanChunkedBody waits for a huge chunk instead of overflowing" {
+    const size_line = "ffffffffffffffff\r\nab";
+    try testing.expect(try scanChunkedBody(size_line, 0, std.math.maxInt(usize)) == null);
+    try testing.expectError(error.BodyTooLarge, scanChunkedBody(size_line, 0, 1024));
+}
+
+test "readBody reads a Content-Length body" {
+    var buf = "POST /send HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello".*;
+    var request = (try parseHead(&buf)).?;
+    try testing.expect(try readBody(&buf, &request, 1024));
+    try testing.expectEqualStrings("hello", request.body);
+    try testing.expectEqual(buf.len, request.len);
+}
+
+test "readBody rejects ambiguous framing" {
+    for ([_][]const u8{
+        // Duplicate Content-Length, even with equal values
+        "POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 5\r\n\r\nhello",
+        // Content-Length and Transfer-Encoding together
+        "POST / HTTP/1.1\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n",
+        // Signs, separators and other digits parseInt would accept
+        "POST / HTTP/1.1\r\nContent-Length: +5\r\n\r\nhello",
+        "POST / HTTP/1.1\r\nContent-Length: -5\r\n\r\nhello",
+        "POST / HTTP/1.1\r\nContent-Length: 0_5\r\n\r\nhello",
+        "POST / HTTP/1.1\r\nContent-Length: 0x5\r\n\r\nhello",
+        "POST / HTTP/1.1\r\nContent-Length: \r\n\r\nhello",
+        "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n+5\r\nhello\r\n0\r\n\r\n",
+        "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0_5\r\nhello\r\n0\r\n\r\n",
+    }) |raw| {
+        const buf = try testing.allocator.dupe(u8, raw);
+        defer testing.allocator.free(buf);
+        var request = (try parseHead(buf)).?;
+        try testing.expectError(error.BadRequest, readBody(buf, &request, 1024));
+    }
+}
+
+test "readBody refuses transfer codings other than chunked" {
+    for ([_][]const u8{ "gzip", "gzip, chunked", "identity" }) |coding| {
+        var raw_buf: [128]u8 = undefined;
+        const raw = try std.fmt.bufPrint(&raw_buf, "POST / HTTP/1.1\r\nTransfer-Encoding: {s}\r\n\r\n0\r\n\r\n", .{coding});
+        var request = (try parseHead(raw)).?;
+        try testing.expectError(error.UnsupportedTransferEncoding, readBody(raw, &request, 1024));
+    }
+}
+
+test "fuzz parseHead and readBody" {
+    try testing.fuzz({}, fuzzRequest, .{ .corpus = &.{
+        "GET /chat?room=a HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n" ++
+            "Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        "POST /send HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello",
+        "POST /send HTTP/1.0\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n",
+    } });
+}
+
+fn fuzzRequest(_: void, input: []const u8) anyerror!void {
+    const buf = try testing.allocator.dupe(u8, input);
+    defer testing.allocator.free(buf);
+
+    var request = (parseHead(buf) catch return) orelse return;
+    try testing.expect(request.len <= buf.len);
+    try testing.expect(request.header_count <= max_headers);
+    // The upgrade checks only read what was parsed
+    if (request.isWebSocketUpgrade()) _ = request.header("Sec-WebSocket-Key");
+
+    const max_body = 1024;
+    if (!(readBody(buf, &request, max_body) catch return)) return;
+    try testing.expect(request.len <= buf.len);
+    try testing.expect(request.body.len <= max_body);
+    // The body lies inside the request
+    const offset = @intFromPtr(request.body.ptr) -% @intFromPtr(buf.ptr);
+    try testing.expect(offset + request.body.len <= request.len);
+}
+
+test "fuzz scanChunkedBody" {
+    try testing.fuzz({}, fuzzChunkedBody, .{ .corpus = &.{
+        "5\r\nhello\r\n0\r\n\r\n",
+        "a;name=value\r\n0123456789\r\n0\r\nX-Trailer: y\r\n\r\n",
+    } });
+}
+
+fn fuzzChunkedBody(_: void, input: []const u8) anyerror!void {
+    const max_body = 4096;
+    const framing = (scanChunkedBody(input, 0, max_body) catch return) orelse return;
+    try testing.expect(framing.end <= input.len);
+    try testing.expect(framing.body_len <= max_body);
+
+    // Whatever the scan accepts decodes in place to the length it reported
+    const buf = try testing.allocator.dupe(u8, input);
+    defer testing.allocator.free(buf);
+    try testing.expectEqual(framing.body_len, decodeChunkedBody(buf, 0, framing.body_len).len);
+}
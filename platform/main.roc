@@ -1,6 +1,6 @@
 platform ""
     requires {} { main! : {} => Try({}, [Exit(I32)]) }
-    exposes [Stdout, Stderr, WebServer]
+    exposes [Stdout, Stderr, Stdin, WebServer, Http, Clock, Random, Env, File, Kv]
     packages {}
     provides { main_for_host!: "main_for_host" }
     targets: {
@@ -20,7 +20,14 @@ platform ""
 
 import Stdout
 import Stderr
+import Stdin
 import WebServer
+import Http
+import Clock
+import Random
+import Env
+import File
+import Kv
 
 
 main_for_host! : {} => I32